@@ -0,0 +1,15 @@
+#![no_main]
+use electrs::store::{BlockRow, DBRow};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte tells where the key ends and the value starts
+    if let Some((&key_len, rest)) = data.split_first() {
+        let (key, value) = rest.split_at((key_len as usize).min(rest.len()));
+        let row = DBRow {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let _ = BlockRow::from_row(row);
+    }
+});
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut response = data;
+    let _ = electrs::daemon::read_response(&mut response, |_| Ok(()));
+});
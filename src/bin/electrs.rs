@@ -1,16 +1,31 @@
-use std::{process, sync::Arc};
+use std::{
+    process,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
 
 use electrs::{
-    config::Config,
-    daemon::Daemon,
+    config::{Config, Mode},
+    daemon::{ConsensusMonitor, Daemon},
+    electrum::RPC as ElectrumRPC,
     errors::*,
-    indexer::Indexer,
+    indexer::{
+        mempool::Mempool,
+        query::{ChainQuery, Query},
+        Indexer,
+    },
     metrics::Metrics,
+    rest,
     signal::Waiter,
-    store::{FetchFrom, Store},
+    store::{row_type_name, FetchFrom, Store},
+    util::{spawn_thread, systemd, webhook::Webhooks, zmq},
 };
-use error_chain::ChainedError;
-use log::error;
+use error_chain::{ensure, ChainedError};
+use log::{error, info, warn};
+
+const CACHE_PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+const QUERY_LOG_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
 
 fn main() {
     let config = Arc::new(Config::from_args());
@@ -22,32 +37,222 @@ fn main() {
 }
 
 fn run_server(config: Arc<Config>) -> Result<()> {
+    if config.mode == Mode::DbStats {
+        let store = Store::open(&config.db_path.join("newindex"), &config);
+        print_db_stats(&store);
+        return Ok(());
+    }
+
     let signal = Waiter::start();
     let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
+    metrics.start(
+        config.monitoring_socket_file.as_deref(),
+        config.socket_file_mode,
+    );
 
     let daemon = Arc::new(Daemon::new(
         config.daemon_dir.as_path(),
-        &config.blocks_dir,
+        &config.blocks_dirs,
         config.daemon_rpc_addr,
+        &config.daemon_rpc_fallback_addrs,
         config.cookie_getter(),
         config.network_type,
+        config.allow_pruned,
+        config.rpc_batch_bounds,
         signal.clone(),
         &metrics,
     )?);
 
     let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
-    let mut indexer = Indexer::open(
+    let mut indexer = match config.mode {
+        // the index is never written, so it has to be complete already
+        Mode::ServeOnly => {
+            ensure!(
+                store.done_initial_sync(),
+                "no index to serve found at {:?}",
+                config.db_path
+            );
+            None
+        }
+        Mode::DbStats => unreachable!("db-stats doesn't index"),
+        Mode::Full | Mode::IndexOnly | Mode::CheckSpends { .. } => Some(Indexer::open(
+            Arc::clone(&store),
+            fetch_from(&config, &store),
+            &config,
+            &metrics,
+        )),
+    };
+    let mut tip = match indexer.as_mut() {
+        Some(indexer) => indexer.update(&daemon)?,
+        None => daemon.getbestblockhash()?,
+    };
+
+    if let Mode::CheckSpends { repair } = config.mode {
+        let indexer = indexer.as_ref().expect("check-spends indexes");
+        let check = indexer.check_spends(&daemon, repair)?;
+        store.persist_headers();
+        ensure!(
+            check.heights.is_empty() || repair,
+            "found {} inconsistent blocks, run again with --repair to index them again",
+            check.heights.len()
+        );
+        return Ok(());
+    }
+
+    if config.mode == Mode::IndexOnly {
+        store.persist_headers();
+        info!("indexed up to blockhash={}, exiting", tip);
+        return Ok(());
+    }
+
+    let chain = Arc::new(ChainQuery::new(
         Arc::clone(&store),
-        fetch_from(&config, &store),
+        Arc::clone(&daemon),
         &config,
         &metrics,
-    );
-    let mut tip = indexer.update(&daemon)?;
+    ));
+
+    if config.cache_size_budget > 0 || config.cache_ttl.is_some() {
+        start_cache_janitor(Arc::clone(&chain), &config);
+    }
+    if config.warm_cache_scripts > 0 {
+        start_cache_warmer(Arc::clone(&chain), &config);
+    }
 
+    let mut mempool = Mempool::new(Arc::clone(&chain), &metrics);
+    if let Some(addr) = config.zmq_sequence_addr {
+        mempool.follow_sequence(zmq::subscribe_sequence(addr));
+    }
+    let mempool = Arc::new(RwLock::new(mempool));
+    let query = Arc::new(Query::new(
+        Arc::clone(&chain),
+        Arc::clone(&mempool),
+        Arc::clone(&daemon),
+        &config,
+    ));
+    query.update_mempool()?;
+    let mut last_mempool_update = Instant::now();
+    if let Err(e) = query.update_fee_estimates() {
+        warn!("failed updating fee estimates: {}", e);
+    }
+
+    ConsensusMonitor::start(&config, Arc::clone(&daemon), &metrics);
+    let webhooks = Webhooks::start(&config, &metrics);
+    let zmq_publisher = config
+        .zmq_pub_addr
+        .map(|addr| zmq::Publisher::start(addr, &metrics));
+    let rest_server = rest::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    // the initial sync is done, and the servers are starting up (or already listening, on
+    // sockets passed by systemd)
+    systemd::notify("READY=1");
+
+    loop {
+        let delay = Duration::from_millis(500).min(config.mempool_refresh_interval);
+        if let Err(err) = signal.wait(delay, true) {
+            info!("stopping server: {}", err);
+            systemd::notify("STOPPING=1");
+            // the electrum server is stopped when dropped
+            rest_server.stop();
+            drop(electrum_server);
+            chain.persist_query_log();
+            store.persist_headers();
+            break;
+        }
+
+        daemon.health_check();
+
+        // Index new blocks
+        let current_tip = daemon.getbestblockhash()?;
+        if current_tip != tip {
+            let old_headers = chain.headers();
+            if let Some(indexer) = indexer.as_mut() {
+                indexer.update(&daemon)?;
+            }
+            let new_headers = chain.headers();
+            webhooks.tip_changed(&old_headers, &new_headers, &chain);
+            if let Some(zmq_publisher) = &zmq_publisher {
+                zmq_publisher.tip_changed(&old_headers, &new_headers, &chain);
+            }
+            tip = current_tip;
+            if let Err(e) = query.update_fee_estimates() {
+                warn!("failed updating fee estimates: {}", e);
+            }
+        };
+
+        // Update mempool
+        if last_mempool_update.elapsed() >= config.mempool_refresh_interval {
+            let changes = query.update_mempool()?;
+            if let Some(zmq_publisher) = &zmq_publisher {
+                zmq_publisher.mempool_changed(&changes);
+            }
+            last_mempool_update = Instant::now();
+        }
+
+        // Update subscribed clients
+        electrum_server.notify();
+    }
+    info!("server stopped");
     Ok(())
 }
 
+fn start_cache_janitor(chain: Arc<ChainQuery>, config: &Config) {
+    let (size_budget, ttl) = (config.cache_size_budget, config.cache_ttl);
+    spawn_thread("cache-janitor", move || loop {
+        chain.prune_cache(size_budget, ttl);
+        thread::sleep(CACHE_PRUNE_INTERVAL);
+    });
+}
+
+// Warms up the caches of the scripts queried before the restart, then persists the queries
+// every interval
+fn start_cache_warmer(chain: Arc<ChainQuery>, config: &Config) {
+    let rate = config.warm_cache_rate;
+    spawn_thread("cache-warmer", move || {
+        let start = Instant::now();
+        let warmed = chain.warm_cache(rate);
+        info!(
+            "warmed up the caches of {} scripts in {:?}",
+            warmed,
+            start.elapsed()
+        );
+        loop {
+            thread::sleep(QUERY_LOG_PERSIST_INTERVAL);
+            chain.persist_query_log();
+        }
+    });
+}
+
+// The on-disk size of each row type is estimated from its share of the uncompressed size, the
+// rows of the types marked with `~` being extrapolated from a sample
+fn print_db_stats(store: &Store) {
+    for (name, db) in store.dbs() {
+        let size = db.size();
+        println!(
+            "{}: {} bytes on disk, ~{} keys",
+            name,
+            size,
+            db.estimate_num_keys()
+        );
+        let usage = db.usage_by_code();
+        let total: u64 = usage.values().map(|u| u.key_bytes + u.value_bytes).sum();
+        for (code, u) in usage {
+            let bytes = u.key_bytes + u.value_bytes;
+            let share = bytes as f64 / total.max(1) as f64;
+            println!(
+                "  {} {:<20} {}{:>12} rows {:>16} bytes {:>6.2}% ~{} bytes on disk",
+                code as char,
+                row_type_name(name, code),
+                if u.exhaustive { ' ' } else { '~' },
+                u.rows,
+                bytes,
+                share * 100.0,
+                (size as f64 * share) as u64
+            );
+        }
+    }
+}
+
 fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
     let mut jsonrpc_import = config.jsonrpc_import;
     if !jsonrpc_import {
@@ -1,11 +1,10 @@
 mod network;
+mod spec;
 
 pub use network::*;
+pub use spec::*;
 
-#[cfg(not(feature = "liquid"))]
-pub type Value = u64;
-#[cfg(feature = "liquid")]
-pub use confidential::Value;
+pub type Value = <Chain as ChainSpec>::Value;
 
 #[cfg(not(feature = "liquid"))] // use regular Bitcoin data structures
 pub use bitcoin::{
@@ -16,10 +15,9 @@ pub use bitcoin::{
 #[cfg(feature = "liquid")]
 pub use {
     crate::elements::asset,
+    bitcoin::network::constants::Network as BNetwork,
     elements::{
         address, confidential, encode::deserialize, script, Address, AssetId, Block, BlockHash,
-        BlockHeader, OutPoint, Script, Transaction, TxIn, TxOut, Txid,
+        BlockHeader, ContractHash, OutPoint, Script, Transaction, TxIn, TxOut, Txid,
     },
 };
-
-use bitcoin::blockdata::constants::genesis_block;
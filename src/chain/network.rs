@@ -1,7 +1,24 @@
+#[cfg(not(feature = "liquid"))]
+use std::str::FromStr;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::{
+    bech32::{self, ToBase32},
+    hashes::Hash,
+    util::{
+        address::{self, Address, Payload},
+        base58,
+    },
+    PubkeyHash, ScriptHash,
+};
 use bitcoin::{
     blockdata::constants::genesis_block, network::constants::Network as BNetwork, BlockHash,
 };
 
+#[cfg(feature = "liquid")]
+use super::{address, asset, AssetId};
+use super::{Chain, ChainSpec};
+
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Serialize, Ord, PartialOrd, Eq)]
 pub enum Network {
     #[cfg(not(feature = "liquid"))]
@@ -12,6 +29,8 @@ pub enum Network {
     Regtest,
     #[cfg(not(feature = "liquid"))]
     Signet,
+    #[cfg(not(feature = "liquid"))]
+    CustomSignet(&'static SignetParams),
 
     // for Liquid network
     #[cfg(feature = "liquid")]
@@ -31,7 +50,83 @@ pub const LIQUID_TESTNET_PARAMS: address::AddressParams = address::AddressParams
     blech_hrp: "tlq",
 };
 
+/// The parameters of a (private) signet, which differ from the default signet's
+#[cfg(not(feature = "liquid"))]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Ord, PartialOrd, Eq)]
+pub struct SignetParams {
+    pub magic: u32,
+    pub genesis_hash: BlockHash,
+    pub p2pkh_prefix: u8,
+    pub p2sh_prefix: u8,
+    pub bech32_hrp: String,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl SignetParams {
+    pub fn default_signet() -> Self {
+        Self {
+            magic: BNetwork::Signet.magic(),
+            genesis_hash: bitcoin_genesis_hash(BNetwork::Signet),
+            p2pkh_prefix: 111,
+            p2sh_prefix: 196,
+            bech32_hrp: "tb".to_string(),
+        }
+    }
+
+    pub fn encode_address(&self, payload: &Payload) -> String {
+        match payload {
+            Payload::PubkeyHash(hash) => {
+                base58::check_encode_slice(&[&[self.p2pkh_prefix][..], &hash[..]].concat())
+            }
+            Payload::ScriptHash(hash) => {
+                base58::check_encode_slice(&[&[self.p2sh_prefix][..], &hash[..]].concat())
+            }
+            Payload::WitnessProgram { version, program } => {
+                let variant = match version.into_num() {
+                    0 => bech32::Variant::Bech32,
+                    _ => bech32::Variant::Bech32m,
+                };
+                let mut data = vec![bech32::u5::from(*version)];
+                data.extend(program.to_base32());
+                bech32::encode(&self.bech32_hrp, data, variant).expect("invalid bech32 hrp")
+            }
+        }
+    }
+
+    pub fn decode_address(&self, s: &str) -> Result<Payload, address::Error> {
+        if let Ok((hrp, data, variant)) = bech32::decode(s) {
+            if hrp == self.bech32_hrp {
+                // the checks on witness programs don't depend on the hrp, so reuse the standard
+                // parser's under the default signet's
+                let address = bech32::encode("tb", data, variant)?;
+                return Ok(Address::from_str(&address)?.payload);
+            }
+        }
+
+        let data = base58::from_check(s)?;
+        if data.len() != 21 {
+            return Err(base58::Error::InvalidLength(data.len()).into());
+        }
+        match data[0] {
+            prefix if prefix == self.p2pkh_prefix => Ok(Payload::PubkeyHash(
+                PubkeyHash::from_slice(&data[1..]).unwrap(),
+            )),
+            prefix if prefix == self.p2sh_prefix => Ok(Payload::ScriptHash(
+                ScriptHash::from_slice(&data[1..]).unwrap(),
+            )),
+            prefix => Err(base58::Error::InvalidAddressVersion(prefix).into()),
+        }
+    }
+}
+
 impl Network {
+    /// A custom signet network. The parameters live for the whole process, which keeps `Network`
+    /// a plain `Copy` value.
+    #[cfg(not(feature = "liquid"))]
+    pub fn custom_signet(params: SignetParams) -> Self {
+        Network::CustomSignet(Box::leak(Box::new(params)))
+    }
+
     pub fn names() -> Vec<String> {
         #[cfg(not(feature = "liquid"))]
         return vec![
@@ -51,7 +146,10 @@ impl Network {
 
     #[cfg(not(feature = "liquid"))]
     pub fn magic(self) -> u32 {
-        BNetwork::from(self).magic()
+        match self {
+            Network::CustomSignet(params) => params.magic,
+            _ => BNetwork::from(self).magic(),
+        }
     }
 
     #[cfg(feature = "liquid")]
@@ -62,6 +160,28 @@ impl Network {
         }
     }
 
+    /// The chain bitcoind reports in `getblockchaininfo`, or `None` when it depends on
+    /// the daemon's configuration (Liquid regtest chains)
+    pub fn chain_name(self) -> Option<&'static str> {
+        match self {
+            #[cfg(not(feature = "liquid"))]
+            Network::Bitcoin => Some("main"),
+            #[cfg(not(feature = "liquid"))]
+            Network::Testnet => Some("test"),
+            #[cfg(not(feature = "liquid"))]
+            Network::Regtest => Some("regtest"),
+            #[cfg(not(feature = "liquid"))]
+            Network::Signet | Network::CustomSignet(_) => Some("signet"),
+
+            #[cfg(feature = "liquid")]
+            Network::Liquid => Some("liquidv1"),
+            #[cfg(feature = "liquid")]
+            Network::LiquidTestnet => Some("liquidtestnet"),
+            #[cfg(feature = "liquid")]
+            Network::LiquidRegtest => None,
+        }
+    }
+
     pub fn is_regtest(self) -> bool {
         match self {
             #[cfg(not(feature = "liquid"))]
@@ -131,7 +251,7 @@ impl From<Network> for BNetwork {
             Network::Bitcoin => BNetwork::Bitcoin,
             Network::Testnet => BNetwork::Testnet,
             Network::Regtest => BNetwork::Regtest,
-            Network::Signet => BNetwork::Signet,
+            Network::Signet | Network::CustomSignet(_) => BNetwork::Signet,
         }
     }
 }
@@ -148,11 +268,9 @@ impl From<BNetwork> for Network {
     }
 }
 
+/// The genesis block hash of `network`, as a Bitcoin block hash for the Electrum features
 pub fn genesis_hash(network: Network) -> BlockHash {
-    #[cfg(not(feature = "liquid"))]
-    return bitcoin_genesis_hash(network.into());
-    #[cfg(feature = "liquid")]
-    return liquid_genesis_hash(network.into);
+    Chain::genesis_hash(network)
 }
 
 pub fn bitcoin_genesis_hash(network: BNetwork) -> BlockHash {
@@ -178,17 +296,22 @@ pub fn bitcoin_genesis_hash(network: BNetwork) -> BlockHash {
 #[cfg(feature = "liquid")]
 pub fn liquid_genesis_hash(network: Network) -> elements::BlockHash {
     lazy_static! {
-        static ref LIQUID_GENESIS: BlockHash =
+        static ref LIQUID_GENESIS: elements::BlockHash =
             "1466275836220db2944ca059a3a10ef6fd2ea684b0688d2c379296888a206003"
                 .parse()
                 .unwrap();
+        static ref LIQUID_TESTNET_GENESIS: elements::BlockHash =
+            "a771da8e52ee6ad581ed1e9a99825e5b3b7992225534eaa2ae23244fe26ab1c1"
+                .parse()
+                .unwrap();
     }
 
     match network {
         Network::Liquid => *LIQUID_GENESIS,
+        Network::LiquidTestnet => *LIQUID_TESTNET_GENESIS,
         // The genesis block for liquid regtest chains varies based on the chain configuration.
         // This instead uses an all zeroed-out hash, which doesn't matter in practice because its
         // only used for Electrum server discovery, which isn't active on regtest.
-        _ => Default::default(),
+        Network::LiquidRegtest => Default::default(),
     }
 }
@@ -0,0 +1,190 @@
+// The rules that differ between the chains electrs indexes, so that the rest of the code asks
+// the chain instead of checking the `liquid` feature. A single chain is compiled in, `Chain`
+// being the one of the enabled features. Supporting another network (e.g. another Elements
+// sidechain) means implementing `ChainSpec` for it, along with its `Network` variants.
+
+use std::collections::HashMap;
+#[cfg(not(feature = "liquid"))]
+use std::str::FromStr;
+
+#[cfg(feature = "liquid")]
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+
+#[cfg(feature = "liquid")]
+use crate::elements::asset::{REGTEST_INITIAL_ISSUANCE_PREVOUT, TESTNET_INITIAL_ISSUANCE_PREVOUT};
+
+use super::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+use crate::errors::*;
+
+pub trait ChainSpec {
+    /// The amount of an output
+    type Value;
+
+    /// Whether the outputs carry an asset, and can blind their asset and amount
+    const HAS_ASSETS: bool;
+
+    /// The hash of the genesis block of `network`, as a Bitcoin block hash for the Electrum
+    /// features
+    fn genesis_hash(network: Network) -> BlockHash;
+
+    /// The address of an output script, `None` for the scripts without one
+    fn script_to_address(script: &Script, network: Network) -> Option<String>;
+
+    /// The output script of an address, failing when it isn't an address of `network`
+    fn address_to_script(addr: &str, network: Network) -> Result<Script>;
+
+    /// The amount in satoshis, `None` when it is blinded
+    fn explicit_value(value: &Self::Value) -> Option<u64>;
+
+    /// The fee paid by a transaction, or `None` when some of its previous outputs are unknown
+    fn tx_fee(
+        tx: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        network: Network,
+    ) -> Option<u64>;
+
+    fn is_spendable(txout: &TxOut) -> bool;
+
+    /// Whether an input spends an output of the chain
+    fn has_prevout(txin: &TxIn) -> bool;
+}
+
+#[cfg(not(feature = "liquid"))]
+pub struct Bitcoin;
+
+#[cfg(not(feature = "liquid"))]
+pub type Chain = Bitcoin;
+
+#[cfg(not(feature = "liquid"))]
+impl ChainSpec for Bitcoin {
+    type Value = u64;
+
+    const HAS_ASSETS: bool = false;
+
+    fn genesis_hash(network: Network) -> BlockHash {
+        match network {
+            Network::CustomSignet(params) => params.genesis_hash,
+            _ => super::bitcoin_genesis_hash(network.into()),
+        }
+    }
+
+    fn script_to_address(script: &Script, network: Network) -> Option<String> {
+        let address = bitcoin::Address::from_script(script, network.into())?;
+        Some(match network {
+            Network::CustomSignet(params) => params.encode_address(&address.payload),
+            _ => address.to_string(),
+        })
+    }
+
+    // Segwit addresses are accepted with either bech32 or bech32m, as required by their
+    // witness version
+    fn address_to_script(addr: &str, network: Network) -> Result<Script> {
+        let invalid = |e: bitcoin::util::address::Error| {
+            ErrorKind::InvalidAddress(format!("{}: {}", addr, e))
+        };
+
+        if let Network::CustomSignet(params) = network {
+            let payload = params.decode_address(addr).map_err(invalid)?;
+            return Ok(payload.script_pubkey());
+        }
+
+        let address = bitcoin::Address::from_str(addr).map_err(invalid)?;
+        // testnet addresses are also valid on signet and (except for segwit ones) on regtest
+        if !address.is_valid_for_network(network.into()) {
+            bail!(ErrorKind::InvalidAddress(format!(
+                "{} is not for {:?}",
+                addr, network
+            )));
+        }
+        Ok(address.script_pubkey())
+    }
+
+    fn explicit_value(value: &u64) -> Option<u64> {
+        Some(*value)
+    }
+
+    fn tx_fee(
+        tx: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        _network: Network,
+    ) -> Option<u64> {
+        if tx.is_coin_base() {
+            return Some(0);
+        }
+        let total_in = tx.input.iter().try_fold(0u64, |total, txin| {
+            prevouts
+                .get(&txin.previous_output)
+                .map(|prevout| total + prevout.value)
+        })?;
+        let total_out: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        total_in.checked_sub(total_out)
+    }
+
+    fn is_spendable(txout: &TxOut) -> bool {
+        !txout.script_pubkey.is_provably_unspendable()
+    }
+
+    fn has_prevout(txin: &TxIn) -> bool {
+        !txin.previous_output.is_null()
+    }
+}
+
+#[cfg(feature = "liquid")]
+pub struct Liquid;
+
+#[cfg(feature = "liquid")]
+pub type Chain = Liquid;
+
+#[cfg(feature = "liquid")]
+impl ChainSpec for Liquid {
+    type Value = super::confidential::Value;
+
+    const HAS_ASSETS: bool = true;
+
+    fn genesis_hash(network: Network) -> BlockHash {
+        BlockHash::from_inner(super::liquid_genesis_hash(network).into_inner())
+    }
+
+    fn script_to_address(script: &Script, network: Network) -> Option<String> {
+        super::Address::from_script(script, None, network.address_params()).map(|a| a.to_string())
+    }
+
+    // Confidential addresses are accepted, their blinding key is dropped
+    fn address_to_script(addr: &str, network: Network) -> Result<Script> {
+        let address = addr
+            .parse::<super::Address>()
+            .map_err(|e| ErrorKind::InvalidAddress(format!("{}: {}", addr, e)))?;
+        if address.params != network.address_params() {
+            bail!(ErrorKind::InvalidAddress(format!(
+                "{} is not for {:?}",
+                addr, network
+            )));
+        }
+        Ok(address.script_pubkey())
+    }
+
+    fn explicit_value(value: &Self::Value) -> Option<u64> {
+        value.explicit()
+    }
+
+    // the fees are explicit outputs
+    fn tx_fee(
+        tx: &Transaction,
+        _prevouts: &HashMap<OutPoint, TxOut>,
+        network: Network,
+    ) -> Option<u64> {
+        Some(tx.fee_in(*network.native_asset()))
+    }
+
+    fn is_spendable(txout: &TxOut) -> bool {
+        !txout.is_fee() && !txout.script_pubkey.is_provably_unspendable()
+    }
+
+    fn has_prevout(txin: &TxIn) -> bool {
+        !txin.is_coinbase()
+            && !txin.is_pegin
+            && txin.previous_output.txid != *REGTEST_INITIAL_ISSUANCE_PREVOUT
+            && txin.previous_output.txid != *TESTNET_INITIAL_ISSUANCE_PREVOUT
+    }
+}
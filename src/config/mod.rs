@@ -3,44 +3,198 @@ mod cookie;
 pub use cookie::*;
 
 use std::{
+    collections::HashMap,
+    fmt,
     net::{SocketAddr, ToSocketAddrs},
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use dirs::home_dir;
+use url::Url;
 
-use crate::{chain::Network, daemon::CookieGetter};
+use crate::{
+    chain::Network,
+    daemon::CookieGetter,
+    util::{json_log::JsonLogger, script::address_to_script},
+};
+
+#[cfg(not(feature = "liquid"))]
+use crate::chain::SignetParams;
 
 #[cfg(feature = "liquid")]
 use bitcoin::Network as BNetwork;
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// What the process does with the index, see `--mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Full,
+    IndexOnly,
+    ServeOnly,
+    /// The `db-stats` subcommand
+    DbStats,
+    /// The `check-spends` subcommand
+    CheckSpends {
+        repair: bool,
+    },
+}
+
+/// When the index writes go through RocksDB's write-ahead log and are synced to disk, see
+/// `--db-sync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbSyncPolicy {
+    /// Every write is logged and synced
+    Always,
+    /// The writes skip the log, and the DBs are flushed every this many blocks
+    Interval(usize),
+    /// The writes skip the log until the initial sync reaches the tip, and are synced from then on
+    NeverUntilTip,
+}
+
+/// The compression of a DB's files, see `--db-compression`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbCompression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// The limits of a class of REST clients, see `--api-key` and `--anonymous-quota`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Requests per second, allowing bursts of as many requests (0 refuses every request)
+    pub rate: Option<u32>,
+    /// The largest response body, in bytes
+    pub max_response_size: Option<usize>,
+}
+
+/// The smallest and largest number of requests of a batch, between which its size is adapted
+/// to the observed responses, see `--rpc-batch-size` and `--fetch-batch-size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchBounds {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// The TLS listener of the Electrum server, see `--electrum-tls-cert`
+#[derive(Debug, Clone)]
+pub struct ElectrumTls {
+    pub addr: SocketAddr,
+    pub cert_path: PathBuf, // the PEM certificate chain
+    pub key_path: PathBuf,  // the PEM private key
+}
+
+/// A REST API key, sent in the `X-API-Key` header
+#[derive(Clone)]
+pub struct ApiKey {
+    /// Reported in the logs and metrics instead of the key
+    pub name: String,
+    pub key: String,
+    pub quota: Quota,
+}
+
+// the config is logged at startup, without the keys
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     // See below for the documentation of each field:
     pub log: stderrlog::StdErrLog,
+    pub mode: Mode,
     pub network_type: Network,
     pub db_path: PathBuf,
+    pub txstore_archive_path: Option<PathBuf>,
+    pub txstore_hot_size: u64,
     pub daemon_dir: PathBuf,
-    pub blocks_dir: PathBuf,
+    pub blocks_dirs: Vec<PathBuf>, // the candidate directories of the blk*.dat files
     pub daemon_rpc_addr: SocketAddr,
+    pub daemon_rpc_fallback_addrs: Vec<SocketAddr>,
+    pub daemon_observers: Vec<(SocketAddr, Option<String>)>, // with their 'USER:PASSWORD'
+    pub rpc_batch_bounds: BatchBounds,
+    pub fetch_batch_bounds: BatchBounds,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
     pub http_addr: SocketAddr,
     pub http_socket_file: Option<PathBuf>,
+    pub electrum_socket_file: Option<PathBuf>,
+    pub electrum_tls: Option<ElectrumTls>,
+    pub monitoring_socket_file: Option<PathBuf>,
+    pub socket_file_mode: Option<u32>,
+    pub electrum_idle_timeout: Option<Duration>,
+    pub http_idle_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub http_compression_level: u32,
+    pub http_compression_min_size: usize,
     pub monitoring_addr: SocketAddr,
     pub jsonrpc_import: bool,
+    pub mempool_refresh_interval: Duration,
     pub light_mode: bool,
+    pub allow_pruned: bool,
     pub address_search: bool,
     pub index_unspendables: bool,
+    pub index_op_return: bool,
+    pub index_channels: bool,
+    pub utxo_accumulator: bool,
+    pub script_activity: bool,
+    pub rich_list_size: usize,
+    pub block_age_stats: bool,
+    pub index_start_height: usize,
+    pub intern_scripts: bool,
+    pub skip_dust: u64,
+    pub hide_dust: u64,
+    pub db_sync_policy: DbSyncPolicy,
+    pub db_compression: HashMap<String, DbCompression>,
+    pub zstd_dict_size: usize,
+    pub add_threads: usize,
+    pub index_threads: usize,
+    pub lookup_threads: usize,
+    pub index_cpus: Option<Vec<usize>>,
+    pub sync_memory_limit: usize,
+    pub cache_size_budget: u64,
+    pub cache_ttl: Option<Duration>,
     pub cors: Option<String>,
     pub precache_scripts: Option<String>,
+    pub warm_cache_scripts: usize,
+    pub warm_cache_rate: u32,
+    pub blocked_scripts: Option<String>,
+    pub labels_file: Option<String>,
+    pub api_keys: Vec<ApiKey>,
+    pub api_keys_file: Option<String>,
+    pub admin_api_keys: Vec<String>,
+    pub anonymous_quota: Quota,
+    pub shadow_compare_url: Option<Url>,
+    pub shadow_compare_sample: usize,
+    pub webhook_urls: Vec<Url>,
+    pub webhook_retries: usize,
+    pub zmq_pub_addr: Option<SocketAddr>,
+    pub zmq_sequence_addr: Option<SocketAddr>,
     pub utxos_limit: usize,
+    pub max_query_items: usize,
     pub electrum_txs_limit: usize,
+    pub fee_estimate_targets: Vec<u16>,
+    pub electrum_subscriptions_limit: usize,
+    pub electrum_max_connections: usize,
+    pub electrum_blocking_threads: usize,
+    pub electrum_subscriptions_memory: usize,
     pub electrum_banner: String,
+    /// Reported by `server.version`, `server.features` and the REST root document
+    pub server_version: String,
+    pub donation_address: Option<String>,
+    pub operator_contact: Option<String>,
+    pub electrum_peers: Option<crate::electrum::ServerHosts>,
 
     #[cfg(feature = "liquid")]
     pub parent_network: BNetwork,
@@ -56,6 +210,14 @@ pub struct Config {
 }
 
 impl Config {
+    /// The compression of the DB named `db`
+    pub fn db_compression(&self, db: &str) -> DbCompression {
+        self.db_compression
+            .get(db)
+            .copied()
+            .unwrap_or(DbCompression::Snappy)
+    }
+
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
         if let Some(ref value) = self.cookie {
             Arc::new(StaticCookie {
@@ -84,12 +246,31 @@ impl Config {
                     .long("timestamp")
                     .help("Prepend log lines with a timestamp"),
             )
+            .arg(
+                Arg::with_name("log_format")
+                    .long("log-format")
+                    .help("Log lines format: plain text, or one JSON object per line (always timestamped)")
+                    .possible_values(&["text", "json"])
+                    .default_value("text"),
+            )
             .arg(
                 Arg::with_name("db_dir")
                     .long("db-dir")
                     .help("Directory to store index database (default: ./db/)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("txstore_archive_dir")
+                    .long("txstore-archive-dir")
+                    .help("Directory (e.g. on a slower volume) for the older files of the txstore, which holds most of the index. The most recently written files, i.e. the recent blocks, stay in --db-dir up to --txstore-hot-size. Existing files move over as they get compacted")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("txstore_hot_size")
+                    .long("txstore-hot-size")
+                    .help("Size (in MB) of the txstore files kept in --db-dir with --txstore-archive-dir")
+                    .default_value("65536"),
+            )
             .arg(
                 Arg::with_name("daemon_dir")
                     .long("daemon-dir")
@@ -99,7 +280,7 @@ impl Config {
             .arg(
                 Arg::with_name("blocks_dir")
                     .long("blocks-dir")
-                    .help("Analogous to bitcoind's -blocksdir option, this specifies the directory containing the raw blocks files (blk*.dat) (default: ~/.bitcoin/blocks/)")
+                    .help("Analogous to bitcoind's -blocksdir option, this specifies the directory containing the raw blocks files (blk*.dat), either directly or under the network subdirectory (e.g. testnet3/blocks/) (default: the blocks/ directory of the daemon dir)")
                     .takes_value(true),
             )
             .arg(
@@ -126,16 +307,54 @@ impl Config {
                     .help("HTTP server 'addr:port' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("electrum_idle_timeout")
+                    .long("electrum-idle-timeout")
+                    .help("Seconds after which Electrum connections that sent no request are closed (0 to disable)")
+                    .default_value("600")
+            )
+            .arg(
+                Arg::with_name("http_idle_timeout")
+                    .long("http-idle-timeout")
+                    .help("Seconds after which HTTP connections that sent no complete request are closed (0 to disable)")
+                    .default_value("60")
+            )
+            .arg(
+                Arg::with_name("http_compression_level")
+                    .long("http-compression-level")
+                    .help("Compression level of the HTTP responses, from 1 (fastest) to 9 (smallest), for clients that accept brotli, gzip or deflate (0 to disable)")
+                    .default_value("6")
+            )
+            .arg(
+                Arg::with_name("http_compression_min_size")
+                    .long("http-compression-min-size")
+                    .help("Size in bytes under which HTTP responses are sent uncompressed")
+                    .default_value("1024")
+            )
+            .arg(
+                Arg::with_name("tcp_keepalive")
+                    .long("tcp-keepalive")
+                    .help("Seconds of inactivity before sending TCP keepalive probes on the Electrum and HTTP connections (0 to disable)")
+                    .default_value("120")
+            )
             .arg(
                 Arg::with_name("daemon_rpc_addr")
                     .long("daemon-rpc-addr")
-                    .help("Bitcoin daemon JSONRPC 'addr:port' to connect (default: 127.0.0.1:8332 for mainnet, 127.0.0.1:18332 for testnet and 127.0.0.1:18443 for regtest)")
-                    .takes_value(true),
+                    .help("Bitcoin daemon JSONRPC 'addr:port' to connect (default: 127.0.0.1:8332 for mainnet, 127.0.0.1:18332 for testnet and 127.0.0.1:18443 for regtest). Several comma-separated addresses can be given, the first is the primary and the others serve block and transaction reads while it is unavailable.")
+                    .takes_value(true)
+                    .use_delimiter(true),
+            )
+            .arg(
+                Arg::with_name("observer_rpc_addr")
+                    .long("observer-rpc-addr")
+                    .help("Comma-separated JSONRPC 'addr:port' of other bitcoind nodes, whose best blocks are compared with the primary's to detect chain splits, reported at /internal/consensus on the monitoring address. Their credentials can be given as 'USER:PASSWORD@addr:port', the --cookie of the primary is used otherwise")
+                    .takes_value(true)
+                    .use_delimiter(true),
             )
             .arg(
                 Arg::with_name("monitoring_addr")
                     .long("monitoring-addr")
-                    .help("Prometheus monitoring 'addr:port' to listen on (default: 127.0.0.1:4224 for mainnet, 127.0.0.1:14224 for testnet and 127.0.0.1:24224 for regtest)")
+                    .help("Prometheus monitoring 'addr:port' to listen on, also serving the indexing progress at /internal/sync-status (default: 127.0.0.1:4224 for mainnet, 127.0.0.1:14224 for testnet and 127.0.0.1:24224 for regtest)")
                     .takes_value(true),
             )
             .arg(
@@ -143,10 +362,28 @@ impl Config {
                     .long("jsonrpc-import")
                     .help("Use JSONRPC instead of directly importing blk*.dat files. Useful for remote full node or low memory system"),
             )
+            .arg(
+                Arg::with_name("mempool_refresh_interval")
+                    .long("mempool-refresh-interval")
+                    .help("Milliseconds between mempool refreshes from bitcoind")
+                    .default_value("500")
+            )
+            .arg(
+                Arg::with_name("mode")
+                    .long("mode")
+                    .help("Index and serve (full), only build the index up to the current tip and exit (index-only), or only serve an existing index without ever writing to it (serve-only)")
+                    .possible_values(&["full", "index-only", "serve-only"])
+                    .default_value("full")
+            )
             .arg(
                 Arg::with_name("light_mode")
                     .long("lightmode")
-                    .help("Enable light mode for reduced storage")
+                    .help("Enable light mode for reduced storage. The transactions are fetched from bitcoind by their confirming block, which doesn't require -txindex")
+            )
+            .arg(
+                Arg::with_name("allow_pruned")
+                    .long("allow-pruned")
+                    .help("Allow running against a pruned bitcoind. The initial sync must come from an existing index or from blk*.dat files, since pruned blocks cannot be fetched over JSONRPC")
             )
             .arg(
                 Arg::with_name("address_search")
@@ -158,43 +395,373 @@ impl Config {
                     .long("index-unspendables")
                     .help("Enable indexing of provably unspendable outputs")
             )
+            .arg(
+                Arg::with_name("index_op_return")
+                    .long("index-op-return")
+                    .help("Enable indexing of OP_RETURN payload prefixes for the /op-return/:hexprefix query. Increases the index size")
+                    .requires("index_unspendables")
+            )
+            .arg(
+                Arg::with_name("index_channels")
+                    .long("index-channels")
+                    .help("Enable indexing of the spends of 2-of-2 multisig P2WSH outputs, such as lightning channel closes, for the /channel-like/:outpoint query")
+            )
             .arg(
                 Arg::with_name("cors")
                     .long("cors")
                     .help("Origins allowed to make cross-site requests")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("api_key")
+                    .long("api-key")
+                    .help("REST API key given as <name>:<key>[:<requests-per-second>[:<max-response-bytes>]], where an empty limit is unlimited. Can be repeated")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("api_keys_file")
+                    .long("api-keys-file")
+                    .help("Path to file with REST API keys, one per line in the --api-key format")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("admin_api_key")
+                    .long("admin-api-key")
+                    .help("Name of a REST API key allowed to use the /admin endpoints. Can be repeated")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("anonymous_quota")
+                    .long("anonymous-quota")
+                    .help("Limits of the REST requests without an API key, shared by all of them, as <requests-per-second>[:<max-response-bytes>] (default: unlimited)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("shadow_compare")
+                    .long("shadow-compare")
+                    .help("http:// URL of another Esplora-compatible REST API (e.g. http://host/api) to send a sample of the REST requests to, logging and counting the responses that differ (default disabled)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("shadow_compare_sample")
+                    .long("shadow-compare-sample")
+                    .help("Compare one of every N REST responses with --shadow-compare")
+                    .default_value("100")
+            )
+            .arg(
+                Arg::with_name("webhook_url")
+                    .long("webhook-url")
+                    .help("http:// URL to POST a JSON event to on every new block and reorg. Can be repeated")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("webhook_retries")
+                    .long("webhook-retries")
+                    .help("Number of retries of a failed webhook delivery, with a delay doubling from 1 second up to 5 minutes")
+                    .default_value("5")
+            )
+            .arg(
+                Arg::with_name("zmq_pub_addr")
+                    .long("zmq-pub-addr")
+                    .help("'addr:port' of a ZMQ PUB socket publishing the block, reorg, mempool.add and mempool.remove events (default disabled)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("zmq_sequence_addr")
+                    .long("zmq-sequence-addr")
+                    .help("'addr:port' of bitcoind's -zmqpubsequence notifications, applied to the mempool between full refreshes (default: diff the whole mempool on every refresh)")
+                    .takes_value(true)
+            )
             .arg(
                 Arg::with_name("precache_scripts")
                     .long("precache-scripts")
                     .help("Path to file with list of scripts to pre-cache")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("warm_cache_scripts")
+                    .long("warm-cache-scripts")
+                    .help("Log the N most recently queried scripts to the cache DB, and warm up their stats and UTXO caches in the background on startup (0 to disable)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("warm_cache_rate")
+                    .long("warm-cache-rate")
+                    .help("Scripts warmed up per second on startup, see --warm-cache-scripts")
+                    .default_value("20")
+            )
+            .arg(
+                Arg::with_name("blocked_scripts")
+                    .long("blocked-scripts")
+                    .help("Path to file with list of addresses or scripthashes (one per line) whose history queries are refused")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("labels_file")
+                    .long("labels-file")
+                    .help("Path to file with labels of addresses or scripthashes (one '<address or scripthash> <label>' per line), shown in the REST responses. Loaded on startup and by POST /admin/labels/reload")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("utxo_accumulator")
+                    .long("utxo-accumulator")
+                    .help("Maintain the UTXO set of every script at index time, so that UTXO lookups don't replay the whole history (changing this requires a reindex)")
+            )
+            .arg(
+                Arg::with_name("script_activity")
+                    .long("script-activity")
+                    .help("Maintain the heights at which every script was first funded and last used at index time, served with the address stats (changing this requires a reindex)")
+            )
+            .arg(
+                Arg::with_name("block_age_stats")
+                    .long("block-age-stats")
+                    .help("Compute the coin days destroyed and the age distribution of the outputs spent by each indexed block, served at /block/:hash/age-stats. Slows the indexing down, as the funding height of every spent output is looked up (bitcoin only)")
+            )
+            .arg(
+                Arg::with_name("rich_list_size")
+                    .long("rich-list-size")
+                    .help("Maintain the N largest script balances at index time, served at /stats/rich-list (0 to disable). Only the scripts used by the blocks indexed while enabled are ranked, so enabling it on an existing index requires a reindex")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("index_start_height")
+                    .long("index-start-height")
+                    .help("Only index the history of the blocks from this height on, for a smaller index. The balances, UTXOs and histories of scripts then only cover these blocks (changing this requires a reindex)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("intern_scripts")
+                    .long("intern-scripts")
+                    .help("Store the output scripts that are longer than their hash once, shared by all the outputs paying to them, to reduce the txstore size (changing this requires a reindex)")
+            )
+            .arg(
+                Arg::with_name("skip_dust")
+                    .long("skip-dust")
+                    .help("Leave the outputs below this value (in sats) out of the script histories, stats and UTXOs, to keep the spam out of the index (changing this requires a reindex, 0 to index every output)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("hide_dust")
+                    .long("hide-dust")
+                    .help("Leave the confirmed outputs below this value (in sats) out of the script history and UTXO responses, while still indexing them (0 to show every output)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("db_sync")
+                    .long("db-sync")
+                    .help("When the index writes are synced to disk: on every write (always), by flushing every N blocks without a write-ahead log and before the synced tip is updated (interval:N), or only once the initial sync reaches the tip and on every write from then on (never-until-tip). The blocks whose writes are lost by a crash are indexed again on restart")
+                    .default_value("never-until-tip")
+            )
+            .arg(
+                Arg::with_name("db_compression")
+                    .long("db-compression")
+                    .help("Compression of each DB's files, as a comma-separated list of DB=TYPE (DB being txstore, history or cache, and TYPE none, snappy, lz4 or zstd), snappy for the DBs left out. No reindex is needed: the existing files keep their compression until they get compacted, which a full compaction (e.g. after a reindex) does for all of them")
+                    .default_value("txstore=snappy,history=snappy,cache=snappy")
+            )
+            .arg(
+                Arg::with_name("zstd_dict_size")
+                    .long("zstd-dict-size")
+                    .help("Size (in KB) of the zstd dictionary trained on a sample of each new file of the zstd compressed DBs, which compresses the small values (e.g. the transactions of the txstore) better (0 for no dictionary)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("rpc_batch_size")
+                    .long("rpc-batch-size")
+                    .help("Bounds of the number of requests sent to bitcoind in one JSON-RPC batch (as MIN:MAX), adapted to the size and latency of the responses of each method")
+                    .default_value("100:50000")
+            )
+            .arg(
+                Arg::with_name("fetch_batch_size")
+                    .long("fetch-batch-size")
+                    .help("Bounds of the number of blocks fetched from bitcoind at once while indexing (as MIN:MAX), adapted to the size and latency of the fetched blocks (shrinking for the recent large blocks, growing for the early small ones)")
+                    .default_value("10:1000")
+            )
+            .arg(
+                Arg::with_name("add_threads")
+                    .long("add-threads")
+                    .help("Number of threads processing the blocks added to the txstore (0 for one per CPU)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("index_threads")
+                    .long("index-threads")
+                    .help("Number of threads indexing the history of the blocks (0 for one per CPU)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("lookup_threads")
+                    .long("lookup-threads")
+                    .help("Number of threads looking up the previous outputs of the indexed blocks, enough to saturate the disk's IOPS")
+                    .default_value("16")
+            )
+            .arg(
+                Arg::with_name("index_cpus")
+                    .long("index-cpus")
+                    .help("CPUs to pin the indexing threads to (e.g. '2-7,10'), keeping the other CPUs for the servers. Linux only.")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("sync_memory_limit")
+                    .long("sync-memory-limit")
+                    .help("Size (in MB) of the fetched blocks and pending rows above which the index update pauses fetching blocks (0 for no limit)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("cache_size_budget")
+                    .long("cache-size-budget")
+                    .help("Size (in MB) the cache DB is trimmed to, by evicting the least recently used cached UTXO sets (0 for unbounded)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("cache_ttl")
+                    .long("cache-ttl")
+                    .help("Evict the cached UTXO sets unused for this long (in seconds, 0 to keep them)")
+                    .default_value("0")
+            )
             .arg(
                 Arg::with_name("utxos_limit")
                     .long("utxos-limit")
                     .help("Maximum number of utxos to process per address. Lookups for addresses with more utxos will fail. Applies to the Electrum and HTTP APIs.")
                     .default_value("500")
             )
+            .arg(
+                Arg::with_name("fee_estimate_targets")
+                    .long("fee-estimate-targets")
+                    .help("Comma-separated confirmation targets (in blocks, from 1 to 1008) whose fee rates are estimated on every new block")
+                    .default_value("1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,144,504,1008")
+                    .use_delimiter(true)
+            )
             .arg(
                 Arg::with_name("electrum_txs_limit")
                     .long("electrum-txs-limit")
                     .help("Maximum number of transactions returned by Electrum history queries. Lookups with more results will fail.")
                     .default_value("500")
+            ).arg(
+                Arg::with_name("electrum_max_connections")
+                    .long("electrum-max-connections")
+                    .help("Maximum number of concurrent Electrum connections. Further connections wait to be accepted.")
+                    .default_value("10000")
+            ).arg(
+                Arg::with_name("electrum_blocking_threads")
+                    .long("electrum-blocking-threads")
+                    .help("Maximum number of threads running the Electrum queries against the index")
+                    .default_value("64")
+            ).arg(
+                Arg::with_name("electrum_subscriptions_limit")
+                    .long("electrum-subscriptions-limit")
+                    .help("Maximum number of scripthash subscriptions per Electrum session. The oldest subscriptions of a session are dropped above it.")
+                    .default_value("10000")
+            ).arg(
+                Arg::with_name("electrum_subscriptions_memory")
+                    .long("electrum-subscriptions-memory")
+                    .help("Memory (in MB) for the scripthash subscriptions of all Electrum sessions. Sessions subscribing above it are disconnected.")
+                    .default_value("256")
+            ).arg(
+                Arg::with_name("max_query_items")
+                    .long("max-query-items")
+                    .help("Global cap on the number of items returned by a single history query, applies on top of the per-API limits")
+                    .default_value("100000")
             ).arg(
                 Arg::with_name("electrum_banner")
                     .long("electrum-banner")
                     .help("Welcome banner for the Electrum server, shown in the console to clients.")
                     .takes_value(true)
+            ).arg(
+                Arg::with_name("server_version")
+                    .long("server-version")
+                    .help("Server version string reported to Electrum and REST clients, instead of electrs-esplora and its version")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("donation_address")
+                    .long("donation-address")
+                    .help("Address accepting donations for the server, reported by server.donation_address")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("operator_contact")
+                    .long("operator-contact")
+                    .help("How to reach the operator of the server (e.g. an email address), reported by server.features")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_peers")
+                    .long("electrum-peers")
+                    .help("A dictionary of Electrum peers to advertise via server.peers.subscribe, in the same format as --electrum-public-hosts. With server discovery enabled, these are health-checked like the default servers.")
+                    .takes_value(true)
             );
 
         #[cfg(unix)]
-        let args = args.arg(
-            Arg::with_name("http_socket_file")
-                .long("http-socket-file")
-                .help("HTTP server 'unix socket file' to listen on (default disabled, enabling this disables the http server)")
-                .takes_value(true)
-        );
+        let args = args
+            .arg(
+                Arg::with_name("http_socket_file")
+                    .long("http-socket-file")
+                    .help("HTTP server 'unix socket file' to listen on (default disabled, enabling this disables the http server)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("electrum_socket_file")
+                    .long("electrum-socket-file")
+                    .help("Electrum RPC 'unix socket file' to listen on (default disabled, enabling this disables the Electrum TCP listener)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("electrum_tls_cert")
+                    .long("electrum-tls-cert")
+                    .help("PEM certificate chain of the Electrum TLS listener (default disabled, requires --electrum-tls-key)")
+                    .takes_value(true)
+                    .requires("electrum_tls_key")
+            )
+            .arg(
+                Arg::with_name("electrum_tls_key")
+                    .long("electrum-tls-key")
+                    .help("PEM private key of the Electrum TLS listener")
+                    .takes_value(true)
+                    .requires("electrum_tls_cert")
+            )
+            .arg(
+                Arg::with_name("electrum_tls_addr")
+                    .long("electrum-tls-addr")
+                    .help("Electrum TLS 'addr:port' to listen on (default: the Electrum RPC port + 1 on 127.0.0.1, e.g. '127.0.0.1:50002' for mainnet)")
+                    .takes_value(true)
+                    .requires("electrum_tls_cert")
+            )
+            .arg(
+                Arg::with_name("monitoring_socket_file")
+                    .long("monitoring-socket-file")
+                    .help("Prometheus monitoring 'unix socket file' to listen on (default disabled, enabling this disables the monitoring TCP listener)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("socket_file_mode")
+                    .long("socket-file-mode")
+                    .help("Octal permissions of the unix socket files (e.g. 660, default: from the umask)")
+                    .takes_value(true)
+            );
+
+        #[cfg(not(feature = "liquid"))]
+        let args = args
+            .arg(
+                Arg::with_name("signet_magic")
+                    .long("signet-magic")
+                    .help("Message start of a custom signet in hex, as logged by bitcoind (e.g. '0a03cf40'). Requires --network signet")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("signet_genesis_hash")
+                    .long("signet-genesis-hash")
+                    .help("Genesis block hash of a custom signet (default: the signet genesis block). Requires --network signet")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("signet_address_prefixes")
+                    .long("signet-address-prefixes")
+                    .help("Address prefixes of a custom signet, as '<p2pkh>,<p2sh>,<bech32 hrp>' (default: '111,196,tb'). Requires --network signet")
+                    .takes_value(true),
+            );
 
         #[cfg(feature = "liquid")]
         let args = args
@@ -228,20 +795,57 @@ impl Config {
                 .takes_value(true)
         );
 
+        let args = args.subcommand(SubCommand::with_name("db-stats").about(
+            "Report the disk usage of each DB by row type, without writing to them, and exit",
+        ));
+        let args = args.subcommand(
+            SubCommand::with_name("check-spends")
+                .about("Update the index, check that every spent-TXO edge has a matching spending history entry and vice versa, and exit (slow, scans the whole history DB)")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Index the blocks with inconsistent rows again"),
+                ),
+        );
+
         let m = args.get_matches();
 
         let network_name = m.value_of("network").unwrap_or("mainnet");
         let network_type = Network::from(network_name);
 
+        #[cfg(not(feature = "liquid"))]
+        let network_type = match custom_signet_params(&m) {
+            Some(params) => {
+                assert!(
+                    network_type == Network::Signet,
+                    "--signet-* options require --network signet"
+                );
+                Network::custom_signet(params)
+            }
+            None => network_type,
+        };
+
         let db_dir = Path::new(m.value_of("db_dir").unwrap_or("./db"));
-        let db_path = db_dir.join(network_name);
+        let db_path = match network_type {
+            // keep the index of each custom signet apart
+            #[cfg(not(feature = "liquid"))]
+            Network::CustomSignet(params) => db_dir.join(format!(
+                "signet-{}",
+                hex::encode(params.magic.to_le_bytes())
+            )),
+            _ => db_dir.join(network_name),
+        };
+        // kept apart by network too
+        let txstore_archive_path = m
+            .value_of("txstore_archive_dir")
+            .map(|dir| Path::new(dir).join(db_path.file_name().expect("invalid --db-dir")));
 
         #[cfg(feature = "liquid")]
         let parent_network = m
             .value_of("parent-network")
             .map(|s| s.parse().expect("invalid parent network"))
             .unwrap_or_else(|| match network_type {
-                Network::Liuqid => BNetwork::Bitcoin,
+                Network::Liquid => BNetwork::Bitcoin,
                 Network::LiquidTestnet | Network::LiquidRegtest => BNetwork::Regtest,
             });
 
@@ -256,7 +860,7 @@ impl Config {
             #[cfg(not(feature = "liquid"))]
             Network::Regtest => 18443,
             #[cfg(not(feature = "liquid"))]
-            Network::Signet => 38332,
+            Network::Signet | Network::CustomSignet(_) => 38332,
 
             #[cfg(feature = "liquid")]
             Network::Liquid => 7041,
@@ -272,7 +876,7 @@ impl Config {
             #[cfg(not(feature = "liquid"))]
             Network::Regtest => 60401,
             #[cfg(not(feature = "liquid"))]
-            Network::Signet => 60601,
+            Network::Signet | Network::CustomSignet(_) => 60601,
 
             #[cfg(feature = "liquid")]
             Network::Liquid => 51000,
@@ -289,7 +893,7 @@ impl Config {
             #[cfg(not(feature = "liquid"))]
             Network::Regtest => 3002,
             #[cfg(not(feature = "liquid"))]
-            Network::Signet => 3003,
+            Network::Signet | Network::CustomSignet(_) => 3003,
 
             #[cfg(feature = "liquid")]
             Network::Liquid => 3000,
@@ -306,7 +910,7 @@ impl Config {
             #[cfg(not(feature = "liquid"))]
             Network::Regtest => 24224,
             #[cfg(not(feature = "liquid"))]
-            Network::Signet => 54224,
+            Network::Signet | Network::CustomSignet(_) => 54224,
 
             #[cfg(feature = "liquid")]
             Network::Liquid => 34224,
@@ -315,11 +919,29 @@ impl Config {
             #[cfg(feature = "liquid")]
             Network::LiquidRegtest => 44224,
         };
-        let daemon_rpc_addr: SocketAddr = str_to_socketaddr(
-            m.value_of("daemon_rpc_addr")
-                .unwrap_or(&format!("127.0.0.1:{}", default_daemon_port)),
-            "Bitcoin RPC",
-        );
+        let mut daemon_rpc_addrs: Vec<SocketAddr> = match m.values_of("daemon_rpc_addr") {
+            Some(addrs) => addrs
+                .map(|addr| str_to_socketaddr(addr, "Bitcoin RPC"))
+                .collect(),
+            None => vec![str_to_socketaddr(
+                &format!("127.0.0.1:{}", default_daemon_port),
+                "Bitcoin RPC",
+            )],
+        };
+        let daemon_rpc_addr = daemon_rpc_addrs.remove(0);
+        let daemon_observers = m
+            .values_of("observer_rpc_addr")
+            .map_or_else(Vec::new, |addrs| {
+                addrs
+                    .map(|addr| match addr.rsplit_once('@') {
+                        Some((cookie, addr)) => (
+                            str_to_socketaddr(addr, "observer RPC"),
+                            Some(cookie.to_string()),
+                        ),
+                        None => (str_to_socketaddr(addr, "observer RPC"), None),
+                    })
+                    .collect()
+            });
         let electrum_rpc_addr: SocketAddr = str_to_socketaddr(
             m.value_of("electrum_rpc_addr")
                 .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port)),
@@ -332,11 +954,35 @@ impl Config {
         );
 
         let http_socket_file: Option<PathBuf> = m.value_of("http_socket_file").map(PathBuf::from);
+        let electrum_socket_file: Option<PathBuf> =
+            m.value_of("electrum_socket_file").map(PathBuf::from);
+        let electrum_tls = m
+            .value_of("electrum_tls_cert")
+            .map(|cert_path| ElectrumTls {
+                addr: str_to_socketaddr(
+                    m.value_of("electrum_tls_addr")
+                        .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port + 1)),
+                    "Electrum TLS",
+                ),
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(m.value_of("electrum_tls_key").unwrap()),
+            });
+        let monitoring_socket_file: Option<PathBuf> =
+            m.value_of("monitoring_socket_file").map(PathBuf::from);
+        let socket_file_mode = m
+            .value_of("socket_file_mode")
+            .map(|mode| u32::from_str_radix(mode, 8).expect("invalid --socket-file-mode"));
         let monitoring_addr: SocketAddr = str_to_socketaddr(
             m.value_of("monitoring_addr")
                 .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port)),
             "Prometheus monitoring",
         );
+        let zmq_pub_addr: Option<SocketAddr> = m
+            .value_of("zmq_pub_addr")
+            .map(|addr| str_to_socketaddr(addr, "ZMQ publisher"));
+        let zmq_sequence_addr: Option<SocketAddr> = m
+            .value_of("zmq_sequence_addr")
+            .map(|addr| str_to_socketaddr(addr, "ZMQ sequence notifications"));
 
         let mut daemon_dir = m
             .value_of("daemon_dir")
@@ -346,27 +992,15 @@ impl Config {
                 default_dir.push(".bitcoin");
                 default_dir
             });
-        match network_type {
-            #[cfg(not(feature = "liquid"))]
-            Network::Bitcoin => (),
-            #[cfg(not(feature = "liquid"))]
-            Network::Testnet => daemon_dir.push("testnet3"),
-            #[cfg(not(feature = "liquid"))]
-            Network::Regtest => daemon_dir.push("regtest"),
-            #[cfg(not(feature = "liquid"))]
-            Network::Signet => daemon_dir.push("signet"),
-
-            #[cfg(feature = "liquid")]
-            Network::Liquid => daemon_dir.push("liquidv1"),
-            #[cfg(feature = "liquid")]
-            Network::LiquidTestnet => daemon_dir.push("liquidtestnet"),
-            #[cfg(feature = "liquid")]
-            Network::LiquidRegtest => daemon_dir.push("liquidregtest"),
+        let subdir = network_subdir(network_type);
+        if let Some(subdir) = subdir {
+            // allow pointing --daemon-dir at the network subdirectory itself
+            if !daemon_dir.ends_with(subdir) {
+                daemon_dir.push(subdir);
+            }
         }
-        let blocks_dir = m
-            .value_of("blocks_dir")
-            .map(PathBuf::from)
-            .unwrap_or_else(|| daemon_dir.join("blocks"));
+        let blocks_dirs =
+            blocks_dir_candidates(m.value_of("blocks_dir").map(Path::new), &daemon_dir, subdir);
         let cookie = m.value_of("cookie").map(|s| s.to_owned());
 
         let electrum_banner = m.value_of("electrum_banner").map_or_else(
@@ -374,6 +1008,19 @@ impl Config {
             |s| s.into(),
         );
 
+        let server_version = m.value_of("server_version").map_or_else(
+            || format!("electrs-esplora {}", ELECTRS_VERSION),
+            |s| s.into(),
+        );
+        let donation_address = m.value_of("donation_address").map(|address| {
+            address_to_script(address, network_type).expect("invalid --donation-address");
+            address.to_string()
+        });
+
+        let electrum_peers = m
+            .value_of("electrum_peers")
+            .map(|s| serde_json::from_str(s).expect("invalid --electrum-peers"));
+
         #[cfg(feature = "electrum-discovery")]
         let electrum_public_hosts = m
             .value_of("electrum_public_hosts")
@@ -386,29 +1033,189 @@ impl Config {
         } else {
             stderrlog::Timestamp::Off
         });
-        log.init().expect("logging initialization failed");
+        match m.value_of("log_format") {
+            Some("json") => JsonLogger::init(m.occurrences_of("verbosity") as usize)
+                .expect("logging initialization failed"),
+            _ => log.init().expect("logging initialization failed"),
+        }
+
+        let http_compression_level = value_t_or_exit!(m, "http_compression_level", u32);
+        assert!(
+            http_compression_level <= 9,
+            "invalid --http-compression-level (must be between 0 and 9)"
+        );
+
+        let fee_estimate_targets = values_t_or_exit!(m, "fee_estimate_targets", u16);
+        assert!(
+            fee_estimate_targets
+                .iter()
+                .all(|target| (1..=1008).contains(target)),
+            "invalid --fee-estimate-targets (must be between 1 and 1008)"
+        );
+
+        let index_cpus = m
+            .value_of("index_cpus")
+            .map(|s| parse_cpu_list(s).expect("invalid --index-cpus"));
+
+        let db_sync_policy = parse_db_sync_policy(m.value_of("db_sync").unwrap())
+            .expect("invalid --db-sync (must be always, interval:<blocks> or never-until-tip)");
+
+        let db_compression = parse_db_compression(m.value_of("db_compression").unwrap())
+            .expect("invalid --db-compression (must be DB=TYPE,..., with DB one of txstore, history or cache and TYPE one of none, snappy, lz4 or zstd)");
+
+        let rpc_batch_bounds = parse_batch_bounds(m.value_of("rpc_batch_size").unwrap())
+            .expect("invalid --rpc-batch-size (must be MIN:MAX, with 0 < MIN <= MAX)");
+        let fetch_batch_bounds = parse_batch_bounds(m.value_of("fetch_batch_size").unwrap())
+            .expect("invalid --fetch-batch-size (must be MIN:MAX, with 0 < MIN <= MAX)");
+
+        let api_keys: Vec<ApiKey> = m
+            .values_of("api_key")
+            .map(|keys| {
+                keys.map(|key| parse_api_key(key).expect("invalid --api-key"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let anonymous_quota = m
+            .value_of("anonymous_quota")
+            .map(|quota| parse_quota(quota).expect("invalid --anonymous-quota"))
+            .unwrap_or_default();
+
+        let shadow_compare_url = m.value_of("shadow_compare").map(|url| {
+            Url::parse(url)
+                .ok()
+                .filter(|url| url.scheme() == "http" && url.host_str().is_some())
+                .expect("invalid --shadow-compare (must be an http:// URL)")
+        });
+        let shadow_compare_sample = value_t_or_exit!(m, "shadow_compare_sample", usize);
+        assert!(
+            shadow_compare_sample > 0,
+            "--shadow-compare-sample must be at least 1"
+        );
+
+        // the events are sent over plain HTTP, without TLS
+        let webhook_urls: Vec<Url> = m
+            .values_of("webhook_url")
+            .map(|urls| {
+                urls.map(|url| {
+                    Url::parse(url)
+                        .ok()
+                        .filter(|url| url.scheme() == "http" && url.host_str().is_some())
+                        .expect("invalid --webhook-url (must be an http:// URL)")
+                })
+                .collect()
+            })
+            .unwrap_or_default();
+
+        let mode = match m.value_of("mode") {
+            _ if m.subcommand_matches("db-stats").is_some() => Mode::DbStats,
+            _ if m.subcommand_matches("check-spends").is_some() => Mode::CheckSpends {
+                repair: m
+                    .subcommand_matches("check-spends")
+                    .map_or(false, |m| m.is_present("repair")),
+            },
+            Some("index-only") => Mode::IndexOnly,
+            Some("serve-only") => Mode::ServeOnly,
+            _ => Mode::Full,
+        };
 
         let config = Config {
             log,
+            mode,
             network_type,
             db_path,
+            txstore_archive_path,
+            txstore_hot_size: value_t_or_exit!(m, "txstore_hot_size", u64) << 20,
             daemon_dir,
-            blocks_dir,
+            blocks_dirs,
             daemon_rpc_addr,
+            daemon_rpc_fallback_addrs: daemon_rpc_addrs,
+            daemon_observers,
+            rpc_batch_bounds,
+            fetch_batch_bounds,
             cookie,
+            blocked_scripts: m.value_of("blocked_scripts").map(|s| s.to_string()),
+            labels_file: m.value_of("labels_file").map(|s| s.to_string()),
+            api_keys,
+            api_keys_file: m.value_of("api_keys_file").map(|s| s.to_string()),
+            admin_api_keys: m
+                .values_of("admin_api_key")
+                .map(|names| names.map(String::from).collect())
+                .unwrap_or_default(),
+            anonymous_quota,
+            shadow_compare_url,
+            shadow_compare_sample,
+            webhook_urls,
+            webhook_retries: value_t_or_exit!(m, "webhook_retries", usize),
+            zmq_pub_addr,
+            zmq_sequence_addr,
             utxos_limit: value_t_or_exit!(m, "utxos_limit", usize),
+            max_query_items: value_t_or_exit!(m, "max_query_items", usize),
             electrum_rpc_addr,
             electrum_txs_limit: value_t_or_exit!(m, "electrum_txs_limit", usize),
+            fee_estimate_targets,
+            electrum_subscriptions_limit: value_t_or_exit!(
+                m,
+                "electrum_subscriptions_limit",
+                usize
+            ),
+            electrum_max_connections: value_t_or_exit!(m, "electrum_max_connections", usize),
+            electrum_blocking_threads: value_t_or_exit!(m, "electrum_blocking_threads", usize),
+            electrum_subscriptions_memory: value_t_or_exit!(
+                m,
+                "electrum_subscriptions_memory",
+                usize
+            ) << 20,
             electrum_banner,
+            server_version,
+            donation_address,
+            operator_contact: m.value_of("operator_contact").map(String::from),
+            electrum_peers,
             http_addr,
             http_socket_file,
+            electrum_socket_file,
+            electrum_tls,
+            monitoring_socket_file,
+            socket_file_mode,
+            electrum_idle_timeout: seconds_or_exit(&m, "electrum_idle_timeout"),
+            http_idle_timeout: seconds_or_exit(&m, "http_idle_timeout"),
+            tcp_keepalive: seconds_or_exit(&m, "tcp_keepalive"),
+            mempool_refresh_interval: Duration::from_millis(value_t_or_exit!(
+                m,
+                "mempool_refresh_interval",
+                u64
+            )),
+            http_compression_level,
+            http_compression_min_size: value_t_or_exit!(m, "http_compression_min_size", usize),
             monitoring_addr,
             jsonrpc_import: m.is_present("jsonrpc_import"),
             light_mode: m.is_present("light_mode"),
+            allow_pruned: m.is_present("allow_pruned"),
             address_search: m.is_present("address_search"),
             index_unspendables: m.is_present("index_unspendables"),
+            index_op_return: m.is_present("index_op_return"),
+            index_channels: m.is_present("index_channels"),
+            utxo_accumulator: m.is_present("utxo_accumulator"),
+            script_activity: m.is_present("script_activity"),
+            rich_list_size: value_t_or_exit!(m, "rich_list_size", usize),
+            block_age_stats: m.is_present("block_age_stats"),
+            index_start_height: value_t_or_exit!(m, "index_start_height", usize),
+            intern_scripts: m.is_present("intern_scripts"),
+            skip_dust: value_t_or_exit!(m, "skip_dust", u64),
+            hide_dust: value_t_or_exit!(m, "hide_dust", u64),
+            db_sync_policy,
+            db_compression,
+            zstd_dict_size: value_t_or_exit!(m, "zstd_dict_size", usize) << 10,
+            add_threads: value_t_or_exit!(m, "add_threads", usize),
+            index_threads: value_t_or_exit!(m, "index_threads", usize),
+            lookup_threads: value_t_or_exit!(m, "lookup_threads", usize),
+            index_cpus,
+            sync_memory_limit: value_t_or_exit!(m, "sync_memory_limit", usize) << 20,
+            cache_size_budget: value_t_or_exit!(m, "cache_size_budget", u64) << 20,
+            cache_ttl: seconds_or_exit(&m, "cache_ttl"),
             cors: m.value_of("cors").map(|s| s.to_string()),
             precache_scripts: m.value_of("precache_scripts").map(|s| s.to_string()),
+            warm_cache_scripts: value_t_or_exit!(m, "warm_cache_scripts", usize),
+            warm_cache_rate: value_t_or_exit!(m, "warm_cache_rate", u32),
 
             #[cfg(feature = "liquid")]
             parent_network,
@@ -429,6 +1236,79 @@ impl Config {
     }
 }
 
+#[cfg(not(feature = "liquid"))]
+fn custom_signet_params(m: &ArgMatches) -> Option<SignetParams> {
+    use std::convert::TryInto;
+
+    let magic = m.value_of("signet_magic");
+    let genesis_hash = m.value_of("signet_genesis_hash");
+    let prefixes = m.value_of("signet_address_prefixes");
+    if magic.is_none() && genesis_hash.is_none() && prefixes.is_none() {
+        return None;
+    }
+
+    let mut params = SignetParams::default_signet();
+    if let Some(magic) = magic {
+        let bytes = hex::decode(magic).expect("invalid --signet-magic");
+        let bytes: [u8; 4] = bytes.try_into().expect("--signet-magic must be 4 bytes");
+        params.magic = u32::from_le_bytes(bytes);
+    }
+    if let Some(genesis_hash) = genesis_hash {
+        params.genesis_hash = genesis_hash.parse().expect("invalid --signet-genesis-hash");
+    }
+    if let Some(prefixes) = prefixes {
+        let parts: Vec<&str> = prefixes.split(',').collect();
+        assert!(parts.len() == 3, "invalid --signet-address-prefixes");
+        params.p2pkh_prefix = parts[0].parse().expect("invalid p2pkh prefix");
+        params.p2sh_prefix = parts[1].parse().expect("invalid p2sh prefix");
+        params.bech32_hrp = parts[2].to_lowercase();
+    }
+    Some(params)
+}
+
+/// The subdirectory used by bitcoind (or elementsd) for the network, inside the data directory
+/// and the -blocksdir directory
+fn network_subdir(network: Network) -> Option<&'static str> {
+    match network {
+        #[cfg(not(feature = "liquid"))]
+        Network::Bitcoin => None,
+        #[cfg(not(feature = "liquid"))]
+        Network::Testnet => Some("testnet3"),
+        #[cfg(not(feature = "liquid"))]
+        Network::Regtest => Some("regtest"),
+        #[cfg(not(feature = "liquid"))]
+        Network::Signet | Network::CustomSignet(_) => Some("signet"),
+
+        #[cfg(feature = "liquid")]
+        Network::Liquid => Some("liquidv1"),
+        #[cfg(feature = "liquid")]
+        Network::LiquidTestnet => Some("liquidtestnet"),
+        #[cfg(feature = "liquid")]
+        Network::LiquidRegtest => Some("liquidregtest"),
+    }
+}
+
+// The directories that may hold the blk*.dat files, mirroring bitcoind's layout: an explicit
+// -blocksdir holds the blocks under `<network subdir>/blocks`, the data dir under `blocks`.
+// They are only looked into when the blocks are read from the files.
+fn blocks_dir_candidates(
+    blocks_dir: Option<&Path>,
+    daemon_dir: &Path,
+    subdir: Option<&str>,
+) -> Vec<PathBuf> {
+    match blocks_dir {
+        Some(blocks_dir) => {
+            let mut candidates = vec![blocks_dir.to_path_buf()];
+            if let Some(subdir) = subdir {
+                candidates.push(blocks_dir.join(subdir).join("blocks"));
+            }
+            candidates.push(blocks_dir.join("blocks"));
+            candidates
+        }
+        None => vec![daemon_dir.join("blocks")],
+    }
+}
+
 fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
     address
         .to_socket_addrs()
@@ -437,3 +1317,96 @@ fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
         .pop()
         .unwrap()
 }
+
+// A duration option given in seconds, where 0 disables it
+fn seconds_or_exit(m: &ArgMatches, name: &str) -> Option<Duration> {
+    match value_t_or_exit!(m, name, u64) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// An API key as `<name>:<key>[:<requests-per-second>[:<max-response-bytes>]]`
+pub fn parse_api_key(s: &str) -> Option<ApiKey> {
+    let mut parts = s.splitn(3, ':');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let key = parts.next().filter(|key| !key.is_empty())?;
+    let quota = parts.next().map_or(Some(Quota::default()), parse_quota)?;
+    Some(ApiKey {
+        name: name.to_string(),
+        key: key.to_string(),
+        quota,
+    })
+}
+
+// "<requests-per-second>[:<max-response-bytes>]", where an empty limit is unlimited
+fn parse_quota(s: &str) -> Option<Quota> {
+    fn limit<T: std::str::FromStr>(s: Option<&str>) -> Option<Option<T>> {
+        match s {
+            None | Some("") => Some(None),
+            Some(s) => s.parse().ok().map(Some),
+        }
+    }
+    let mut parts = s.splitn(2, ':');
+    Some(Quota {
+        rate: limit(parts.next())?,
+        max_response_size: limit(parts.next())?,
+    })
+}
+
+// "always", "interval:<blocks>" (with at least one block) or "never-until-tip"
+fn parse_db_sync_policy(s: &str) -> Option<DbSyncPolicy> {
+    match s {
+        "always" => Some(DbSyncPolicy::Always),
+        "never-until-tip" => Some(DbSyncPolicy::NeverUntilTip),
+        _ => {
+            let blocks: usize = s.strip_prefix("interval:")?.parse().ok()?;
+            (blocks > 0).then_some(DbSyncPolicy::Interval(blocks))
+        }
+    }
+}
+
+// "<db>=<type>,...", the DBs being named after their directory
+fn parse_db_compression(s: &str) -> Option<HashMap<String, DbCompression>> {
+    s.split(',')
+        .map(|part| {
+            let (db, compression) = part.split_once('=')?;
+            if !["txstore", "history", "cache"].contains(&db) {
+                return None;
+            }
+            let compression = match compression {
+                "none" => DbCompression::None,
+                "snappy" => DbCompression::Snappy,
+                "lz4" => DbCompression::Lz4,
+                "zstd" => DbCompression::Zstd,
+                _ => return None,
+            };
+            Some((db.to_string(), compression))
+        })
+        .collect()
+}
+
+// "<min>:<max>", with 0 < min <= max
+fn parse_batch_bounds(s: &str) -> Option<BatchBounds> {
+    let (min, max) = s.split_once(':')?;
+    let (min, max): (usize, usize) = (min.parse().ok()?, max.parse().ok()?);
+    (0 < min && min <= max).then_some(BatchBounds { min, max })
+}
+
+// A comma-separated list of CPUs and CPU ranges, e.g. "0,2-5"
+fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
@@ -0,0 +1,45 @@
+// The size of the request batches sent to bitcoind, adapted to the observed responses: the
+// blocks of the early chain are tiny and can be fetched by the thousand, while a batch of the
+// recent (up to 4MB) blocks has to be much smaller to keep its memory and latency in check.
+
+use std::time::Duration;
+
+use crate::config::BatchBounds;
+
+// a batch is sized so that its responses take about this much memory and time
+const TARGET_BYTES: f64 = (64 << 20) as f64;
+const TARGET_LATENCY: Duration = Duration::from_secs(5);
+
+pub struct BatchSize {
+    size: usize,
+    bounds: BatchBounds,
+}
+
+impl BatchSize {
+    pub fn new(initial: usize, bounds: BatchBounds) -> Self {
+        BatchSize {
+            size: initial.clamp(bounds.min, bounds.max),
+            bounds,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.size
+    }
+
+    /// Adapts the size to a full batch of `count` requests, whose responses took `bytes` and
+    /// `elapsed`. The size changes by a factor of 2 at most, so that a single outlier batch
+    /// doesn't swing it.
+    pub fn observe(&mut self, count: usize, bytes: usize, elapsed: Duration) {
+        if count < self.size {
+            // the partial batches tell nothing about how large a batch can be
+            return;
+        }
+        let by_bytes = TARGET_BYTES / (bytes.max(1) as f64 / count as f64);
+        let by_latency =
+            TARGET_LATENCY.as_secs_f64() / (elapsed.as_secs_f64().max(1e-3) / count as f64);
+        let current = self.size as f64;
+        let target = by_bytes.min(by_latency).clamp(current / 2.0, current * 2.0);
+        self.size = (target as usize).clamp(self.bounds.min, self.bounds.max);
+    }
+}
@@ -1,32 +1,50 @@
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Lines, Write},
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
     net::SocketAddr,
     net::TcpStream,
     sync::Arc,
     time::Duration,
 };
 
+use serde::{
+    de::{value::MapAccessDeserializer, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+
 use crate::errors::*;
 use crate::signal::Waiter;
 
 use super::CookieGetter;
 
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub(super) struct Connection {
     tx: TcpStream,
-    rx: Lines<BufReader<TcpStream>>,
+    rx: BufReader<TcpStream>,
     cookie_getter: Arc<dyn CookieGetter>,
-    addr: SocketAddr,
-    signal: Waiter,
 }
 
 impl Connection {
+    /// Connects to the daemon, retrying until it is reachable
     pub fn new(
         addr: SocketAddr,
         cookie_getter: Arc<dyn CookieGetter>,
-        signal: Waiter,
+        signal: &Waiter,
     ) -> Result<Self> {
-        let conn = tcp_connect(addr, &signal)?;
+        Self::from_stream(tcp_connect(addr, signal)?, cookie_getter)
+    }
+
+    /// Connects to the daemon once, failing with a `Connection` error when it is unreachable
+    pub fn connect(addr: SocketAddr, cookie_getter: Arc<dyn CookieGetter>) -> Result<Self> {
+        let conn = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .chain_err(|| ErrorKind::Connection(format!("failed to connect daemon at {}", addr)))?;
+        Self::from_stream(conn, cookie_getter)
+    }
+
+    fn from_stream(conn: TcpStream, cookie_getter: Arc<dyn CookieGetter>) -> Result<Self> {
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone: {:?}", conn))?,
@@ -34,17 +52,11 @@ impl Connection {
 
         Ok(Self {
             tx: conn,
-            rx: reader.lines(),
+            rx: reader,
             cookie_getter,
-            addr,
-            signal,
         })
     }
 
-    pub fn reconnect(&self) -> Result<Self> {
-        Self::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
-    }
-
     pub fn send(&mut self, request: &str) -> Result<()> {
         let cookie = &self.cookie_getter.get()?;
         let msg = format!(
@@ -59,69 +71,134 @@ impl Connection {
         })
     }
 
-    pub fn recv(&mut self) -> Result<String> {
-        let mut in_header = true;
-        let mut contents: Option<String> = None;
-
-        let iter = self.rx.by_ref();
-        let status = iter
-            .next()
-            .chain_err(|| {
-                ErrorKind::Connection("disconnected from daemon while receiving".to_string())
-            })?
-            .chain_err(|| "failed to read status")?;
-
-        let mut headers = HashMap::new();
-
-        for line in iter {
-            let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_string()))?;
-            if line.is_empty() {
-                in_header = false;
-            } else if in_header {
-                let parts: Vec<&str> = line.splitn(2, ": ").collect();
-                if parts.len() == 2 {
-                    headers.insert(parts[0].to_owned(), parts[1].to_owned());
-                } else {
-                    warn!("invalid header: {:?}", line);
-                }
-            } else {
-                contents = Some(line);
-                break;
-            }
+    /// Receive a JSON array reply, passing its elements to `f` as they are parsed, so that
+    /// the (possibly huge) response doesn't need to be buffered. Returns the response size.
+    pub fn recv_each<F>(&mut self, f: F) -> Result<usize>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        read_response(&mut self.rx, f)
+    }
+}
+
+/// Parses an HTTP response holding a JSON array, as `Connection::recv_each` does for the
+/// daemon's replies. Malformed responses fail with an error, the daemon can't be trusted to
+/// send well-formed ones.
+pub fn read_response<R, F>(rx: &mut R, f: F) -> Result<usize>
+where
+    R: BufRead,
+    F: FnMut(Value) -> Result<()>,
+{
+    let status = read_line(rx).chain_err(|| {
+        ErrorKind::Connection("disconnected from daemon while receiving".to_string())
+    })?;
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(rx)?;
+        if line.is_empty() {
+            break;
         }
+        let parts: Vec<&str> = line.splitn(2, ": ").collect();
+        if parts.len() == 2 {
+            headers.insert(parts[0].to_owned(), parts[1].to_owned());
+        } else {
+            warn!("invalid header: {:?}", line);
+        }
+    }
 
-        let contents =
-            contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_string()))?;
-        let contents_length = headers
-            .get("Content-Length")
-            .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
+    let contents_length = headers
+        .get("Content-Length")
+        .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
+    let contents_length: u64 = contents_length
+        .parse()
+        .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
+
+    let mut contents = rx.take(contents_length);
+    if status == "HTTP/1.1 500 Internal Server Error" {
+        warn!("HTTP status: {}", status); // the contents should have a JSONRPC error field
+    } else if status != "HTTP/1.1 200 OK" {
+        let mut body = String::new();
+        let _ = contents.read_to_string(&mut body);
+        bail!("request failed {:?}: {:?} = {:?}", status, headers, body);
+    }
 
-        let contents_length: usize = contents_length
-            .parse()
-            .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
+    let mut visitor = ReplyVisitor { f, error: None };
+    let parsed = serde_json::Deserializer::from_reader(&mut contents).deserialize_any(&mut visitor);
+    // consume the rest of the response (e.g. the trailing EOL), keeping the stream in sync
+    io::copy(&mut contents, &mut io::sink())
+        .chain_err(|| ErrorKind::Connection("failed to read".to_string()))?;
 
-        let expected_length = contents_length - 1; // trailing EOL is skipped
-        if expected_length != contents.len() {
+    if let Err(e) = parsed {
+        if e.is_io() || e.is_eof() {
             bail!(ErrorKind::Connection(format!(
-                "expected {} bytes, got {}",
-                expected_length,
-                contents.len()
+                "failed to read reply: {}",
+                e
             )));
         }
+        bail!("invalid JSON: {}", e);
+    }
+    if let Some(e) = visitor.error {
+        return Err(e);
+    }
+    Ok(contents_length as usize)
+}
 
-        Ok(if status == "HTTP/1.1 200 OK" {
-            contents
-        } else if status == "HTTP/1.1 500 Internal Server Error" {
-            warn!("HTTP status: {}", status);
-            contents // the contents should have a JSONRPC error field
-        } else {
-            bail!(
-                "request failed {:?}: {:?} = {:?}",
-                status,
-                headers,
-                contents
-            );
-        })
+fn read_line<R: BufRead>(rx: &mut R) -> Result<String> {
+    let mut line = String::new();
+    let size = rx
+        .read_line(&mut line)
+        .chain_err(|| ErrorKind::Connection("failed to read".to_string()))?;
+    if size == 0 {
+        bail!(ErrorKind::Connection(
+            "disconnected from daemon".to_string()
+        ));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+// Passes the array elements to `f`. After `f` fails, the rest of the elements are skipped
+// (but still consumed) and the error is kept for the caller.
+struct ReplyVisitor<F> {
+    f: F,
+    error: Option<Error>,
+}
+
+impl<'de, F> Visitor<'de> for &mut ReplyVisitor<F>
+where
+    F: FnMut(Value) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of JSONRPC replies")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while self.error.is_none() {
+            match seq.next_element::<Value>()? {
+                Some(reply) => {
+                    if let Err(e) = (self.f)(reply) {
+                        self.error = Some(e);
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_map<A>(self, map: A) -> std::result::Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let reply = Value::deserialize(MapAccessDeserializer::new(map))?;
+        self.error = Some(format!("non-array replies: {:?}", reply).into());
+        Ok(())
     }
 }
 
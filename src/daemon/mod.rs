@@ -1,28 +1,45 @@
+mod batch;
 mod connection;
 mod counter;
 mod network;
+mod observer;
 
+pub use batch::BatchSize;
 use bitcoin::consensus::serialize;
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::{consensus::deserialize, hashes::hex::FromHex, Block, BlockHeader, Transaction};
 use bitcoin::{BlockHash, Txid};
+pub use connection::read_response;
 use connection::*;
 pub use counter::*;
 pub use network::*;
+pub use observer::ConsensusMonitor;
 
 use itertools::Itertools;
 use prometheus::{HistogramOpts, HistogramVec};
-use serde_json::{from_str, from_value, Value};
+use serde_json::{from_value, Value};
 use std::collections::{HashMap, HashSet};
 use std::{
+    fs, iter,
     net::SocketAddr,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::util::block::HeaderList;
-use crate::{chain::Network, errors::*, metrics::Metrics, signal::Waiter};
+use crate::util::{block::HeaderList, request_id};
+use crate::{
+    chain::{genesis_hash, Network},
+    config::BatchBounds,
+    errors::*,
+    metrics::{CounterVec, GaugeVec, MetricOpts, Metrics},
+    signal::Waiter,
+};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
@@ -30,38 +47,98 @@ pub trait CookieGetter: Send + Sync {
 
 pub struct Daemon {
     daemon_dir: PathBuf,
-    blocks_dir: PathBuf,
+    blocks_dirs: Vec<PathBuf>, // the candidate directories of the blk*.dat files
     network: Network,
-    conn: Mutex<Connection>,
+    allow_pruned: bool,
+    mempool_sequence: bool, // whether getrawmempool reports the mempool sequence (0.21+)
+    primary: Endpoint,
+    fallbacks: Vec<Endpoint>, // for reads, while the primary is unhealthy
+    cookie_getter: Arc<dyn CookieGetter>,
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
+    last_health_check: Mutex<Option<Instant>>,
+    batch_bounds: BatchBounds,
+    // by method, kept by the reconnected clones
+    batch_sizes: Arc<Mutex<HashMap<String, BatchSize>>>,
 
     // For monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    endpoint_up: GaugeVec,
+    endpoint_failovers: CounterVec,
+    batch_size: GaugeVec,
+}
+
+/// A bitcoind JSONRPC endpoint, whose connection is re-established on its next use after
+/// connection errors
+struct Endpoint {
+    addr: SocketAddr,
+    conn: Mutex<Option<Connection>>,
+    healthy: Arc<AtomicBool>, // reachable, and neither in warmup nor syncing
+}
+
+impl Endpoint {
+    fn new(addr: SocketAddr, conn: Option<Connection>, healthy: bool) -> Self {
+        Endpoint {
+            addr,
+            conn: Mutex::new(conn),
+            healthy: Arc::new(AtomicBool::new(healthy)),
+        }
+    }
+
+    // The same endpoint for another thread, sharing its health. It connects on its first use,
+    // without waiting for an unreachable endpoint.
+    fn reconnect(&self) -> Self {
+        Endpoint {
+            addr: self.addr,
+            conn: Mutex::new(None),
+            healthy: Arc::clone(&self.healthy),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
 }
 
 impl Daemon {
     pub fn new(
         daemon_dir: &Path,
-        blocks_dir: &Path,
+        blocks_dirs: &[PathBuf],
         daemon_rpc_addr: SocketAddr,
+        fallback_addrs: &[SocketAddr],
         cookie_getter: Arc<dyn CookieGetter>,
         network: Network,
+        allow_pruned: bool,
+        batch_bounds: BatchBounds,
         signal: Waiter,
         metrics: &Metrics,
     ) -> Result<Self> {
-        let daemon = Self {
+        let mut daemon = Self {
             daemon_dir: daemon_dir.to_path_buf(),
-            blocks_dir: blocks_dir.to_path_buf(),
+            blocks_dirs: blocks_dirs.to_vec(),
             network,
-            conn: Mutex::new(Connection::new(
+            allow_pruned,
+            mempool_sequence: false,
+            primary: Endpoint::new(
                 daemon_rpc_addr,
-                cookie_getter,
-                signal.clone(),
-            )?),
+                Some(Connection::new(
+                    daemon_rpc_addr,
+                    Arc::clone(&cookie_getter),
+                    &signal,
+                )?),
+                true,
+            ),
+            fallbacks: fallback_addrs
+                .iter()
+                .map(|addr| Endpoint::new(*addr, None, false))
+                .collect(),
+            cookie_getter,
             message_id: Counter::default(),
             signal: signal.clone(),
+            last_health_check: Mutex::new(None),
+            batch_bounds,
+            batch_sizes: Arc::new(Mutex::new(HashMap::new())),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("daemon_rpc", "Bitcoind RPC latency (in seconds)"),
                 &["method"],
@@ -70,6 +147,27 @@ impl Daemon {
                 HistogramOpts::new("daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            endpoint_up: metrics.gauge_vec(
+                MetricOpts::new(
+                    "daemon_endpoint_up",
+                    "Whether a bitcoind endpoint is healthy",
+                ),
+                &["endpoint"],
+            ),
+            endpoint_failovers: metrics.counter_vec(
+                MetricOpts::new(
+                    "daemon_endpoint_failovers",
+                    "# of read requests served by a fallback bitcoind endpoint",
+                ),
+                &["endpoint"],
+            ),
+            batch_size: metrics.gauge_vec(
+                MetricOpts::new(
+                    "daemon_batch_size",
+                    "# of requests sent in a JSON-RPC batch, by method",
+                ),
+                &["method"],
+            ),
         };
 
         let network_info = daemon.getnetworkinfo()?;
@@ -81,12 +179,36 @@ impl Daemon {
                 network_info.subversion
             );
         }
+        daemon.mempool_sequence = network_info.version >= 210_000;
 
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:#?}", blockchain_info);
 
+        // fail before indexing another chain into the database of the configured one
+        if let Some(chain) = network.chain_name() {
+            ensure!(
+                blockchain_info.chain == chain,
+                "bitcoind is on the '{}' chain, but the configured network expects '{}'",
+                blockchain_info.chain,
+                chain
+            );
+            let genesis = daemon.getblockhash(0)?;
+            ensure!(
+                genesis == genesis_hash(network),
+                "bitcoind's genesis block {} doesn't match the configured network's {}",
+                genesis,
+                genesis_hash(network)
+            );
+        }
+
         if blockchain_info.pruned {
-            bail!("pruned node is not supported (use '-prune=0' bitcoind flag");
+            if !allow_pruned {
+                bail!("pruned node is not supported (use '-prune=0' bitcoind flag, or --allow-pruned)");
+            }
+            warn!(
+                "running against a pruned node, blocks below prune_height={} are not available",
+                blockchain_info.pruneheight.unwrap_or(0)
+            );
         }
 
         loop {
@@ -97,7 +219,7 @@ impl Daemon {
             }
 
             warn!(
-                "Waiting for bitcoind sync to finish: {}/{} blocks, verification progress: {:.3}%",
+                "Waiting for bitcoind sync to finish: blocks={} headers={} progress={:.3}%",
                 info.blocks,
                 info.headers,
                 info.verificationprogress * 100.0
@@ -106,28 +228,103 @@ impl Daemon {
             signal.wait(Duration::from_secs(5), false)?;
         }
 
+        daemon.health_check();
+
         Ok(daemon)
     }
 
     pub fn reconnect(&self) -> Result<Self> {
         Ok(Self {
             daemon_dir: self.daemon_dir.clone(),
-            blocks_dir: self.blocks_dir.clone(),
+            blocks_dirs: self.blocks_dirs.clone(),
             network: self.network,
-            conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
+            allow_pruned: self.allow_pruned,
+            mempool_sequence: self.mempool_sequence,
+            primary: self.primary.reconnect(),
+            fallbacks: self.fallbacks.iter().map(Endpoint::reconnect).collect(),
+            cookie_getter: Arc::clone(&self.cookie_getter),
             message_id: Counter::default(),
             signal: self.signal.clone(),
+            last_health_check: Mutex::new(*self.last_health_check.lock().unwrap()),
+            batch_bounds: self.batch_bounds,
+            batch_sizes: Arc::clone(&self.batch_sizes),
             latency: self.latency.clone(),
             size: self.size.clone(),
+            endpoint_up: self.endpoint_up.clone(),
+            endpoint_failovers: self.endpoint_failovers.clone(),
+            batch_size: self.batch_size.clone(),
         })
     }
 
+    /// Probes the endpoints, at most every `HEALTH_CHECK_INTERVAL`, so that reads go back to
+    /// the primary once it recovers, and only fail over to the available fallbacks
+    pub fn health_check(&self) {
+        {
+            let mut last_health_check = self.last_health_check.lock().unwrap();
+            if let Some(last) = *last_health_check {
+                if last.elapsed() < HEALTH_CHECK_INTERVAL {
+                    return;
+                }
+            }
+            *last_health_check = Some(Instant::now());
+        }
+        for endpoint in iter::once(&self.primary).chain(&self.fallbacks) {
+            let result =
+                self.handle_request_batch(endpoint, "getblockchaininfo", &[json!([])], |reply| {
+                    let info: BlockchainInfo =
+                        from_value(reply).chain_err(|| "invalid blockchain info")?;
+                    ensure!(
+                        !info.initialblockdownload.unwrap_or(false),
+                        "initial block download"
+                    );
+                    Ok(())
+                });
+            if let Err(e) = &result {
+                debug!("daemon endpoint={} is unhealthy: {}", endpoint.addr, e);
+            }
+            self.set_healthy(endpoint, result.is_ok());
+        }
+    }
+
+    fn set_healthy(&self, endpoint: &Endpoint, healthy: bool) {
+        if endpoint.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match healthy {
+                true => info!("daemon endpoint={} is healthy", endpoint.addr),
+                false => warn!("daemon endpoint={} is unhealthy", endpoint.addr),
+            }
+        }
+        self.endpoint_up
+            .with_label_values(&[&endpoint.addr.to_string()])
+            .set(if healthy { 1.0 } else { 0.0 });
+    }
+
     pub fn magic(&self) -> u32 {
         self.network.magic()
     }
 
+    // The first candidate directory holding blk*.dat files. It is only needed to read the blocks
+    // from the files, which the deployments importing over JSONRPC (or with bitcoind on another
+    // host) don't do.
+    fn blocks_dir(&self) -> Result<&Path> {
+        self.blocks_dirs
+            .iter()
+            .find(|dir| has_blk_files(dir))
+            .map(PathBuf::as_path)
+            .ok_or_else(|| {
+                let tried: Vec<String> = self
+                    .blocks_dirs
+                    .iter()
+                    .map(|dir| format!("  {}", dir.display()))
+                    .collect();
+                Error::from(format!(
+                    "no blk*.dat files found, tried:\n{}\nuse --blocks-dir to point at bitcoind's blocks directory, or --jsonrpc-import",
+                    tried.join("\n")
+                ))
+            })
+    }
+
     pub fn list_block_files(&self) -> Result<Vec<PathBuf>> {
-        let path = self.blocks_dir.join("blk*.dat");
+        let path = self.blocks_dir()?.join("blk*.dat");
         debug!("Listing block files at: {:?}", path);
         let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
             .chain_err(|| "failed to list blk*.dat files")?
@@ -139,8 +336,20 @@ impl Daemon {
         Ok(paths)
     }
 
-    // Get estimated feerates for the provided confirmation targets using a batch RPC request
-    // Missing estimates are logged but do not cause a failure, whatever is available is returned
+    /// The key used by bitcoind (28+) to obfuscate the blk*.dat files, if they are obfuscated
+    pub fn block_files_xor_key(&self) -> Result<Option<Vec<u8>>> {
+        let path = self.blocks_dir()?.join("xor.dat");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let key = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        ensure!(!key.is_empty(), "empty XOR key at {:?}", path);
+        // an all-zero key leaves the files as they are
+        Ok(Some(key).filter(|key| key.iter().any(|b| *b != 0)))
+    }
+
+    /// The fee rates (in BTC/kvB) of the confirmation targets, estimated in a single batch RPC
+    /// request. The targets that bitcoind couldn't estimate are logged and left out.
     #[allow(clippy::float_cmp)]
     pub fn estimatesmartfee_batch(&self, conf_targets: &[u16]) -> Result<HashMap<u16, f64>> {
         let params_list: Vec<Value> = conf_targets.iter().map(|t| json!([t])).collect();
@@ -152,7 +361,7 @@ impl Daemon {
             .filter_map(|(reply, target)| {
                 if !reply["errors"].is_null() {
                     warn!(
-                        "failed estimating fee for target {}: {:?}",
+                        "failed estimating fee for target={}: {:?}",
                         target, reply["errors"]
                     );
                     return None;
@@ -163,18 +372,17 @@ impl Daemon {
                     .unwrap_or_else(|| panic!("invalid estimatesmartfee response: {:?}", reply));
 
                 if feerate == -1f64 {
-                    warn!("not enough data to estimate fee for target {}", target);
+                    warn!("not enough data to estimate fee for target={}", target);
                     return None;
                 }
 
-                // from BTC/kB to sat/b
-                Some((*target, feerate * 100_000f64))
+                Some((*target, feerate))
             })
             .collect())
     }
 
     fn get_all_headers(&self, tip: &BlockHash) -> Result<Vec<BlockHeader>> {
-        let info: Value = self.request("getblockheader", json!([tip.to_hex()]))?;
+        let info: Value = self.read_request("getblockheader", json!([tip.to_hex()]))?;
         let tip_height = info
             .get("height")
             .expect("missing height")
@@ -207,11 +415,14 @@ impl Daemon {
     ) -> Result<Vec<BlockHeader>> {
         // Iterate back over headers until known blockash is found:
         if indexed_headers.is_empty() {
-            debug!("downloading all block headers up to {}", bestblockhash);
+            debug!(
+                "downloading all block headers up to blockhash={}",
+                bestblockhash
+            );
             return self.get_all_headers(bestblockhash);
         }
         debug!(
-            "downloading new block headers ({} already indexed) from {}",
+            "downloading new block headers (indexed={}) from blockhash={}",
             indexed_headers.len(),
             bestblockhash,
         );
@@ -233,33 +444,115 @@ impl Daemon {
         Ok(new_headers)
     }
 
-    pub fn get_relayfee(&self) -> Result<f64> {
-        let relayfee = self.getnetworkinfo()?.relayfee;
+    /// The height of the oldest block still available from bitcoind, for pruned nodes
+    pub fn prune_height(&self) -> Result<Option<usize>> {
+        if !self.allow_pruned {
+            return Ok(None);
+        }
+        let info = self.getblockchaininfo()?;
+        Ok(match info.pruned {
+            true => info.pruneheight.map(|height| height as usize),
+            false => None,
+        })
+    }
 
-        // from BTC/kB to sat/b
-        Ok(relayfee * 100_000f64)
+    /// The minimum relay fee rate, in BTC/kvB
+    pub fn get_relayfee(&self) -> Result<f64> {
+        Ok(self.getnetworkinfo()?.relayfee)
     }
 
     fn request(&self, method: &str, params: Value) -> Result<Value> {
-        let mut values = self.retry_request_batch(method, &[params])?;
+        let mut values = self.requests(method, &[params])?;
+        assert_eq!(values.len(), 1);
+        Ok(values.remove(0))
+    }
+
+    fn read_request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut values = Vec::with_capacity(1);
+        self.read_requests_each(method, &[params], |value| {
+            values.push(value);
+            Ok(())
+        })?;
         assert_eq!(values.len(), 1);
         Ok(values.remove(0))
     }
 
     fn requests(&self, method: &str, params: &[Value]) -> Result<Vec<Value>> {
-        self.retry_request_batch(method, params)
+        let mut results = Vec::with_capacity(params.len());
+        self.requests_each(method, params, |result| {
+            results.push(result);
+            Ok(())
+        })?;
+        Ok(results)
     }
 
-    fn retry_request_batch(&self, method: &str, params: &[Value]) -> Result<Vec<Value>> {
+    fn read_requests(&self, method: &str, params: &[Value]) -> Result<Vec<Value>> {
+        let mut results = Vec::with_capacity(params.len());
+        self.read_requests_each(method, params, |result| {
+            results.push(result);
+            Ok(())
+        })?;
+        Ok(results)
+    }
+
+    /// Pass the results to `f` as they are received, in the order of `params`
+    fn requests_each<F>(&self, method: &str, params: &[Value], f: F) -> Result<()>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        self.requests_each_with(method, params, false, f)
+    }
+
+    /// Like `requests_each`, for reads that are sent to the fallback endpoints while the
+    /// primary is in warmup or unreachable
+    fn read_requests_each<F>(&self, method: &str, params: &[Value], f: F) -> Result<()>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        self.requests_each_with(method, params, !self.fallbacks.is_empty(), f)
+    }
+
+    fn requests_each_with<F>(
+        &self,
+        method: &str,
+        params: &[Value],
+        failover: bool,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        if let Some(id) = request_id::current() {
+            debug!(
+                "daemon rpc method={} batch={} request_id={}",
+                method,
+                params.len(),
+                id
+            );
+        }
+        // on reconnection, resume after the results that were already passed to `f`
+        let mut done = 0;
         loop {
-            match self.handle_request_batch(method, params) {
+            if failover
+                && !self.primary.is_healthy()
+                && self.failover_requests(method, params, &mut done, &mut f)?
+            {
+                return Ok(());
+            }
+            let result =
+                self.handle_request_batch(&self.primary, method, &params[done..], |result| {
+                    f(result)?;
+                    done += 1;
+                    Ok(())
+                });
+            match result {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
+                    self.set_healthy(&self.primary, false);
+                    if failover && self.failover_requests(method, params, &mut done, &mut f)? {
+                        return Ok(());
+                    }
                     warn!("reconnecting to bitcoind: {}", msg);
                     self.signal.wait(Duration::from_secs(3), false)?;
-
-                    let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
-
                     continue;
                 }
                 result => return result,
@@ -267,33 +560,128 @@ impl Daemon {
         }
     }
 
-    fn handle_request_batch(&self, method: &str, params: &[Value]) -> Result<Vec<Value>> {
+    // Sends the remaining requests to the first healthy fallback that serves them all, returning
+    // false if none did
+    fn failover_requests<F>(
+        &self,
+        method: &str,
+        params: &[Value],
+        done: &mut usize,
+        f: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        for fallback in self.fallbacks.iter().filter(|e| e.is_healthy()) {
+            let result = self.handle_request_batch(fallback, method, &params[*done..], |result| {
+                f(result)?;
+                *done += 1;
+                Ok(())
+            });
+            match result {
+                Ok(()) => {
+                    self.endpoint_failovers
+                        .with_label_values(&[&fallback.addr.to_string()])
+                        .inc();
+                    return Ok(true);
+                }
+                Err(Error(ErrorKind::Connection(msg), _)) => {
+                    warn!("daemon endpoint={} failed: {}", fallback.addr, msg);
+                    self.set_healthy(fallback, false);
+                }
+                Err(e) => {
+                    // e.g. a fallback that is behind the primary, let the primary serve it
+                    warn!("daemon endpoint={} failed: {}", fallback.addr, e);
+                    break;
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn handle_request_batch<F>(
+        &self,
+        endpoint: &Endpoint,
+        method: &str,
+        params: &[Value],
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
         let id = self.message_id.next();
-        let chunks = params
-            .iter()
-            .map(|p| json!({"method": method, "params": p, "id": id}))
-            .chunks(50_000);
+        let mut params = params;
+        while !params.is_empty() {
+            let size = self.next_batch_size(method).min(params.len());
+            let (chunk, rest) = params.split_at(size);
+            let req = chunk
+                .iter()
+                .map(|p| json!({"method": method, "params": p, "id": id}))
+                .collect();
+            let start = Instant::now();
+            let response_len = self.call_jsonrpc(endpoint, method, &req, |reply| {
+                f(parse_jsonrpc_reply(reply, method, id)?)
+            })?;
+            self.observe_batch(method, size, response_len, start.elapsed());
+            params = rest;
+        }
 
-        let mut results = vec![];
+        Ok(())
+    }
 
-        for chunk in &chunks {
-            let req = chunk.collect();
-            let mut replies = self.call_jsonrpc(method, &req)?;
+    fn next_batch_size(&self, method: &str) -> usize {
+        let batch_sizes = self.batch_sizes.lock().unwrap();
+        batch_sizes
+            .get(method)
+            .map_or(self.batch_bounds.max, BatchSize::get)
+    }
 
-            if let Some(replies_vec) = replies.as_array_mut() {
-                for reply in replies_vec {
-                    results.push(parse_jsonrpc_reply(reply.take(), method, id)?);
-                }
-            } else {
-                bail!("non-array replies: {:?}", replies);
-            }
-        }
+    fn observe_batch(&self, method: &str, count: usize, bytes: usize, elapsed: Duration) {
+        let mut batch_sizes = self.batch_sizes.lock().unwrap();
+        let batch_size = batch_sizes
+            .entry(method.to_string())
+            .or_insert_with(|| BatchSize::new(self.batch_bounds.max, self.batch_bounds));
+        batch_size.observe(count, bytes, elapsed);
+        self.batch_size
+            .with_label_values(&[method])
+            .set(batch_size.get() as f64);
+    }
 
-        Ok(results)
+    fn call_jsonrpc<F>(
+        &self,
+        endpoint: &Endpoint,
+        method: &str,
+        request: &Value,
+        f: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        let mut conn = endpoint.conn.lock().unwrap();
+        if conn.is_none() {
+            *conn = Some(Connection::connect(
+                endpoint.addr,
+                Arc::clone(&self.cookie_getter),
+            )?);
+        }
+        let result = self.send_jsonrpc(conn.as_mut().unwrap(), method, request, f);
+        if let Err(Error(ErrorKind::Connection(_), _)) = result {
+            *conn = None; // reconnect on the next request
+        }
+        result
     }
 
-    fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
-        let mut conn = self.conn.lock().unwrap();
+    // Returns the size of the response
+    fn send_jsonrpc<F>(
+        &self,
+        conn: &mut Connection,
+        method: &str,
+        request: &Value,
+        f: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
 
@@ -303,17 +691,15 @@ impl Daemon {
             .with_label_values(&[method, "send"])
             .observe(request.len() as f64);
 
-        let response = conn.recv()?;
-
-        let result: Value = from_str(&response).chain_err(|| "invalid JSON")?;
+        let response_len = conn.recv_each(f)?;
 
         timer.observe_duration();
 
         self.size
             .with_label_values(&[method, "recv"])
-            .observe(response.len() as f64);
+            .observe(response_len as f64);
 
-        Ok(result)
+        Ok(response_len)
     }
 }
 
@@ -324,28 +710,37 @@ impl Daemon {
         from_value(info).chain_err(|| "invalid network info")
     }
 
+    pub fn getmempoolinfo(&self) -> Result<MempoolInfo> {
+        let info: Value = self.request("getmempoolinfo", json!([]))?;
+        from_value(info).chain_err(|| "invalid mempool info")
+    }
+
     fn getblockchaininfo(&self) -> Result<BlockchainInfo> {
         let info: Value = self.request("getblockchaininfo", json!([]))?;
         from_value(info).chain_err(|| "invalid blockchain info")
     }
 
     pub fn getbestblockhash(&self) -> Result<BlockHash> {
-        parse_hash(&self.request("getbestblockhash", json!([]))?)
+        parse_hash(&self.read_request("getbestblockhash", json!([]))?)
+    }
+
+    pub fn getblockhash(&self, height: usize) -> Result<BlockHash> {
+        parse_hash(&self.read_request("getblockhash", json!([height]))?)
     }
 
     pub fn getblockheader(&self, blockhash: &BlockHash) -> Result<BlockHeader> {
-        header_from_value(self.request("getblockheader", json!([blockhash.to_hex(), false]))?)
+        header_from_value(self.read_request("getblockheader", json!([blockhash.to_hex(), false]))?)
     }
 
     pub fn getblockheaders(&self, heights: &[usize]) -> Result<Vec<BlockHeader>> {
         let heights: Vec<Value> = heights.iter().map(|height| json!([height])).collect();
         let params_list: Vec<Value> = self
-            .requests("getblockhash", &heights)?
+            .read_requests("getblockhash", &heights)?
             .into_iter()
             .map(|hash| json!([hash, /*verbose=*/ false]))
             .collect();
         let mut result = vec![];
-        for h in self.requests("getblockheader", &params_list)? {
+        for h in self.read_requests("getblockheader", &params_list)? {
             result.push(header_from_value(h)?);
         }
 
@@ -354,7 +749,7 @@ impl Daemon {
 
     pub fn getblock(&self, blockhash: &BlockHash) -> Result<Block> {
         let block = block_from_value(
-            self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?,
+            self.read_request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?,
         )?;
         assert_eq!(block.block_hash(), *blockhash);
 
@@ -362,7 +757,7 @@ impl Daemon {
     }
 
     pub fn getblock_raw(&self, blockhash: &BlockHash, verbose: u32) -> Result<Value> {
-        self.request("getblock", json!([blockhash.to_hex(), verbose]))
+        self.read_request("getblock", json!([blockhash.to_hex(), verbose]))
     }
 
     pub fn getblocks(&self, blockhashes: &[BlockHash]) -> Result<Vec<Block>> {
@@ -370,11 +765,12 @@ impl Daemon {
             .iter()
             .map(|hash| json!([hash.to_hex(), /*verbose=*/ false]))
             .collect();
-        let values = self.requests("getblock", &params_list)?;
-        let mut blocks = vec![];
-        for value in values {
+        // parse the blocks as they arrive, without keeping their hex around
+        let mut blocks = Vec::with_capacity(blockhashes.len());
+        self.read_requests_each("getblock", &params_list, |value| {
             blocks.push(block_from_value(value)?);
-        }
+            Ok(())
+        })?;
         Ok(blocks)
     }
 
@@ -384,7 +780,11 @@ impl Daemon {
             .map(|txhash| json!([txhash.to_hex(), /*verbose=*/ false]))
             .collect();
 
-        let values = self.requests("getrawtransaction", &params_list)?;
+        let mut values = Vec::with_capacity(params_list.len());
+        self.read_requests_each("getrawtransaction", &params_list, |value| {
+            values.push(value);
+            Ok(())
+        })?;
         let mut txs = vec![];
         for value in values {
             txs.push(tx_from_value(value)?);
@@ -393,20 +793,39 @@ impl Daemon {
         Ok(txs)
     }
 
+    /// Like `gettransactions`, for transactions of known blocks, which don't need txindex
+    pub fn gettransactions_in_blocks(
+        &self,
+        txids: &[(&Txid, &BlockHash)],
+    ) -> Result<Vec<Transaction>> {
+        let params_list: Vec<Value> = txids
+            .iter()
+            .map(|(txid, blockhash)| json!([txid.to_hex(), /*verbose=*/ false, blockhash]))
+            .collect();
+
+        let mut txs = Vec::with_capacity(params_list.len());
+        self.read_requests_each("getrawtransaction", &params_list, |value| {
+            txs.push(tx_from_value(value)?);
+            Ok(())
+        })?;
+        assert_eq!(txids.len(), txs.len());
+        Ok(txs)
+    }
+
     pub fn gettransaction_raw(
         &self,
         txid: &Txid,
         blockhash: &BlockHash,
         verbose: bool,
     ) -> Result<Value> {
-        self.request(
+        self.read_request(
             "getrawtransaction",
             json!([txid.to_hex(), verbose, blockhash]),
         )
     }
 
     pub fn getmempooltx(&self, txhash: &Txid) -> Result<Transaction> {
-        let value = self.request(
+        let value = self.read_request(
             "getrawtransaction",
             json!([txhash.to_hex(), /*verbose=*/ false]),
         )?;
@@ -418,6 +837,55 @@ impl Daemon {
         serde_json::from_value(res).chain_err(|| "invalid getrawmempool reply")
     }
 
+    /// The mempool txids along with the mempool sequence, which bitcoind bumps on every
+    /// addition or removal. The sequence is `None` on daemons that don't report it.
+    pub fn getmempooltxids_sequence(&self) -> Result<(HashSet<Txid>, Option<u64>)> {
+        if !self.mempool_sequence {
+            return Ok((self.getmempooltxids()?, None));
+        }
+        let res = self.request(
+            "getrawmempool",
+            json!([/*verbose=*/ false, /*mempool_sequence=*/ true]),
+        )?;
+        let reply: MempoolSequence =
+            serde_json::from_value(res).chain_err(|| "invalid getrawmempool reply")?;
+        Ok((reply.txids, Some(reply.mempool_sequence)))
+    }
+
+    // Regtest-only helpers, for scripting blocks and reorgs against the indexer
+
+    pub fn generatetoaddress(&self, nblocks: u32, address: &str) -> Result<Vec<BlockHash>> {
+        self.ensure_regtest("generatetoaddress")?;
+        let blockhashes = self.request("generatetoaddress", json!([nblocks, address]))?;
+        blockhashes
+            .as_array()
+            .chain_err(|| "non-array generatetoaddress reply")?
+            .iter()
+            .map(parse_hash)
+            .collect()
+    }
+
+    pub fn invalidateblock(&self, blockhash: &BlockHash) -> Result<()> {
+        self.ensure_regtest("invalidateblock")?;
+        self.request("invalidateblock", json!([blockhash.to_hex()]))?;
+        Ok(())
+    }
+
+    pub fn reconsiderblock(&self, blockhash: &BlockHash) -> Result<()> {
+        self.ensure_regtest("reconsiderblock")?;
+        self.request("reconsiderblock", json!([blockhash.to_hex()]))?;
+        Ok(())
+    }
+
+    fn ensure_regtest(&self, method: &str) -> Result<()> {
+        ensure!(
+            self.network.is_regtest(),
+            "{} is only available on regtest",
+            method
+        );
+        Ok(())
+    }
+
     pub fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
         self.broadcast_raw(&hex::encode(serialize(tx)))
     }
@@ -438,6 +906,10 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
+                        // RPC_MISC_ERROR for blocks deleted by pruning
+                        -1 if err.to_string().contains("pruned data") => {
+                            bail!(ErrorKind::PrunedBlock(err.to_string()))
+                        }
                         _ => bail!("{} RPC error: {}", method, err),
                     }
                 }
@@ -468,6 +940,14 @@ fn parse_error_code(err: &Value) -> Option<i64> {
     err.as_object()?.get("code")?.as_i64()
 }
 
+// the first files may be gone on pruned nodes, so look for any of them
+fn has_blk_files(dir: &Path) -> bool {
+    dir.join("blk*.dat")
+        .to_str()
+        .and_then(|pattern| glob::glob(pattern).ok())
+        .map_or(false, |mut paths| paths.next().is_some())
+}
+
 fn parse_hash<T>(value: &Value) -> Result<T>
 where
     T: FromHex,
@@ -1,3 +1,6 @@
+use std::collections::HashSet;
+
+use bitcoin::Txid;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -7,6 +10,21 @@ pub struct NetworkInfo {
     pub(super) relayfee: f64, // in BTC/kB
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MempoolInfo {
+    pub size: u64,          // # of transactions
+    pub usage: u64,         // in bytes
+    pub maxmempool: u64,    // in bytes
+    pub mempoolminfee: f64, // in BTC/kB
+    pub minrelaytxfee: f64, // in BTC/kB
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MempoolSequence {
+    pub(super) txids: HashSet<Txid>,
+    pub(super) mempool_sequence: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BlockchainInfo {
     pub chain: String,
@@ -14,6 +32,7 @@ pub struct BlockchainInfo {
     pub headers: u32,
     pub bestblockhash: String,
     pub pruned: bool,
+    pub pruneheight: Option<u32>,
     pub verificationprogress: f32,
     pub initialblockdownload: Option<bool>,
 }
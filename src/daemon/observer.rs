@@ -0,0 +1,250 @@
+// Chain split monitoring: the best blocks of other bitcoind nodes (see `--observer-rpc-addr`)
+// are polled and compared with the one of the primary, so that the operators notice when their
+// node follows a minority fork or stopped following the chain. A tip that is one or two blocks
+// apart is normal while a block propagates, only the tips that aren't in each other's best
+// chain are reported as a split.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bitcoin::{hashes::hex::ToHex, BlockHash};
+use serde_json::Value;
+
+use super::{parse_hash, parse_jsonrpc_reply, Connection, CookieGetter, Daemon};
+use crate::{
+    config::{Config, StaticCookie},
+    errors::*,
+    metrics::{Counter, GaugeVec, MetricOpts, Metrics},
+    util::{spawn_thread, unix_time},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const AGREEMENTS: &[Agreement] = &[
+    Agreement::Agrees,
+    Agreement::Ahead,
+    Agreement::Behind,
+    Agreement::Diverged,
+    Agreement::Unreachable,
+];
+
+/// How the best block of an observer relates to the one of the primary
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Agreement {
+    Agrees,
+    /// The observer's best chain extends the primary's
+    Ahead,
+    /// The primary's best chain extends the observer's
+    Behind,
+    /// Neither best chain includes the other's tip
+    Diverged,
+    Unreachable,
+}
+
+impl Agreement {
+    fn name(self) -> &'static str {
+        match self {
+            Agreement::Agrees => "agrees",
+            Agreement::Ahead => "ahead",
+            Agreement::Behind => "behind",
+            Agreement::Diverged => "diverged",
+            Agreement::Unreachable => "unreachable",
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct ObserverStatus {
+    endpoint: String,
+    agreement: Agreement,
+    best_hash: Option<BlockHash>,
+    error: Option<String>,
+    checked_at: u32,
+}
+
+#[derive(Serialize, Clone, Default)]
+struct ConsensusStatus {
+    primary_best_hash: Option<BlockHash>,
+    observers: Vec<ObserverStatus>,
+}
+
+// A bitcoind node that is only asked for its best chain
+struct Observer {
+    addr: SocketAddr,
+    cookie_getter: Arc<dyn CookieGetter>,
+    conn: Option<Connection>,
+}
+
+impl Observer {
+    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        if self.conn.is_none() {
+            self.conn = Some(Connection::connect(
+                self.addr,
+                Arc::clone(&self.cookie_getter),
+            )?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        let request = json!([{ "method": method, "params": params, "id": 0 }]);
+        let mut reply = None;
+        let result = conn.send(&request.to_string()).and_then(|_| {
+            conn.recv_each(|value| {
+                reply = Some(parse_jsonrpc_reply(value, method, 0)?);
+                Ok(())
+            })
+        });
+        if let Err(Error(ErrorKind::Connection(_), _)) = result {
+            self.conn = None; // reconnect on the next poll
+        }
+        result?;
+        reply.chain_err(|| format!("empty {} reply", method))
+    }
+
+    fn getbestblockhash(&mut self) -> Result<BlockHash> {
+        parse_hash(&self.request("getbestblockhash", json!([]))?)
+    }
+
+    fn in_best_chain(&mut self, blockhash: &BlockHash) -> Result<bool> {
+        in_best_chain(self.request("getblockheader", json!([blockhash.to_hex()])))
+    }
+}
+
+impl Daemon {
+    /// Whether the primary knows `blockhash` as part of its best chain
+    pub fn in_best_chain(&self, blockhash: &BlockHash) -> Result<bool> {
+        in_best_chain(self.request("getblockheader", json!([blockhash.to_hex()])))
+    }
+}
+
+// The unknown blocks fail with an RPC error, while the stale ones have -1 confirmations
+fn in_best_chain(header: Result<Value>) -> Result<bool> {
+    match header {
+        Ok(header) => Ok(header["confirmations"].as_i64().unwrap_or(-1) > 0),
+        Err(Error(ErrorKind::Connection(msg), _)) => bail!(ErrorKind::Connection(msg)),
+        Err(_) => Ok(false),
+    }
+}
+
+pub struct ConsensusMonitor {
+    status: Arc<Mutex<ConsensusStatus>>,
+    agreement: GaugeVec,
+    splits: Counter,
+}
+
+impl ConsensusMonitor {
+    /// Polls the observers in the background, serving their status at /internal/consensus.
+    /// Nothing is started without observers.
+    pub fn start(config: &Config, daemon: Arc<Daemon>, metrics: &Metrics) {
+        if config.daemon_observers.is_empty() {
+            return;
+        }
+        let mut observers: Vec<Observer> = config
+            .daemon_observers
+            .iter()
+            .map(|(addr, cookie)| Observer {
+                addr: *addr,
+                cookie_getter: match cookie {
+                    Some(cookie) => Arc::new(StaticCookie {
+                        value: cookie.as_bytes().to_vec(),
+                    }),
+                    None => config.cookie_getter(),
+                },
+                conn: None,
+            })
+            .collect();
+        info!("monitoring the chain of {} observers", observers.len());
+
+        let monitor = ConsensusMonitor {
+            status: Arc::default(),
+            agreement: metrics.gauge_vec(
+                MetricOpts::new(
+                    "daemon_observer_agreement",
+                    "Whether the best block of an observer bitcoind relates to the primary's as the status says",
+                ),
+                &["endpoint", "status"],
+            ),
+            splits: metrics.counter(MetricOpts::new(
+                "daemon_observer_splits",
+                "# of times an observer bitcoind was found on a chain diverging from the primary's",
+            )),
+        };
+        let status = Arc::clone(&monitor.status);
+        metrics.document("/internal/consensus", move || {
+            json!(*status.lock().unwrap())
+        });
+
+        spawn_thread("consensus-monitor", move || loop {
+            monitor.poll(&daemon, &mut observers);
+            std::thread::sleep(POLL_INTERVAL);
+        });
+    }
+
+    fn poll(&self, daemon: &Daemon, observers: &mut [Observer]) {
+        let primary_tip = match daemon.getbestblockhash() {
+            Ok(tip) => tip,
+            Err(e) => {
+                warn!("failed to get the primary's best block: {}", e);
+                return;
+            }
+        };
+        let previous = self.status.lock().unwrap().observers.clone();
+        let statuses: Vec<ObserverStatus> = observers
+            .iter_mut()
+            .map(|observer| {
+                let endpoint = observer.addr.to_string();
+                let (agreement, best_hash, error) = match compare(daemon, observer, &primary_tip) {
+                    Ok((agreement, tip)) => (agreement, Some(tip), None),
+                    Err(e) => (Agreement::Unreachable, None, Some(e.to_string())),
+                };
+                let was_diverged = previous.iter().any(|status| {
+                    status.endpoint == endpoint && status.agreement == Agreement::Diverged
+                });
+                if let (Agreement::Diverged, Some(tip), false) =
+                    (agreement, best_hash, was_diverged)
+                {
+                    warn!(
+                        "chain split: observer {} is at {}, which diverges from the primary's {}",
+                        endpoint, tip, primary_tip
+                    );
+                    self.splits.inc();
+                }
+                for other in AGREEMENTS {
+                    self.agreement
+                        .with_label_values(&[&endpoint, other.name()])
+                        .set((*other == agreement) as i64 as f64);
+                }
+                ObserverStatus {
+                    endpoint,
+                    agreement,
+                    best_hash,
+                    error,
+                    checked_at: unix_time(),
+                }
+            })
+            .collect();
+        *self.status.lock().unwrap() = ConsensusStatus {
+            primary_best_hash: Some(primary_tip),
+            observers: statuses,
+        };
+    }
+}
+
+fn compare(
+    daemon: &Daemon,
+    observer: &mut Observer,
+    primary_tip: &BlockHash,
+) -> Result<(Agreement, BlockHash)> {
+    let tip = observer.getbestblockhash()?;
+    let agreement = if tip == *primary_tip {
+        Agreement::Agrees
+    } else if observer.in_best_chain(primary_tip)? {
+        Agreement::Ahead
+    } else if daemon.in_best_chain(&tip)? {
+        Agreement::Behind
+    } else {
+        Agreement::Diverged
+    };
+    Ok((agreement, tip))
+}
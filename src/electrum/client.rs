@@ -0,0 +1,69 @@
+use std::{collections::HashMap, convert::TryFrom, ops::Deref};
+
+use bitcoin::{hashes::Hash, BlockHash};
+use electrum_client::ServerFeaturesRes;
+
+use crate::{
+    electrum::ServerFeatures,
+    errors::{Error, Result, ResultExt},
+};
+
+pub struct Client {
+    client: electrum_client::Client,
+}
+
+impl Client {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: electrum_client::Client::new(url)?,
+        })
+    }
+
+    pub fn from_config(url: &str, config: electrum_client::Config) -> Result<Self> {
+        Ok(Self {
+            client: electrum_client::Client::from_config(url, config)?,
+        })
+    }
+}
+
+impl Deref for Client {
+    type Target = electrum_client::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl TryFrom<ServerFeaturesRes> for ServerFeatures {
+    type Error = Error;
+
+    fn try_from(features: ServerFeaturesRes) -> Result<Self> {
+        let genesis_hash = {
+            // electrum-client parses the hex in display (reversed) order
+            let mut genesis_hash = features.genesis_hash;
+            genesis_hash.reverse();
+            BlockHash::from_inner(genesis_hash)
+        };
+
+        Ok(Self {
+            // electrum-client doesn't retain the hosts map data, but we already have it from the add_peer request
+            hosts: HashMap::new(),
+            genesis_hash,
+            server_version: features.server_version,
+            protocol_min: features
+                .protocol_min
+                .parse()
+                .chain_err(|| "invalid protocol_min")?,
+            protocol_max: features
+                .protocol_max
+                .parse()
+                .chain_err(|| "invalid protocol_max")?,
+            pruning: features.pruning.map(|pruning| pruning as usize),
+            hash_function: features
+                .hash_function
+                .chain_err(|| "missing hash_function")?,
+            donation_address: None,
+            operator_contact: None,
+        })
+    }
+}
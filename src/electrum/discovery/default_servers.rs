@@ -0,0 +1,57 @@
+use crate::chain::Network;
+use crate::electrum::discovery::{DiscoveryManager, Service};
+
+pub fn add_default_servers(discovery: &DiscoveryManager, network: Network) {
+    match network {
+        #[cfg(not(feature = "liquid"))]
+        Network::Bitcoin => {
+            discovery
+                .add_default_server(
+                    "electrum.blockstream.info".into(),
+                    vec![Service::Tcp(50001), Service::Ssl(50002)],
+                )
+                .ok();
+            discovery
+                .add_default_server(
+                    "blockstream.info".into(),
+                    vec![Service::Tcp(110), Service::Ssl(700)],
+                )
+                .ok();
+            discovery
+                .add_default_server(
+                    "electrum.emzy.de".into(),
+                    vec![Service::Tcp(50001), Service::Ssl(50002)],
+                )
+                .ok();
+            discovery
+                .add_default_server(
+                    "electrum.bitaroo.net".into(),
+                    vec![Service::Tcp(50001), Service::Ssl(50002)],
+                )
+                .ok();
+        }
+        #[cfg(not(feature = "liquid"))]
+        Network::Testnet => {
+            discovery
+                .add_default_server(
+                    "electrum.blockstream.info".into(),
+                    vec![Service::Tcp(60001), Service::Ssl(60002)],
+                )
+                .ok();
+            discovery
+                .add_default_server(
+                    "blockstream.info".into(),
+                    vec![Service::Tcp(143), Service::Ssl(993)],
+                )
+                .ok();
+            discovery
+                .add_default_server(
+                    "testnet.aranguren.org".into(),
+                    vec![Service::Tcp(51001), Service::Ssl(51002)],
+                )
+                .ok();
+        }
+
+        _ => (),
+    }
+}
@@ -1 +1,122 @@
+mod server;
 
+pub use server::{parse_request, Request, RPC};
+
+#[cfg(feature = "electrum-discovery")]
+mod client;
+#[cfg(feature = "electrum-discovery")]
+mod discovery;
+#[cfg(feature = "electrum-discovery")]
+pub use {client::Client, discovery::DiscoveryManager};
+
+use std::{cmp::Ordering, collections::HashMap, fmt, str::FromStr};
+
+use bitcoin::BlockHash;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::errors::*;
+
+pub type Port = u16;
+pub type Hostname = String;
+
+pub type ServerHosts = HashMap<Hostname, ServerPorts>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct ServerFeatures {
+    pub hosts: ServerHosts,
+    pub genesis_hash: BlockHash,
+    pub server_version: String,
+    pub protocol_min: ProtocolVersion,
+    pub protocol_max: ProtocolVersion,
+    pub pruning: Option<usize>,
+    pub hash_function: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub donation_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_contact: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerPorts {
+    pub tcp_port: Option<Port>,
+    pub ssl_port: Option<Port>,
+}
+
+impl ServerPorts {
+    /// The port entries in the `server.peers.subscribe` format (e.g. `t50001`, `s50002`)
+    pub fn feature_strs(&self) -> Vec<String> {
+        let tcp = self.tcp_port.map(|port| format!("t{}", port));
+        let ssl = self.ssl_port.map(|port| format!("s{}", port));
+        tcp.into_iter().chain(ssl).collect()
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct ProtocolVersion {
+    major: usize,
+    minor: usize,
+}
+
+impl ProtocolVersion {
+    pub const fn new(major: usize, minor: usize) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl Ord for ProtocolVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.major
+            .cmp(&other.major)
+            .then_with(|| self.minor.cmp(&other.minor))
+    }
+}
+
+impl PartialOrd for ProtocolVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iter = s.split('.');
+        Ok(Self {
+            major: iter
+                .next()
+                .chain_err(|| "missing major")?
+                .parse()
+                .chain_err(|| "invalid major")?,
+            minor: iter
+                .next()
+                .chain_err(|| "missing minor")?
+                .parse()
+                .chain_err(|| "invalid minor")?,
+        })
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl Serialize for ProtocolVersion {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolVersion {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
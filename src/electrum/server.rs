@@ -0,0 +1,1253 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io, iter, mem,
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use bitcoin::{consensus::serialize, Txid};
+use error_chain::ChainedError;
+use serde_json::{from_str, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{oneshot, watch, Semaphore},
+    task, time,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+#[cfg(feature = "electrum-discovery")]
+use crate::electrum::DiscoveryManager;
+use crate::{
+    chain::{genesis_hash, BlockHash},
+    config::{Config, ElectrumTls},
+    electrum::{ProtocolVersion, ServerFeatures, ServerHosts},
+    errors::*,
+    indexer::query::Query,
+    metrics::{Counter, CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics},
+    store::ScriptStats,
+    util::{
+        block::{HeaderEntry, HeaderList, HeaderSnapshot},
+        electrum_status::{status_hash, HistoryEntry},
+        fees::sat_per_vb_to_btc_per_kvb,
+        full_hash, remove_stale_socket, request_id, set_socket_mode, spawn_thread,
+        systemd::{self, ListenSocket},
+        FullHash,
+    },
+};
+
+const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+const MAX_HEADERS: usize = 2016;
+// a client further behind syncs with blockchain.block.headers instead of the backfill
+const MAX_BACKFILL_HEADERS: usize = 10 * MAX_HEADERS;
+const MAX_LOCATOR_HASHES: usize = 64;
+const BAD_REQUEST: i32 = 1; // ElectrumX's error code for requests it refuses to serve
+const INTERNAL_ERROR: i32 = -32603; // JSONRPC's error code for a failure of the server
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the map and queue entries of a subscription
+const SUBSCRIPTION_SIZE: usize =
+    mem::size_of::<(FullHash, Option<FullHash>)>() + mem::size_of::<FullHash>();
+
+struct Connection {
+    query: Arc<Query>,
+    addr: SocketAddr,
+    stats: Arc<Stats>,
+    info: Arc<ServerInfo>,
+    txs_limit: usize,
+    subscriptions: Subscriptions,
+    headers_subscription: Option<HeadersSubscription>,
+    // the notifications to send after the reply to the current request
+    pending: Vec<Value>,
+    limits: Arc<SessionLimits>,
+    // the number of mempool conflicts recorded as of the last update
+    conflicts_seen: u64,
+}
+
+struct HeadersSubscription {
+    // the header chain the client was last notified of
+    sent: HeaderSnapshot,
+    // sent the headers from the fork point with `sent` in batches, instead of the new tip
+    backfill: bool,
+}
+
+impl Connection {
+    fn new(
+        query: Arc<Query>,
+        addr: SocketAddr,
+        stats: Arc<Stats>,
+        info: Arc<ServerInfo>,
+        txs_limit: usize,
+        limits: Arc<SessionLimits>,
+    ) -> Self {
+        let conflicts_seen = query.mempool().conflicts_recorded();
+        Self {
+            query,
+            addr,
+            stats,
+            info,
+            txs_limit,
+            subscriptions: Subscriptions::default(),
+            headers_subscription: None,
+            pending: vec![],
+            limits,
+            conflicts_seen,
+        }
+    }
+
+    fn server_version(&self) -> Result<Value> {
+        Ok(json!([self.info.features.server_version, PROTOCOL_VERSION]))
+    }
+
+    fn server_banner(&self) -> Result<Value> {
+        Ok(json!(self.info.banner))
+    }
+
+    fn server_donation_address(&self) -> Result<Value> {
+        Ok(json!(self.info.features.donation_address))
+    }
+
+    fn server_features(&self) -> Result<Value> {
+        Ok(json!(self.info.features))
+    }
+
+    fn server_peers_subscribe(&self) -> Result<Value> {
+        #[cfg(feature = "electrum-discovery")]
+        {
+            if let Some(discovery) = &self.info.discovery {
+                return Ok(json!(discovery.get_servers()));
+            }
+        }
+
+        Ok(json!(self.info.peers))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn server_add_peer(&self, params: &[Value]) -> Result<Value> {
+        let ip = self.addr.ip();
+        let features = params
+            .get(0)
+            .chain_err(|| "missing features param")?
+            .clone();
+        let features = serde_json::from_value(features).chain_err(|| "invalid features")?;
+        let discovery = self
+            .info
+            .discovery
+            .as_ref()
+            .chain_err(|| "discovery is disabled")?;
+        discovery.add_server_request(ip, features)?;
+        Ok(json!(true))
+    }
+
+    fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.get(0), "height")?;
+        let cp_height = usize_from_value_or(params.get(1), "cp_height", 0)?;
+
+        let raw_header_hex = self.raw_header_hex(height)?;
+        if cp_height == 0 {
+            return Ok(json!(raw_header_hex));
+        }
+        let (branch, root) = self.query.chain().header_merkle_proof(height, cp_height)?;
+
+        Ok(json!({
+            "header": raw_header_hex,
+            "root": root,
+            "branch": branch
+        }))
+    }
+
+    fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
+        let start_height = usize_from_value(params.get(0), "start_height")?;
+        let count = MAX_HEADERS.min(usize_from_value(params.get(1), "count")?);
+        let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
+        let end_height = start_height
+            .checked_add(count)
+            .chain_err(|| format!("start_height {} out of range", start_height))?;
+
+        let headers: Vec<String> = self
+            .query
+            .chain()
+            .headers_range(start_height, count)?
+            .iter()
+            .map(|header| hex::encode(serialize(header)))
+            .collect();
+
+        if count == 0 || cp_height == 0 {
+            return Ok(json!({
+                "count": headers.len(),
+                "hex": headers.join(""),
+                "max": MAX_HEADERS,
+            }));
+        }
+
+        // the proof is for the last requested header, which must not be above the checkpoint
+        let last_height = end_height - 1;
+        ensure!(
+            headers.len() == count,
+            "header #{} above the best block",
+            last_height
+        );
+        let (branch, root) = self
+            .query
+            .chain()
+            .header_merkle_proof(last_height, cp_height)?;
+
+        Ok(json!({
+            "count": headers.len(),
+            "hex": headers.join(""),
+            "max": MAX_HEADERS,
+            "root": root,
+            "branch": branch,
+        }))
+    }
+
+    // With a locator (the hashes of the client's latest blocks, newest first), the headers
+    // after the latest of them still in the best chain are then sent as
+    // `blockchain.headers.backfill` notifications, and so are the headers from the fork point
+    // on every later tip change, so that a client also resumes across reorgs.
+    fn blockchain_headers_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let headers = self.query.chain().headers();
+        let backfill_start = params
+            .get(0)
+            .map(|locator| backfill_start(&headers, locator))
+            .transpose()?;
+        let tip = headers
+            .header_by_blockhash(headers.tip())
+            .chain_err(|| "no headers indexed yet")?;
+        let result = header_value(tip);
+
+        if let Some(start_height) = backfill_start {
+            self.pending
+                .extend(backfill_notifications(&headers, start_height));
+        }
+        self.headers_subscription = Some(HeadersSubscription {
+            sent: headers,
+            backfill: backfill_start.is_some(),
+        });
+        Ok(result)
+    }
+
+    // The notifications of a new tip for the headers subscriber
+    fn update_headers_subscription(&mut self) -> Vec<Value> {
+        let subscription = match &mut self.headers_subscription {
+            Some(subscription) => subscription,
+            None => return vec![],
+        };
+        let headers = self.query.chain().headers();
+        if subscription.sent.equals(&headers) {
+            return vec![];
+        }
+        let notifications = if subscription.backfill {
+            backfill_notifications(&headers, subscription.sent.fork_height(&headers))
+        } else {
+            headers
+                .header_by_blockhash(headers.tip())
+                .map(|tip| {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "method": "blockchain.headers.subscribe",
+                        "params": [header_value(tip)],
+                    })
+                })
+                .into_iter()
+                .collect()
+        };
+        subscription.sent = headers;
+        notifications
+    }
+
+    fn raw_header_hex(&self, height: usize) -> Result<String> {
+        let header = self
+            .query
+            .chain()
+            .header_by_height(height)
+            .chain_err(|| format!("missing header #{}", height))?;
+        Ok(hex::encode(serialize(&header)))
+    }
+
+    fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
+        let scripthash = scripthash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let (chain_stats, mempool_stats) = self.query.stats(&scripthash)?;
+
+        Ok(json!({
+            "confirmed": balance(&chain_stats),
+            "unconfirmed": balance(&mempool_stats),
+        }))
+    }
+
+    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
+        let scripthash = scripthash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        Ok(json!(self.history(&scripthash)?))
+    }
+
+    // The confirmed then unconfirmed history of a script, used for its status too so that
+    // both always agree
+    fn history(&self, scripthash: &FullHash) -> Result<Vec<HistoryEntry>> {
+        let history_txids = self.query.history_txids(scripthash, self.txs_limit)?;
+        let mempool = self.query.mempool();
+        Ok(history_txids
+            .into_iter()
+            .map(|(txid, blockid)| {
+                let has_unconfirmed_parents =
+                    blockid.is_none() && mempool.has_unconfirmed_parents(&txid);
+                HistoryEntry::new(txid, blockid.as_ref(), has_unconfirmed_parents)
+            })
+            .collect())
+    }
+
+    fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let scripthash = scripthash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let status = self.scripthash_status(&scripthash)?;
+
+        if !self.subscriptions.contains(&scripthash) {
+            self.reserve_subscription()?;
+        }
+        self.subscriptions.insert(scripthash, status);
+        while self.subscriptions.len() > self.limits.max_subscriptions {
+            let oldest = self.subscriptions.pop_oldest().unwrap();
+            self.release_subscriptions(1);
+            self.stats.subscriptions_evicted.inc();
+            debug!(
+                "[{}] dropped oldest subscription scripthash={}",
+                self.addr,
+                hex::encode(&oldest)
+            );
+        }
+
+        Ok(json!(status.map(hex::encode)))
+    }
+
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let scripthash = scripthash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let removed = self.subscriptions.remove(&scripthash);
+        if removed {
+            self.release_subscriptions(1);
+        }
+        Ok(json!(removed))
+    }
+
+    fn scripthash_status(&self, scripthash: &FullHash) -> Result<Option<FullHash>> {
+        Ok(status_hash(&self.history(scripthash)?))
+    }
+
+    // Accounts for a new subscription in the memory of all sessions, failing with
+    // `SessionLimit` (which closes the session) when it would exceed the limit
+    fn reserve_subscription(&self) -> Result<()> {
+        let memory = self
+            .limits
+            .memory
+            .fetch_add(SUBSCRIPTION_SIZE, Ordering::Relaxed)
+            + SUBSCRIPTION_SIZE;
+        if memory > self.limits.max_memory {
+            self.limits
+                .memory
+                .fetch_sub(SUBSCRIPTION_SIZE, Ordering::Relaxed);
+            self.stats.sessions_dropped.inc();
+            bail!(ErrorKind::SessionLimit(format!(
+                "subscriptions memory above {} bytes",
+                self.limits.max_memory
+            )));
+        }
+        self.stats.subscriptions.inc();
+        self.stats.subscriptions_memory.set(memory as i64);
+        Ok(())
+    }
+
+    fn release_subscriptions(&self, count: usize) {
+        let size = count * SUBSCRIPTION_SIZE;
+        let memory = self.limits.memory.fetch_sub(size, Ordering::Relaxed) - size;
+        self.stats.subscriptions.sub(count as i64);
+        self.stats.subscriptions_memory.set(memory as i64);
+    }
+
+    // The notifications of the subscriptions whose status changed
+    fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&["periodic_update"])
+            .start_timer();
+        let scripthashes: Vec<FullHash> = self.subscriptions.scripthashes().cloned().collect();
+        let mut notifications = self.update_headers_subscription();
+        for scripthash in scripthashes {
+            let status = match self.scripthash_status(&scripthash) {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!(
+                        "[{}] failed updating scripthash={}: {}",
+                        self.addr,
+                        hex::encode(&scripthash),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if self.subscriptions.insert(scripthash, status) {
+                notifications.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [scripthash_to_value(&scripthash), status.map(hex::encode)]
+                }));
+            }
+        }
+        notifications.extend(self.conflict_notifications());
+        timer.observe_duration();
+        Ok(notifications)
+    }
+
+    // The double spends involving the subscribed scripts since the last update, sent as
+    // `blockchain.scripthash.conflict` notifications so that the wallets can warn about them
+    fn conflict_notifications(&mut self) -> Vec<Value> {
+        let conflicts = {
+            let mempool = self.query.mempool();
+            let conflicts = mempool.conflicts_since(self.conflicts_seen);
+            self.conflicts_seen = mempool.conflicts_recorded();
+            conflicts
+        };
+        conflicts
+            .into_iter()
+            .filter(|(scripthash, ..)| self.subscriptions.contains(scripthash))
+            .map(|(scripthash, txid, other)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.conflict",
+                    "params": [
+                        scripthash_to_value(&scripthash),
+                        { "txid": txid, "conflicting_txid": other },
+                    ],
+                })
+            })
+            .collect()
+    }
+
+    fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
+        let conf_target = usize_from_value(params.get(0), "blocks_count")?;
+        let fee_rate = self
+            .query
+            .estimate_fee(conf_target.min(u16::MAX as usize) as u16);
+        // in BTC/kvB, or -1 when there is no estimate
+        Ok(json!(fee_rate.map_or(-1.0, sat_per_vb_to_btc_per_kvb)))
+    }
+
+    // The minimum fee for transactions to enter bitcoind's mempool, which is above the relay
+    // fee while the mempool is full
+    fn blockchain_relayfee(&self) -> Result<Value> {
+        let min_fees = self
+            .query
+            .min_fees()
+            .chain_err(|| "relay fee is unavailable")?;
+        let min_fee = min_fees.relay_fee.max(min_fees.mempool_min_fee);
+        Ok(json!(sat_per_vb_to_btc_per_kvb(min_fee)))
+    }
+
+    fn mempool_get_fee_histogram(&self) -> Result<Value> {
+        Ok(json!(self.query.mempool().fee_histogram()))
+    }
+
+    fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
+        let txid = txid_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
+        let verbose = match params.get(1) {
+            Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
+            None => false,
+        };
+
+        let (tx, blockid) = self
+            .query
+            .lookup_txn(&txid)?
+            .chain_err(|| format!("tx {} not found", txid))?;
+        if !verbose {
+            return Ok(json!(hex::encode(serialize(&tx))));
+        }
+
+        // bitcoind only finds the confirmed transactions by their block without -txindex
+        let blockid = blockid.chain_err(|| format!("tx {} is unconfirmed", txid))?;
+        let mut txval = self
+            .query
+            .daemon()
+            .gettransaction_raw(&txid, &blockid.hash, true)?;
+        let fee = self.query.chain().tx_fee(&tx, None)?;
+        if let (Some(txval), Some(fee)) = (txval.as_object_mut(), fee) {
+            // in BTC, like bitcoind's verbose transactions
+            txval.insert("fee".to_string(), json!(fee as f64 / 100_000_000f64));
+        }
+        Ok(txval)
+    }
+
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+        // commands are handled on the blocking threads, outside of the connection's task
+        request_id::enter(Some(request_id::next()), || {
+            self.handle_command_inner(method, params, id)
+        })
+    }
+
+    fn handle_command_inner(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        id: &Value,
+    ) -> Result<Value> {
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&[method])
+            .start_timer();
+        let result = match method {
+            "blockchain.block.header" => self.blockchain_block_header(params),
+            "blockchain.block.headers" => self.blockchain_block_headers(params),
+            "blockchain.estimatefee" => self.blockchain_estimatefee(params),
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(params),
+            "blockchain.relayfee" => self.blockchain_relayfee(),
+            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(params),
+            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(params),
+            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(params),
+            "blockchain.transaction.get" => self.blockchain_transaction_get(params),
+            "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "server.banner" => self.server_banner(),
+            "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
+            "server.peers.subscribe" => self.server_peers_subscribe(),
+            "server.ping" => Ok(Value::Null),
+            "server.version" => self.server_version(),
+
+            #[cfg(feature = "electrum-discovery")]
+            "server.add_peer" => self.server_add_peer(params),
+
+            &_ => bail!("unknown method {} {:?}", method, params),
+        };
+        timer.observe_duration();
+
+        // closes the session instead of replying
+        if let Err(Error(ErrorKind::SessionLimit(_), _)) = result {
+            return result;
+        }
+
+        Ok(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            // the error ElectrumX replies with, which clients know to report to the user
+            Err(Error(ErrorKind::TooPopular, _)) => {
+                self.stats
+                    .history_refused
+                    .with_label_values(&[method])
+                    .inc();
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": BAD_REQUEST, "message": "history too large"},
+                })
+            }
+            // a corrupt or incomplete index, the client isn't to blame
+            Err(e @ Error(ErrorKind::DbInconsistency(_), _)) => {
+                error!(
+                    "rpc id={} method={} {:?} failed (request_id={}): {}",
+                    id,
+                    method,
+                    params,
+                    request_id::current().unwrap_or_default(),
+                    e.display_chain()
+                );
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": INTERNAL_ERROR, "message": e.to_string()},
+                })
+            }
+            Err(e) => {
+                warn!(
+                    "rpc id={} method={} {:?} failed (request_id={}): {}",
+                    id,
+                    method,
+                    params,
+                    request_id::current().unwrap_or_default(),
+                    e.display_chain()
+                );
+                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+            }
+        })
+    }
+
+    // The reply, followed by the notifications it queued
+    fn handle_line(&mut self, line: &[u8]) -> Result<Vec<Value>> {
+        let request = parse_request(line)?;
+        let reply = self.handle_command(&request.method, &request.params, &request.id)?;
+        let mut values = vec![reply];
+        values.append(&mut self.pending);
+        Ok(values)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        debug!("[{}] shutting down connection", self.addr);
+        self.release_subscriptions(self.subscriptions.len());
+    }
+}
+
+// Serves the requests and subscription updates of a connection, one at a time. The queries run
+// on the blocking pool, so that they don't stall the other connections.
+async fn serve<S>(
+    conn: Connection,
+    stream: S,
+    mut updates: watch::Receiver<()>,
+    idle_timeout: Option<Duration>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let conn = Arc::new(Mutex::new(conn));
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::<u8>::new();
+    let idle = time::sleep(idle_timeout.unwrap_or_default());
+    tokio::pin!(idle);
+
+    loop {
+        let replies = tokio::select! {
+            // partially read lines are kept in `line` when another branch completes first
+            read = reader.read_until(b'\n', &mut line) => {
+                if read.chain_err(|| "failed to read a request")? == 0 {
+                    return Ok(()); // closed by the peer
+                }
+                if let Some(timeout) = idle_timeout {
+                    idle.as_mut().reset(time::Instant::now() + timeout);
+                }
+                let request = mem::take(&mut line);
+                let conn = Arc::clone(&conn);
+                blocking(move || conn.lock().unwrap().handle_line(&request)).await?
+            }
+            changed = updates.changed() => {
+                if changed.is_err() {
+                    return Ok(()); // the server is stopping
+                }
+                let conn = Arc::clone(&conn);
+                blocking(move || conn.lock().unwrap().update_subscriptions()).await?
+            }
+            _ = &mut idle, if idle_timeout.is_some() => {
+                debug!("closing idle connection");
+                return Ok(());
+            }
+        };
+        send_values(&mut writer, &replies).await?;
+    }
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let id = request_id::current();
+    task::spawn_blocking(move || request_id::enter(id, f))
+        .await
+        .chain_err(|| "blocking task failed")?
+}
+
+async fn send_values<W: AsyncWrite + Unpin>(writer: &mut W, values: &[Value]) -> Result<()> {
+    for value in values {
+        let line = value.to_string() + "\n";
+        writer
+            .write_all(line.as_bytes())
+            .await
+            .chain_err(|| format!("failed to send {}", value))?;
+    }
+    Ok(())
+}
+
+struct Stats {
+    latency: HistogramVec,
+    connections: Gauge,
+    subscriptions: Gauge,
+    subscriptions_memory: Gauge,
+    subscriptions_evicted: Counter,
+    sessions_dropped: Counter,
+    history_refused: CounterVec,
+}
+
+/// Limits on the subscriptions held by the sessions, shared by all connections
+struct SessionLimits {
+    max_subscriptions: usize, // per session, the oldest are dropped above it
+    max_memory: usize,        // of all the sessions, in bytes
+    memory: AtomicUsize,
+}
+
+/// The scripthashes a session is subscribed to, with the last status sent for each
+#[derive(Default)]
+struct Subscriptions {
+    statuses: HashMap<FullHash, Option<FullHash>>,
+    order: VecDeque<FullHash>, // oldest first
+}
+
+impl Subscriptions {
+    fn len(&self) -> usize {
+        self.statuses.len()
+    }
+
+    fn contains(&self, scripthash: &FullHash) -> bool {
+        self.statuses.contains_key(scripthash)
+    }
+
+    fn scripthashes(&self) -> impl Iterator<Item = &FullHash> {
+        self.order.iter()
+    }
+
+    // Returns whether the status changed, including for new subscriptions
+    fn insert(&mut self, scripthash: FullHash, status: Option<FullHash>) -> bool {
+        match self.statuses.insert(scripthash, status) {
+            Some(prev) => prev != status,
+            None => {
+                self.order.push_back(scripthash);
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, scripthash: &FullHash) -> bool {
+        if self.statuses.remove(scripthash).is_none() {
+            return false;
+        }
+        self.order.retain(|s| s != scripthash);
+        true
+    }
+
+    fn pop_oldest(&mut self) -> Option<FullHash> {
+        let scripthash = self.order.pop_front()?;
+        self.statuses.remove(&scripthash);
+        Some(scripthash)
+    }
+}
+
+/// Server information that is shared (read-only) by all connections
+struct ServerInfo {
+    banner: String,
+    features: ServerFeatures,
+    peers: Vec<Value>,
+    #[cfg(feature = "electrum-discovery")]
+    discovery: Option<Arc<DiscoveryManager>>,
+}
+
+pub struct RPC {
+    updates: watch::Sender<()>,
+    shutdown: Option<oneshot::Sender<()>>,
+    server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this object
+}
+
+impl RPC {
+    /// Notifies the sessions of new blocks and mempool transactions
+    pub fn notify(&self) {
+        // busy sessions get a single update once they are done
+        self.updates.send_replace(());
+    }
+
+    pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> Self {
+        let stats = Arc::new(Stats {
+            latency: metrics.histogram_vec(
+                HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
+                &["method"],
+            ),
+            connections: metrics.gauge(MetricOpts::new(
+                "electrum_connections",
+                "# of open Electrum connections",
+            )),
+            subscriptions: metrics.gauge(MetricOpts::new(
+                "electrum_subscriptions",
+                "# of scripthash subscriptions of all sessions",
+            )),
+            subscriptions_memory: metrics.gauge(MetricOpts::new(
+                "electrum_subscriptions_memory",
+                "Memory used by the scripthash subscriptions of all sessions (in bytes)",
+            )),
+            subscriptions_evicted: metrics.counter(MetricOpts::new(
+                "electrum_subscriptions_evicted",
+                "# of subscriptions dropped for exceeding the per-session limit",
+            )),
+            sessions_dropped: metrics.counter(MetricOpts::new(
+                "electrum_sessions_dropped",
+                "# of sessions closed for exceeding the subscriptions memory limit",
+            )),
+            history_refused: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_history_refused",
+                    "# of requests refused for scripts whose history exceeds the limit",
+                ),
+                &["method"],
+            ),
+        });
+        let limits = Arc::new(SessionLimits {
+            max_subscriptions: config.electrum_subscriptions_limit,
+            max_memory: config.electrum_subscriptions_memory,
+            memory: AtomicUsize::new(0),
+        });
+
+        let features = server_features(&config);
+
+        #[cfg(feature = "electrum-discovery")]
+        let discovery = config.electrum_public_hosts.as_ref().map(|_| {
+            let discovery = Arc::new(DiscoveryManager::new(
+                config.network_type,
+                features.clone(),
+                PROTOCOL_VERSION,
+                config.electrum_announce,
+                config.tor_proxy,
+            ));
+            if let Some(peers) = &config.electrum_peers {
+                discovery.add_static_servers(peers);
+            }
+            DiscoveryManager::spawn_jobs_thread(Arc::clone(&discovery));
+            discovery
+        });
+
+        let info = Arc::new(ServerInfo {
+            banner: config.electrum_banner.clone(),
+            features,
+            peers: config
+                .electrum_peers
+                .as_ref()
+                .map_or_else(Vec::new, static_peers),
+            #[cfg(feature = "electrum-discovery")]
+            discovery,
+        });
+
+        let rpc_addr = config.electrum_rpc_addr;
+        let socket_file = config.electrum_socket_file.clone();
+        let socket_file_mode = config.socket_file_mode;
+        let tls = config.electrum_tls.clone();
+        let txs_limit = config.electrum_txs_limit;
+        let idle_timeout = config.electrum_idle_timeout;
+        let keepalive = config.tcp_keepalive;
+        let max_connections = config.electrum_max_connections;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("electrum-server")
+            .max_blocking_threads(config.electrum_blocking_threads)
+            .build()
+            .expect("failed to build the Electrum server runtime");
+
+        let (updates, updates_rx) = watch::channel(());
+        let (shutdown, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let server = spawn_thread("rpc", move || {
+            runtime.block_on(async move {
+                let listener =
+                    Listener::bind(rpc_addr, socket_file.as_deref(), socket_file_mode).await;
+                let tls_listener = match &tls {
+                    Some(tls) => Some(Listener::bind_tls(tls).await),
+                    None => None,
+                };
+                // new connections wait in the listen backlog while all the permits are taken
+                let permits = Arc::new(Semaphore::new(max_connections));
+
+                loop {
+                    let permit = tokio::select! {
+                        permit = Arc::clone(&permits).acquire_owned() => permit.unwrap(),
+                        _ = &mut shutdown_rx => break,
+                    };
+                    let accepted = tokio::select! {
+                        accepted = listener.accept() => accepted,
+                        accepted = accept_tls(tls_listener.as_ref()) => accepted,
+                        _ = &mut shutdown_rx => break,
+                    };
+                    let (stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // e.g. out of file descriptors
+                            warn!("accept failed: {}", e);
+                            time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    if let Stream::Tcp(stream) | Stream::Tls(stream, _) = &stream {
+                        if let Err(e) = set_keepalive(stream, keepalive) {
+                            warn!("[{}] failed setting TCP keepalive: {}", addr, e);
+                        }
+                    }
+
+                    let conn = Connection::new(
+                        Arc::clone(&query),
+                        addr,
+                        Arc::clone(&stats),
+                        Arc::clone(&info),
+                        txs_limit,
+                        Arc::clone(&limits),
+                    );
+                    let mut updates = updates_rx.clone();
+                    updates.borrow_and_update();
+                    let stats = Arc::clone(&stats);
+
+                    tokio::spawn(async move {
+                        info!("connected peer addr={}", addr);
+                        stats.connections.inc();
+                        let result = match stream {
+                            Stream::Tcp(stream) => serve(conn, stream, updates, idle_timeout).await,
+                            Stream::Unix(stream) => {
+                                serve(conn, stream, updates, idle_timeout).await
+                            }
+                            // the handshake runs here, so that slow peers don't stall the accepts
+                            Stream::Tls(stream, acceptor) => {
+                                match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                                    .await
+                                {
+                                    Ok(Ok(stream)) => {
+                                        serve(conn, stream, updates, idle_timeout).await
+                                    }
+                                    Ok(Err(e)) => Err(e).chain_err(|| "TLS handshake failed"),
+                                    Err(_) => Err("TLS handshake timed out".into()),
+                                }
+                            }
+                        };
+                        if let Err(e) = result {
+                            error!(
+                                "[{}] connection handling failed: {}",
+                                addr,
+                                e.display_chain()
+                            )
+                        }
+                        stats.connections.dec();
+                        info!("disconnected peer addr={}", addr);
+                        drop(permit);
+                    });
+                }
+                trace!("stopped accepting new RPCs");
+            });
+            // dropping the runtime closes the remaining connections
+            trace!("RPC connections are closed");
+        });
+
+        Self {
+            updates,
+            shutdown: Some(shutdown),
+            server: Some(server),
+        }
+    }
+}
+
+impl Drop for RPC {
+    fn drop(&mut self) {
+        trace!("stop accepting new RPCs");
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(handle) = self.server.take() {
+            handle.join().unwrap();
+        }
+        trace!("RPC server is stopped");
+    }
+}
+
+// The Electrum listener, on TCP or on a unix socket, or on TCP with TLS
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Tls(TcpListener, TlsAcceptor),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TcpStream, TlsAcceptor), // before the TLS handshake
+}
+
+impl Listener {
+    // Prefers the socket passed by systemd socket activation, if any
+    async fn bind(addr: SocketAddr, socket_file: Option<&Path>, mode: Option<u32>) -> Self {
+        let (listener, location) = match systemd::take_socket("electrum") {
+            Some(ListenSocket::Tcp(listener)) => (
+                listener
+                    .set_nonblocking(true)
+                    .and_then(|_| TcpListener::from_std(listener))
+                    .map(Listener::Tcp),
+                "the TCP socket passed by systemd".to_string(),
+            ),
+            Some(ListenSocket::Unix(listener)) => (
+                listener
+                    .set_nonblocking(true)
+                    .and_then(|_| UnixListener::from_std(listener))
+                    .map(Listener::Unix),
+                "the unix socket passed by systemd".to_string(),
+            ),
+            None => match socket_file {
+                None => (
+                    TcpListener::bind(addr).await.map(Listener::Tcp),
+                    addr.to_string(),
+                ),
+                Some(path) => {
+                    remove_stale_socket(path);
+                    let listener = UnixListener::bind(path).map(Listener::Unix);
+                    if listener.is_ok() {
+                        set_socket_mode(path, mode);
+                    }
+                    (listener, format!("unix socket {}", path.display()))
+                }
+            },
+        };
+        let listener = listener.unwrap_or_else(|e| panic!("bind({}) failed: {}", location, e));
+        info!(
+            "Electrum RPC server running on {} (protocol {})",
+            location, PROTOCOL_VERSION
+        );
+        listener
+    }
+
+    async fn bind_tls(tls: &ElectrumTls) -> Self {
+        let acceptor = tls_acceptor(tls).unwrap_or_else(|e| {
+            panic!("failed to load the TLS certificate: {}", e.display_chain())
+        });
+        let listener = TcpListener::bind(tls.addr)
+            .await
+            .unwrap_or_else(|e| panic!("bind({}) failed: {}", tls.addr, e));
+        info!("Electrum TLS server running on {}", tls.addr);
+        Listener::Tls(listener, acceptor)
+    }
+
+    async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr))
+            }
+            // unix socket peers are local, like the peers behind a local reverse proxy
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), SocketAddr::from(([127, 0, 0, 1], 0))))
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tls(stream, acceptor.clone()), addr))
+            }
+        }
+    }
+}
+
+// Never completes without a TLS listener
+async fn accept_tls(listener: Option<&Listener>) -> io::Result<(Stream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// The certificate chain and the first private key of the PEM files
+fn tls_acceptor(tls: &ElectrumTls) -> Result<TlsAcceptor> {
+    let read_pem = |path: &Path| -> Result<Vec<rustls_pemfile::Item>> {
+        let file = fs::File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+        let mut reader = io::BufReader::new(file);
+        iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose())
+            .collect::<io::Result<_>>()
+            .chain_err(|| format!("invalid PEM file {:?}", path))
+    };
+    let certs: Vec<Certificate> = read_pem(&tls.cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    ensure!(!certs.is_empty(), "no certificate in {:?}", tls.cert_path);
+    let key = read_pem(&tls.key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .chain_err(|| format!("no private key in {:?}", tls.key_path))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .chain_err(|| "invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Detects the connections whose peer is gone (e.g. dropped by a NAT) with TCP keepalive probes.
+// Connections that send no request are closed after the idle timeout by `serve`.
+fn set_keepalive(stream: &TcpStream, keepalive: Option<Duration>) -> io::Result<()> {
+    if let Some(time) = keepalive {
+        socket2::SockRef::from(stream)
+            .set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
+
+/// A JSONRPC request received from an Electrum client
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub params: Vec<Value>,
+    pub id: Value,
+}
+
+/// Parses a request line, failing on anything but a JSONRPC request with positional params
+pub fn parse_request(line: &[u8]) -> Result<Request> {
+    if line.starts_with(&[22, 3, 1]) {
+        // (very) naive SSL handshake detection
+        bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+    }
+    let line = std::str::from_utf8(line).chain_err(|| "invalid UTF8")?;
+    let cmd: Value = from_str(line).chain_err(|| "invalid JSON format")?;
+    let params = cmd.get("params").cloned().unwrap_or_else(|| json!([]));
+    match (cmd.get("method"), params, cmd.get("id")) {
+        (Some(Value::String(method)), Value::Array(params), Some(id)) => Ok(Request {
+            method: method.clone(),
+            params,
+            id: id.clone(),
+        }),
+        _ => bail!("invalid command: {}", cmd),
+    }
+}
+
+// The height after the latest block of the locator that is in the best chain
+fn backfill_start(headers: &HeaderList, locator: &Value) -> Result<usize> {
+    let locator = locator.as_array().chain_err(|| "non-array locator")?;
+    ensure!(
+        locator.len() <= MAX_LOCATOR_HASHES,
+        "locator longer than {} hashes",
+        MAX_LOCATOR_HASHES
+    );
+    for hash in locator {
+        let hash = hash
+            .as_str()
+            .and_then(|hash| BlockHash::from_str(hash).ok())
+            .chain_err(|| "bad block hash in locator")?;
+        if let Some(entry) = headers.header_by_blockhash(&hash) {
+            let start_height = entry.height() + 1;
+            ensure!(
+                headers.len() - start_height <= MAX_BACKFILL_HEADERS,
+                "more than {} headers behind, use blockchain.block.headers",
+                MAX_BACKFILL_HEADERS
+            );
+            return Ok(start_height);
+        }
+    }
+    bail!("no block of the locator is in the best chain")
+}
+
+// The headers from `start_height` up to the tip, in batches of up to MAX_HEADERS
+fn backfill_notifications(headers: &HeaderList, start_height: usize) -> Vec<Value> {
+    let entries: Vec<&HeaderEntry> = headers.iter_range(start_height..headers.len()).collect();
+    entries
+        .chunks(MAX_HEADERS)
+        .map(|batch| {
+            let hex: String = batch
+                .iter()
+                .map(|entry| hex::encode(serialize(entry.header())))
+                .collect();
+            json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.headers.backfill",
+                "params": [{
+                    "start_height": batch[0].height(),
+                    "count": batch.len(),
+                    "hex": hex,
+                }],
+            })
+        })
+        .collect()
+}
+
+fn header_value(entry: &HeaderEntry) -> Value {
+    json!({
+        "hex": hex::encode(serialize(entry.header())),
+        "height": entry.height(),
+    })
+}
+
+// Electrum scripthashes are sent in reversed byte order
+fn scripthash_from_value(val: Option<&Value>) -> Result<FullHash> {
+    let scripthash = val.chain_err(|| "missing hash")?;
+    let scripthash = scripthash.as_str().chain_err(|| "non-string hash")?;
+    let mut scripthash = hex::decode(scripthash).chain_err(|| "non-hex hash")?;
+    ensure!(scripthash.len() == 32, "invalid hash length");
+    scripthash.reverse();
+    Ok(full_hash(&scripthash))
+}
+
+fn scripthash_to_value(scripthash: &FullHash) -> Value {
+    let mut scripthash = *scripthash;
+    scripthash.reverse();
+    json!(hex::encode(scripthash))
+}
+
+// The balance change in satoshis, negative when the spends (e.g. of unconfirmed transactions
+// spending confirmed outputs) exceed the funds
+#[cfg(not(feature = "liquid"))]
+fn balance(stats: &ScriptStats) -> i64 {
+    stats.funded_txo_sum as i64 - stats.spent_txo_sum as i64
+}
+
+#[cfg(feature = "liquid")]
+fn balance(_stats: &ScriptStats) -> i64 {
+    0 // confidential amounts
+}
+
+fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
+    let val = val.chain_err(|| format!("missing {}", name))?;
+    let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
+    Ok(val as usize)
+}
+
+fn usize_from_value_or(val: Option<&Value>, name: &str, default: usize) -> Result<usize> {
+    if val.is_none() {
+        return Ok(default);
+    }
+    usize_from_value(val, name)
+}
+
+fn txid_from_value(val: Option<&Value>) -> Result<Txid> {
+    let txid = val.chain_err(|| "missing hash")?;
+    let txid = txid.as_str().chain_err(|| "non-string hash")?;
+    Txid::from_str(txid).chain_err(|| "non-hex hash")
+}
+
+fn server_features(config: &Config) -> ServerFeatures {
+    #[cfg(feature = "electrum-discovery")]
+    let hosts = config.electrum_public_hosts.clone().unwrap_or_default();
+    #[cfg(not(feature = "electrum-discovery"))]
+    let hosts = ServerHosts::new();
+
+    ServerFeatures {
+        hosts,
+        genesis_hash: genesis_hash(config.network_type),
+        server_version: config.server_version.clone(),
+        protocol_min: PROTOCOL_VERSION,
+        protocol_max: PROTOCOL_VERSION,
+        pruning: None,
+        hash_function: "sha256".into(),
+        donation_address: config.donation_address.clone(),
+        operator_contact: config.operator_contact.clone(),
+    }
+}
+
+// Format the statically configured peers as `server.peers.subscribe` entries,
+// resolving their hostnames once at startup.
+fn static_peers(hosts: &ServerHosts) -> Vec<Value> {
+    hosts
+        .iter()
+        .filter_map(|(hostname, ports)| {
+            let addr = if hostname.ends_with(".onion") {
+                hostname.clone()
+            } else {
+                match (hostname.as_str(), 0).to_socket_addrs() {
+                    Ok(mut addrs) => addrs.next()?.ip().to_string(),
+                    Err(e) => {
+                        warn!("failed resolving static peer {}: {}", hostname, e);
+                        return None;
+                    }
+                }
+            };
+            let mut features = vec![format!("v{}", PROTOCOL_VERSION)];
+            features.extend(ports.feature_strs());
+            Some(json!([addr, hostname, features]))
+        })
+        .collect()
+}
@@ -0,0 +1,358 @@
+// The history of the Liquid assets: the issuances and burns of the issued assets, and the
+// pegins, pegouts and burns of the native asset. Indexed along with the script history:
+//      I{asset-id}{height}{tx-position}{tag}{index} → {issuance, burn, pegin or pegout}
+//      i{asset-id} → {issuance txin and prevout}{reissuance token}
+// Only the initial issuance of an asset gets an `i` row, its reissuances are in its history.
+
+use bitcoin::hashes::{hex::FromHex, sha256, Hash};
+use elements::{
+    confidential::{Asset, Value},
+    encode::{deserialize, serialize},
+    secp256k1_zkp::ZERO_TWEAK,
+    AssetId, AssetIssuance, ContractHash, OutPoint, Transaction, TxIn, Txid,
+};
+
+use super::peg::{get_pegin_data, get_pegout_data, PeginInfo, PegoutInfo};
+use crate::{
+    chain::{BNetwork, Network},
+    errors::*,
+    store::{DBRow, TxHistoryInfo, TxHistoryRow},
+    util::{full_hash, Bytes, FullHash},
+};
+
+lazy_static! {
+    pub static ref NATIVE_ASSET_ID: AssetId =
+        AssetId::from_hex("6f0279e9ed041c3d710a9f57d0c02928416460c4b722ae3457a11eec381c526d")
+            .unwrap();
+    pub static ref NATIVE_ASSET_ID_TESTNET: AssetId =
+        AssetId::from_hex("144c654344aa716d6f3abcc1ca90e5641e4e2a7f633bc09fe3baf64585819a49")
+            .unwrap();
+    pub static ref NATIVE_ASSET_ID_REGTEST: AssetId =
+        AssetId::from_hex("5ac9f65c0efcc4775e0baec4ec03abdde22473cd3cf33c0419ca290e0751b225")
+            .unwrap();
+
+    // The initial issuances of the native asset spend these made-up previous outputs
+    pub static ref REGTEST_INITIAL_ISSUANCE_PREVOUT: Txid =
+        Txid::from_hex("50cdc410c9d0d61eeacc531f52d2c70af741da33af127c364e52ac1ee7c030a5")
+            .unwrap();
+    pub static ref TESTNET_INITIAL_ISSUANCE_PREVOUT: Txid =
+        Txid::from_hex("0c52d2526a5c9f00e9fb74afd15dd3caaf17c823159a514f929ae25193a43a52")
+            .unwrap();
+}
+
+// An issuance or reissuance, in the history of the issued asset
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IssuingInfo {
+    pub txid: FullHash,
+    pub vin: u16,
+    pub is_reissuance: bool,
+    // None for blinded issuances
+    pub issued_amount: Option<u64>,
+    pub token_amount: Option<u64>,
+}
+
+// An explicit amount sent to a provably unspendable output
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BurningInfo {
+    pub txid: FullHash,
+    pub vout: u16,
+    pub value: u64,
+}
+
+const ASSET_CODE: u8 = b'i';
+
+/// The initial issuance of an asset
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AssetRow {
+    pub issuance_txid: FullHash,
+    pub issuance_vin: u16,
+    pub prev_txid: FullHash,
+    pub prev_vout: u16,
+    // consensus-serialized, bincode can't deserialize the AssetIssuance commitments
+    pub issuance: Bytes,
+    pub reissuance_token: FullHash,
+}
+
+impl AssetRow {
+    pub fn key(asset_id: &AssetId) -> Bytes {
+        [&[ASSET_CODE], &asset_id.into_inner()[..]].concat()
+    }
+
+    fn into_row(self, asset_id: &AssetId) -> DBRow {
+        DBRow {
+            key: AssetRow::key(asset_id),
+            value: bincode::serialize(&self).unwrap(),
+        }
+    }
+
+    pub fn from_value(value: &[u8]) -> Result<Self> {
+        bincode::deserialize(value).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid asset row {}", hex::encode(value)))
+        })
+    }
+
+    pub fn issuance_txid(&self) -> Txid {
+        deserialize(&self.issuance_txid).expect("cannot parse Txid")
+    }
+
+    pub fn issuance_prevout(&self) -> OutPoint {
+        OutPoint {
+            txid: deserialize(&self.prev_txid).expect("cannot parse Txid"),
+            vout: self.prev_vout as u32,
+        }
+    }
+
+    pub fn issuance(&self) -> Result<AssetIssuance> {
+        deserialize(&self.issuance).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid issuance {}", hex::encode(&self.issuance)))
+        })
+    }
+
+    pub fn reissuance_token(&self) -> AssetId {
+        AssetId::from_inner(sha256::Midstate::from_inner(self.reissuance_token))
+    }
+
+    /// The contract hash committed to by the issuance, unless left empty
+    pub fn contract_hash(&self) -> Result<Option<ContractHash>> {
+        let entropy = self.issuance()?.asset_entropy;
+        Ok(if entropy == [0u8; 32] {
+            None
+        } else {
+            Some(ContractHash::from_inner(entropy))
+        })
+    }
+}
+
+pub fn index_confirmed_tx_assets(
+    tx: &Transaction,
+    confirmed_height: u32,
+    tx_position: u16,
+    network: Network,
+    parent_network: BNetwork,
+    rows: &mut Vec<DBRow>,
+) {
+    let (history, issuances) = index_tx_assets(tx, network, parent_network);
+
+    rows.extend(history.into_iter().map(|(asset_id, info)| {
+        TxHistoryRow::new_asset(&asset_id, confirmed_height, tx_position, info).into_row()
+    }));
+    rows.extend(
+        issuances
+            .into_iter()
+            .map(|(asset_id, asset_row)| asset_row.into_row(&asset_id)),
+    );
+}
+
+fn index_tx_assets(
+    tx: &Transaction,
+    network: Network,
+    parent_network: BNetwork,
+) -> (Vec<(AssetId, TxHistoryInfo)>, Vec<(AssetId, AssetRow)>) {
+    let mut history = vec![];
+    let mut issuances = vec![];
+
+    let txid = full_hash(&tx.txid()[..]);
+
+    for (txo_index, txo) in tx.output.iter().enumerate() {
+        if let Some(pegout) = get_pegout_data(txo, network, parent_network) {
+            history.push((
+                pegout.asset.explicit().unwrap(),
+                TxHistoryInfo::Pegout(PegoutInfo {
+                    txid,
+                    vout: txo_index as u16,
+                    value: pegout.value,
+                }),
+            ));
+        } else if txo.script_pubkey.is_provably_unspendable() && !txo.is_fee() {
+            if let (Asset::Explicit(asset_id), Value::Explicit(value)) = (txo.asset, txo.value) {
+                if value > 0 {
+                    history.push((
+                        asset_id,
+                        TxHistoryInfo::Burning(BurningInfo {
+                            txid,
+                            vout: txo_index as u16,
+                            value,
+                        }),
+                    ));
+                }
+            }
+        }
+    }
+
+    for (txi_index, txi) in tx.input.iter().enumerate() {
+        if let Some(pegin) = get_pegin_data(txi, network) {
+            history.push((
+                pegin.asset.explicit().unwrap(),
+                TxHistoryInfo::Pegin(PeginInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    value: pegin.value,
+                }),
+            ));
+        } else if txi.has_issuance() {
+            let asset_entropy = match get_issuance_entropy(txi) {
+                Ok(entropy) => entropy,
+                Err(e) => {
+                    warn!(
+                        "skipping the issuance of {}:{}: {}",
+                        tx.txid(),
+                        txi_index,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let is_reissuance = txi.asset_issuance.asset_blinding_nonce != ZERO_TWEAK;
+            let asset_id = AssetId::from_entropy(asset_entropy);
+
+            history.push((
+                asset_id,
+                TxHistoryInfo::Issuing(IssuingInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    is_reissuance,
+                    issued_amount: explicit_amount(&txi.asset_issuance.amount),
+                    token_amount: explicit_amount(&txi.asset_issuance.inflation_keys),
+                }),
+            ));
+
+            if !is_reissuance {
+                let is_confidential =
+                    matches!(txi.asset_issuance.inflation_keys, Value::Confidential(..));
+                let reissuance_token =
+                    AssetId::reissuance_token_from_entropy(asset_entropy, is_confidential);
+
+                issuances.push((
+                    asset_id,
+                    AssetRow {
+                        issuance_txid: txid,
+                        issuance_vin: txi_index as u16,
+                        prev_txid: full_hash(&txi.previous_output.txid[..]),
+                        prev_vout: txi.previous_output.vout as u16,
+                        issuance: serialize(&txi.asset_issuance),
+                        reissuance_token: reissuance_token.into_inner().into_inner(),
+                    },
+                ));
+            }
+        }
+    }
+
+    (history, issuances)
+}
+
+// A null amount issues nothing, a blinded one is unknown
+fn explicit_amount(value: &Value) -> Option<u64> {
+    match value {
+        Value::Explicit(amount) => Some(*amount),
+        Value::Null => Some(0),
+        Value::Confidential(..) => None,
+    }
+}
+
+/// The asset entropy of an issuance: derived from the spent outpoint and the contract hash
+/// for an initial issuance, and carried as is by a reissuance
+pub fn get_issuance_entropy(txin: &TxIn) -> Result<sha256::Midstate> {
+    ensure!(txin.has_issuance(), "input has no issuance");
+
+    let is_reissuance = txin.asset_issuance.asset_blinding_nonce != ZERO_TWEAK;
+    Ok(if is_reissuance {
+        sha256::Midstate::from_inner(txin.asset_issuance.asset_entropy)
+    } else {
+        let contract_hash = ContractHash::from_inner(txin.asset_issuance.asset_entropy);
+        AssetId::generate_asset_entropy(txin.previous_output, contract_hash)
+    })
+}
+
+/// The confirmed activity of an asset: the pegs of the native asset, or the issuances of
+/// the issued ones. Both count their burns.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum AssetStats {
+    Pegged(PeggedAssetStats),
+    Issued(IssuedAssetStats),
+}
+
+impl AssetStats {
+    pub fn new(asset_id: &AssetId, network: Network) -> Self {
+        if asset_id == network.native_asset() {
+            AssetStats::Pegged(PeggedAssetStats::default())
+        } else {
+            AssetStats::Issued(IssuedAssetStats::default())
+        }
+    }
+
+    // The history entries of a single transaction are added up together
+    pub fn apply(&mut self, txinfos: &[TxHistoryInfo]) {
+        match self {
+            AssetStats::Pegged(stats) => stats.apply(txinfos),
+            AssetStats::Issued(stats) => stats.apply(txinfos),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct IssuedAssetStats {
+    pub tx_count: usize,
+    pub issuance_count: usize,
+    pub issued_amount: u64,
+    pub burned_amount: u64,
+    pub has_blinded_issuances: bool,
+    // created by the initial issuance, none when blinded
+    pub reissuance_tokens: Option<u64>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct PeggedAssetStats {
+    pub tx_count: usize,
+    pub peg_in_count: usize,
+    pub peg_in_amount: u64,
+    pub peg_out_count: usize,
+    pub peg_out_amount: u64,
+    pub burn_count: usize,
+    pub burned_amount: u64,
+}
+
+impl IssuedAssetStats {
+    fn apply(&mut self, txinfos: &[TxHistoryInfo]) {
+        self.tx_count += 1;
+        for info in txinfos {
+            match info {
+                TxHistoryInfo::Issuing(issuance) => {
+                    self.issuance_count += 1;
+                    match issuance.issued_amount {
+                        Some(amount) => self.issued_amount += amount,
+                        None => self.has_blinded_issuances = true,
+                    }
+                    if !issuance.is_reissuance {
+                        self.reissuance_tokens = issuance.token_amount;
+                    }
+                }
+                TxHistoryInfo::Burning(burn) => self.burned_amount += burn.value,
+                _ => (),
+            }
+        }
+    }
+}
+
+impl PeggedAssetStats {
+    fn apply(&mut self, txinfos: &[TxHistoryInfo]) {
+        self.tx_count += 1;
+        for info in txinfos {
+            match info {
+                TxHistoryInfo::Pegin(pegin) => {
+                    self.peg_in_count += 1;
+                    self.peg_in_amount += pegin.value;
+                }
+                TxHistoryInfo::Pegout(pegout) => {
+                    self.peg_out_count += 1;
+                    self.peg_out_amount += pegout.value;
+                }
+                TxHistoryInfo::Burning(burn) => {
+                    self.burn_count += 1;
+                    self.burned_amount += burn.value;
+                }
+                _ => (),
+            }
+        }
+    }
+}
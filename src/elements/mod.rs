@@ -1 +1,2 @@
-
+pub mod asset;
+pub mod peg;
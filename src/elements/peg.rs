@@ -0,0 +1,42 @@
+use crate::{
+    chain::{bitcoin_genesis_hash, BNetwork, Network, TxIn, TxOut},
+    util::FullHash,
+};
+
+use elements::{confidential::Asset, PeginData, PegoutData};
+
+/// The pegin of an input, when it pegs in the native asset of `network`
+pub fn get_pegin_data(txin: &TxIn, network: Network) -> Option<PeginData> {
+    let pegged_asset_id = network.pegged_asset()?;
+    txin.pegin_data()
+        .filter(|pegin| pegin.asset == Asset::Explicit(*pegged_asset_id))
+}
+
+/// The pegout of an output, when it pegs the native asset of `network` out to `parent_network`
+pub fn get_pegout_data(
+    txout: &TxOut,
+    network: Network,
+    parent_network: BNetwork,
+) -> Option<PegoutData> {
+    let pegged_asset_id = network.pegged_asset()?;
+    txout.pegout_data().filter(|pegout| {
+        pegout.asset == Asset::Explicit(*pegged_asset_id)
+            && pegout.genesis_hash == bitcoin_genesis_hash(parent_network)
+    })
+}
+
+// A pegin of the native asset, in its history
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeginInfo {
+    pub txid: FullHash,
+    pub vin: u16,
+    pub value: u64,
+}
+
+// A pegout of the native asset, in its history
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PegoutInfo {
+    pub txid: FullHash,
+    pub vout: u16,
+    pub value: u64,
+}
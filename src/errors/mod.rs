@@ -14,11 +14,41 @@ error_chain! {
             display("Iterrupted by signal {}", sig)
         }
 
+        PrunedBlock(msg: String) {
+            description("Block pruned by bitcoind")
+            display("Block not available on the pruned node: {}", msg)
+        }
+
         TooPopular {
             description("Too many history entries")
             display("Too many history entries")
         }
 
+        Blocked {
+            description("Script is blocked")
+            display("Script is blocked")
+        }
+
+        NotIndexed(msg: String) {
+            description("Not indexed")
+            display("Not indexed: {}", msg)
+        }
+
+        InvalidAddress(msg: String) {
+            description("Invalid address")
+            display("Invalid address: {}", msg)
+        }
+
+        DbInconsistency(msg: String) {
+            description("Database inconsistency")
+            display("Database inconsistency: {}", msg)
+        }
+
+        SessionLimit(msg: String) {
+            description("Session resources limit exceeded")
+            display("Session resources limit exceeded: {}", msg)
+        }
+
         #[cfg(feature = "electrum-discovery")]
         ElectrumClient(e: electrum_client::Error) {
             description("Electrum client error")
@@ -0,0 +1,199 @@
+// Consistency check of the spent-TXO edges (`S` rows) against the spending entries of the script
+// histories (`H` rows). Both are written by `index_transaction` for every input, but a partially
+// written batch (e.g. a crash with a relaxed `--db-sync`) can leave one without the other, and
+// a missing edge makes `/tx/:txid/outspends` silently report the output as unspent. The blocks
+// found inconsistent can be indexed again to restore the missing rows.
+
+use std::collections::BTreeSet;
+
+use bitcoin::{consensus::deserialize, OutPoint};
+
+use super::{get_previous_txos, index_blocks, lookup_txo, lookup_txos, Indexer};
+use crate::{
+    daemon::Daemon,
+    errors::*,
+    store::{
+        compute_script_hash, start_fetcher, BlockEntry, DBFlush, FetchFrom, ScriptHashes,
+        TxConfRow, TxEdgeRow, TxHistoryInfo, TxHistoryRow, DB,
+    },
+    util::{block::HeaderList, transaction::is_dust, FullHash},
+};
+
+// the scans log their progress every this many rows
+const PROGRESS_INTERVAL: usize = 10_000_000;
+
+#[derive(Default, Debug)]
+pub struct SpendsCheck {
+    pub edges: usize,
+    pub spends: usize,
+    /// The edges without a spending history entry
+    pub missing_spends: usize,
+    /// The spending history entries without an edge
+    pub missing_edges: usize,
+    /// The rows of transactions that are no longer confirmed, which are expected after reorgs
+    pub stale: usize,
+    /// The heights of the blocks with inconsistent rows
+    pub heights: BTreeSet<usize>,
+}
+
+impl Indexer {
+    /// Checks every edge against the history and back, then indexes the inconsistent blocks
+    /// again when `repair` is set. This scans both row families and looks each row up in the
+    /// other, so it takes hours on mainnet.
+    pub fn check_spends(&self, daemon: &Daemon, repair: bool) -> Result<SpendsCheck> {
+        let headers = self.store.headers();
+        let mut check = SpendsCheck::default();
+
+        info!("checking the spent-TXO edges against the script histories");
+        for row in self.store.history.iter_scan(b"S") {
+            let edge = TxEdgeRow::from_row(row)?.key;
+            check.edges += 1;
+            if check.edges % PROGRESS_INTERVAL == 0 {
+                info!("checked {} edges", check.edges);
+            }
+            let height = match confirmed_height(&self.store.txstore, &headers, &edge.spending_txid)
+            {
+                Some(height) => height,
+                None => {
+                    check.stale += 1;
+                    continue;
+                }
+            };
+            if height < self.iconfig.index_start_height {
+                continue;
+            }
+            let funding = OutPoint::new(
+                deserialize(&edge.funding_txid).expect("cannot parse Txid"),
+                edge.funding_vout as u32,
+            );
+            let txo = lookup_txo(&self.store.txstore, &funding, self.iconfig.intern_scripts)?
+                .ok_or_else(|| {
+                    ErrorKind::DbInconsistency(format!("missing txo {} of an edge", funding))
+                })?;
+            // the dust outputs are left out of the history on purpose
+            if is_dust(&txo.value, self.iconfig.skip_dust) {
+                continue;
+            }
+            let scripthash = compute_script_hash(&txo.script_pubkey);
+            let found = self
+                .store
+                .history
+                .iter_scan(&TxHistoryRow::prefix_height(
+                    b'H',
+                    &scripthash,
+                    height as u32,
+                ))
+                .filter_map(|row| TxHistoryRow::from_row(row).ok())
+                .any(|row| match row.txinfo {
+                    TxHistoryInfo::Spending(info) => {
+                        info.txid == edge.spending_txid && info.vin == edge.spending_vin
+                    }
+                    _ => false,
+                });
+            if !found {
+                warn!(
+                    "the spend of {} at height {} has an edge but no history entry",
+                    funding, height
+                );
+                check.missing_spends += 1;
+                check.heights.insert(height);
+            }
+        }
+
+        info!("checking the script histories against the spent-TXO edges");
+        for row in self.store.history.iter_scan(b"H") {
+            let row = TxHistoryRow::from_row(row)?;
+            let info = match row.txinfo {
+                TxHistoryInfo::Spending(info) => info,
+                _ => continue,
+            };
+            check.spends += 1;
+            if check.spends % PROGRESS_INTERVAL == 0 {
+                info!("checked {} spends", check.spends);
+            }
+            let edge = TxEdgeRow::new(info.prev_txid, info.prev_vout, info.txid, info.vin);
+            if self.store.history.get(&edge.into_row().key).is_some() {
+                continue;
+            }
+            let height = row.key.confirmed_height as usize;
+            if confirmed_height(&self.store.txstore, &headers, &info.txid) != Some(height) {
+                check.stale += 1;
+                continue;
+            }
+            let funding = OutPoint::new(
+                deserialize(&info.prev_txid).expect("cannot parse Txid"),
+                info.prev_vout as u32,
+            );
+            warn!(
+                "the spend of {} at height {} has a history entry but no edge",
+                funding, height
+            );
+            check.missing_edges += 1;
+            check.heights.insert(height);
+        }
+
+        info!(
+            "checked {} edges and {} spends: {} edges without history entry, {} spends without edge, {} stale rows",
+            check.edges, check.spends, check.missing_spends, check.missing_edges, check.stale
+        );
+        if repair && !check.heights.is_empty() {
+            self.repair(daemon, &headers, &check.heights)?;
+        }
+        Ok(check)
+    }
+
+    fn repair(
+        &self,
+        daemon: &Daemon,
+        headers: &HeaderList,
+        heights: &BTreeSet<usize>,
+    ) -> Result<()> {
+        info!("indexing {} inconsistent blocks again", heights.len());
+        let entries = heights
+            .iter()
+            .map(|height| headers.header_by_height(*height).cloned())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("inconsistent block above the indexed tip")?;
+        let daemon = daemon.reconnect()?;
+        start_fetcher(
+            FetchFrom::Bitcoind,
+            &daemon,
+            entries,
+            self.iconfig.fetch_batch_bounds,
+            &self.memory,
+        )?
+        .each(|blocks| self.reindex(&blocks));
+        Ok(())
+    }
+
+    // Writes the history rows of already indexed blocks again. The merged counters (the script
+    // and chain stats, the UTXO accumulator) are left alone, replaying them would count the
+    // blocks twice.
+    fn reindex(&self, blocks: &[BlockEntry]) {
+        let previous_txos_map = lookup_txos(
+            &self.lookup_pool,
+            &self.store.txstore,
+            &get_previous_txos(blocks),
+            false,
+            self.iconfig.intern_scripts,
+        )
+        .expect("failed to look up the previous outputs of the reindexed blocks");
+        let script_hashes = ScriptHashes::new(blocks, &previous_txos_map);
+        let rows = self
+            .index_pool
+            .install(|| index_blocks(blocks, &previous_txos_map, &script_hashes, &self.iconfig));
+        self.store.history.write(rows, DBFlush::Enable);
+    }
+}
+
+// The height of the best chain block confirming `txid`, if any
+fn confirmed_height(txstore: &DB, headers: &HeaderList, txid: &FullHash) -> Option<usize> {
+    txstore
+        .iter_scan(&TxConfRow::filter(&txid[..]))
+        .filter_map(|row| TxConfRow::from_row(row).ok())
+        .find_map(|conf| {
+            headers
+                .header_by_blockhash(&deserialize(&conf.key.blockhash).unwrap())
+                .map(|entry| entry.height())
+        })
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    daemon::{Daemon, MempoolInfo},
+    errors::*,
+    util::fees::btc_per_kvb_to_sat_per_vb,
+};
+
+// bitcoind evicts its lowest fee rate transactions, raising its minimum fee, once full
+const MEMPOOL_NEAR_FULL: f64 = 0.8;
+
+/// Fee rate estimates from bitcoind, in sat/vB. They are cached for the configured targets and
+/// refreshed with `update()` on new blocks, rather than queried per request.
+pub struct FeeEstimator {
+    daemon: Arc<Daemon>,
+    targets: Vec<u16>, // sorted
+    estimates: RwLock<HashMap<u16, f64>>,
+    min_fees: RwLock<Option<MinFees>>,
+}
+
+/// The minimum fee rates for transactions to be accepted by bitcoind, in sat/vB
+#[derive(Clone, Copy, Serialize)]
+pub struct MinFees {
+    pub relay_fee: f64,
+    pub mempool_min_fee: f64,
+    #[serde(skip)]
+    near_full: bool,
+}
+
+impl From<MempoolInfo> for MinFees {
+    fn from(info: MempoolInfo) -> Self {
+        MinFees {
+            relay_fee: btc_per_kvb_to_sat_per_vb(info.minrelaytxfee),
+            mempool_min_fee: btc_per_kvb_to_sat_per_vb(info.mempoolminfee),
+            near_full: info.usage as f64 >= info.maxmempool as f64 * MEMPOOL_NEAR_FULL,
+        }
+    }
+}
+
+impl FeeEstimator {
+    pub fn new(daemon: Arc<Daemon>, targets: &[u16]) -> Self {
+        let mut targets = targets.to_vec();
+        targets.sort_unstable();
+        targets.dedup();
+        FeeEstimator {
+            daemon,
+            targets,
+            estimates: RwLock::new(HashMap::new()),
+            min_fees: RwLock::new(None),
+        }
+    }
+
+    /// Refreshes the cached estimates, keeping the previous ones if bitcoind fails
+    pub fn update(&self) -> Result<()> {
+        let estimates = self
+            .daemon
+            .estimatesmartfee_batch(&self.targets)?
+            .into_iter()
+            .map(|(target, rate)| (target, btc_per_kvb_to_sat_per_vb(rate)))
+            .collect();
+        *self.estimates.write().unwrap() = estimates;
+        self.update_min_fees()
+    }
+
+    /// Refreshes the minimum fees between blocks too while bitcoind's mempool is near full,
+    /// since its minimum fee then changes with every eviction
+    pub fn update_mempool(&self) -> Result<()> {
+        let near_full = self.min_fees().map_or(true, |fees| fees.near_full);
+        if near_full {
+            self.update_min_fees()?;
+        }
+        Ok(())
+    }
+
+    fn update_min_fees(&self) -> Result<()> {
+        let min_fees = MinFees::from(self.daemon.getmempoolinfo()?);
+        *self.min_fees.write().unwrap() = Some(min_fees);
+        Ok(())
+    }
+
+    /// The fee rate for a confirmation within `target` blocks, estimated for the highest
+    /// configured target that isn't above it (or the lowest one, for lower targets)
+    pub fn estimate_fee(&self, target: u16) -> Option<f64> {
+        let estimates = self.estimates.read().unwrap();
+        self.targets
+            .iter()
+            .rev()
+            .filter(|t| **t <= target)
+            .find_map(|t| estimates.get(t))
+            .or_else(|| self.targets.iter().find_map(|t| estimates.get(t)))
+            .copied()
+    }
+
+    /// The fee rates of the configured targets that could be estimated
+    pub fn estimate_fee_map(&self) -> HashMap<u16, f64> {
+        self.estimates.read().unwrap().clone()
+    }
+
+    pub fn min_fees(&self) -> Option<MinFees> {
+        *self.min_fees.read().unwrap()
+    }
+}
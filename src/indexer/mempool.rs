@@ -0,0 +1,847 @@
+use std::{
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam_channel::Receiver;
+
+use crate::{
+    chain::{OutPoint, Transaction, TxOut, Txid},
+    daemon::Daemon,
+    errors::*,
+    indexer::query::ChainQuery,
+    metrics::{Counter, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics},
+    store::{compute_script_hash, DBFlush, FirstSeenRow, ScriptStats},
+    util::{
+        fees::fee_histogram,
+        transaction::{get_tx_fee, has_prevout},
+        unix_time,
+        zmq::SequenceEvent,
+        FullHash,
+    },
+};
+
+const MAX_CONFLICTS: usize = 10_000; // number of recent (txid, txid) conflict pairs kept in memory
+
+// the next block template leaves room for the coinbase transaction and the block header
+const MAX_BLOCK_VSIZE: u64 = 1_000_000;
+const COINBASE_RESERVED_VSIZE: u64 = 1_000;
+// the selection gives up after this many packages in a row didn't fit in a nearly full block
+const MAX_CONSECUTIVE_FAILURES: usize = 1_000;
+const NEXT_BLOCK_INTERVAL: Duration = Duration::from_secs(10);
+// the whole mempool is diffed at least this often when following the ZMQ notifications
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Mempool {
+    chain: Arc<ChainQuery>,
+    txstore: HashMap<Txid, Transaction>,
+    edges: HashMap<OutPoint, (Txid, u32)>, // OutPoint -> (spending_txid, spending_vin)
+    prevouts: HashMap<OutPoint, TxOut>,    // confirmed outputs spent by mempool transactions
+    first_seen: HashMap<Txid, u32>,        // unix timestamp
+    conflicts: Conflicts,
+    script_stats: HashMap<FullHash, ScriptStats>,
+    tx_stats: HashMap<Txid, Vec<(FullHash, ScriptStats)>>, // to revert the stats on removal
+    script_txids: HashMap<FullHash, BTreeSet<Txid>>,
+    fees: HashMap<Txid, (u64, u64)>, // (fee, vsize) of the transactions of known fee
+    by_feerate: BTreeSet<(u64, Txid)>, // by effective feerate (in sat/kvB), lowest first
+    feerates: HashMap<Txid, u64>,    // the keys of `by_feerate`
+    generation: u64,                 // bumped on every change of the mempool contents
+    next_block: NextBlock,
+    fee_histogram: Vec<(f64, u64)>,
+    next_block_time: Option<Instant>,
+    sequence: Option<u64>, // bitcoind's mempool sequence as of the last applied update
+    sequence_events: Option<Receiver<SequenceEvent>>, // with --zmq-sequence-addr
+    last_resync: Option<Instant>,
+
+    // monitoring
+    latency: HistogramVec, // mempool requests latency
+    delta: HistogramVec,   // # of added/removed txs
+    count: GaugeVec,       // current state of the mempool
+    conflicts_count: Counter,
+    unchanged_updates: Counter,
+    resyncs: Counter,
+}
+
+/// The transactions added to and removed from the mempool by an update
+#[derive(Default)]
+pub struct MempoolChanges {
+    pub added: Vec<Txid>,
+    pub removed: Vec<Txid>,
+}
+
+/// The projection of the next block, as a miner would assemble it from the mempool
+#[derive(Default)]
+pub struct NextBlock {
+    /// The mempool generation it was projected from
+    pub generation: u64,
+    pub txids: Vec<Txid>,
+    pub total_fees: u64,
+    pub vsize: u64,
+    /// The lowest package feerate (in sat/vB) included
+    pub min_feerate: Option<f64>,
+}
+
+/// Pairs of transactions that were seen spending the same outpoint, either both in the mempool
+/// (e.g. an RBF replacement) or a mempool transaction that was evicted by a conflicting block.
+#[derive(Default)]
+struct Conflicts {
+    by_txid: HashMap<Txid, HashSet<Txid>>,
+    recent: VecDeque<(Txid, Txid)>, // oldest first, for eviction
+    // the scripts of the recent conflicts, numbered in the order they were recorded, for the
+    // Electrum subscriptions to notify
+    scripts: VecDeque<(u64, FullHash, Txid, Txid)>,
+    recorded: u64,
+}
+
+impl Mempool {
+    pub fn new(chain: Arc<ChainQuery>, metrics: &Metrics) -> Self {
+        Self {
+            chain,
+            txstore: HashMap::new(),
+            edges: HashMap::new(),
+            prevouts: HashMap::new(),
+            first_seen: HashMap::new(),
+            conflicts: Conflicts::default(),
+            script_stats: HashMap::new(),
+            tx_stats: HashMap::new(),
+            script_txids: HashMap::new(),
+            fees: HashMap::new(),
+            by_feerate: BTreeSet::new(),
+            feerates: HashMap::new(),
+            // starts at the startup time, so that a generation isn't reused after a restart
+            generation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system clock is before the unix epoch")
+                .as_secs(),
+            sequence: None,
+            sequence_events: None,
+            last_resync: None,
+            next_block: NextBlock::default(),
+            fee_histogram: vec![],
+            next_block_time: None,
+            latency: metrics.histogram_vec(
+                HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
+                &["part"],
+            ),
+            delta: metrics.histogram_vec(
+                HistogramOpts::new("mempool_delta", "# of transactions added/removed"),
+                &["type"],
+            ),
+            count: metrics.gauge_vec(
+                MetricOpts::new("mempool_count", "# of elements currently at the mempool"),
+                &["type"],
+            ),
+            conflicts_count: metrics.counter(MetricOpts::new(
+                "mempool_conflicts",
+                "# of double-spend conflicts detected",
+            )),
+            unchanged_updates: metrics.counter(MetricOpts::new(
+                "mempool_unchanged_updates",
+                "# of mempool updates skipped since bitcoind's mempool sequence didn't change",
+            )),
+            resyncs: metrics.counter(MetricOpts::new(
+                "mempool_resyncs",
+                "# of mempool updates diffing bitcoind's whole mempool",
+            )),
+        }
+    }
+
+    /// Applies bitcoind's ZMQ `sequence` notifications between the full refreshes
+    pub fn follow_sequence(&mut self, events: Receiver<SequenceEvent>) {
+        self.sequence_events = Some(events);
+    }
+
+    pub fn network(&self) -> crate::chain::Network {
+        self.chain.network()
+    }
+
+    pub fn lookup_txn(&self, txid: &Txid) -> Option<Transaction> {
+        self.txstore.get(txid).cloned()
+    }
+
+    /// The previous outputs that are funded by mempool transactions
+    pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
+        outpoints
+            .iter()
+            .filter_map(|outpoint| {
+                self.txstore
+                    .get(&outpoint.txid)
+                    .and_then(|tx| tx.output.get(outpoint.vout as usize))
+                    .map(|txo| (*outpoint, txo.clone()))
+            })
+            .collect()
+    }
+
+    /// The previous outputs spent by `tx`, funded either by mempool or confirmed transactions.
+    /// Only reads the index for transactions that aren't in the mempool.
+    pub fn lookup_prevouts(&self, tx: &Transaction) -> HashMap<OutPoint, TxOut> {
+        let outpoints: BTreeSet<OutPoint> = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| txin.previous_output)
+            .collect();
+        let mut prevouts = self.lookup_txos(&outpoints);
+        let mut missing = BTreeSet::new();
+        for outpoint in outpoints {
+            if prevouts.contains_key(&outpoint) {
+                continue;
+            }
+            match self.prevouts.get(&outpoint) {
+                Some(txo) => {
+                    prevouts.insert(outpoint, txo.clone());
+                }
+                None => {
+                    missing.insert(outpoint);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            // left unknown rather than failing the mempool sync, as with missing outputs
+            match self.chain.lookup_txos(&missing) {
+                Ok(txos) => prevouts.extend(txos),
+                Err(e) => error!("failed looking up the prevouts of {}: {}", tx.txid(), e),
+            }
+        }
+        prevouts
+    }
+
+    /// The stats of the mempool transactions funding or spending from a script
+    pub fn stats(&self, scripthash: &[u8]) -> ScriptStats {
+        self.script_stats
+            .get(scripthash)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The mempool transactions funding or spending from a script, ordered by txid
+    pub fn history_txids(&self, scripthash: &[u8]) -> Vec<Txid> {
+        self.script_txids
+            .get(scripthash)
+            .map_or_else(Vec::new, |txids| txids.iter().cloned().collect())
+    }
+
+    /// Whether `txid` spends the outputs of other mempool transactions
+    pub fn has_unconfirmed_parents(&self, txid: &Txid) -> bool {
+        self.txstore.get(txid).map_or(false, |tx| {
+            tx.input
+                .iter()
+                .any(|txin| self.txstore.contains_key(&txin.previous_output.txid))
+        })
+    }
+
+    pub fn has_txn(&self, txid: &Txid) -> bool {
+        self.txstore.contains_key(txid)
+    }
+
+    pub fn first_seen(&self, txid: &Txid) -> Option<u32> {
+        self.first_seen.get(txid).copied()
+    }
+
+    /// Whether a mempool transaction spends `outpoint`
+    pub fn has_spend(&self, outpoint: &OutPoint) -> bool {
+        self.edges.contains_key(outpoint)
+    }
+
+    /// The transactions that were seen spending any of the outpoints spent by `txid`
+    pub fn conflicts(&self, txid: &Txid) -> Vec<Txid> {
+        self.conflicts
+            .by_txid
+            .get(txid)
+            .map_or_else(Vec::new, |txids| txids.iter().cloned().collect())
+    }
+
+    /// The mempool transactions `txid` spends from, directly or through other mempool
+    /// transactions, nearest first. `None` when `txid` isn't in the mempool.
+    pub fn ancestors(&self, txid: &Txid) -> Option<Vec<Txid>> {
+        self.walk(txid, |_, tx| {
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output.txid)
+                .collect()
+        })
+    }
+
+    /// The mempool transactions spending from `txid`, directly or through other mempool
+    /// transactions, nearest first. `None` when `txid` isn't in the mempool.
+    pub fn descendants(&self, txid: &Txid) -> Option<Vec<Txid>> {
+        self.walk(txid, |txid, tx| {
+            (0..tx.output.len() as u32)
+                .filter_map(|vout| self.edges.get(&OutPoint::new(*txid, vout)))
+                .map(|(spending_txid, _)| *spending_txid)
+                .collect()
+        })
+    }
+
+    /// The length of the longest chain of mempool transactions `txid` spends from. `None` when
+    /// `txid` isn't in the mempool.
+    pub fn ancestor_depth(&self, txid: &Txid) -> Option<usize> {
+        self.txstore.get(txid)?;
+        Some(self.depth(txid, &mut HashMap::new()))
+    }
+
+    fn depth(&self, txid: &Txid, depths: &mut HashMap<Txid, usize>) -> usize {
+        if let Some(depth) = depths.get(txid) {
+            return *depth;
+        }
+        let depth = self.txstore[txid]
+            .input
+            .iter()
+            .map(|txin| txin.previous_output.txid)
+            .filter(|parent| self.txstore.contains_key(parent))
+            .map(|parent| self.depth(&parent, depths) + 1)
+            .max()
+            .unwrap_or(0);
+        depths.insert(*txid, depth);
+        depth
+    }
+
+    // Breadth-first traversal of the mempool graph, following the edges returned by `next`
+    fn walk<F>(&self, txid: &Txid, next: F) -> Option<Vec<Txid>>
+    where
+        F: Fn(&Txid, &Transaction) -> Vec<Txid>,
+    {
+        self.txstore.get(txid)?;
+        let mut visited: HashSet<Txid> = HashSet::new();
+        visited.insert(*txid);
+        let mut queue: VecDeque<Txid> = VecDeque::new();
+        queue.push_back(*txid);
+        let mut found = vec![];
+        while let Some(current) = queue.pop_front() {
+            for next_txid in next(&current, &self.txstore[&current]) {
+                if self.txstore.contains_key(&next_txid) && visited.insert(next_txid) {
+                    found.push(next_txid);
+                    queue.push_back(next_txid);
+                }
+            }
+        }
+        Some(found)
+    }
+
+    /// The fee (when all its previous outputs are known) and virtual size of a mempool
+    /// transaction
+    pub fn fee_and_vsize(&self, txid: &Txid) -> Option<(Option<u64>, u64)> {
+        let tx = self.txstore.get(txid)?;
+        let fee = get_tx_fee(tx, &self.lookup_prevouts(tx), self.network());
+        Some((fee, (tx.weight() as u64 + 3) / 4))
+    }
+
+    /// The transactions of known fee ordered by effective feerate (highest first), skipping
+    /// `skip` of them. Along with each txid, its fee, virtual size and effective feerate.
+    pub fn txs_by_feerate(&self, skip: usize, limit: usize) -> Vec<(Txid, u64, u64, f64)> {
+        self.by_feerate
+            .iter()
+            .rev()
+            .skip(skip)
+            .take(limit)
+            .map(|(feerate, txid)| {
+                let (fee, vsize) = self.fees[txid];
+                (*txid, fee, vsize, *feerate as f64 / 1000.0)
+            })
+            .collect()
+    }
+
+    // The effective feerate of a transaction is the one of the package it forms with its
+    // mempool ancestors, which are mined together, as ranked by the miners' ancestor score.
+    // The ancestors of unknown fee are left out.
+    fn update_feerates(&mut self, txids: impl Iterator<Item = Txid>) {
+        for txid in txids {
+            if let Some(feerate) = self.feerates.remove(&txid) {
+                self.by_feerate.remove(&(feerate, txid));
+            }
+            let (fee, vsize) = match self.fees.get(&txid) {
+                Some(fee_and_vsize) => *fee_and_vsize,
+                None => continue,
+            };
+            let (fee, vsize) = self
+                .ancestors(&txid)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|ancestor| self.fees.get(ancestor))
+                .fold(
+                    (fee, vsize),
+                    |(fee, vsize), (ancestor_fee, ancestor_vsize)| {
+                        (fee + ancestor_fee, vsize + ancestor_vsize)
+                    },
+                );
+            let feerate = fee * 1000 / vsize.max(1);
+            self.feerates.insert(txid, feerate);
+            self.by_feerate.insert((feerate, txid));
+        }
+    }
+
+    /// The last projection of the next block, which lags behind the mempool by up to
+    /// `NEXT_BLOCK_INTERVAL`
+    pub fn next_block(&self) -> &NextBlock {
+        &self.next_block
+    }
+
+    /// The fee histogram of the transactions of known fee, as of the last update
+    pub fn fee_histogram(&self) -> &[(f64, u64)] {
+        &self.fee_histogram
+    }
+
+    // Greedy selection by ancestor score, as done by bitcoind's block assembly: the package of
+    // the transaction with the highest effective feerate is included along with its ancestors,
+    // then the effective feerates of its descendants are computed again without them. The
+    // packages with an ancestor of unknown fee are left out.
+    fn project_next_block(&self) -> NextBlock {
+        let _timer = self
+            .latency
+            .with_label_values(&["next_block"])
+            .start_timer();
+        let max_vsize = MAX_BLOCK_VSIZE - COINBASE_RESERVED_VSIZE;
+        let mut block = NextBlock {
+            generation: self.generation,
+            ..Default::default()
+        };
+        let mut included: HashSet<Txid> = HashSet::new();
+        let mut depths: HashMap<Txid, usize> = HashMap::new();
+        // the current effective feerates, the heap entries that differ are outdated
+        let mut scores: HashMap<Txid, u64> = self.feerates.clone();
+        let mut heap: BinaryHeap<(u64, Txid)> = self.by_feerate.iter().copied().collect();
+        let mut failures = 0;
+
+        while let Some((score, txid)) = heap.pop() {
+            if scores.get(&txid) != Some(&score) {
+                continue;
+            }
+            scores.remove(&txid);
+            let (mut package, fee, vsize) = match self.package(&txid, &included) {
+                Some(package) => package,
+                None => continue,
+            };
+            if block.vsize + vsize > max_vsize {
+                failures += 1;
+                if failures > MAX_CONSECUTIVE_FAILURES
+                    && block.vsize + COINBASE_RESERVED_VSIZE > max_vsize
+                {
+                    break;
+                }
+                continue;
+            }
+            failures = 0;
+
+            // parents first
+            package.sort_by_cached_key(|txid| self.depth(txid, &mut depths));
+            let feerate = fee as f64 / vsize.max(1) as f64;
+            block.min_feerate = Some(block.min_feerate.map_or(feerate, |min| min.min(feerate)));
+            block.total_fees += fee;
+            block.vsize += vsize;
+            for txid in &package {
+                included.insert(*txid);
+                scores.remove(txid);
+            }
+
+            let descendants: HashSet<Txid> = package
+                .iter()
+                .flat_map(|txid| self.descendants(txid).unwrap_or_default())
+                .filter(|txid| scores.contains_key(txid))
+                .collect();
+            for descendant in descendants {
+                match self.package(&descendant, &included) {
+                    Some((_, fee, vsize)) => {
+                        let score = fee * 1000 / vsize.max(1);
+                        scores.insert(descendant, score);
+                        heap.push((score, descendant));
+                    }
+                    None => {
+                        scores.remove(&descendant);
+                    }
+                }
+            }
+            block.txids.extend(package);
+        }
+        block
+    }
+
+    // The transactions mined along with `txid` (itself and its ancestors not yet `included`),
+    // with their total fee and virtual size. `None` when any of them is of unknown fee.
+    fn package(&self, txid: &Txid, included: &HashSet<Txid>) -> Option<(Vec<Txid>, u64, u64)> {
+        let mut package = self.ancestors(txid)?;
+        package.retain(|ancestor| !included.contains(ancestor));
+        package.push(*txid);
+        let (fee, vsize) = package.iter().try_fold((0, 0), |(fee, vsize), txid| {
+            let (tx_fee, tx_vsize) = self.fees.get(txid)?;
+            Some((fee + tx_fee, vsize + tx_vsize))
+        })?;
+        Some((package, fee, vsize))
+    }
+
+    /// Changes whenever transactions are added to or removed from the mempool
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn update(&mut self, daemon: &Daemon) -> Result<MempoolChanges> {
+        let generation = self.generation;
+        let changes = self.sync(daemon)?;
+        if self.generation != generation {
+            self.fee_histogram = fee_histogram(self.fees.values().copied());
+        }
+        // projected again once the mempool changed, but at most every NEXT_BLOCK_INTERVAL as
+        // this walks the whole mempool
+        if self.next_block.generation != self.generation
+            && self
+                .next_block_time
+                .map_or(true, |time| time.elapsed() >= NEXT_BLOCK_INTERVAL)
+        {
+            self.next_block = self.project_next_block();
+            self.next_block_time = Some(Instant::now());
+        }
+        Ok(changes)
+    }
+
+    fn sync(&mut self, daemon: &Daemon) -> Result<MempoolChanges> {
+        let _timer = self.latency.with_label_values(&["update"]).start_timer();
+        if let Some(changes) = self.apply_sequence_events(daemon) {
+            return Ok(changes);
+        }
+
+        self.resyncs.inc();
+        self.last_resync = None; // until it succeeds
+        let (new_txids, sequence) = daemon
+            .getmempooltxids_sequence()
+            .chain_err(|| "failed to update mempool from daemon")?;
+
+        // An unchanged sequence means nothing was added or removed since the last update
+        if sequence.is_some() && sequence == self.sequence {
+            self.last_resync = Some(Instant::now());
+            self.unchanged_updates.inc();
+            return Ok(MempoolChanges::default());
+        }
+
+        // Diff against the stored transactions, without collecting a set of their txids
+        let to_remove: Vec<Txid> = self
+            .txstore
+            .keys()
+            .filter(|txid| !new_txids.contains(*txid))
+            .cloned()
+            .collect();
+
+        // Download and add new transactions from bitcoind's mempool
+        let txids: Vec<&Txid> = new_txids
+            .iter()
+            .filter(|txid| !self.txstore.contains_key(*txid))
+            .collect();
+        let to_add = match daemon.gettransactions(&txids) {
+            Ok(txs) => txs,
+            Err(err) => {
+                warn!("failed to get {} transactions: {}", txids.len(), err); // e.g. new block or RBF
+                return Ok(MempoolChanges::default()); // keep the mempool until next update()
+            }
+        };
+        // Failed updates return early, so that the next one diffs the whole mempool again
+        self.last_resync = Some(Instant::now());
+        Ok(self.apply(to_add, to_remove, sequence))
+    }
+
+    // The changes notified since the last update, or `None` when the whole mempool has to be
+    // diffed: without notifications, once a block was connected (whose transactions leave the
+    // mempool without a notification), on a gap in the mempool sequence, or every
+    // RESYNC_INTERVAL in case the last notifications were missed.
+    fn apply_sequence_events(&mut self, daemon: &Daemon) -> Option<MempoolChanges> {
+        let events: Vec<SequenceEvent> = self.sequence_events.as_ref()?.try_iter().collect();
+        let synced = self.sequence?;
+        if self
+            .last_resync
+            .map_or(true, |time| time.elapsed() >= RESYNC_INTERVAL)
+        {
+            return None;
+        }
+
+        // the txids in the order they were added or removed, and whether they are in the mempool
+        let mut changed: Vec<(Txid, bool)> = vec![];
+        let mut sequence = synced;
+        for event in events {
+            let (txid, event_sequence, added) = match event {
+                SequenceEvent::Added(txid, seq) => (txid, seq, true),
+                SequenceEvent::Removed(txid, seq) => (txid, seq, false),
+                SequenceEvent::Resync => return None,
+            };
+            // getrawmempool reports the sequence of the next event
+            if event_sequence < sequence {
+                continue; // already seen by the last update
+            }
+            if event_sequence != sequence {
+                return None;
+            }
+            sequence += 1;
+            changed.retain(|(changed_txid, _)| *changed_txid != txid);
+            changed.push((txid, added));
+        }
+        if changed.is_empty() {
+            self.unchanged_updates.inc();
+            return Some(MempoolChanges::default());
+        }
+
+        let txids: Vec<&Txid> = changed
+            .iter()
+            .filter(|(txid, added)| *added && !self.txstore.contains_key(txid))
+            .map(|(txid, _)| txid)
+            .collect();
+        // e.g. replaced since, which the next notifications report
+        let to_add = daemon.gettransactions(&txids).ok()?;
+        let to_remove: Vec<Txid> = changed
+            .iter()
+            .filter(|(txid, added)| !*added && self.txstore.contains_key(txid))
+            .map(|(txid, _)| *txid)
+            .collect();
+        Some(self.apply(to_add, to_remove, Some(sequence)))
+    }
+
+    fn apply(
+        &mut self,
+        to_add: Vec<Transaction>,
+        to_remove: Vec<Txid>,
+        sequence: Option<u64>,
+    ) -> MempoolChanges {
+        let added: Vec<Txid> = to_add.iter().map(|tx| tx.txid()).collect();
+        if !to_add.is_empty() || !to_remove.is_empty() {
+            self.generation += 1;
+        }
+        // Add new transactions before removing the old ones, so that replacements are
+        // detected against the transactions they replaced
+        self.add(to_add);
+        // Remove missing transactions
+        self.remove(to_remove.iter().collect());
+
+        self.count
+            .with_label_values(&["txs"])
+            .set(self.txstore.len() as f64);
+        self.count
+            .with_label_values(&["conflicts"])
+            .set(self.conflicts.by_txid.len() as f64);
+
+        self.sequence = sequence;
+        MempoolChanges {
+            added,
+            removed: to_remove,
+        }
+    }
+
+    fn add(&mut self, txs: Vec<Transaction>) {
+        self.delta
+            .with_label_values(&["add"])
+            .observe(txs.len() as f64);
+        let _timer = self.latency.with_label_values(&["add"]).start_timer();
+        let now = unix_time();
+
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let mut conflicts: Vec<(Txid, Txid)> = vec![];
+        for tx in txs {
+            let txid = tx.txid();
+            self.first_seen.insert(txid, now);
+            for (index, txi) in tx.input.iter().enumerate() {
+                if !has_prevout(txi) {
+                    continue;
+                }
+                if let Some((spending_txid, _)) = self.edges.get(&txi.previous_output) {
+                    conflicts.push((txid, *spending_txid));
+                }
+                self.edges.insert(txi.previous_output, (txid, index as u32));
+            }
+            self.txstore.insert(txid, tx);
+        }
+
+        // once all the new transactions are known, so that their mempool parents are found
+        for txid in txids.iter().copied() {
+            let tx = &self.txstore[&txid];
+            let prevouts = self.lookup_prevouts(tx);
+            let tx_stats: Vec<(FullHash, ScriptStats)> =
+                ScriptStats::from_tx(tx, &prevouts, false, 0, compute_script_hash)
+                    .into_iter()
+                    .collect();
+            if let Some(fee) = get_tx_fee(tx, &prevouts, self.network()) {
+                self.fees.insert(txid, (fee, (tx.weight() as u64 + 3) / 4));
+            }
+            // keep the confirmed ones in memory, for serving the transaction without the index
+            for (outpoint, txo) in prevouts {
+                if !self.txstore.contains_key(&outpoint.txid) {
+                    self.prevouts.insert(outpoint, txo);
+                }
+            }
+            for (scripthash, stats) in &tx_stats {
+                self.script_stats.entry(*scripthash).or_default().add(stats);
+                self.script_txids
+                    .entry(*scripthash)
+                    .or_default()
+                    .insert(txid);
+            }
+            self.tx_stats.insert(txid, tx_stats);
+        }
+
+        for (txid, other) in conflicts {
+            let mut scripthashes = self.tx_scripthashes(&self.txstore[&txid]);
+            scripthashes.extend(self.tx_scripthashes(&self.txstore[&other]));
+            self.record_conflict(txid, other, scripthashes);
+        }
+
+        // the new transactions can be the parents of others received in the same batch
+        let mut updated: HashSet<Txid> = HashSet::new();
+        for txid in &txids {
+            updated.insert(*txid);
+            updated.extend(self.descendants(txid).unwrap_or_default());
+        }
+        self.update_feerates(updated.into_iter());
+    }
+
+    fn remove(&mut self, to_remove: HashSet<&Txid>) {
+        self.delta
+            .with_label_values(&["remove"])
+            .observe(to_remove.len() as f64);
+        let _timer = self.latency.with_label_values(&["remove"]).start_timer();
+        let mut first_seen_rows = vec![];
+
+        // the effective feerates of the remaining descendants change with their ancestors
+        let descendants: HashSet<Txid> = to_remove
+            .iter()
+            .flat_map(|txid| self.descendants(txid).unwrap_or_default())
+            .filter(|txid| !to_remove.contains(txid))
+            .collect();
+
+        for txid in &to_remove {
+            let tx = self
+                .txstore
+                .remove(*txid)
+                .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
+
+            self.fees.remove(*txid);
+            if let Some(feerate) = self.feerates.remove(*txid) {
+                self.by_feerate.remove(&(feerate, **txid));
+            }
+
+            for (scripthash, stats) in self.tx_stats.remove(*txid).unwrap_or_default() {
+                let script_stats = self
+                    .script_stats
+                    .get_mut(&scripthash)
+                    .expect("missing mempool script stats");
+                script_stats.subtract(&stats);
+                if script_stats.is_empty() {
+                    self.script_stats.remove(&scripthash);
+                }
+                if let Some(txids) = self.script_txids.get_mut(&scripthash) {
+                    txids.remove(*txid);
+                    if txids.is_empty() {
+                        self.script_txids.remove(&scripthash);
+                    }
+                }
+            }
+
+            // keep the first seen time of transactions that left the mempool by confirming
+            if let Some(first_seen) = self.first_seen.remove(*txid) {
+                if self.chain.tx_confirming_block(txid).is_some() {
+                    first_seen_rows.push(FirstSeenRow::new(txid, first_seen).into_row());
+                }
+            }
+
+            for txi in tx.input.iter().filter(|txi| has_prevout(txi)) {
+                // keep the edge if it was taken over by a replacing transaction
+                if let Some((spending_txid, _)) = self.edges.get(&txi.previous_output) {
+                    if spending_txid == *txid {
+                        self.edges.remove(&txi.previous_output);
+                    }
+                }
+                if !self.edges.contains_key(&txi.previous_output) {
+                    self.prevouts.remove(&txi.previous_output);
+                }
+
+                // a confirmed spend by another transaction means this one was double-spent by a block
+                if let Some(spend) = self.chain.lookup_spend(&txi.previous_output) {
+                    if spend.txid != **txid {
+                        let scripthashes = self.tx_scripthashes(&tx);
+                        self.record_conflict(**txid, spend.txid, scripthashes);
+                    }
+                }
+            }
+        }
+
+        self.update_feerates(descendants.into_iter());
+
+        if !first_seen_rows.is_empty() {
+            self.chain
+                .store
+                .txstore
+                .write(first_seen_rows, DBFlush::Enable);
+        }
+    }
+
+    // The scripts of the subscriptions to notify of a conflict are the ones of its transactions
+    fn record_conflict(&mut self, txid: Txid, other: Txid, scripthashes: HashSet<FullHash>) {
+        if self.conflicts.insert(txid, other) {
+            debug!(
+                "double-spend conflict detected: txid={} conflicting_txid={}",
+                txid, other
+            );
+            self.conflicts_count.inc();
+            self.conflicts.notify(txid, other, scripthashes);
+        }
+    }
+
+    // The scripts a transaction funds or spends from, as far as its previous outputs are found
+    fn tx_scripthashes(&self, tx: &Transaction) -> HashSet<FullHash> {
+        let prevouts = self.lookup_prevouts(tx);
+        tx.output
+            .iter()
+            .map(|txo| &txo.script_pubkey)
+            .chain(prevouts.values().map(|txo| &txo.script_pubkey))
+            .map(compute_script_hash)
+            .collect()
+    }
+
+    /// The number of conflicts recorded so far, to pass to `conflicts_since` later on
+    pub fn conflicts_recorded(&self) -> u64 {
+        self.conflicts.recorded
+    }
+
+    /// The conflicts recorded after the first `seen` ones, along with each of the scripts of
+    /// their transactions, as (scripthash, txid, conflicting txid). The oldest ones may have
+    /// been forgotten already.
+    pub fn conflicts_since(&self, seen: u64) -> Vec<(FullHash, Txid, Txid)> {
+        self.conflicts
+            .scripts
+            .iter()
+            .filter(|(number, ..)| *number > seen)
+            .map(|(_, scripthash, txid, other)| (*scripthash, *txid, *other))
+            .collect()
+    }
+}
+
+impl Conflicts {
+    // Returns false if the conflict was already known
+    fn insert(&mut self, txid: Txid, other: Txid) -> bool {
+        if !self.by_txid.entry(txid).or_default().insert(other) {
+            return false;
+        }
+        self.by_txid.entry(other).or_default().insert(txid);
+        self.recent.push_back((txid, other));
+
+        while self.recent.len() > MAX_CONFLICTS {
+            let (txid, other) = self.recent.pop_front().unwrap();
+            self.forget(&txid, &other);
+            self.forget(&other, &txid);
+        }
+        true
+    }
+
+    fn notify(&mut self, txid: Txid, other: Txid, scripthashes: HashSet<FullHash>) {
+        self.recorded += 1;
+        for scripthash in scripthashes {
+            self.scripts
+                .push_back((self.recorded, scripthash, txid, other));
+        }
+        while self.scripts.len() > MAX_CONFLICTS {
+            self.scripts.pop_front();
+        }
+    }
+
+    fn forget(&mut self, txid: &Txid, other: &Txid) {
+        if let Some(txids) = self.by_txid.get_mut(txid) {
+            txids.remove(other);
+            if txids.is_empty() {
+                self.by_txid.remove(txid);
+            }
+        }
+    }
+}
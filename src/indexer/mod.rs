@@ -1,56 +1,111 @@
+#[cfg(not(feature = "liquid"))]
+use std::collections::HashSet;
 use std::{
     collections::{BTreeSet, HashMap},
     sync::Arc,
+    time::Instant,
 };
 
-use bitcoin::{
-    consensus::{deserialize, serialize},
-    BlockHash, OutPoint, Script, Transaction, TxOut, Txid,
-};
+use bitcoin::{consensus::serialize, BlockHash, OutPoint, Script, Transaction, TxOut, Txid};
 use itertools::Itertools;
-use rayon::prelude::*;
+use rayon::{prelude::*, ThreadPool};
 
 use crate::{
     chain::Network,
-    config::Config,
+    config::{BatchBounds, Config, DbSyncPolicy},
     daemon::Daemon,
     store::{
-        start_fetcher, BlockEntry, BlockRow, CachedUtxoMap, DBFlush, DBRow, FetchFrom, Fetcher,
-        FundingInfo, SpendingInfo, Store, TxConfRow, TxEdgeRow, TxHistoryInfo, TxHistoryRow,
-        TxOutRow, TxRow, UtxoMap, DB,
+        set_utxo_acc_tip_height, start_fetcher, BlockEntry, BlockRow, CachedUtxoMap, ChainStats,
+        ChainStatsRow, ChannelCloseRow, DBFlush, DBRow, FetchFrom, Fetcher, FundingInfo,
+        OpReturnRow, ScriptActivity, ScriptActivityRow, ScriptHashes, ScriptRow, ScriptStats,
+        ScriptStatsRow, ScriptTypeStats, ScriptTypeStatsRow, SpendingInfo, Store, SyncMemory,
+        TxConfRow, TxEdgeRow, TxHistoryInfo, TxHistoryRow, TxOutRow, TxRow, UtxoAccRow, UtxoMap,
+        DB,
     },
     util::{
-        block::{BlockMeta, HeaderEntry},
-        full_hash,
-        script::ScriptToAddr,
-        transaction::{has_prevout, is_spendable},
+        block::{BlockMeta, HeaderEntry, HeaderList},
+        fees::FeeratePercentiles,
+        full_hash, request_id,
+        script::{is_channel_funding_spend, ScriptToAddr},
+        thread_pool,
+        transaction::{get_tx_fee, has_prevout, is_dust, is_spendable},
         FullHash,
     },
 };
 
-use crate::metrics::{Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use crate::metrics::{
+    FloatGauge, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec,
+    MetricOpts, Metrics,
+};
 
 use crate::errors::*;
 
-use self::query::ChainQuery;
+use bitcoin::consensus::deserialize;
+
+#[cfg(not(feature = "liquid"))]
+use crate::{
+    store::{BlockAgeStats, RichListEntry, RichListRow},
+    util::Bytes,
+};
+
+#[cfg(feature = "liquid")]
+use crate::elements::asset;
+#[cfg(feature = "ordinals")]
+use crate::{store::InscriptionRow, util::inscription::parse_inscriptions};
+#[cfg(feature = "runes")]
+use crate::{store::RunestoneRow, util::runestone::parse_runestone};
 
+use self::{progress::SyncProgress, query::ChainQuery};
+
+pub mod check;
+pub mod fees;
+pub mod mempool;
+pub mod progress;
 pub mod query;
 pub mod schema;
 
 pub struct Indexer {
     pub store: Arc<Store>,
     pub flush: DBFlush,
+    // until the first update reaches the tip
+    initial_sync: bool,
     pub from: FetchFrom,
     pub iconfig: IndexerConfig,
     pub duration: HistogramVec,
     pub tip_metric: Gauge,
+    pub block_latency: Histogram,
+    pub last_block_latency: FloatGauge,
+    pub tx_count: Gauge,
+    pub utxo_count: Gauge,
+    pub store_size: GaugeVec,
+    pub progress: Arc<SyncProgress>,
+    pub memory: Arc<SyncMemory>,
+    // the blocks written to the txstore and history DBs since they were last flushed, under
+    // `--db-sync=interval:N`
+    unflushed_txstore: usize,
+    unflushed_history: usize,
+    // the heights of the blocks of the current update, not in the store's headers yet
+    #[cfg(not(feature = "liquid"))]
+    new_heights: HashMap<BlockHash, usize>,
+    add_pool: ThreadPool,
+    index_pool: ThreadPool,
+    lookup_pool: ThreadPool,
 }
 
 impl Indexer {
     pub fn open(store: Arc<Store>, from: FetchFrom, config: &Config, metrics: &Metrics) -> Self {
+        let cpus = config.index_cpus.as_deref();
+        let flush = match config.db_sync_policy {
+            DbSyncPolicy::Always => DBFlush::Enable,
+            DbSyncPolicy::Interval(_) | DbSyncPolicy::NeverUntilTip => DBFlush::Disable,
+        };
+        let progress = Arc::new(SyncProgress::new(from, metrics));
+        let status = Arc::clone(&progress);
+        metrics.document("/internal/sync-status", move || status.status());
         Self {
             store,
-            flush: DBFlush::Disable,
+            flush,
+            initial_sync: true,
             from,
             iconfig: IndexerConfig::from(config),
             duration: metrics.histogram_vec(
@@ -58,67 +113,199 @@ impl Indexer {
                 &["step"],
             ),
             tip_metric: metrics.gauge(MetricOpts::new("tip_height", "Current chain tip height")),
+            block_latency: metrics.histogram(
+                HistogramOpts::new(
+                    "block_index_latency",
+                    "Time from seeing a new best block at bitcoind to having it indexed (in seconds)",
+                )
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0]),
+            ),
+            last_block_latency: metrics.float_gauge(MetricOpts::new(
+                "last_block_index_seconds",
+                "Time it took to index the last new best block (in seconds)",
+            )),
+            tx_count: metrics.gauge(MetricOpts::new(
+                "chain_tx_count",
+                "Number of indexed transactions",
+            )),
+            utxo_count: metrics.gauge(MetricOpts::new(
+                "chain_utxo_count",
+                "Number of unspent transaction outputs",
+            )),
+            store_size: metrics.gauge_vec(
+                MetricOpts::new("store_size_bytes", "On-disk size of each DB (in bytes)"),
+                &["db"],
+            ),
+            progress,
+            memory: SyncMemory::new(config.sync_memory_limit, metrics),
+            unflushed_txstore: 0,
+            unflushed_history: 0,
+            #[cfg(not(feature = "liquid"))]
+            new_heights: HashMap::new(),
+            add_pool: thread_pool("add", config.add_threads, cpus),
+            index_pool: thread_pool("index", config.index_threads, cpus),
+            lookup_pool: thread_pool("lookup-txo", config.lookup_threads, cpus),
         }
     }
 
     pub fn update(&mut self, daemon: &Daemon) -> Result<BlockHash> {
         let daemon = daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
+        // bitcoind is polled, so a new block is seen at most a polling interval after it is
+        let seen = Instant::now();
         let new_headers = self.get_new_headers(&daemon, &tip)?;
+        let initial_sync = self.initial_sync;
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.block_age_stats {
+            self.new_heights = new_headers
+                .iter()
+                .map(|entry| (*entry.hash(), entry.height()))
+                .collect();
+        }
 
         let to_add = self.headers_to_add(&new_headers);
+        self.ensure_available(&daemon, &to_add)?;
+        // adding doesn't change which blocks are indexed
+        let (skipped, to_index): (Vec<HeaderEntry>, Vec<HeaderEntry>) = self
+            .headers_to_index(&new_headers)
+            .into_iter()
+            .partition(|entry| entry.height() < self.iconfig.index_start_height);
+        let tip_height = new_headers
+            .last()
+            .map_or(self.store.headers().len().saturating_sub(1), |entry| {
+                entry.height()
+            });
+        self.progress
+            .start(self.from, tip_height, to_add.len(), to_index.len());
 
         debug!(
-            "adding transactions from {} blocks using {:?}",
+            "adding transactions from blocks={} using {:?}",
             to_add.len(),
             self.from
         );
 
-        start_fetcher(self.from, &daemon, to_add)?.each(|blocks| self.add(&blocks));
+        let mut unflushed = self.unflushed_txstore;
+        start_fetcher(
+            self.from,
+            &daemon,
+            to_add,
+            self.iconfig.fetch_batch_bounds,
+            &self.memory,
+        )?
+        .each(|blocks| {
+            self.add(&blocks);
+            self.flush_every_interval(&self.store.txstore, &mut unflushed, blocks.len());
+            self.progress.added(blocks.len());
+        });
+        self.unflushed_txstore = unflushed;
 
         self.start_auto_compactions(&self.store.txstore);
 
-        let to_index = self.headers_to_index(&new_headers);
+        self.disconnect(&daemon, &new_headers);
+        let mut unflushed = self.unflushed_history;
+        self.skip(&skipped);
+        self.flush_every_interval(&self.store.history, &mut unflushed, skipped.len());
         debug!(
-            "indexing history from {} blocks using {:?}",
+            "indexing history from blocks={} using {:?}",
             to_index.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_index)?.each(|blocks| self.index(&blocks));
+        start_fetcher(
+            self.from,
+            &daemon,
+            to_index,
+            self.iconfig.fetch_batch_bounds,
+            &self.memory,
+        )?
+        .each(|blocks| {
+            self.index(&blocks);
+            self.flush_every_interval(&self.store.history, &mut unflushed, blocks.len());
+            self.progress.indexed(blocks.len());
+        });
+        self.unflushed_history = unflushed;
         self.start_auto_compactions(&self.store.history);
+        #[cfg(not(feature = "liquid"))]
+        self.new_heights.clear();
 
-        if let DBFlush::Disable = self.flush {
+        // the synced tip is never ahead of the rows on disk, which the unlogged writes of the
+        // initial sync and of `--db-sync=interval:N` are not until flushed
+        if initial_sync || self.unflushed_txstore > 0 || self.unflushed_history > 0 {
             debug!("flushing to disk");
             self.store.txstore.flush();
             self.store.history.flush();
-            self.flush = DBFlush::Enable;
+            self.unflushed_txstore = 0;
+            self.unflushed_history = 0;
+        }
+        if initial_sync {
+            self.initial_sync = false;
+            if self.iconfig.db_sync_policy == DbSyncPolicy::NeverUntilTip {
+                self.flush = DBFlush::Enable;
+            }
         }
 
         // update the synced tip *after* the new data is flushed to disk
-        debug!("updating synced tip to {:?}", tip);
+        debug!("updating synced tip to blockhash={}", tip);
         self.store.txstore.put_sync(b"t", &serialize(&tip));
 
-        let mut headers = self.store.indexed_headers.write().unwrap();
+        let new_headers_empty = new_headers.is_empty();
+        // the indexer is the only writer, so the snapshot can't change until it's replaced
+        let mut headers = HeaderList::clone(&self.store.headers());
         headers.apply(new_headers);
         assert_eq!(tip, *headers.tip());
+        self.tip_metric.set(headers.len() as i64 - 1);
+        set_utxo_acc_tip_height(headers.len().saturating_sub(1) as u32);
+        self.store.set_headers(headers);
+
+        if initial_sync {
+            self.store.persist_headers();
+        } else if !new_headers_empty {
+            let latency = seen.elapsed().as_secs_f64();
+            self.block_latency.observe(latency);
+            self.last_block_latency.set(latency);
+        }
+
+        self.update_chain_metrics();
+        self.progress.done();
 
         if let FetchFrom::BlkFiles = self.from {
             self.from = FetchFrom::Bitcoind;
         }
 
-        self.tip_metric.set(headers.len() as i64 - 1);
-
         Ok(tip)
     }
 
+    // Blocks below the prune height can only come from blk*.dat files, never from JSONRPC
+    fn ensure_available(&self, daemon: &Daemon, headers: &[HeaderEntry]) -> Result<()> {
+        if let (FetchFrom::Bitcoind, Some(first)) = (self.from, headers.first()) {
+            if let Some(prune_height) = daemon.prune_height()? {
+                ensure!(
+                    first.height() >= prune_height,
+                    ErrorKind::PrunedBlock(format!(
+                        "cannot sync block {} at height {}, below the prune height {}. \
+                         The initial sync with a pruned node needs an existing index or blk*.dat files",
+                        first.hash(),
+                        first.height(),
+                        prune_height
+                    ))
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn get_new_headers(&self, daemon: &Daemon, tip: &BlockHash) -> Result<Vec<HeaderEntry>> {
-        let headers = self.store.indexed_headers.read().unwrap();
+        let headers = self.store.headers();
         let new_headers = daemon.get_new_headers(&headers, tip)?;
 
         let res = headers.order(new_headers);
 
         if let Some(tip) = res.last() {
-            info!("{:#?} ({} left to index", tip, res.len());
+            info!(
+                "new tip height={} blockhash={} ({} left to index)",
+                tip.height(),
+                tip.hash(),
+                res.len()
+            );
         }
 
         Ok(res)
@@ -146,9 +333,10 @@ impl Indexer {
         // TODO: skip orphaned blocks?
         let rows = {
             let _timer = self.start_timer("add_process");
-            add_blocks(blocks, &self.iconfig)
+            self.add_pool.install(|| add_blocks(blocks, &self.iconfig))
         };
         {
+            let _hold = self.memory.hold("rows", rows_size(&rows));
             let _timer = self.start_timer("add_write");
             self.store.txstore.write(rows, self.flush);
         }
@@ -160,12 +348,197 @@ impl Indexer {
             .extend(blocks.iter().map(|b| b.entry.hash()));
     }
 
+    // Flushes `db` once `unflushed` reaches the interval of `--db-sync=interval:N`, so that a
+    // crash loses at most that many blocks of writes
+    fn flush_every_interval(&self, db: &DB, unflushed: &mut usize, blocks: usize) {
+        if let DbSyncPolicy::Interval(interval) = self.iconfig.db_sync_policy {
+            *unflushed += blocks;
+            if *unflushed >= interval {
+                debug!("flushing {} blocks to disk", unflushed);
+                db.flush();
+                *unflushed = 0;
+            }
+        }
+    }
+
+    // Marks the blocks below the index start height as indexed, without fetching them
+
+    fn skip(&self, headers: &[HeaderEntry]) {
+        if headers.is_empty() {
+            return;
+        }
+        debug!("skipping the history of blocks={}", headers.len());
+        let rows = headers
+            .iter()
+            .map(|entry| BlockRow::new_done(full_hash(&entry.hash()[..])).into_row())
+            .collect();
+        self.store.history.write(rows, self.flush);
+    }
+
+    // Reverts what the indexed blocks that `new_headers` disconnect from the best chain merged
+    // into the script stats and the UTXO accumulator, so that their transactions aren't counted
+    // twice once mined again. Their history rows stay, as the queries skip the rows of the
+    // blocks that are no longer confirmed.
+    fn disconnect(&self, daemon: &Daemon, new_headers: &[HeaderEntry]) {
+        let headers = self.store.headers();
+        let fork_height = match new_headers.first() {
+            Some(first) if first.height() < headers.len() => first.height(),
+            _ => return,
+        };
+        let disconnected: Vec<HeaderEntry> = headers
+            .iter_range(fork_height.max(self.iconfig.index_start_height)..headers.len())
+            .cloned()
+            .collect();
+        if disconnected.is_empty() {
+            return;
+        }
+        info!("reverting {} disconnected blocks", disconnected.len());
+        if let Err(e) = self.revert(daemon, &disconnected) {
+            // the accumulator queries still skip the spends of the transactions that are no
+            // longer confirmed, the stats of the scripts used by the blocks stay off
+            warn!("failed to revert the disconnected blocks: {}", e);
+        }
+    }
+
+    fn revert(&self, daemon: &Daemon, disconnected: &[HeaderEntry]) -> Result<()> {
+        let blockhashes: Vec<BlockHash> = disconnected.iter().map(|entry| *entry.hash()).collect();
+        let blocks: Vec<BlockEntry> = daemon
+            .getblocks(&blockhashes)?
+            .into_iter()
+            .zip(disconnected)
+            .map(|(block, entry)| BlockEntry {
+                entry: entry.clone(),
+                size: block.size() as u32,
+                block,
+            })
+            .collect();
+        let previous_txos_map = lookup_txos(
+            &self.lookup_pool,
+            &self.store.txstore,
+            &get_previous_txos(&blocks),
+            false,
+            self.iconfig.intern_scripts,
+        )?;
+        let script_hashes = self
+            .index_pool
+            .install(|| ScriptHashes::new(&blocks, &previous_txos_map));
+        let (script_stats, script_activity) =
+            script_stats(&blocks, &previous_txos_map, &script_hashes, &self.iconfig);
+        let mut merges: Vec<DBRow> = script_stats
+            .iter()
+            .map(|(scripthash, stats)| ScriptStatsRow::new(scripthash, stats.negated()).into_row())
+            .collect();
+        let txs = blocks.iter().flat_map(|b| b.block.txdata.iter());
+        merges.push(ChainStatsRow::new(&ChainStats::from_txs(txs).negated()));
+        // the activity heights can't be negated, they are replaced by the ones below the fork
+        let fork_height = disconnected.iter().map(HeaderEntry::height).min();
+        let mut rows = vec![];
+        if let Some(fork_height) = fork_height {
+            for scripthash in script_activity.keys() {
+                rows.extend(self.reverted_activity_row(scripthash, fork_height)?);
+            }
+        }
+        if self.iconfig.utxo_accumulator {
+            merges.extend(reverted_utxo_acc_rows(
+                &blocks,
+                &previous_txos_map,
+                &script_hashes,
+                self.iconfig.skip_dust,
+            ));
+        }
+        // the blocks are indexed again if they are reconnected, so their done markers go along
+        // with the reverted merges: otherwise a crash in between reverts them twice
+        let done_keys = blockhashes
+            .iter()
+            .map(|hash| BlockRow::done_key(full_hash(&hash[..])))
+            .collect();
+        self.store
+            .history
+            .write_batch(rows, merges, done_keys, self.flush);
+        let mut indexed_blockhashes = self.store.indexed_blockhashes.write().unwrap();
+        for hash in &blockhashes {
+            indexed_blockhashes.remove(hash);
+        }
+        Ok(())
+    }
+
+    // The activity of a script as of the blocks below `fork_height`, unless unchanged by the
+    // blocks disconnected above it
+    fn reverted_activity_row(
+        &self,
+        scripthash: &FullHash,
+        fork_height: usize,
+    ) -> Result<Option<DBRow>> {
+        let activity = match self.store.history.get(&ScriptActivityRow::key(scripthash)) {
+            Some(value) => ScriptActivityRow::value_from_row(&value)?,
+            None => return Ok(None),
+        };
+        let below_fork = |height: &u32| (*height as usize) < fork_height;
+        let reverted = ScriptActivity {
+            // no block below the fork funded the script if none of them was the first to
+            first_funded: activity.first_funded.filter(below_fork),
+            last_active: match activity.last_active {
+                Some(height) if !below_fork(&height) => {
+                    self.last_active_below(scripthash, fork_height)?
+                }
+                last_active => last_active,
+            },
+        };
+        Ok((reverted != activity).then(|| ScriptActivityRow::new(scripthash, &reverted)))
+    }
+
+    // The height of the latest history entry of a script below `height`, skipping the ones of
+    // the transactions no longer confirmed by the best chain
+    fn last_active_below(&self, scripthash: &FullHash, height: usize) -> Result<Option<u32>> {
+        let headers = self.store.headers();
+        let rows = self
+            .store
+            .history
+            .iter_scan_reverse(
+                &TxHistoryRow::filter(b'H', scripthash),
+                &TxHistoryRow::prefix_height(b'H', scripthash, height as u32),
+            )
+            .map(TxHistoryRow::from_row);
+        for row in rows {
+            let row = row?;
+            let txid = row.get_txid();
+            let confirmed = self
+                .store
+                .txstore
+                .iter_scan(&TxConfRow::filter(&txid[..]))
+                .filter_map(|row| TxConfRow::from_row(row).ok())
+                .any(|conf| {
+                    let blockhash: BlockHash = deserialize(&conf.key.blockhash).unwrap();
+                    headers
+                        .header_by_blockhash(&blockhash)
+                        .map_or(false, |entry| entry.height() < height)
+                });
+            if confirmed {
+                return Ok(Some(row.key.confirmed_height));
+            }
+        }
+        Ok(None)
+    }
+
     fn index(&self, blocks: &[BlockEntry]) {
         let previous_txos_map = {
             let _timer = self.start_timer("index_lookup");
-            lookup_txos(&self.store.txstore, &get_previous_txos(blocks), false)
+            lookup_txos(
+                &self.lookup_pool,
+                &self.store.txstore,
+                &get_previous_txos(blocks),
+                false,
+                self.iconfig.intern_scripts,
+            )
+            .expect("failed to look up the previous outputs of the indexed blocks")
         };
-        let rows = {
+        let script_hashes = {
+            let _timer = self.start_timer("index_script_hashes");
+            self.index_pool
+                .install(|| ScriptHashes::new(blocks, &previous_txos_map))
+        };
+        #[allow(unused_mut)]
+        let mut rows = {
             let _timer = self.start_timer("index_process");
             let added_blockhashes = self.store.added_blockhashes.read().unwrap();
             for b in blocks {
@@ -175,9 +548,185 @@ impl Indexer {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
-            index_blocks(blocks, &previous_txos_map, &self.iconfig)
+            self.index_pool
+                .install(|| index_blocks(blocks, &previous_txos_map, &script_hashes, &self.iconfig))
         };
-        self.store.history.write(rows, self.flush);
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.block_age_stats {
+            let _timer = self.start_timer("index_age_stats");
+            rows.extend(self.age_stats_rows(blocks, &previous_txos_map));
+        }
+        let (script_stats, script_activity) =
+            script_stats(blocks, &previous_txos_map, &script_hashes, &self.iconfig);
+        let merges = {
+            let _timer = self.start_timer("index_merges");
+            let mut merges: Vec<DBRow> = script_stats
+                .iter()
+                .map(|(scripthash, stats)| {
+                    ScriptStatsRow::new(scripthash, stats.clone()).into_row()
+                })
+                .collect();
+            merges.extend(
+                script_activity
+                    .iter()
+                    .map(|(scripthash, activity)| ScriptActivityRow::new(scripthash, activity)),
+            );
+            if self.iconfig.utxo_accumulator {
+                merges.extend(utxo_acc_rows(
+                    blocks,
+                    &previous_txos_map,
+                    &script_hashes,
+                    self.iconfig.skip_dust,
+                ));
+            }
+            let txs = blocks.iter().flat_map(|b| b.block.txdata.iter());
+            merges.push(ChainStatsRow::new(&ChainStats::from_txs(txs.clone())));
+            merges.push(ScriptTypeStatsRow::new(&ScriptTypeStats::from_txs(txs)));
+            merges
+        };
+        let hold = self
+            .memory
+            .hold("rows", rows_size(&rows) + rows_size(&merges));
+        self.store
+            .history
+            .write_with_merges(rows, merges, self.flush);
+        drop(hold);
+
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.rich_list_size > 0 {
+            self.update_rich_list(blocks, &script_stats, &script_hashes);
+        }
+    }
+
+    // The age stats of each block, from the confirmation heights of the transactions funding
+    // the spent outputs, which are either in the best chain or among the blocks of this update
+    #[cfg(not(feature = "liquid"))]
+    fn age_stats_rows(
+        &self,
+        blocks: &[BlockEntry],
+        previous_txos_map: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<DBRow> {
+        let headers = self.store.headers();
+        let txids: HashSet<Txid> = previous_txos_map
+            .keys()
+            .map(|outpoint| outpoint.txid)
+            .collect();
+        let heights: HashMap<Txid, u32> = self.lookup_pool.install(|| {
+            txids
+                .into_par_iter()
+                .filter_map(|txid| {
+                    let height = self
+                        .store
+                        .txstore
+                        .iter_scan(&TxConfRow::filter(&txid[..]))
+                        .map(|row| TxConfRow::from_row(row).expect("invalid TxConf row"))
+                        .find_map(|conf| {
+                            let blockhash: BlockHash = deserialize(&conf.key.blockhash).unwrap();
+                            self.new_heights.get(&blockhash).copied().or_else(|| {
+                                headers
+                                    .header_by_blockhash(&blockhash)
+                                    .map(HeaderEntry::height)
+                            })
+                        })?;
+                    Some((txid, height as u32))
+                })
+                .collect()
+        });
+        blocks
+            .iter()
+            .map(|b| {
+                let spends = b
+                    .block
+                    .txdata
+                    .iter()
+                    .flat_map(|tx| tx.input.iter())
+                    .filter(|txin| has_prevout(txin))
+                    .filter_map(|txin| {
+                        let height = heights.get(&txin.previous_output.txid)?;
+                        Some((*height, previous_txos_map[&txin.previous_output].value))
+                    });
+                let stats = BlockAgeStats::from_spends(b.entry.height() as u32, spends);
+                BlockRow::new_age_stats(full_hash(&b.entry.hash()[..]), &stats).into_row()
+            })
+            .collect()
+    }
+
+    // Ranks the scripts used by the blocks by their balance, read back from the script stats
+    // that were just merged. The listed scripts are always updated, while the others can only
+    // enter the list by receiving more than they spent.
+    #[cfg(not(feature = "liquid"))]
+    fn update_rich_list(
+        &self,
+        blocks: &[BlockEntry],
+        script_stats: &HashMap<FullHash, ScriptStats>,
+        script_hashes: &ScriptHashes,
+    ) {
+        let _timer = self.start_timer("index_rich_list");
+        let height = match blocks.last() {
+            Some(b) => b.entry.height() as u32,
+            None => return,
+        };
+        let mut rich_list = self.store.rich_list().expect("invalid rich list");
+        let listed: HashSet<&FullHash> = rich_list
+            .entries
+            .iter()
+            .map(|entry| &entry.scripthash)
+            .collect();
+        let candidates: HashSet<&FullHash> = script_stats
+            .iter()
+            .filter(|(scripthash, stats)| {
+                listed.contains(scripthash) || stats.funded_txo_sum > stats.spent_txo_sum
+            })
+            .map(|(scripthash, _)| scripthash)
+            .collect();
+        let scripts: HashMap<&FullHash, &Script> = script_hashes
+            .iter()
+            .filter(|(_, scripthash)| candidates.contains(scripthash))
+            .map(|(script, scripthash)| (scripthash, script))
+            .collect();
+
+        let scripthashes: Vec<&FullHash> = scripts.keys().copied().collect();
+        let keys: Vec<Bytes> = scripthashes
+            .iter()
+            .map(|scripthash| ScriptStatsRow::key(*scripthash))
+            .collect();
+        let updated = self
+            .store
+            .history
+            .multi_get(&keys)
+            .into_iter()
+            .zip(scripthashes)
+            .map(|(value, scripthash)| {
+                let stats = value
+                    .map(|value| ScriptStatsRow::value_from_row(&value))
+                    .transpose()
+                    .expect("invalid script stats")
+                    .unwrap_or_default();
+                RichListEntry {
+                    scripthash: *scripthash,
+                    script: scripts[scripthash].to_bytes(),
+                    balance: stats.funded_txo_sum.saturating_sub(stats.spent_txo_sum),
+                }
+            })
+            .collect();
+
+        rich_list.update(height, updated, self.iconfig.rich_list_size);
+        self.store
+            .history
+            .write(vec![RichListRow::new(&rich_list)], self.flush);
+    }
+
+    fn update_chain_metrics(&self) {
+        match self.store.chain_stats() {
+            Ok(stats) => {
+                self.tx_count.set(stats.tx_count as i64);
+                self.utxo_count.set(stats.utxo_count() as i64);
+            }
+            Err(e) => warn!("failed to read the chain stats: {}", e),
+        }
+        for (db, size) in self.store.sizes() {
+            self.store_size.with_label_values(&[db]).set(size as f64);
+        }
     }
 
     fn start_auto_compactions(&self, store: &DB) {
@@ -199,6 +748,17 @@ pub struct IndexerConfig {
     pub light_mode: bool,
     pub address_search: bool,
     pub index_unspendables: bool,
+    pub index_op_return: bool,
+    pub index_channels: bool,
+    pub utxo_accumulator: bool,
+    pub script_activity: bool,
+    pub rich_list_size: usize,
+    pub block_age_stats: bool,
+    pub index_start_height: usize,
+    pub intern_scripts: bool,
+    pub skip_dust: u64,
+    pub db_sync_policy: DbSyncPolicy,
+    pub fetch_batch_bounds: BatchBounds,
     pub network: Network,
     #[cfg(feature = "liquid")]
     pub parent_network: crate::chain::BNetwork,
@@ -210,6 +770,17 @@ impl From<&Config> for IndexerConfig {
             light_mode: config.light_mode,
             address_search: config.address_search,
             index_unspendables: config.index_unspendables,
+            index_op_return: config.index_op_return,
+            index_channels: config.index_channels,
+            utxo_accumulator: config.utxo_accumulator,
+            script_activity: config.script_activity,
+            rich_list_size: config.rich_list_size,
+            block_age_stats: config.block_age_stats,
+            index_start_height: config.index_start_height,
+            intern_scripts: config.intern_scripts,
+            skip_dust: config.skip_dust,
+            db_sync_policy: config.db_sync_policy,
+            fetch_batch_bounds: config.fetch_batch_bounds,
             network: config.network_type,
             #[cfg(feature = "liquid")]
             parent_network: config.parent_network,
@@ -217,11 +788,51 @@ impl From<&Config> for IndexerConfig {
     }
 }
 
+// The inscriptions of each transaction revealing any, and their count in the block
+#[cfg(feature = "ordinals")]
+fn inscription_rows(blockhash: FullHash, txs: &[Transaction]) -> Vec<DBRow> {
+    let mut rows = vec![];
+    let mut count = 0;
+    for tx in txs {
+        let inscriptions = parse_inscriptions(tx);
+        if !inscriptions.is_empty() {
+            count += inscriptions.len() as u32;
+            rows.push(InscriptionRow::new(
+                full_hash(&tx.txid()[..]),
+                &inscriptions,
+            ));
+        }
+    }
+    rows.push(BlockRow::new_inscription_count(blockhash, count).into_row());
+    rows
+}
+
+// The runestone of each transaction carrying one, and their txids in the block
+#[cfg(feature = "runes")]
+fn runestone_rows(blockhash: FullHash, txs: &[Transaction]) -> Vec<DBRow> {
+    let mut rows = vec![];
+    let mut txids = vec![];
+    for tx in txs {
+        if let Some(runestone) = parse_runestone(tx) {
+            let txid = tx.txid();
+            rows.push(RunestoneRow::new(full_hash(&txid[..]), &runestone));
+            txids.push(txid);
+        }
+    }
+    rows.push(BlockRow::new_runestone_txids(blockhash, &txids).into_row());
+    rows
+}
+
+fn rows_size(rows: &[DBRow]) -> usize {
+    rows.iter().map(|row| row.key.len() + row.value.len()).sum()
+}
+
 fn add_blocks(block_entries: &[BlockEntry], iconfig: &IndexerConfig) -> Vec<DBRow> {
     // Persist individual transactions:
     //  T{Txid} -> {rawtx}
     //  C{txid}{blockhash}{height} ->
     //  O{txid}{index} -> {txout}
+    //  P{scripthash} -> {script} (interned scripts only)
     // Persist block headers', block txids' and metadata rows:
     //  B{blockhash} -> {header}
     //  X{blockhash} -> {txid1}...{txidN}
@@ -277,43 +888,57 @@ fn add_transaction(
 
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
-        if is_spendable(txo) {
+        if !is_spendable(txo) {
+            continue;
+        }
+        if iconfig.intern_scripts {
+            let (row, script_row) = TxOutRow::new_interned(&txid, txo_index, txo);
+            rows.push(row.into_row());
+            rows.extend(script_row.map(ScriptRow::into_row));
+        } else {
             rows.push(TxOutRow::new(&txid, txo_index, txo).into_row());
         }
     }
 }
 
+// The pool should have enough threads to saturate the disk's IOPS
 fn lookup_txos(
+    pool: &ThreadPool,
     txstore_db: &DB,
     outpoints: &BTreeSet<OutPoint>,
     allow_missing: bool,
-) -> HashMap<OutPoint, TxOut> {
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(16) // we need to saturate SSD IOPS
-        .thread_name(|i| format!("lookup-txo-{}", i))
-        .build()
-        .unwrap();
+    intern_scripts: bool,
+) -> Result<HashMap<OutPoint, TxOut>> {
+    // the lookups run on the pool's threads, which only see the request through its ID
+    let id = request_id::current();
     pool.install(|| {
         outpoints
             .par_iter()
             .filter_map(|outpoint| {
-                lookup_txo(&txstore_db, &outpoint)
-                    .or_else(|| {
-                        if !allow_missing {
-                            panic!("missing txo {} in {:?}", outpoint, txstore_db);
-                        }
-                        None
-                    })
-                    .map(|txo| (*outpoint, txo))
+                match request_id::enter(id, || lookup_txo(&txstore_db, &outpoint, intern_scripts)) {
+                    Ok(Some(txo)) => Some(Ok((*outpoint, txo))),
+                    Ok(None) if allow_missing => None,
+                    Ok(None) => Some(Err(ErrorKind::DbInconsistency(format!(
+                        "missing txo {} in {:?}",
+                        outpoint, txstore_db
+                    ))
+                    .into())),
+                    Err(e) => Some(Err(e)),
+                }
             })
             .collect()
     })
 }
 
-fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
+fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint, intern_scripts: bool) -> Result<Option<TxOut>> {
     txstore_db
         .get(&TxOutRow::key(&outpoint))
-        .map(|val| deserialize(&val).expect("failed to parse TxOut"))
+        .map(|val| {
+            TxOutRow::parse_value(&val, intern_scripts, |scripthash| {
+                txstore_db.get(&ScriptRow::key(scripthash))
+            })
+        })
+        .transpose()
 }
 
 fn get_previous_txos(block_entries: &[BlockEntry]) -> BTreeSet<OutPoint> {
@@ -332,55 +957,212 @@ fn get_previous_txos(block_entries: &[BlockEntry]) -> BTreeSet<OutPoint> {
 fn index_blocks(
     block_entries: &[BlockEntry],
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    script_hashes: &ScriptHashes,
     iconfig: &IndexerConfig,
 ) -> Vec<DBRow> {
     block_entries
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
             let mut rows = vec![];
-            for tx in &b.block.txdata {
-                let height = b.entry.height() as u32;
-                index_transaction(tx, height, previous_txos_map, &mut rows, iconfig);
+            let height = b.entry.height() as u32;
+            for (tx_position, tx) in b.block.txdata.iter().enumerate() {
+                index_transaction(
+                    tx,
+                    height,
+                    tx_position as u16,
+                    previous_txos_map,
+                    script_hashes,
+                    &mut rows,
+                    iconfig,
+                );
+            }
+            let blockhash = full_hash(&b.entry.hash()[..]);
+            #[cfg(feature = "ordinals")]
+            rows.extend(inscription_rows(blockhash, &b.block.txdata));
+            #[cfg(feature = "runes")]
+            rows.extend(runestone_rows(blockhash, &b.block.txdata));
+            if let Some(feerates) = block_feerates(b, previous_txos_map, iconfig.network) {
+                rows.push(BlockRow::new_feerates(blockhash, &feerates).into_row());
             }
-            rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).into_row()); // mark block as "indexed"
+            let script_types = ScriptTypeStats::from_txs(b.block.txdata.iter());
+            rows.push(BlockRow::new_script_types(blockhash, &script_types).into_row());
+            rows.push(BlockRow::new_done(blockhash).into_row()); // mark block as "indexed"
             rows
         })
         .flatten()
         .collect()
 }
 
+// F{blockhash} -> {p10}{p50}{p90}, so that historical feerates don't need the blocks again
+fn block_feerates(
+    b: &BlockEntry,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    network: Network,
+) -> Option<FeeratePercentiles> {
+    let txs = b
+        .block
+        .txdata
+        .iter()
+        .filter(|tx| !tx.is_coin_base())
+        .filter_map(|tx| {
+            let fee = get_tx_fee(tx, previous_txos_map, network)?;
+            Some((fee, (tx.weight() as u64 + 3) / 4))
+        })
+        .collect();
+    FeeratePercentiles::from_txs(txs)
+}
+
+// The stats of the scripts used by the blocks, summed up to a single merge operand per script,
+// and their activity heights (with --script-activity). The stats of blocks that are later
+// reorged out are reverted by `Indexer::revert`, their activity heights aren't.
+fn script_stats(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    script_hashes: &ScriptHashes,
+    iconfig: &IndexerConfig,
+) -> (
+    HashMap<FullHash, ScriptStats>,
+    HashMap<FullHash, ScriptActivity>,
+) {
+    let mut stats: HashMap<FullHash, ScriptStats> = HashMap::new();
+    let mut activity: HashMap<FullHash, ScriptActivity> = HashMap::new();
+    for b in block_entries {
+        let height = b.entry.height() as u32;
+        for tx in &b.block.txdata {
+            for (scripthash, tx_stats) in ScriptStats::from_tx(
+                tx,
+                previous_txos_map,
+                iconfig.index_unspendables,
+                iconfig.skip_dust,
+                |script| script_hashes.get(script),
+            ) {
+                if iconfig.script_activity {
+                    activity
+                        .entry(scripthash)
+                        .or_default()
+                        .observe(height, tx_stats.funded_txo_count > 0);
+                }
+                stats.entry(scripthash).or_default().add(&tx_stats);
+            }
+        }
+    }
+    (stats, activity)
+}
+
+// Adds the outputs funded by the blocks to the UTXO accumulator, and tombstones the spent ones.
+// Outputs of blocks that are later reorged out stay in the accumulator, so queries have to
+// check that the funding transaction is still confirmed. Their spends are reverted by
+// `reverted_utxo_acc_rows`, and are checked as well.
+fn utxo_acc_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    script_hashes: &ScriptHashes,
+    skip_dust: u64,
+) -> Vec<DBRow> {
+    let mut rows = vec![];
+    for b in block_entries {
+        let height = b.entry.height() as u32;
+        for tx in &b.block.txdata {
+            let txid = tx.txid();
+            for (vout, txo) in tx.output.iter().enumerate() {
+                if !is_spendable(txo) || is_dust(&txo.value, skip_dust) {
+                    continue;
+                }
+                #[cfg(not(feature = "liquid"))]
+                let value = txo.value;
+                #[cfg(feature = "liquid")]
+                let value = txo.value.explicit().unwrap_or(0);
+
+                let outpoint = OutPoint::new(txid, vout as u32);
+                let scripthash = script_hashes.get(&txo.script_pubkey);
+                rows.push(UtxoAccRow::funding(scripthash, outpoint, height, value).into_row());
+            }
+            for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                let prev_txo = &previous_txos_map[&txin.previous_output];
+                if is_dust(&prev_txo.value, skip_dust) {
+                    continue;
+                }
+                let scripthash = script_hashes.get(&prev_txo.script_pubkey);
+                rows.push(
+                    UtxoAccRow::spending(scripthash, txin.previous_output, txid, height).into_row(),
+                );
+            }
+        }
+    }
+    rows
+}
+
+// Reverts the tombstones of the outputs spent by disconnected blocks
+fn reverted_utxo_acc_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    script_hashes: &ScriptHashes,
+    skip_dust: u64,
+) -> Vec<DBRow> {
+    block_entries
+        .iter()
+        .flat_map(|b| b.block.txdata.iter())
+        .flat_map(|tx| tx.input.iter().filter(|txin| has_prevout(txin)))
+        .filter_map(|txin| {
+            let prev_txo = &previous_txos_map[&txin.previous_output];
+            if is_dust(&prev_txo.value, skip_dust) {
+                return None;
+            }
+            let scripthash = script_hashes.get(&prev_txo.script_pubkey);
+            Some(UtxoAccRow::reverting(scripthash, txin.previous_output).into_row())
+        })
+        .collect()
+}
+
 // TODO: return an iterator?
 fn index_transaction(
     tx: &Transaction,
     confirmed_height: u32,
+    tx_position: u16,
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    script_hashes: &ScriptHashes,
     rows: &mut Vec<DBRow>,
     iconfig: &IndexerConfig,
 ) {
     // persist history index:
-    //      H{funding-scripthash}{funding-height}F{funding-txid:vout} → ""
-    //      H{funding-scripthash}{spending-height}S{spending-txid:vin}{funding-txid:vout} → ""
+    //      H{funding-scripthash}{funding-height}{tx-position}F{vout} → {funding-txid:vout}{value}
+    //      H{funding-scripthash}{spending-height}{tx-position}S{vin} → {spending-txid:vin}{funding-txid:vout}{value}
     // persist "edges" for fast is-this-TXO-spent check
     //      S{funding-txid:vout}{spending-txid:vin} → ""
+    // persist OP_RETURN payload prefixes (with index_op_return)
+    //      R{payload-prefix}{funding-height}{funding-txid:vout} → ""
+    // persist the spends of channel-like outputs (with index_channels)
+    //      L{funding-txid:vout}{spending-txid} → ""
+    // the outputs below --skip-dust are left out of the history (and so are their spends), but
+    // not out of the edges, so that they are still known to be spent
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
         if is_spendable(txo) || iconfig.index_unspendables {
-            let history = TxHistoryRow::new(
-                &txo.script_pubkey,
-                confirmed_height,
-                TxHistoryInfo::Funding(FundingInfo {
-                    txid,
-                    vout: txo_index as u16,
-                    value: txo.value,
-                }),
-            );
-            rows.push(history.into_row());
+            if !is_dust(&txo.value, iconfig.skip_dust) {
+                let history = TxHistoryRow::new(
+                    script_hashes.get(&txo.script_pubkey),
+                    confirmed_height,
+                    tx_position,
+                    TxHistoryInfo::Funding(FundingInfo {
+                        txid,
+                        vout: txo_index as u16,
+                        value: txo.value,
+                    }),
+                );
+                rows.push(history.into_row());
+            }
 
             if iconfig.address_search {
                 if let Some(row) = addr_search_row(&txo.script_pubkey, iconfig.network) {
                     rows.push(row);
                 }
             }
+
+            if iconfig.index_op_return && txo.script_pubkey.is_op_return() {
+                let row =
+                    OpReturnRow::new(&txo.script_pubkey, confirmed_height, txid, txo_index as u16);
+                rows.push(row.into_row());
+            }
         }
     }
     for (txi_index, txi) in tx.input.iter().enumerate() {
@@ -391,18 +1173,21 @@ fn index_transaction(
             .get(&txi.previous_output)
             .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output));
 
-        let history = TxHistoryRow::new(
-            &prev_txo.script_pubkey,
-            confirmed_height,
-            TxHistoryInfo::Spending(SpendingInfo {
-                txid,
-                vin: txi_index as u16,
-                prev_txid: full_hash(&txi.previous_output.txid[..]),
-                prev_vout: txi.previous_output.vout as u16,
-                value: prev_txo.value,
-            }),
-        );
-        rows.push(history.into_row());
+        if !is_dust(&prev_txo.value, iconfig.skip_dust) {
+            let history = TxHistoryRow::new(
+                script_hashes.get(&prev_txo.script_pubkey),
+                confirmed_height,
+                tx_position,
+                TxHistoryInfo::Spending(SpendingInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    prev_txid: full_hash(&txi.previous_output.txid[..]),
+                    prev_vout: txi.previous_output.vout as u16,
+                    value: prev_txo.value,
+                }),
+            );
+            rows.push(history.into_row());
+        }
 
         let edge = TxEdgeRow::new(
             full_hash(&txi.previous_output.txid[..]),
@@ -411,6 +1196,15 @@ fn index_transaction(
             txi_index as u16,
         );
         rows.push(edge.into_row());
+
+        if iconfig.index_channels && is_channel_funding_spend(txi, prev_txo) {
+            let row = ChannelCloseRow::new(
+                full_hash(&txi.previous_output.txid[..]),
+                txi.previous_output.vout as u16,
+                txid,
+            );
+            rows.push(row.into_row());
+        }
     }
 
     // Index issued assets & native asset pegins/pegouts/burns
@@ -418,6 +1212,7 @@ fn index_transaction(
     asset::index_confirmed_tx_assets(
         tx,
         confirmed_height,
+        tx_position,
         iconfig.network,
         iconfig.parent_network,
         rows,
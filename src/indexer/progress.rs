@@ -0,0 +1,176 @@
+// The progress of the index updates, for the operators watching a long initial sync: the blocks
+// left to add and to index, and the completion time estimated from the recent throughput.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    metrics::{FloatGauge, GaugeVec, MetricOpts, Metrics},
+    store::FetchFrom,
+};
+
+// the throughput is measured over the last batches processed within this window
+const RATE_WINDOW: Duration = Duration::from_secs(600);
+
+pub struct SyncProgress {
+    state: Mutex<State>,
+    remaining: GaugeVec,
+    rate: FloatGauge,
+    eta: FloatGauge,
+}
+
+struct State {
+    from: FetchFrom,
+    tip_height: Option<usize>,
+    to_add: usize,
+    added: usize,
+    to_index: usize,
+    indexed: usize,
+    // the blocks processed by each batch (of either stage), newest last
+    batches: VecDeque<(Instant, usize)>,
+    synced: bool,
+}
+
+impl SyncProgress {
+    pub fn new(from: FetchFrom, metrics: &Metrics) -> Self {
+        SyncProgress {
+            state: Mutex::new(State {
+                from,
+                tip_height: None,
+                to_add: 0,
+                added: 0,
+                to_index: 0,
+                indexed: 0,
+                batches: VecDeque::new(),
+                synced: false,
+            }),
+            remaining: metrics.gauge_vec(
+                MetricOpts::new(
+                    "sync_blocks_remaining",
+                    "# of blocks left to process by the index update, by stage",
+                ),
+                &["stage"],
+            ),
+            rate: metrics.float_gauge(MetricOpts::new(
+                "sync_blocks_per_second",
+                "Blocks processed per second by the index update, over the last 10 minutes",
+            )),
+            eta: metrics.float_gauge(MetricOpts::new(
+                "sync_eta_seconds",
+                "Estimated time left for the index update (in seconds)",
+            )),
+        }
+    }
+
+    /// An index update towards `tip_height` begins
+    pub fn start(&self, from: FetchFrom, tip_height: usize, to_add: usize, to_index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.from = from;
+        state.tip_height = Some(tip_height);
+        state.to_add = to_add;
+        state.added = 0;
+        state.to_index = to_index;
+        state.indexed = 0;
+        state.synced = false;
+        self.update_metrics(&mut state);
+    }
+
+    pub fn added(&self, blocks: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.added += blocks;
+        state.batches.push_back((Instant::now(), blocks));
+        self.update_metrics(&mut state);
+    }
+
+    pub fn indexed(&self, blocks: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.indexed += blocks;
+        state.batches.push_back((Instant::now(), blocks));
+        self.update_metrics(&mut state);
+    }
+
+    /// The index update reached the tip
+    pub fn done(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.synced = true;
+        state.added = state.to_add;
+        state.indexed = state.to_index;
+        self.update_metrics(&mut state);
+    }
+
+    /// The current stage and counts, as served at /internal/sync-status
+    pub fn status(&self) -> Value {
+        let mut state = self.state.lock().unwrap();
+        let (rate, eta) = state.estimate();
+        let stage = if state.synced {
+            "synced"
+        } else if state.added < state.to_add {
+            "adding"
+        } else {
+            "indexing"
+        };
+        json!({
+            "stage": stage,
+            "fetch_from": format!("{:?}", state.from).to_lowercase(),
+            "tip_height": state.tip_height,
+            "blocks_to_add": state.to_add,
+            "blocks_added": state.added,
+            "blocks_to_index": state.to_index,
+            "blocks_indexed": state.indexed,
+            "blocks_remaining": state.remaining(),
+            "blocks_per_second": rate,
+            "eta_seconds": eta.map(|eta| eta.round()),
+        })
+    }
+
+    fn update_metrics(&self, state: &mut State) {
+        self.remaining
+            .with_label_values(&["add"])
+            .set(state.to_add.saturating_sub(state.added) as f64);
+        self.remaining
+            .with_label_values(&["index"])
+            .set(state.to_index.saturating_sub(state.indexed) as f64);
+        let (rate, eta) = state.estimate();
+        self.rate.set(rate);
+        self.eta.set(eta.unwrap_or_default());
+    }
+}
+
+impl State {
+    // Each block is counted once when added and once when indexed
+    fn remaining(&self) -> usize {
+        self.to_add.saturating_sub(self.added) + self.to_index.saturating_sub(self.indexed)
+    }
+
+    // The recent throughput (in blocks per second) and the time left at that rate, unknown
+    // until a batch was processed. Both stages are averaged together, so the estimate is
+    // rough while the stage changes.
+    fn estimate(&mut self) -> (f64, Option<f64>) {
+        let now = Instant::now();
+        while let Some((time, _)) = self.batches.front() {
+            if now.duration_since(*time) <= RATE_WINDOW {
+                break;
+            }
+            self.batches.pop_front();
+        }
+        let (first, _) = match self.batches.front() {
+            Some(batch) => *batch,
+            None => return (0.0, None),
+        };
+        let blocks: usize = self.batches.iter().map(|(_, blocks)| blocks).sum();
+        // the first batch's own duration is unknown, so at least a second is assumed
+        let elapsed = now.duration_since(first).as_secs_f64().max(1.0);
+        let rate = blocks as f64 / elapsed;
+        let eta = if self.synced {
+            Some(0.0)
+        } else {
+            Some(self.remaining() as f64 / rate)
+        };
+        (rate, eta)
+    }
+}
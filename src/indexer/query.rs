@@ -1,18 +1,1694 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{store::Store, util::block::BlockId};
+use bitcoin::{consensus::deserialize, hashes::sha256d::Hash as Sha256dHash};
+use itertools::Itertools;
+use rayon::{prelude::*, ThreadPool};
+
+use super::{
+    fees::{FeeEstimator, MinFees},
+    from_utxo_cache,
+    mempool::{Mempool, MempoolChanges},
+};
+use crate::{
+    chain::{BlockHash, BlockHeader, Network, OutPoint, Transaction, TxOut, Txid},
+    config::{Config, Mode},
+    daemon::Daemon,
+    errors::*,
+    metrics::{HistogramOpts, HistogramTimer, HistogramVec, Metrics},
+    store::{
+        compute_script_hash, BlockRow, CacheAccessRow, CachedUtxoMap, ChainStats, ChannelCloseRow,
+        DBFlush, DBRow, FirstSeenRow, LabelRow, OpReturnRow, QueryLogRow, ReverseScanIterator,
+        ScanIterator, ScriptActivity, ScriptActivityRow, ScriptStats, ScriptStatsRow,
+        ScriptTypeStats, SpendingInput, Store, TxConfRow, TxEdgeRow, TxFeeRow, TxHistoryInfo,
+        TxHistoryRow, TxRow, Utxo, UtxoAccRow, UtxoCacheRow, UtxoMap, MIN_HISTORY_ITEMS_TO_CACHE,
+        OP_RETURN_PREFIX_LEN,
+    },
+    util::{
+        block::{BlockId, BlockMeta, HeaderList, HeaderSnapshot},
+        electrum_merkle::get_header_merkle_proof,
+        fees::FeeratePercentiles,
+        full_hash, request_id,
+        script::address_to_script,
+        thread_pool,
+        transaction::{get_tx_fee, has_prevout, is_dust, COINBASE_MATURITY},
+        unix_time, Bytes, FullHash,
+    },
+};
+
+#[cfg(not(feature = "liquid"))]
+use crate::store::{BlockAgeStats, RichList};
+#[cfg(feature = "liquid")]
+use crate::{
+    chain::AssetId,
+    elements::asset::{AssetRow, AssetStats},
+};
+
+#[cfg(feature = "ordinals")]
+use crate::{store::InscriptionRow, util::inscription::Inscription};
+#[cfg(feature = "runes")]
+use crate::{store::RunestoneRow, util::runestone::Runestone};
+
+const SLOW_QUERY: Duration = Duration::from_secs(1);
+
+/// The chain and the mempool together, so that the Electrum and REST servers combine the
+/// confirmed and unconfirmed data the same way
+pub struct Query {
+    chain: Arc<ChainQuery>,
+    mempool: Arc<RwLock<Mempool>>,
+    daemon: Arc<Daemon>,
+    fee_estimator: FeeEstimator,
+}
+
+impl Query {
+    pub fn new(
+        chain: Arc<ChainQuery>,
+        mempool: Arc<RwLock<Mempool>>,
+        daemon: Arc<Daemon>,
+        config: &Config,
+    ) -> Self {
+        Query {
+            chain,
+            mempool,
+            fee_estimator: FeeEstimator::new(Arc::clone(&daemon), &config.fee_estimate_targets),
+            daemon,
+        }
+    }
+
+    pub fn chain(&self) -> &ChainQuery {
+        &self.chain
+    }
+
+    pub fn mempool(&self) -> RwLockReadGuard<Mempool> {
+        self.mempool.read().unwrap()
+    }
+
+    pub fn daemon(&self) -> &Daemon {
+        &self.daemon
+    }
+
+    pub fn network(&self) -> Network {
+        self.chain.network()
+    }
+
+    pub fn update_mempool(&self) -> Result<MempoolChanges> {
+        let changes = self.mempool.write().unwrap().update(&self.daemon)?;
+        if let Err(e) = self.fee_estimator.update_mempool() {
+            warn!("failed updating the minimum fees: {}", e);
+        }
+        Ok(changes)
+    }
+
+    /// The confirmed txids of a script oldest first, followed by its unconfirmed ones.
+    /// Fails with `TooPopular` when it has more than `limit` transactions.
+    pub fn history_txids(
+        &self,
+        scripthash: &[u8],
+        limit: usize,
+    ) -> Result<Vec<(Txid, Option<BlockId>)>> {
+        let confirmed = self.chain.history_txids(scripthash, limit)?;
+        let unconfirmed = self.mempool().history_txids(scripthash);
+        ensure!(
+            confirmed.len() + unconfirmed.len() <= limit,
+            ErrorKind::TooPopular
+        );
+        Ok(confirmed
+            .into_iter()
+            .map(|(txid, blockid)| (txid, Some(blockid)))
+            .chain(unconfirmed.into_iter().map(|txid| (txid, None)))
+            .collect())
+    }
+
+    /// The confirmed and the unconfirmed stats of a script
+    pub fn stats(&self, scripthash: &[u8]) -> Result<(ScriptStats, ScriptStats)> {
+        let chain_stats = self.chain.stats(scripthash)?;
+        let mempool_stats = self.mempool().stats(scripthash);
+        Ok((chain_stats, mempool_stats))
+    }
+
+    /// The stats of many scripts, looked up in parallel. Each script is looked up once, however
+    /// many times it is listed.
+    pub fn stats_batch(
+        &self,
+        scripthashes: &[FullHash],
+    ) -> HashMap<FullHash, Result<(ScriptStats, ScriptStats)>> {
+        let unique: HashSet<&FullHash> = scripthashes.iter().collect();
+        let id = request_id::current();
+        unique
+            .into_par_iter()
+            .map(|scripthash| {
+                let stats = request_id::enter(id, || self.stats(scripthash));
+                (*scripthash, stats)
+            })
+            .collect()
+    }
+
+    /// The confirmed unspent outputs of a script that aren't spent by a mempool transaction
+    pub fn utxo(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
+        let utxos = self.chain.utxo(scripthash, limit)?;
+        let mempool = self.mempool();
+        Ok(utxos
+            .into_iter()
+            .filter(|utxo| !mempool.has_spend(&OutPoint::from(utxo)))
+            .collect())
+    }
+
+    /// A transaction and its confirming block, looked up in the index, then in the mempool,
+    /// then in bitcoind's mempool for the transactions that weren't synced yet
+    pub fn lookup_txn(&self, txid: &Txid) -> Result<Option<(Transaction, Option<BlockId>)>> {
+        if let Some(blockid) = self.chain.tx_confirming_block(txid) {
+            return Ok(self
+                .chain
+                .lookup_txn(txid, Some(&blockid.hash))?
+                .map(|tx| (tx, Some(blockid))));
+        }
+        Ok(self
+            .mempool()
+            .lookup_txn(txid)
+            .or_else(|| self.daemon.getmempooltx(txid).ok())
+            .map(|tx| (tx, None)))
+    }
+
+    pub fn update_fee_estimates(&self) -> Result<()> {
+        self.fee_estimator.update()
+    }
+
+    /// The fee rate (in sat/vB) for a confirmation within `target` blocks
+    pub fn estimate_fee(&self, target: u16) -> Option<f64> {
+        self.fee_estimator.estimate_fee(target)
+    }
+
+    pub fn estimate_fee_map(&self) -> HashMap<u16, f64> {
+        self.fee_estimator.estimate_fee_map()
+    }
+
+    /// The minimum fee rates (in sat/vB) for bitcoind to accept transactions
+    pub fn min_fees(&self) -> Option<MinFees> {
+        self.fee_estimator.min_fees()
+    }
+}
 
 pub struct ChainQuery {
     pub store: Arc<Store>,
+    daemon: Arc<Daemon>,
+    light_mode: bool,
+    network: Network,
+    blocklist: HashSet<FullHash>,
+    labels_file: Option<String>,
+    // skips the label lookups when there are none
+    has_labels: AtomicBool,
+    // the number of recently queried scripts logged for warming up their caches on restart
+    warm_cache_scripts: usize,
+    // the queries since the log was last persisted
+    query_log: Mutex<HashMap<FullHash, u32>>,
+    max_query_items: usize,
+    hide_dust: u64,
+    index_op_return: bool,
+    index_channels: bool,
+    utxo_accumulator: bool,
+    script_activity: bool,
+    rich_list_size: usize,
+    index_start_height: usize,
+    intern_scripts: bool,
+    lookup_pool: ThreadPool,
+    duration: HistogramVec,
 }
 
 impl ChainQuery {
+    pub fn new(store: Arc<Store>, daemon: Arc<Daemon>, config: &Config, metrics: &Metrics) -> Self {
+        let blocklist = config
+            .blocked_scripts
+            .as_ref()
+            .map_or_else(HashSet::new, |path| {
+                load_blocklist(path, config.network_type)
+                    .unwrap_or_else(|e| panic!("failed loading blocklist {}: {}", path, e))
+            });
+        if !blocklist.is_empty() {
+            info!(
+                "{} scripts are blocked from history queries",
+                blocklist.len()
+            );
+        }
+
+        // the label rows are written to the cache DB, which serve-only instances can't write
+        let labels_file = config.labels_file.clone().filter(|path| {
+            let writable = config.mode != Mode::ServeOnly;
+            if !writable {
+                warn!("not loading {} into the read-only index", path);
+            }
+            writable
+        });
+
+        // the query log is written to the cache DB too
+        let warm_cache_scripts = match config.mode {
+            Mode::ServeOnly if config.warm_cache_scripts > 0 => {
+                warn!("not logging the queries into the read-only index");
+                0
+            }
+            _ => config.warm_cache_scripts,
+        };
+
+        let chain = Self {
+            store,
+            daemon,
+            light_mode: config.light_mode,
+            network: config.network_type,
+            blocklist,
+            labels_file,
+            has_labels: AtomicBool::new(false),
+            warm_cache_scripts,
+            query_log: Mutex::new(HashMap::new()),
+            max_query_items: config.max_query_items,
+            hide_dust: config.hide_dust,
+            index_op_return: config.index_op_return,
+            index_channels: config.index_channels,
+            utxo_accumulator: config.utxo_accumulator,
+            script_activity: config.script_activity,
+            rich_list_size: config.rich_list_size,
+            index_start_height: config.index_start_height,
+            intern_scripts: config.intern_scripts,
+            // not pinned to the indexing CPUs, as it serves queries
+            lookup_pool: thread_pool("query-lookup-txo", config.lookup_threads, None),
+            duration: metrics.histogram_vec(
+                HistogramOpts::new("query_duration", "Index query duration (in seconds)"),
+                &["name"],
+            ),
+        };
+        if let Some(path) = &chain.labels_file {
+            chain
+                .load_labels()
+                .unwrap_or_else(|e| panic!("failed loading labels {}: {}", path, e));
+        }
+        let has_labels = chain
+            .store
+            .cache
+            .iter_scan(&LabelRow::filter())
+            .next()
+            .is_some();
+        chain.has_labels.store(has_labels, Ordering::Relaxed);
+        chain
+    }
+
+    /// Replace the label rows with the contents of the labels file, returning their count
+    pub fn load_labels(&self) -> Result<usize> {
+        let path = self.labels_file.as_ref().ok_or("no labels file to load")?;
+        let labels = load_labels(path, self.network)?;
+        let cache = &self.store.cache;
+        let old_keys: Vec<Bytes> = cache
+            .iter_scan(&LabelRow::filter())
+            .map(|row| row.key)
+            .collect();
+        cache.delete(old_keys);
+        cache.write(
+            labels
+                .iter()
+                .map(|(scripthash, label)| LabelRow::new(scripthash, label))
+                .collect(),
+            DBFlush::Enable,
+        );
+        self.has_labels.store(!labels.is_empty(), Ordering::Relaxed);
+        info!("{} labels loaded from {}", labels.len(), path);
+        Ok(labels.len())
+    }
+
+    /// The labels of the scripts that have one
+    pub fn labels(&self, scripthashes: &[FullHash]) -> Result<HashMap<FullHash, String>> {
+        if !self.has_labels.load(Ordering::Relaxed) {
+            return Ok(HashMap::new());
+        }
+        let keys: Vec<Bytes> = scripthashes.iter().map(|sh| LabelRow::key(sh)).collect();
+        self.store
+            .cache
+            .multi_get(&keys)
+            .into_iter()
+            .zip(scripthashes)
+            .filter_map(|(value, scripthash)| value.map(|value| (*scripthash, value)))
+            .map(|(scripthash, value)| Ok((scripthash, LabelRow::label_from_value(value)?)))
+            .collect()
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn daemon(&self) -> &Daemon {
+        &self.daemon
+    }
+
     pub fn blockid_by_height(&self, height: usize) -> Option<BlockId> {
+        self.store.blockid_by_height(height)
+    }
+
+    pub fn blockid_by_hash(&self, hash: &BlockHash) -> Option<BlockId> {
         self.store
-            .indexed_headers
-            .read()
-            .unwrap()
+            .headers()
+            .header_by_blockhash(hash)
+            .map(BlockId::from)
+    }
+
+    /// The indexed header chain, for the callers reading several headers at once
+    pub fn headers(&self) -> HeaderSnapshot {
+        self.store.headers()
+    }
+
+    /// The height of the best block, `None` until the genesis block is indexed
+    pub fn best_height(&self) -> Option<usize> {
+        self.store.height_index_len().checked_sub(1)
+    }
+
+    /// The median time past of the best block
+    pub fn best_mtp(&self) -> Option<u32> {
+        let headers = self.store.headers();
+        Some(headers.get_mtp(headers.len().checked_sub(1)?))
+    }
+
+    pub fn header_by_height(&self, height: usize) -> Option<BlockHeader> {
+        self.store
+            .headers()
             .header_by_height(height)
+            .map(|entry| entry.header().clone())
+    }
+
+    /// The headers at heights `start..start + count`, truncated to the current tip
+    /// The headers from height `start` on, fewer than `count` past the best block
+    pub fn headers_range(&self, start: usize, count: usize) -> Result<Vec<BlockHeader>> {
+        let end = start
+            .checked_add(count)
+            .chain_err(|| format!("header range {}+{} out of range", start, count))?;
+        Ok(self
+            .store
+            .headers()
+            .iter_range(start..end)
+            .map(|entry| entry.header().clone())
+            .collect())
+    }
+
+    /// Proof that the header at `height` is part of the chain up to the `cp_height` checkpoint
+    pub fn header_merkle_proof(
+        &self,
+        height: usize,
+        cp_height: usize,
+    ) -> Result<(Vec<Sha256dHash>, Sha256dHash)> {
+        // a snapshot rather than a lock, as the proof reads up to the whole chain
+        let headers = self.store.headers();
+        get_header_merkle_proof(&headers, height, cp_height)
+    }
+
+    /// Refuse queries for blocked scripts, before any history rows are read
+    pub fn ensure_allowed(&self, scripthash: &[u8]) -> Result<()> {
+        if self.blocklist.contains(&full_hash(scripthash)) {
+            bail!(ErrorKind::Blocked)
+        }
+        Ok(())
+    }
+
+    /// Refuse queries about the history of blocks below the index start height
+    pub fn ensure_indexed(&self, height: usize) -> Result<()> {
+        if height < self.index_start_height {
+            bail!(ErrorKind::NotIndexed(format!(
+                "the history is indexed from height {} on",
+                self.index_start_height
+            )))
+        }
+        Ok(())
+    }
+
+    /// Refuse the queries that add up the whole history of scripts (balances, UTXOs and
+    /// counters), which a partial index can't answer
+    pub fn ensure_full_history(&self) -> Result<()> {
+        if self.index_start_height > 0 {
+            bail!(ErrorKind::NotIndexed(format!(
+                "the history is indexed from height {} on, script balances and UTXOs are not available",
+                self.index_start_height
+            )))
+        }
+        Ok(())
+    }
+
+    pub fn history_iter_scan(&self, code: u8, hash: &[u8], start_height: usize) -> ScanIterator {
+        self.store.history.iter_scan_from(
+            &TxHistoryRow::filter(code, hash),
+            &TxHistoryRow::prefix_height(code, hash, start_height as u32),
+        )
+    }
+
+    pub fn history_iter_scan_reverse(&self, code: u8, hash: &[u8]) -> ReverseScanIterator {
+        self.store.history.iter_scan_reverse(
+            &TxHistoryRow::filter(code, hash),
+            &TxHistoryRow::prefix_end(code, hash),
+        )
+    }
+
+    /// The confirmed transactions of a script, most recent first, continuing after
+    /// `last_seen_txid` when given.
+    pub fn history(
+        &self,
+        scripthash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        self.ensure_allowed(scripthash)?;
+        let _timer = self.start_timer("history");
+        self.history_page(b'H', scripthash, last_seen_txid, limit)
+    }
+
+    // The history of a script or of an asset, most recent first
+    fn history_page(
+        &self,
+        code: u8,
+        hash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        if let Some(blockid) = last_seen_txid.and_then(|txid| self.tx_confirming_block(txid)) {
+            self.ensure_indexed(blockid.height)?;
+        }
+
+        let rows = self
+            .history_iter_scan_reverse(code, hash)
+            .map(TxHistoryRow::from_row);
+        let txs_conf = itertools::process_results(rows, |rows| {
+            rows.filter(|history| !self.is_hidden(history))
+                .map(|history| history.get_txid())
+                .unique()
+                .skip_while(|txid| {
+                    // skip until we reach the last_seen_txid
+                    last_seen_txid.map_or(false, |last_seen_txid| last_seen_txid != txid)
+                })
+                .skip(match last_seen_txid {
+                    Some(_) => 1, // skip the last_seen_txid itself
+                    None => 0,
+                })
+                .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+                .take(limit.min(self.max_query_items))
+                .collect::<Vec<(Txid, BlockId)>>()
+        })?;
+
+        Ok(self
+            .lookup_txns(&txs_conf)?
+            .into_iter()
+            .zip(txs_conf)
+            .map(|(tx, (_, blockid))| (tx, blockid))
+            .collect())
+    }
+
+    /// The confirmed txids of a script, oldest first. Fails with `TooPopular` when the script
+    /// has more than `limit` transactions, or more than the global `max_query_items`.
+    pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Result<Vec<(Txid, BlockId)>> {
+        self.ensure_allowed(scripthash)?;
+        let _timer = self.start_timer("history_txids");
+
+        let limit = limit.min(self.max_query_items);
+        // to avoid silently truncating history entries, ask for one extra more than the limit and fail if it exists
+        let rows = self
+            .history_iter_scan(b'H', scripthash, 0)
+            .map(TxHistoryRow::from_row);
+        let txids: Vec<(Txid, BlockId)> = itertools::process_results(rows, |rows| {
+            rows.filter(|history| !self.is_hidden(history))
+                .map(|history| history.get_txid())
+                .unique()
+                .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+                .take(limit + 1)
+                .collect()
+        })?;
+        ensure!(txids.len() <= limit, ErrorKind::TooPopular);
+
+        Ok(txids)
+    }
+
+    // Whether a history entry funds or spends an output below --hide-dust
+    fn is_hidden(&self, history: &TxHistoryRow) -> bool {
+        history
+            .txinfo
+            .value()
+            .map_or(false, |value| is_dust(value, self.hide_dust))
+    }
+
+    /// The confirmed OP_RETURN outputs whose payload starts with `prefix`, as (txid, vout)
+    pub fn op_return_outputs(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<(Txid, u32, BlockId)>> {
+        ensure!(self.index_op_return, "OP_RETURN index is disabled");
+        ensure!(
+            !prefix.is_empty() && prefix.len() <= OP_RETURN_PREFIX_LEN,
+            "OP_RETURN prefix must be 1 to {} bytes",
+            OP_RETURN_PREFIX_LEN
+        );
+        let _timer = self.start_timer("op_return_outputs");
+
+        let rows = self
+            .store
+            .history
+            .iter_scan(&OpReturnRow::filter(prefix))
+            .map(OpReturnRow::from_row);
+        itertools::process_results(rows, |rows| {
+            rows.filter_map(|row| {
+                let txid = row.get_txid();
+                self.tx_confirming_block(&txid)
+                    .map(|b| (txid, row.key.vout as u32, b))
+            })
+            .take(limit.min(self.max_query_items))
+            .collect()
+        })
+    }
+
+    /// The confirmed transactions of an asset (its issuances and burns, or the pegs of the
+    /// native asset), most recent first, continuing after `last_seen_txid` when given.
+    #[cfg(feature = "liquid")]
+    pub fn asset_history(
+        &self,
+        asset_id: &AssetId,
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        let _timer = self.start_timer("asset_history");
+        self.history_page(b'I', &asset_id.into_inner()[..], last_seen_txid, limit)
+    }
+
+    /// The initial issuance of an asset, when confirmed
+    #[cfg(feature = "liquid")]
+    pub fn asset_issuance(&self, asset_id: &AssetId) -> Result<Option<(AssetRow, BlockId)>> {
+        let _timer = self.start_timer("asset_issuance");
+        let row = match self.store.history.get(&AssetRow::key(asset_id)) {
+            Some(value) => AssetRow::from_value(&value)?,
+            None => return Ok(None),
+        };
+        // the issuance may have been reorged out
+        Ok(self
+            .tx_confirming_block(&row.issuance_txid())
+            .map(|blockid| (row, blockid)))
+    }
+
+    /// The confirmed activity of an asset, added up from its whole history. Fails with
+    /// `TooPopular` for the assets with more than `max_query_items` transactions.
+    #[cfg(feature = "liquid")]
+    pub fn asset_stats(&self, asset_id: &AssetId) -> Result<AssetStats> {
+        self.ensure_full_history()?;
+        let _timer = self.start_timer("asset_stats");
+
+        let mut stats = AssetStats::new(asset_id, self.network);
+        let rows = self
+            .history_iter_scan(b'I', &asset_id.into_inner()[..], 0)
+            .map(TxHistoryRow::from_row);
+        itertools::process_results(rows, |rows| {
+            // the entries of a transaction are next to each other
+            let mut tx_count = 0;
+            for (txid, entries) in &rows.group_by(|row| row.get_txid()) {
+                if self.tx_confirming_block(&txid).is_none() {
+                    continue;
+                }
+                tx_count += 1;
+                ensure!(tx_count <= self.max_query_items, ErrorKind::TooPopular);
+                let txinfos: Vec<TxHistoryInfo> = entries.map(|row| row.txinfo).collect();
+                stats.apply(&txinfos);
+            }
+            Ok(())
+        })??;
+        Ok(stats)
+    }
+
+    /// The height of the first block that may spend a coinbase output that is still immature,
+    /// or `None` for the outputs that can be spent by the next block
+    pub fn coinbase_maturity_height(&self, utxo: &Utxo) -> Result<Option<usize>> {
+        let blockid = match utxo.confirmed {
+            Some(ref blockid) => blockid,
+            None => return Ok(None),
+        };
+        let maturity_height = blockid.height + COINBASE_MATURITY;
+        if maturity_height <= self.best_height().map_or(0, |height| height + 1) {
+            return Ok(None);
+        }
+        // only the recent outputs are looked up, the coinbase transaction being the block's first
+        let txids = self.get_block_txids(&blockid.hash)?.unwrap_or_default();
+        Ok((txids.first() == Some(&utxo.txid)).then_some(maturity_height))
+    }
+
+    /// The confirmed transaction closing a channel-like output, along with its block
+    pub fn channel_close(&self, outpoint: &OutPoint) -> Result<Option<(Txid, BlockId)>> {
+        ensure!(self.index_channels, "channel index is disabled");
+        let _timer = self.start_timer("channel_close");
+
+        let rows = self
+            .store
+            .history
+            .iter_scan(&ChannelCloseRow::filter(outpoint))
+            .map(ChannelCloseRow::from_row);
+        itertools::process_results(rows, |mut rows| {
+            rows.find_map(|row| {
+                let txid = row.get_closing_txid();
+                self.tx_confirming_block(&txid).map(|b| (txid, b))
+            })
+        })
+    }
+
+    pub fn tx_confirming_block(&self, txid: &Txid) -> Option<BlockId> {
+        let _timer = self.start_timer("tx_confirming_block");
+        let headers = self.store.headers();
+        self.store
+            .txstore
+            .iter_scan(&TxConfRow::filter(&txid[..]))
+            .filter_map(|row| skip_invalid(TxConfRow::from_row(row)))
+            // header_by_blockhash only returns blocks that are part of the best chain,
+            // or None for orphaned blocks.
+            .filter_map(|conf| {
+                headers.header_by_blockhash(&deserialize(&conf.key.blockhash).unwrap())
+            })
+            .next()
             .map(BlockId::from)
     }
+
+    /// The counters of the whole chain, as of the indexed tip
+    pub fn chain_stats(&self) -> Result<ChainStats> {
+        self.store.chain_stats()
+    }
+
+    /// The outputs created by the indexed blocks, counted by script type
+    pub fn script_type_stats(&self) -> Result<ScriptTypeStats> {
+        self.store.script_type_stats()
+    }
+
+    /// The ages of the outputs spent by a block, unless it was indexed without them
+    #[cfg(not(feature = "liquid"))]
+    pub fn block_age_stats(&self, blockhash: &BlockHash) -> Result<Option<BlockAgeStats>> {
+        self.store
+            .history
+            .get(&BlockRow::age_stats_key(full_hash(&blockhash[..])))
+            .map(|value| bincode::deserialize(&value).chain_err(|| "invalid block age stats"))
+            .transpose()
+    }
+
+    pub fn block_script_types(&self, blockhash: &BlockHash) -> Result<Option<ScriptTypeStats>> {
+        self.store
+            .history
+            .get(&BlockRow::script_types_key(full_hash(&blockhash[..])))
+            .map(|val| ScriptTypeStats::decode(&val))
+            .transpose()
+    }
+
+    /// The number of inscriptions revealed by a block, unless it was indexed without them
+    #[cfg(feature = "ordinals")]
+    pub fn block_inscription_count(&self, blockhash: &BlockHash) -> Result<Option<u32>> {
+        self.store
+            .history
+            .get(&BlockRow::inscription_count_key(full_hash(&blockhash[..])))
+            .map(|value| bincode::deserialize(&value).chain_err(|| "invalid inscription count"))
+            .transpose()
+    }
+
+    /// The inscriptions revealed by a transaction confirmed in `blockhash`, unless the block was
+    /// indexed without them
+    #[cfg(feature = "ordinals")]
+    pub fn tx_inscriptions(
+        &self,
+        txid: &Txid,
+        blockhash: &BlockHash,
+    ) -> Result<Option<Vec<Inscription>>> {
+        if self.block_inscription_count(blockhash)?.is_none() {
+            return Ok(None);
+        }
+        self.store
+            .history
+            .get(&InscriptionRow::key(&txid[..]))
+            .map_or(Ok(vec![]), |value| InscriptionRow::value_from_row(&value))
+            .map(Some)
+    }
+
+    /// The txids of the transactions carrying a runestone in a block, unless it was indexed
+    /// without them
+    #[cfg(feature = "runes")]
+    pub fn block_runestone_txids(&self, blockhash: &BlockHash) -> Result<Option<Vec<Txid>>> {
+        self.store
+            .history
+            .get(&BlockRow::runestone_txids_key(full_hash(&blockhash[..])))
+            .map(|value| bincode::deserialize(&value).chain_err(|| "invalid runestone txids"))
+            .transpose()
+    }
+
+    /// The runestone (if any) of a transaction confirmed in `blockhash`, unless the block was
+    /// indexed without them
+    #[cfg(feature = "runes")]
+    pub fn tx_runestone(
+        &self,
+        txid: &Txid,
+        blockhash: &BlockHash,
+    ) -> Result<Option<Option<Runestone>>> {
+        if self.block_runestone_txids(blockhash)?.is_none() {
+            return Ok(None);
+        }
+        self.store
+            .history
+            .get(&RunestoneRow::key(&txid[..]))
+            .map(|value| RunestoneRow::value_from_row(&value))
+            .transpose()
+            .map(Some)
+    }
+
+    pub fn store_sizes(&self) -> Vec<(&'static str, u64)> {
+        self.store.sizes()
+    }
+
+    /// The tx count and sizes of a block, when it was added
+    pub fn get_block_meta(&self, blockhash: &BlockHash) -> Result<Option<BlockMeta>> {
+        self.store
+            .txstore
+            .get(&BlockRow::meta_key(full_hash(&blockhash[..])))
+            .map(|val| {
+                bincode::deserialize(&val).chain_err(|| {
+                    ErrorKind::DbInconsistency(format!("invalid meta of block {}", blockhash))
+                })
+            })
+            .transpose()
+    }
+
+    /// The feerate percentiles of a block's transactions, `None` when none of them (besides the
+    /// coinbase) is of known fee
+    pub fn block_feerates(&self, blockhash: &BlockHash) -> Result<Option<FeeratePercentiles>> {
+        self.store
+            .history
+            .get(&BlockRow::feerates_key(full_hash(&blockhash[..])))
+            .map(|val| {
+                bincode::deserialize(&val).chain_err(|| {
+                    ErrorKind::DbInconsistency(format!("invalid feerates of block {}", blockhash))
+                })
+            })
+            .transpose()
+    }
+
+    /// The feerates of up to `count` blocks, going down from the one at `start_height`. Blocks
+    /// without feerates (with no transactions but the coinbase) are skipped.
+    pub fn feerate_history(
+        &self,
+        start_height: usize,
+        count: usize,
+    ) -> Result<Vec<(BlockId, FeeratePercentiles)>> {
+        let _timer = self.start_timer("feerate_history");
+        let start_height = match self.best_height() {
+            Some(best_height) => start_height.min(best_height),
+            None => return Ok(vec![]),
+        };
+        let mut history = vec![];
+        for height in (0..count).filter_map(|depth| start_height.checked_sub(depth)) {
+            let blockid = match self.blockid_by_height(height) {
+                Some(blockid) => blockid,
+                None => continue,
+            };
+            if let Some(feerates) = self.block_feerates(&blockid.hash)? {
+                history.push((blockid, feerates));
+            }
+        }
+        Ok(history)
+    }
+
+    /// The time a confirmed transaction was first seen in the mempool, if it was
+    pub fn tx_first_seen(&self, txid: &Txid) -> Result<Option<u32>> {
+        self.store
+            .txstore
+            .get(&FirstSeenRow::key(txid))
+            .map(|value| FirstSeenRow::value_from_row(&value))
+            .transpose()
+    }
+
+    /// The confirmed unspent outputs of a script, read from the UTXO accumulator when it is
+    /// enabled, or from the history since its cached UTXO set. Fails with `TooPopular` above
+    /// `limit`.
+    pub fn utxo(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
+        self.ensure_allowed(scripthash)?;
+        let _timer = self.start_timer("utxo");
+        self.log_query(scripthash);
+        let limit = limit.min(self.max_query_items);
+        self.make_utxos(self.utxo_map(scripthash, limit)?)
+    }
+
+    fn utxo_map(&self, scripthash: &[u8], limit: usize) -> Result<UtxoMap> {
+        self.ensure_full_history()?;
+        if self.utxo_accumulator {
+            self.accumulated_utxo(scripthash, limit)
+        } else {
+            self.cached_utxo(scripthash, limit)
+        }
+    }
+
+    fn log_query(&self, scripthash: &[u8]) {
+        if self.warm_cache_scripts > 0 {
+            let mut query_log = self.query_log.lock().unwrap();
+            query_log.insert(full_hash(scripthash), unix_time());
+        }
+    }
+
+    /// Writes the scripts queried since the last call to the query log, and trims it to the
+    /// most recently queried ones
+    pub fn persist_query_log(&self) {
+        let queries: Vec<DBRow> = self
+            .query_log
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(scripthash, time)| QueryLogRow::new(&scripthash, time))
+            .collect();
+        if queries.is_empty() {
+            return;
+        }
+        let cache = &self.store.cache;
+        cache.write(queries, DBFlush::Enable);
+
+        let mut logged: Vec<(FullHash, u32)> = cache
+            .iter_scan(&QueryLogRow::filter())
+            .map(QueryLogRow::from_row)
+            .collect();
+        if logged.len() > self.warm_cache_scripts {
+            logged.sort_unstable_by_key(|(_, time)| *time);
+            let excess = logged.len() - self.warm_cache_scripts;
+            cache.delete(
+                logged[..excess]
+                    .iter()
+                    .map(|(scripthash, _)| QueryLogRow::key(scripthash))
+                    .collect(),
+            );
+        }
+    }
+
+    /// Reads the stats and UTXOs of the logged scripts, most recently queried first and at most
+    /// `rate` per second, so that the returning clients find their caches warm. Returns the
+    /// number of scripts warmed up.
+    pub fn warm_cache(&self, rate: u32) -> usize {
+        let mut logged: Vec<(FullHash, u32)> = self
+            .store
+            .cache
+            .iter_scan(&QueryLogRow::filter())
+            .map(QueryLogRow::from_row)
+            .collect();
+        logged.sort_unstable_by_key(|(_, time)| std::cmp::Reverse(*time));
+        let interval = Duration::from_secs(1) / rate.max(1);
+
+        let mut warmed = 0;
+        for (scripthash, _) in logged.into_iter().take(self.warm_cache_scripts) {
+            if self.blocklist.contains(&scripthash) {
+                continue;
+            }
+            let _timer = self.start_timer("warm_cache");
+            let _ = self.store.history.get(&ScriptStatsRow::key(&scripthash));
+            // the popular scripts are refused by the queries as well, so aren't worth warming
+            match self.utxo_map(&scripthash, self.max_query_items) {
+                Ok(_) => warmed += 1,
+                Err(e) => trace!("not warming {}: {}", hex::encode(scripthash), e),
+            }
+            thread::sleep(interval);
+        }
+        warmed
+    }
+
+    fn accumulated_utxo(&self, scripthash: &[u8], limit: usize) -> Result<UtxoMap> {
+        let mut utxos = UtxoMap::new();
+        let rows = self
+            .store
+            .history
+            .iter_scan(&UtxoAccRow::filter(scripthash))
+            .map(UtxoAccRow::from_row);
+        for row in rows {
+            let row = row?;
+            if row.value.funded.is_none() {
+                continue;
+            }
+            // the spends of blocks that were reorged out are ignored, in case their revert failed
+            if let Some(spender) = row.value.spender() {
+                if self.tx_confirming_block(&spender).is_some() {
+                    continue;
+                }
+            }
+            let (_height, value) = row.value.funded.unwrap();
+            #[cfg(feature = "liquid")]
+            let value = crate::chain::confidential::Value::Explicit(value);
+            // outputs of blocks that were reorged out are skipped
+            if let Some(blockid) = self.tx_confirming_block(&row.outpoint.txid) {
+                utxos.insert(row.outpoint, (blockid, value));
+                ensure!(utxos.len() <= limit, ErrorKind::TooPopular);
+            }
+        }
+        Ok(utxos)
+    }
+
+    // The UTXO set from the cache, updated with the history of the blocks since it was cached.
+    // The cache is written back when it was updated, or once the history is long enough.
+    fn cached_utxo(&self, scripthash: &[u8], limit: usize) -> Result<UtxoMap> {
+        let cache = self.utxo_cache(scripthash)?;
+        let had_cache = cache.is_some();
+
+        let (utxos, lastblock, processed_items) = match cache {
+            Some((utxos, height)) => self.utxo_delta(scripthash, utxos, height + 1, None, limit)?,
+            None => self.utxo_delta(scripthash, UtxoMap::new(), 0, None, limit)?,
+        };
+
+        let mut rows = vec![];
+        if let Some(lastblock) = lastblock {
+            if had_cache || processed_items > MIN_HISTORY_ITEMS_TO_CACHE {
+                rows.push(UtxoCacheRow::new(scripthash, &utxos, &lastblock).into_row());
+            }
+        }
+        if had_cache || !rows.is_empty() {
+            rows.push(CacheAccessRow::new(
+                &UtxoCacheRow::key(scripthash),
+                unix_time(),
+            ));
+        }
+        if !rows.is_empty() {
+            self.store.cache.write(rows, DBFlush::Enable);
+        }
+        Ok(utxos)
+    }
+
+    // The cached UTXO set of a script and the height of the block it was cached at. A cache for
+    // a block that was since reorged out is recomputed from scratch.
+    fn utxo_cache(&self, scripthash: &[u8]) -> Result<Option<(UtxoMap, usize)>> {
+        let headers = self.store.headers();
+        Ok(self
+            .store
+            .cache
+            .get(&UtxoCacheRow::key(scripthash))
+            .map(|c| {
+                bincode::deserialize::<(CachedUtxoMap, BlockHash)>(&c).chain_err(|| {
+                    ErrorKind::DbInconsistency(format!("invalid utxo cache {}", hex::encode(&c)))
+                })
+            })
+            .transpose()?
+            .and_then(|cache| valid_utxo_cache(cache, &headers))
+            .map(|(utxos_cache, height)| (from_utxo_cache(utxos_cache, self), height)))
+    }
+
+    /// The confirmed unspent outputs of a script as of the block at `height`, replaying its
+    /// history up to that block (from the cached UTXO set when it is older). The outputs spent
+    /// by the later blocks are included, with their confirming block. Fails with `TooPopular`
+    /// above `limit`.
+    pub fn utxo_at_height(
+        &self,
+        scripthash: &[u8],
+        height: usize,
+        limit: usize,
+    ) -> Result<Vec<Utxo>> {
+        self.ensure_allowed(scripthash)?;
+        self.ensure_full_history()?;
+        self.ensure_indexed(height)?;
+        let _timer = self.start_timer("utxo_at_height");
+        let limit = limit.min(self.max_query_items);
+
+        let (utxos, start_height) = match self.utxo_cache(scripthash)? {
+            Some((utxos, cached_height)) if cached_height <= height => (utxos, cached_height + 1),
+            _ => (UtxoMap::new(), 0),
+        };
+        let (utxos, _, _) =
+            self.utxo_delta(scripthash, utxos, start_height, Some(height), limit)?;
+        self.make_utxos(utxos)
+    }
+
+    /// Evicts the cache rows unused for longer than `ttl`, then the least recently used ones
+    /// until the cache DB fits in `size_budget` bytes (0 for unbounded). Returns the number of
+    /// evicted rows.
+    pub fn prune_cache(&self, size_budget: u64, ttl: Option<Duration>) -> usize {
+        let _timer = self.start_timer("prune_cache");
+        let cache = &self.store.cache;
+        let now = unix_time();
+        let mut excess = match size_budget {
+            0 => 0,
+            budget => cache.size().saturating_sub(budget),
+        };
+
+        let mut accesses: Vec<(Bytes, u32)> = cache
+            .iter_scan(&CacheAccessRow::filter())
+            .map(CacheAccessRow::from_row)
+            .collect();
+        accesses.sort_unstable_by_key(|(_, time)| *time);
+
+        let mut evicted = vec![];
+        for (key, time) in accesses {
+            let expired = ttl.map_or(false, |ttl| {
+                u64::from(now.saturating_sub(time)) > ttl.as_secs()
+            });
+            // the following rows were used more recently, so aren't expired either
+            if !expired && excess == 0 {
+                break;
+            }
+            // the on-disk size is compressed, so this may free less than needed. The next
+            // pruning evicts more if so.
+            let row_size = cache.get(&key).map_or(0, |value| key.len() + value.len());
+            excess = excess.saturating_sub(row_size as u64);
+            evicted.push(CacheAccessRow::key(&key));
+            evicted.push(key);
+        }
+
+        let count = evicted.len() / 2;
+        if count > 0 {
+            cache.delete(evicted);
+            // reclaim the space of the deleted rows right away
+            cache.full_compaction();
+            info!("evicted {} rows from the cache", count);
+        }
+        count
+    }
+
+    /// Applies the confirmed history of a script from `start_height` (up to `end_height`, when
+    /// given) to `init_utxos`, returning the updated UTXO set, the last block that was applied and
+    /// the number of history entries. Fails with `TooPopular` if the set exceeds `limit` at any
+    /// point.
+    pub fn utxo_delta(
+        &self,
+        scripthash: &[u8],
+        init_utxos: UtxoMap,
+        start_height: usize,
+        end_height: Option<usize>,
+        limit: usize,
+    ) -> Result<(UtxoMap, Option<BlockHash>, usize)> {
+        let _timer = self.start_timer("utxo_delta");
+        let history_iter = self
+            .history_iter_scan(b'H', scripthash, start_height)
+            .map(TxHistoryRow::from_row);
+        apply_history(
+            init_utxos,
+            history_iter,
+            |txid| self.tx_confirming_block(txid),
+            end_height,
+            limit,
+        )
+    }
+
+    fn make_utxos(&self, utxos: UtxoMap) -> Result<Vec<Utxo>> {
+        #[cfg(feature = "liquid")]
+        let txos = self.lookup_txos(&utxos.keys().cloned().collect())?;
+
+        Ok(utxos
+            .into_iter()
+            .filter(|(_, (_, value))| !is_dust(value, self.hide_dust))
+            .map(|(outpoint, (blockid, value))| {
+                #[cfg(feature = "liquid")]
+                let txo = &txos[&outpoint];
+                Utxo {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout,
+                    confirmed: Some(blockid),
+                    value,
+                    #[cfg(feature = "liquid")]
+                    asset: txo.asset,
+                    #[cfg(feature = "liquid")]
+                    nonce: txo.nonce,
+                    #[cfg(feature = "liquid")]
+                    witness: txo.witness.clone(),
+                }
+            })
+            .collect())
+    }
+
+    /// The largest script balances maintained by the indexer, without the blocked scripts
+    #[cfg(not(feature = "liquid"))]
+    pub fn rich_list(&self, limit: usize) -> Result<RichList> {
+        self.ensure_full_history()?;
+        if self.rich_list_size == 0 {
+            bail!(ErrorKind::NotIndexed(
+                "the rich list is not maintained (see --rich-list-size)".to_string()
+            ))
+        }
+        let mut rich_list = self.store.rich_list()?;
+        rich_list
+            .entries
+            .retain(|entry| !self.blocklist.contains(&entry.scripthash));
+        rich_list.entries.truncate(limit);
+        Ok(rich_list)
+    }
+
+    /// The confirmed stats of a script, read from its counters
+    pub fn stats(&self, scripthash: &[u8]) -> Result<ScriptStats> {
+        self.ensure_allowed(scripthash)?;
+        self.ensure_full_history()?;
+        let _timer = self.start_timer("stats");
+        self.log_query(scripthash);
+        self.store
+            .history
+            .get(&ScriptStatsRow::key(scripthash))
+            .map_or_else(
+                || Ok(ScriptStats::default()),
+                |value| ScriptStatsRow::value_from_row(&value),
+            )
+    }
+
+    /// The activity heights of a script, when they are indexed (with --script-activity)
+    pub fn activity(&self, scripthash: &[u8]) -> Result<Option<ScriptActivity>> {
+        if !self.script_activity {
+            return Ok(None);
+        }
+        self.ensure_full_history()?;
+        self.store
+            .history
+            .get(&ScriptActivityRow::key(scripthash))
+            .map_or_else(
+                || Ok(ScriptActivity::default()),
+                |value| ScriptActivityRow::value_from_row(&value),
+            )
+            .map(Some)
+    }
+
+    /// The previous outputs found in the txstore, falling back to the full previous
+    /// transactions (fetched from bitcoind in light mode) when a row is missing
+    pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> Result<HashMap<OutPoint, TxOut>> {
+        let _timer = self.start_timer("lookup_txos");
+        let mut txos = super::lookup_txos(
+            &self.lookup_pool,
+            &self.store.txstore,
+            outpoints,
+            true,
+            self.intern_scripts,
+        )?;
+
+        for outpoint in outpoints {
+            if txos.contains_key(outpoint) {
+                continue;
+            }
+            let txo = self
+                .lookup_txn(&outpoint.txid, None)?
+                .and_then(|tx| tx.output.into_iter().nth(outpoint.vout as usize));
+            if let Some(txo) = txo {
+                txos.insert(*outpoint, txo);
+            }
+        }
+        Ok(txos)
+    }
+
+    /// The fee of a confirmed transaction, cached once computed. The previous outputs are
+    /// looked up unless given.
+    pub fn tx_fee(
+        &self,
+        tx: &Transaction,
+        prevouts: Option<&HashMap<OutPoint, TxOut>>,
+    ) -> Result<Option<u64>> {
+        let _timer = self.start_timer("tx_fee");
+        let txid = tx.txid();
+        if let Some(value) = self.store.cache.get(&TxFeeRow::key(&txid)) {
+            return TxFeeRow::value_from_row(&value).map(Some);
+        }
+
+        let fee = match prevouts {
+            Some(prevouts) => get_tx_fee(tx, prevouts, self.network),
+            None => {
+                let outpoints = tx
+                    .input
+                    .iter()
+                    .filter(|txin| has_prevout(txin))
+                    .map(|txin| txin.previous_output)
+                    .collect();
+                get_tx_fee(tx, &self.lookup_txos(&outpoints)?, self.network)
+            }
+        };
+        if let Some(fee) = fee {
+            self.store
+                .cache
+                .write(vec![TxFeeRow::new(&txid, fee).into_row()], DBFlush::Disable);
+        }
+        Ok(fee)
+    }
+
+    pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
+        let _timer = self.start_timer("lookup_spend");
+        self.store
+            .history
+            .iter_scan(&TxEdgeRow::filter(outpoint))
+            .filter_map(|row| skip_invalid(TxEdgeRow::from_row(row)))
+            .find_map(|edge| {
+                let txid: Txid = deserialize(&edge.key.spending_txid).unwrap();
+                self.tx_confirming_block(&txid).map(|b| SpendingInput {
+                    txid,
+                    vin: edge.key.spending_vin as u32,
+                    confirmed: Some(b),
+                })
+            })
+    }
+
+    /// The transactions of known blocks, read in a single batch
+    pub fn lookup_txns(&self, txids: &[(Txid, BlockId)]) -> Result<Vec<Transaction>> {
+        let _timer = self.start_timer("lookup_txns");
+        if self.light_mode {
+            let txids: Vec<(&Txid, &BlockHash)> = txids
+                .iter()
+                .map(|(txid, blockid)| (txid, &blockid.hash))
+                .collect();
+            return self.daemon.gettransactions_in_blocks(&txids);
+        }
+
+        let keys: Vec<Bytes> = txids
+            .iter()
+            .map(|(txid, _)| TxRow::key(&txid[..]))
+            .collect();
+        let id = request_id::current();
+        self.store
+            .txstore
+            .multi_get(&keys)
+            .into_par_iter()
+            .zip(txids)
+            .map(|(rawtx, (txid, _))| {
+                request_id::enter(id, || {
+                    let rawtx = rawtx.chain_err(|| format!("missing tx {}", txid))?;
+                    parse_txn(txid, &rawtx)
+                })
+            })
+            .collect()
+    }
+
+    /// The txids of a block, in block order
+    pub fn get_block_txids(&self, blockhash: &BlockHash) -> Result<Option<Vec<Txid>>> {
+        let _timer = self.start_timer("get_block_txids");
+
+        if self.light_mode {
+            // the txids aren't indexed in light mode, ask bitcoind for them
+            let mut blockval = match self.daemon.getblock_raw(blockhash, 1) {
+                Ok(blockval) => blockval,
+                Err(_) => return Ok(None),
+            };
+            serde_json::from_value(blockval["tx"].take())
+                .chain_err(|| format!("invalid txids of block {} from bitcoind", blockhash))
+                .map(Some)
+        } else {
+            self.store
+                .txstore
+                .get(&BlockRow::txids_key(full_hash(&blockhash[..])))
+                .map(|val| {
+                    bincode::deserialize(&val).chain_err(|| {
+                        ErrorKind::DbInconsistency(format!("invalid txids of block {}", blockhash))
+                    })
+                })
+                .transpose()
+        }
+    }
+
+    pub fn lookup_txn(
+        &self,
+        txid: &Txid,
+        blockhash: Option<&BlockHash>,
+    ) -> Result<Option<Transaction>> {
+        let _timer = self.start_timer("lookup_txn");
+        self.lookup_raw_txn(txid, blockhash)?
+            .map(|rawtx| parse_txn(txid, &rawtx))
+            .transpose()
+    }
+
+    /// In light mode, the transaction is fetched from bitcoind along with the hash of its
+    /// block (given or looked up in the `C` rows), so that bitcoind doesn't need -txindex
+    pub fn lookup_raw_txn(
+        &self,
+        txid: &Txid,
+        blockhash: Option<&BlockHash>,
+    ) -> Result<Option<Bytes>> {
+        let _timer = self.start_timer("lookup_raw_txn");
+
+        if self.light_mode {
+            let queried_blockhash =
+                blockhash.map_or_else(|| self.tx_confirming_block(txid).map(|b| b.hash), |_| None);
+            let blockhash = match blockhash.or(queried_blockhash.as_ref()) {
+                Some(blockhash) => blockhash,
+                None => return Ok(None),
+            };
+            let txval = match self.daemon.gettransaction_raw(txid, blockhash, false) {
+                Ok(txval) => txval,
+                Err(_) => return Ok(None),
+            };
+            let txhex = txval
+                .as_str()
+                .chain_err(|| format!("non-string tx {} from bitcoind", txid))?;
+            hex::decode(txhex)
+                .chain_err(|| format!("non-hex tx {} from bitcoind", txid))
+                .map(Some)
+        } else {
+            Ok(self.store.txstore.get(&TxRow::key(&txid[..])))
+        }
+    }
+
+    fn start_timer(&self, name: &'static str) -> QueryTimer {
+        QueryTimer {
+            name,
+            started: Instant::now(),
+            _timer: self.duration.with_label_values(&[name]).start_timer(),
+        }
+    }
+}
+
+// A raw transaction read from the txstore (or bitcoind, in light mode), checked to be the one
+// that was asked for
+fn parse_txn(txid: &Txid, rawtx: &[u8]) -> Result<Transaction> {
+    let txn: Transaction = deserialize(rawtx)
+        .chain_err(|| ErrorKind::DbInconsistency(format!("invalid tx {}", txid)))?;
+    ensure!(
+        txn.txid() == *txid,
+        ErrorKind::DbInconsistency(format!("tx {} found as {}", txid, txn.txid()))
+    );
+    Ok(txn)
+}
+
+// Observes the query duration when dropped, and logs the queries slower than SLOW_QUERY
+struct QueryTimer {
+    name: &'static str,
+    started: Instant,
+    _timer: HistogramTimer,
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed >= SLOW_QUERY {
+            warn!(
+                "slow query name={} duration_ms={} request_id={}",
+                self.name,
+                elapsed.as_millis(),
+                request_id::current().map_or_else(|| "none".to_string(), |id| id.to_string())
+            );
+        }
+    }
+}
+
+// Read a list of blocked scripts, one address or (Electrum-style) hex scripthash per line.
+// Empty lines and lines starting with '#' are ignored.
+fn load_blocklist(path: &str, network: Network) -> Result<HashSet<FullHash>> {
+    let contents = fs::read_to_string(path).chain_err(|| "cannot read blocklist")?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| parse_script(line, network))
+        .collect()
+}
+
+// Read the labels of scripts, as an address or scripthash followed by the label on each line.
+// Empty lines and lines starting with '#' are ignored.
+fn load_labels(path: &str, network: Network) -> Result<Vec<(FullHash, String)>> {
+    let contents = fs::read_to_string(path).chain_err(|| "cannot read labels")?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (script, label) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("missing label: {}", line))?;
+            Ok((parse_script(script, network)?, label.trim().to_string()))
+        })
+        .collect()
+}
+
+// An address or (Electrum-style) hex scripthash
+fn parse_script(line: &str, network: Network) -> Result<FullHash> {
+    if line.len() == 64 {
+        if let Ok(mut hash) = hex::decode(line) {
+            // Electrum scripthashes are displayed in reversed byte order
+            hash.reverse();
+            return Ok(full_hash(&hash));
+        }
+    }
+
+    Ok(compute_script_hash(&address_to_script(line, network)?))
+}
+
+// The cached UTXO set and the height of the block it was computed at, `None` when that block
+// was since reorged out
+fn valid_utxo_cache(
+    (utxos_cache, blockhash): (CachedUtxoMap, BlockHash),
+    headers: &HeaderList,
+) -> Option<(CachedUtxoMap, usize)> {
+    headers
+        .header_by_blockhash(&blockhash)
+        .map(|entry| (utxos_cache, entry.height()))
+}
+
+// Applies the history rows (ordered by height) to `utxos`, see `ChainQuery::utxo_delta`.
+// `confirming_block` returns the best chain block of a transaction.
+fn apply_history(
+    mut utxos: UtxoMap,
+    history_iter: impl Iterator<Item = Result<TxHistoryRow>>,
+    confirming_block: impl Fn(&Txid) -> Option<BlockId>,
+    end_height: Option<usize>,
+    limit: usize,
+) -> Result<(UtxoMap, Option<BlockHash>, usize)> {
+    let mut processed_items = 0;
+    let mut lastblock = None;
+
+    for history in history_iter {
+        let history = history?;
+        if end_height.map_or(false, |end| history.key.confirmed_height as usize > end) {
+            break;
+        }
+        // the entries of blocks that were reorged out are kept in the index, skip them (even
+        // when their transaction was confirmed again by another block)
+        let blockid = match confirming_block(&history.get_txid())
+            .filter(|b| b.height == history.key.confirmed_height as usize)
+        {
+            Some(blockid) => blockid,
+            None => continue,
+        };
+        processed_items += 1;
+        lastblock = Some(blockid.hash);
+
+        match history.txinfo {
+            TxHistoryInfo::Funding(ref info) => {
+                utxos.insert(history.get_funded_outpoint(), (blockid, info.value));
+            }
+            TxHistoryInfo::Spending(_) => {
+                utxos.remove(&history.get_funded_outpoint());
+            }
+            #[cfg(feature = "liquid")]
+            _ => (),
+        }
+
+        // abort if the utxo set size exceeds the limit at any point in time
+        ensure!(utxos.len() <= limit, ErrorKind::TooPopular);
+    }
+
+    Ok((utxos, lastblock, processed_items))
+}
+
+// The lookups that can't fail report and skip the rows that don't parse
+fn skip_invalid<T>(row: Result<T>) -> Option<T> {
+    row.map_err(|e| warn!("skipping row: {}", e)).ok()
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use bitcoin::{hashes::Hash, TxMerkleNode};
+
+    use super::*;
+    use crate::store::fixtures::{funding, spending};
+
+    const SCRIPTHASH: FullHash = [9; 32];
+
+    fn header(prev_blockhash: BlockHash, nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::default(),
+            time: 1_600_000_000 + nonce,
+            bits: 0x207fffff,
+            nonce,
+        }
+    }
+
+    // A chain of `len` blocks
+    fn chain(len: u32) -> HeaderList {
+        let mut headers = HeaderList::default();
+        let mut prev_blockhash = BlockHash::default();
+        let new_headers: Vec<BlockHeader> = (0..len)
+            .map(|nonce| {
+                let header = header(prev_blockhash, nonce);
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect();
+        headers.apply(headers.order(new_headers));
+        headers
+    }
+
+    // Replaces the blocks from `height` with another branch of the same length
+    fn reorg(headers: &mut HeaderList, height: usize) {
+        let len = headers.len();
+        let mut prev_blockhash = *headers.header_by_height(height - 1).unwrap().hash();
+        let new_headers: Vec<BlockHeader> = (height..len)
+            .map(|h| {
+                let header = header(prev_blockhash, 1000 + h as u32);
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect();
+        let new_headers = headers.order(new_headers);
+        headers.apply(new_headers);
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_inner([n; 32])
+    }
+
+    fn hash(height: usize, headers: &HeaderList) -> BlockHash {
+        *headers.header_by_height(height).unwrap().hash()
+    }
+
+    fn row(height: usize, txinfo: TxHistoryInfo) -> TxHistoryRow {
+        TxHistoryRow::new(SCRIPTHASH, height as u32, 0, txinfo)
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint {
+            txid: txid(n),
+            vout: 0,
+        }
+    }
+
+    // Replays `rows` with the transactions confirmed by the given blocks, like the TxConf rows
+    fn replay(
+        utxos: UtxoMap,
+        rows: Vec<TxHistoryRow>,
+        confirmations: &HashMap<Txid, Vec<BlockHash>>,
+        headers: &HeaderList,
+        end_height: Option<usize>,
+    ) -> Result<(UtxoMap, Option<BlockHash>, usize)> {
+        apply_history(
+            utxos,
+            rows.into_iter().map(Ok),
+            |txid| {
+                confirmations
+                    .get(txid)?
+                    .iter()
+                    .find_map(|hash| headers.header_by_blockhash(hash))
+                    .map(BlockId::from)
+            },
+            end_height,
+            100,
+        )
+    }
+
+    #[test]
+    fn delta_on_cached_utxos() {
+        let headers = chain(5);
+        let confirmations: HashMap<Txid, Vec<BlockHash>> = [(1, 1), (2, 2), (3, 3), (4, 4)]
+            .iter()
+            .map(|&(n, height)| (txid(n), vec![hash(height, &headers)]))
+            .collect();
+        let rows = || {
+            vec![
+                row(1, funding(1, 0, 1000)),
+                row(2, funding(2, 0, 2000)),
+                row(3, spending(3, 0, 1, 0, 0)),
+                row(4, funding(4, 0, 4000)),
+            ]
+        };
+
+        // the cache as of block 2
+        let (cached, lastblock, items) =
+            replay(UtxoMap::new(), rows(), &confirmations, &headers, Some(2)).unwrap();
+        assert_eq!(lastblock, Some(hash(2, &headers)));
+        assert_eq!(items, 2);
+        assert_eq!(cached.len(), 2);
+
+        let later_rows = rows().into_iter().skip(2).collect();
+        let (utxos, lastblock, items) =
+            replay(cached, later_rows, &confirmations, &headers, None).unwrap();
+        assert_eq!(lastblock, Some(hash(4, &headers)));
+        assert_eq!(items, 2);
+        let (full, _, _) = replay(UtxoMap::new(), rows(), &confirmations, &headers, None).unwrap();
+        assert_eq!(
+            utxos.keys().collect::<HashSet<_>>(),
+            full.keys().collect::<HashSet<_>>()
+        );
+        assert!(utxos.contains_key(&outpoint(2)) && utxos.contains_key(&outpoint(4)));
+        assert_eq!(utxos[&outpoint(4)].0.height, 4);
+        assert_eq!(utxos[&outpoint(4)].1, 4000);
+    }
+
+    #[test]
+    fn reorged_cache_is_recomputed() {
+        let mut headers = chain(5);
+        let orphaned = hash(3, &headers);
+        let cached: CachedUtxoMap = [((txid(1), 0), (1, 1000)), ((txid(2), 0), (3, 2000))]
+            .iter()
+            .cloned()
+            .collect();
+        let cache = (cached, orphaned);
+        assert_eq!(
+            valid_utxo_cache(cache.clone(), &headers).map(|(_, height)| height),
+            Some(3)
+        );
+
+        reorg(&mut headers, 3);
+        assert!(valid_utxo_cache(cache, &headers).is_none());
+        let cache = (CachedUtxoMap::new(), hash(3, &headers));
+        assert_eq!(
+            valid_utxo_cache(cache, &headers).map(|(_, height)| height),
+            Some(3)
+        );
+
+        // without a valid cache, the whole history is replayed
+        let confirmations: HashMap<Txid, Vec<BlockHash>> = vec![
+            (txid(1), vec![hash(1, &headers)]),
+            (txid(2), vec![orphaned]),
+        ]
+        .into_iter()
+        .collect();
+        let rows = vec![row(1, funding(1, 0, 1000)), row(3, funding(2, 0, 2000))];
+        let (utxos, lastblock, _) =
+            replay(UtxoMap::new(), rows, &confirmations, &headers, None).unwrap();
+        assert_eq!(lastblock, Some(hash(1, &headers)));
+        assert_eq!(utxos.keys().collect::<Vec<_>>(), vec![&outpoint(1)]);
+    }
+
+    #[test]
+    fn orphaned_rows_are_skipped() {
+        let mut headers = chain(5);
+        let orphaned = hash(3, &headers);
+        reorg(&mut headers, 3);
+        let confirmations: HashMap<Txid, Vec<BlockHash>> = vec![
+            (txid(1), vec![hash(1, &headers)]),
+            // only confirmed by the orphaned block
+            (txid(2), vec![orphaned]),
+            // confirmed again by the new branch, one block later
+            (txid(3), vec![orphaned, hash(4, &headers)]),
+        ]
+        .into_iter()
+        .collect();
+        let rows = vec![
+            row(1, funding(1, 0, 1000)),
+            row(3, spending(2, 0, 1, 0, 0)),
+            row(3, funding(3, 0, 3000)),
+            row(4, funding(3, 0, 3000)),
+        ];
+
+        let (utxos, lastblock, items) =
+            replay(UtxoMap::new(), rows, &confirmations, &headers, None).unwrap();
+        assert_eq!(items, 2);
+        assert_eq!(lastblock, Some(hash(4, &headers)));
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos[&outpoint(1)].0.height, 1);
+        assert_eq!(utxos[&outpoint(3)].0.hash, hash(4, &headers));
+    }
+
+    #[test]
+    fn delta_over_limit() {
+        let headers = chain(3);
+        let confirmations: HashMap<Txid, Vec<BlockHash>> = (1..=2)
+            .map(|n| (txid(n), vec![hash(n as usize, &headers)]))
+            .collect();
+        let rows: Vec<TxHistoryRow> = (1..=2)
+            .map(|n| row(n as usize, funding(n, 0, 1000)))
+            .collect();
+        let result = apply_history(
+            UtxoMap::new(),
+            rows.into_iter().map(Ok),
+            |txid| {
+                confirmations
+                    .get(txid)
+                    .and_then(|hashes| headers.header_by_blockhash(&hashes[0]))
+                    .map(BlockId::from)
+            },
+            None,
+            1,
+        );
+        assert!(matches!(result, Err(Error(ErrorKind::TooPopular, _))));
+    }
 }
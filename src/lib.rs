@@ -36,3 +36,8 @@ pub mod util;
 
 #[cfg(feature = "liquid")]
 pub mod elements;
+
+#[cfg(all(feature = "ordinals", feature = "liquid"))]
+compile_error!("the ordinals feature is not supported on liquid");
+#[cfg(all(feature = "runes", feature = "liquid"))]
+compile_error!("the runes feature is not supported on liquid");
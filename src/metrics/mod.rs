@@ -2,20 +2,38 @@ pub mod stats;
 
 pub use stats::*;
 
-use std::{io, net::SocketAddr, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{self, SocketAddr},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
 
 use prometheus::{self, Encoder};
+use serde_json::Value;
 
 pub use prometheus::{
-    GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter as Counter,
-    IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
+    Gauge as FloatGauge, GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec,
+    IntCounter as Counter, IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
+};
+
+use crate::util::{
+    remove_stale_socket, set_socket_mode, spawn_thread,
+    systemd::{self, ListenSocket},
 };
 
-use crate::util::spawn_thread;
+// a json document served next to the metrics, built on every request
+type Document = Box<dyn Fn() -> Value + Send + Sync>;
+type Documents = Arc<RwLock<HashMap<String, Document>>>;
 
 pub struct Metrics {
     reg: prometheus::Registry,
     addr: SocketAddr,
+    documents: Documents,
 }
 
 impl Metrics {
@@ -23,9 +41,19 @@ impl Metrics {
         Self {
             reg: prometheus::Registry::new(),
             addr,
+            documents: Arc::default(),
         }
     }
 
+    /// Serves the json document returned by `document` at `path`, the other paths serving the
+    /// metrics. It can be added after the server is started.
+    pub fn document(&self, path: &str, document: impl Fn() -> Value + Send + Sync + 'static) {
+        self.documents
+            .write()
+            .unwrap()
+            .insert(path.to_string(), Box::new(document));
+    }
+
     pub fn counter(&self, opts: prometheus::Opts) -> Counter {
         let c = Counter::with_opts(opts).unwrap();
         self.reg.register(Box::new(c.clone())).unwrap();
@@ -45,6 +73,12 @@ impl Metrics {
         g
     }
 
+    pub fn float_gauge(&self, opts: prometheus::Opts) -> FloatGauge {
+        let g = FloatGauge::with_opts(opts).unwrap();
+        self.reg.register(Box::new(g.clone())).unwrap();
+        g
+    }
+
     pub fn gauge_vec(&self, opts: prometheus::Opts, labels: &[&str]) -> GaugeVec {
         let g = GaugeVec::new(opts, labels).unwrap();
         self.reg.register(Box::new(g.clone())).unwrap();
@@ -63,19 +97,58 @@ impl Metrics {
         h
     }
 
-    pub fn start(&self) {
-        let server = tiny_http::Server::http(self.addr)
-            .unwrap_or_else(|_| panic!("failed to start monitoring HTTP server at {}", self.addr));
+    /// Serves the metrics on `addr`, or on the unix socket at `socket_file` when given. The
+    /// socket passed by systemd socket activation takes precedence over both.
+    pub fn start(&self, socket_file: Option<&Path>, socket_mode: Option<u32>) {
+        let socket = systemd::take_socket("monitoring").unwrap_or_else(|| match socket_file {
+            None => ListenSocket::Tcp(net::TcpListener::bind(self.addr).unwrap_or_else(|e| {
+                panic!(
+                    "failed to start monitoring HTTP server at {}: {}",
+                    self.addr, e
+                )
+            })),
+            Some(path) => {
+                remove_stale_socket(path);
+                let listener = UnixListener::bind(path).unwrap_or_else(|e| {
+                    panic!(
+                        "failed to start monitoring HTTP server at {}: {}",
+                        path.display(),
+                        e
+                    )
+                });
+                set_socket_mode(path, socket_mode);
+                ListenSocket::Unix(listener)
+            }
+        });
 
         start_process_exporter(self);
 
         let reg = self.reg.clone();
-
-        spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
-                error!("http error: {}", e);
+        let documents = Arc::clone(&self.documents);
+
+        match socket {
+            ListenSocket::Tcp(listener) => {
+                let server = tiny_http::Server::from_listener(listener, None)
+                    .expect("failed to start monitoring HTTP server");
+                spawn_thread("metrics", move || loop {
+                    if let Err(e) = handle_request(&reg, &documents, server.recv()) {
+                        error!("http error: {}", e);
+                    }
+                });
             }
-        });
+            // tiny_http can't serve unix sockets
+            ListenSocket::Unix(listener) => {
+                spawn_thread("metrics", move || {
+                    for stream in listener.incoming() {
+                        if let Err(e) =
+                            stream.and_then(|stream| handle_unix_request(&reg, &documents, stream))
+                        {
+                            error!("http error: {}", e);
+                        }
+                    }
+                });
+            }
+        }
     }
 }
 
@@ -104,16 +177,66 @@ fn start_process_exporter(metrics: &Metrics) {
 
 fn handle_request(
     reg: &prometheus::Registry,
+    documents: &Documents,
     request: io::Result<tiny_http::Request>,
 ) -> io::Result<()> {
     let request = request?;
-    let mut buffer = vec![];
+    let (content_type, buffer) = response_body(reg, documents, request.url());
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("invalid Content-Type header");
+    let response = tiny_http::Response::from_data(buffer).with_header(header);
+
+    request.respond(response)
+}
+
+// Replies to a request once its header is read
+fn handle_unix_request(
+    reg: &prometheus::Registry,
+    documents: &Documents,
+    stream: UnixStream,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(&stream);
+    // e.g. "GET /metrics HTTP/1.1"
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
 
-    prometheus::TextEncoder::new()
-        .encode(&reg.gather(), &mut buffer)
-        .unwrap();
+    let url = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (content_type, buffer) = response_body(reg, documents, url);
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        buffer.len()
+    )?;
+    stream.write_all(&buffer)
+}
 
-    let response = tiny_http::Response::from_data(buffer);
+// The document at the url's path, or the metrics
+fn response_body(
+    reg: &prometheus::Registry,
+    documents: &Documents,
+    url: &str,
+) -> (String, Vec<u8>) {
+    let path = url.split('?').next().unwrap_or_default();
+    if let Some(document) = documents.read().unwrap().get(path) {
+        return (
+            "application/json".to_string(),
+            document().to_string().into_bytes(),
+        );
+    }
 
-    request.respond(response)
+    let mut buffer = vec![];
+    let encoder = prometheus::TextEncoder::new();
+    encoder.encode(&reg.gather(), &mut buffer).unwrap();
+    (encoder.format_type().to_string(), buffer)
 }
@@ -0,0 +1,195 @@
+// The optional API keys of the REST server. Each key gets its own quota (a request rate and
+// a response size), while the requests without a key share the anonymous quota, so that the
+// partners of a semi-public deployment can be given higher limits than the anonymous traffic.
+
+use std::{collections::HashMap, fs, sync::Mutex, time::Instant};
+
+use hyper::{body::HttpBody, header::HeaderValue, Body, Response, StatusCode};
+
+use crate::{
+    config::{parse_api_key, ApiKey, Config, Quota},
+    errors::{self, ResultExt},
+    metrics::{CounterVec, MetricOpts, Metrics},
+};
+
+use super::validate::HttpError;
+
+pub(super) const API_KEY_HEADER: &str = "X-API-Key";
+
+const ANONYMOUS: &str = "anonymous";
+
+pub(super) struct ApiKeys {
+    clients: HashMap<String, Client>,
+    anonymous: Client,
+    requests: CounterVec,
+    rejected: CounterVec,
+    response_bytes: CounterVec,
+}
+
+/// The holders of an API key, or the anonymous clients
+pub(super) struct Client {
+    name: String,
+    /// Allowed to use the /admin endpoints
+    pub(super) admin: bool,
+    quota: Quota,
+    bucket: Mutex<Bucket>,
+}
+
+// Refilled at the quota's rate, up to a second worth of requests
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ApiKeys {
+    pub(super) fn new(config: &Config, metrics: &Metrics) -> errors::Result<Self> {
+        let mut keys = config.api_keys.clone();
+        if let Some(path) = &config.api_keys_file {
+            keys.extend(load_api_keys(path).chain_err(|| format!("cannot load {}", path))?);
+        }
+        let mut clients = HashMap::new();
+        for ApiKey { name, key, quota } in keys {
+            ensure!(
+                !clients.contains_key(&key),
+                "API key of {} is given twice",
+                name
+            );
+            let admin = config.admin_api_keys.contains(&name);
+            clients.insert(key, Client::new(name, quota, admin));
+        }
+        for name in &config.admin_api_keys {
+            ensure!(
+                clients.values().any(|client| &client.name == name),
+                "no API key named {} for --admin-api-key",
+                name
+            );
+        }
+        if !clients.is_empty() {
+            info!("{} REST API keys loaded", clients.len());
+        }
+
+        Ok(ApiKeys {
+            clients,
+            anonymous: Client::new(ANONYMOUS.to_string(), config.anonymous_quota, false),
+            requests: metrics.counter_vec(
+                MetricOpts::new(
+                    "rest_client_requests",
+                    "# of REST requests, by API key name",
+                ),
+                &["client"],
+            ),
+            rejected: metrics.counter_vec(
+                MetricOpts::new(
+                    "rest_client_rejected",
+                    "# of REST requests refused by the quota of their API key",
+                ),
+                &["client", "reason"],
+            ),
+            response_bytes: metrics.counter_vec(
+                MetricOpts::new(
+                    "rest_client_response_bytes",
+                    "Size of the REST responses (before compression), by API key name",
+                ),
+                &["client"],
+            ),
+        })
+    }
+
+    /// The client sending a request with this `X-API-Key` header, if its quota allows one more
+    pub(super) fn admit(&self, key: Option<&HeaderValue>) -> Result<&Client, HttpError> {
+        let client = match key {
+            None => &self.anonymous,
+            Some(key) => key
+                .to_str()
+                .ok()
+                .and_then(|key| self.clients.get(key))
+                .ok_or_else(|| HttpError(StatusCode::UNAUTHORIZED, "Invalid API key".into()))?,
+        };
+        self.requests.with_label_values(&[&client.name]).inc();
+        if !client.take_token() {
+            self.rejected
+                .with_label_values(&[&client.name, "rate"])
+                .inc();
+            return Err(HttpError(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "rate limit of {} requests per second exceeded",
+                    client.quota.rate.unwrap_or_default()
+                ),
+            ));
+        }
+        Ok(client)
+    }
+
+    /// Refuses the responses larger than the client's quota
+    pub(super) fn check_response(
+        &self,
+        client: &Client,
+        resp: Response<Body>,
+    ) -> Result<Response<Body>, HttpError> {
+        // the responses are built in memory, so their size is known
+        let size = resp.body().size_hint().exact().unwrap_or_default() as usize;
+        self.response_bytes
+            .with_label_values(&[&client.name])
+            .inc_by(size as u64);
+        match client.quota.max_response_size {
+            Some(max) if size > max => {
+                self.rejected
+                    .with_label_values(&[&client.name, "response_size"])
+                    .inc();
+                Err(HttpError(
+                    StatusCode::FORBIDDEN,
+                    format!("response of {} bytes over the limit of {} bytes", size, max),
+                ))
+            }
+            _ => Ok(resp),
+        }
+    }
+}
+
+impl Client {
+    fn new(name: String, quota: Quota, admin: bool) -> Self {
+        let tokens = quota.rate.unwrap_or_default() as f64;
+        Client {
+            name,
+            admin,
+            quota,
+            bucket: Mutex::new(Bucket {
+                tokens,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn take_token(&self) -> bool {
+        let rate = match self.quota.rate {
+            Some(rate) => rate as f64,
+            None => return true,
+        };
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+// One API key per line, in the `--api-key` format.
+// Empty lines and lines starting with '#' are ignored.
+fn load_api_keys(path: &str) -> errors::Result<Vec<ApiKey>> {
+    let contents = fs::read_to_string(path).chain_err(|| "cannot read API keys")?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .enumerate()
+        .map(|(index, line)| {
+            parse_api_key(line).ok_or_else(|| format!("invalid API key #{}", index + 1).into())
+        })
+        .collect()
+}
@@ -0,0 +1,190 @@
+use std::io::{self, Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    /// The encoding to use for an `Accept-Encoding` request header: the one with the highest
+    /// quality value, preferring brotli, then gzip, then deflate on ties.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let items: Vec<(String, f32)> = accept_encoding
+            .split(',')
+            .map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+                (name, quality.unwrap_or(0.0))
+            })
+            .collect();
+        // "*" only stands for the encodings not listed, even those listed as not acceptable
+        let listed: Vec<Encoding> = items
+            .iter()
+            .filter_map(|(name, _)| Encoding::from_name(name))
+            .collect();
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for (name, quality) in items {
+            if quality <= 0.0 {
+                continue; // "q=0" means not acceptable
+            }
+            let candidates: Vec<Encoding> = if name == "*" {
+                [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+                    .iter()
+                    .copied()
+                    .filter(|encoding| !listed.contains(encoding))
+                    .collect()
+            } else {
+                Encoding::from_name(&name).into_iter().collect()
+            };
+            for encoding in candidates {
+                let better = match best {
+                    None => true,
+                    Some((best_encoding, best_quality)) => {
+                        quality > best_quality
+                            || (quality == best_quality && encoding.rank() < best_encoding.rank())
+                    }
+                };
+                if better {
+                    best = Some((encoding, quality));
+                }
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            Encoding::Brotli => 0,
+            Encoding::Gzip => 1,
+            Encoding::Deflate => 2,
+        }
+    }
+
+    /// Compresses `data` at `level` (1 to 9, the brotli quality is the same)
+    pub fn compress(self, data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER_SIZE,
+                    level,
+                    BROTLI_WINDOW_BITS,
+                );
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // HTTP's "deflate" is the zlib format (RFC 9110), not raw deflate
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+
+    #[test]
+    fn negotiate_quality() {
+        assert_eq!(Encoding::negotiate(""), None);
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("X-GZIP"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate("br;q=0.5, gzip;q=0.8, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(Encoding::negotiate("br;q=0, gzip;q=0"), None);
+        assert_eq!(Encoding::negotiate("gzip;q=bogus"), None);
+    }
+
+    #[test]
+    fn negotiate_wildcard() {
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("*;q=0"), None);
+        // RFC 9110 §12.5.3: "*" doesn't match the encodings listed as not acceptable
+        assert_eq!(Encoding::negotiate("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("*, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate("br;q=0, gzip;q=0, *;q=0.1"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            Encoding::negotiate("gzip;q=0.5, *;q=0.9"),
+            Some(Encoding::Brotli)
+        );
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        let data = b"{\"txid\":\"00\"}".repeat(100);
+
+        let gzip = Encoding::Gzip.compress(&data, 6).unwrap();
+        let mut decoded = vec![];
+        GzDecoder::new(&gzip[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        let zlib = Encoding::Deflate.compress(&data, 6).unwrap();
+        let mut decoded = vec![];
+        ZlibDecoder::new(&zlib[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let br = Encoding::Brotli.compress(&data, 6).unwrap();
+        let mut decoded = vec![];
+        brotli::Decompressor::new(&br[..], BROTLI_BUFFER_SIZE)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        assert!(br.len() < data.len());
+    }
+}
@@ -1 +1,1773 @@
+mod auth;
+mod compress;
+mod openapi;
+mod shadow;
+mod tx;
+mod validate;
 
+pub use tx::{TransactionStatus, TransactionValue, TxInValue, TxOutValue};
+
+use auth::{ApiKeys, API_KEY_HEADER};
+use compress::Encoding;
+use shadow::ShadowCompare;
+use validate::{
+    body_limit, check_max, parse_blockhash, parse_height, parse_hex, parse_outpoint, parse_param,
+    parse_scripthash, parse_txid, read_body, HttpError, QueryParams,
+};
+
+use std::{collections::HashMap, io, iter, sync::Arc, thread, time::Duration};
+
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, ETAG, IF_NONE_MATCH, VARY,
+    },
+    server::Builder,
+    service::{make_service_fn, service_fn},
+    Body, Method, Response, Server, StatusCode,
+};
+use hyperlocal::SocketIncoming;
+use serde::Serialize;
+use tokio::{net::UnixListener, sync::oneshot};
+
+use crate::{
+    chain::{BlockHash, Network, Transaction, Txid},
+    config::Config,
+    errors,
+    indexer::{
+        mempool::Mempool,
+        query::{ChainQuery, Query},
+    },
+    metrics::{Gauge, MetricOpts, Metrics},
+    store::{compute_script_hash, Utxo},
+    util::{
+        block::BlockId,
+        coin_select::select_coins,
+        fees::FeeratePercentiles,
+        remove_stale_socket, request_id,
+        script::address_to_script,
+        set_socket_mode,
+        systemd::{self, ListenSocket},
+        transaction::{channel_close_type, get_tx_fee, has_prevout, is_final, signals_rbf},
+        FullHash,
+    },
+};
+
+#[cfg(not(feature = "liquid"))]
+use crate::{chain::Script, store::AGE_BUCKETS, util::script::ScriptToAddr};
+#[cfg(feature = "liquid")]
+use crate::{
+    chain::{AssetId, ContractHash},
+    elements::asset::{AssetRow, AssetStats},
+};
+#[cfg(feature = "liquid")]
+use validate::parse_asset_id;
+
+#[cfg(feature = "ordinals")]
+use crate::util::inscription::parse_inscriptions;
+#[cfg(feature = "runes")]
+use crate::util::runestone::{parse_runestone, Runestone};
+
+const TTL_LONG: u32 = 157_784_630; // ttl for static resources (5 years)
+const TTL_SHORT: u32 = 10; // ttl for volatile resources
+const OP_RETURN_RESULTS: usize = 50;
+const BLOCK_TXS_PER_PAGE: usize = 25;
+const MAX_MEMPOOL_TXS: usize = 50;
+#[cfg(feature = "liquid")]
+const ASSET_TXS_PER_PAGE: usize = 25;
+const MEMPOOL_TXS_PER_PAGE: usize = 25;
+const MAX_BATCH_TXS: usize = 100;
+const MAX_BATCH_SCRIPTS: usize = 500;
+const FEERATE_HISTORY_BLOCKS: usize = 144;
+// the size of a P2WPKH input, for coin selection when the request doesn't tell
+const DEFAULT_INPUT_VSIZE: u64 = 68;
+// larger than any standard input, bounding the fees of a selection
+const MAX_INPUT_VSIZE: u64 = 10_000;
+const MAX_GENERATE_BLOCKS: u32 = 1000;
+const CONF_FINAL: usize = 10; // reorgs deeper than this are not expected
+
+pub struct Handle {
+    tx: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Handle {
+    pub fn stop(self) {
+        self.tx.send(()).expect("failed to send shutdown signal");
+        self.thread.join().expect("REST server failed");
+    }
+}
+
+pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> Handle {
+    let (tx, rx) = oneshot::channel::<()>();
+    let connections = metrics.gauge(MetricOpts::new(
+        "rest_connections",
+        "# of open HTTP connections",
+    ));
+
+    let api_keys = ApiKeys::new(&config, metrics)
+        .unwrap_or_else(|e| panic!("failed loading the REST API keys: {}", e));
+
+    let shadow = ShadowCompare::start(&config, metrics).map(Arc::new);
+
+    Handle {
+        tx,
+        thread: crate::util::spawn_thread("rest-server", move || {
+            run_server(config, query, Arc::new(api_keys), shadow, connections, rx);
+        }),
+    }
+}
+
+// Counts an open connection for as long as it is alive
+struct ConnectionGuard(Gauge);
+
+impl ConnectionGuard {
+    fn new(connections: &Gauge) -> Self {
+        connections.inc();
+        ConnectionGuard(connections.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[tokio::main]
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    api_keys: Arc<ApiKeys>,
+    shadow: Option<Arc<ShadowCompare>>,
+    connections: Gauge,
+    rx: oneshot::Receiver<()>,
+) {
+    let addr = &config.http_addr;
+    let socket_file = &config.http_socket_file;
+
+    let config = Arc::clone(&config);
+    // checked once, rather than on every response
+    let cors = config.cors.as_ref().map(|origins| {
+        HeaderValue::from_str(origins).expect("invalid CORS origins for the REST server")
+    });
+
+    let make_service_fn_inn = || {
+        let query = Arc::clone(&query);
+        let config = Arc::clone(&config);
+        let api_keys = Arc::clone(&api_keys);
+        let shadow = shadow.clone();
+        let cors = cors.clone();
+        let guard = ConnectionGuard::new(&connections);
+
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                // dropped with the service, when the connection is closed
+                let _guard = &guard;
+                let query = Arc::clone(&query);
+                let config = Arc::clone(&config);
+                let api_keys = Arc::clone(&api_keys);
+                let shadow = shadow.clone();
+                let cors = cors.clone();
+
+                async move {
+                    let (req, body) = req.into_parts();
+                    let method = req.method.clone();
+                    let uri = req.uri.clone();
+                    // taken before handling the request, so that a response is never tagged
+                    // with a state that is newer than the one it was computed from
+                    let etag = (method == Method::GET).then(|| current_etag(&query));
+                    let shadowed = shadow
+                        .as_ref()
+                        .filter(|shadow| method == Method::GET && shadow.sampled(uri.path()));
+                    // checked before reading the body, which the refused requests don't need
+                    let client = api_keys.admit(req.headers.get(API_KEY_HEADER));
+                    let body = match client {
+                        Ok(_) => read_body(body, body_limit(&method)).await,
+                        Err(_) => Ok(vec![]),
+                    };
+
+                    let mut resp = request_id::scope(async {
+                        client
+                            .and_then(|client| {
+                                let resp = handle_request(
+                                    method,
+                                    uri,
+                                    &body?,
+                                    client.admin,
+                                    &config,
+                                    &query,
+                                )?;
+                                api_keys.check_response(client, resp)
+                            })
+                            .unwrap_or_else(|err| {
+                                warn!(
+                                    "{:?} (request_id={})",
+                                    err,
+                                    request_id::current().unwrap_or_default()
+                                );
+                                err.into_response()
+                            })
+                    })
+                    .await;
+                    if let Some(shadow) = shadowed {
+                        if resp.status() == StatusCode::OK {
+                            let path = req.uri.path_and_query().map_or("", |p| p.as_str());
+                            resp = shadow.compare(path.to_string(), resp).await;
+                        }
+                    }
+                    if let Some(etag) = etag {
+                        resp = with_etag(resp, &etag, req.headers.get(IF_NONE_MATCH));
+                    }
+                    if config.http_compression_level > 0 {
+                        let accept_encoding = req.headers.get(ACCEPT_ENCODING);
+                        resp = with_compression(resp, accept_encoding, &config).await;
+                    }
+                    if let Some(ref origins) = cors {
+                        resp.headers_mut()
+                            .insert("Access-Control-Allow-Origin", origins.clone());
+                    }
+                    Ok::<_, hyper::Error>(resp)
+                }
+            }))
+        }
+    };
+
+    let (listener, location) = match (systemd::take_socket("http"), socket_file) {
+        (Some(listener), _) => (listener, "the socket passed by systemd".to_string()),
+        (None, None) => {
+            let socket = create_socket(addr);
+            socket.listen(511).expect("setting backlog failed");
+            (ListenSocket::Tcp(socket.into()), addr.to_string())
+        }
+        (None, Some(path)) => {
+            remove_stale_socket(path);
+            let listener = std::os::unix::net::UnixListener::bind(path)
+                .unwrap_or_else(|e| panic!("bind({}) failed: {}", path.display(), e));
+            set_socket_mode(path, config.socket_file_mode);
+            let location = format!("unix socket {}", path.display());
+            (ListenSocket::Unix(listener), location)
+        }
+    };
+    info!("REST server running on {}", location);
+
+    let server = match listener {
+        ListenSocket::Tcp(listener) => {
+            let builder = Server::from_tcp(listener)
+                .expect("Server::from_tcp failed")
+                .tcp_keepalive(config.tcp_keepalive);
+            with_idle_timeout(builder, config.http_idle_timeout)
+                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .with_graceful_shutdown(async {
+                    rx.await.ok();
+                })
+                .await
+        }
+        ListenSocket::Unix(listener) => {
+            let listener = listener
+                .set_nonblocking(true)
+                .and_then(|_| UnixListener::from_std(listener))
+                .expect("UnixListener::from_std failed");
+            let builder = Server::builder(SocketIncoming::from_listener(listener));
+            with_idle_timeout(builder, config.http_idle_timeout)
+                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .with_graceful_shutdown(async {
+                    rx.await.ok();
+                })
+                .await
+        }
+    };
+
+    if let Err(e) = server {
+        error!("server error: {}", e);
+    }
+}
+
+// Closes the connections that don't send a complete request header within `idle_timeout`,
+// including idle keep-alive connections
+fn with_idle_timeout<I, E>(
+    builder: Builder<I, E>,
+    idle_timeout: Option<Duration>,
+) -> Builder<I, E> {
+    match idle_timeout {
+        Some(timeout) => builder.http1_header_read_timeout(timeout),
+        None => builder,
+    }
+}
+
+fn create_socket(addr: &std::net::SocketAddr) -> socket2::Socket {
+    let domain = match &addr {
+        std::net::SocketAddr::V4(_) => socket2::Domain::IPV4,
+        std::net::SocketAddr::V6(_) => socket2::Domain::IPV6,
+    };
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))
+        .expect("creating socket failed");
+
+    #[cfg(unix)]
+    socket
+        .set_reuse_port(true)
+        .expect("cannot enable SO_REUSEPORT");
+
+    socket.bind(&(*addr).into()).expect("cannot bind");
+
+    socket
+}
+
+fn handle_request(
+    method: Method,
+    uri: hyper::Uri,
+    body: &[u8],
+    admin: bool,
+    config: &Config,
+    query: &Query,
+) -> Result<Response<Body>, HttpError> {
+    let chain = query.chain();
+    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+
+    match (&method, path.get(0), path.get(1), path.get(2), path.get(3)) {
+        (&Method::GET, Some(&""), None, None, None) => {
+            let value = ServerValue {
+                server_version: &config.server_version,
+                network: chain.network().chain_name(),
+                donation_address: config.donation_address.as_deref(),
+                operator_contact: config.operator_contact.as_deref(),
+            };
+            json_response(value, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"openapi.json"), None, None, None) => {
+            json_response(openapi::document(config, chain.network()), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"tx"), Some(hash), None, None) => {
+            let txid = parse_txid(hash)?;
+            let (tx, blockid) = query
+                .lookup_txn(&txid)?
+                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+
+            let mempool = query.mempool();
+            let conflicts = mempool.conflicts(&txid);
+            let first_seen = match blockid {
+                Some(_) => chain.tx_first_seen(&txid)?,
+                None => mempool.first_seen(&txid),
+            };
+
+            // confirmed transactions are immutable, unless they conflict with a mempool transaction
+            let ttl = if blockid.is_some() && conflicts.is_empty() {
+                TTL_LONG
+            } else {
+                TTL_SHORT
+            };
+            let prevouts = mempool.lookup_prevouts(&tx);
+            let fee = match blockid {
+                Some(_) => chain.tx_fee(&tx, Some(&prevouts))?,
+                None => get_tx_fee(&tx, &prevouts, chain.network()),
+            };
+            let is_final = match blockid {
+                Some(_) => None,
+                None => Some(is_final(
+                    &tx,
+                    chain.best_height().map_or(0, |height| height + 1),
+                    chain.best_mtp().unwrap_or(0),
+                )),
+            };
+            let mut value = TransactionValue {
+                is_final,
+                ..TransactionValue::new(
+                    tx,
+                    blockid,
+                    &prevouts,
+                    fee,
+                    first_seen,
+                    conflicts,
+                    chain.network(),
+                )
+            };
+            annotate_labels(iter::once(&mut value), chain)?;
+            json_response(value, ttl)
+        }
+
+        (&Method::POST, Some(&"txs"), None, None, None) => {
+            let txids: Vec<String> = serde_json::from_slice(body)?;
+            if txids.len() > MAX_BATCH_TXS {
+                return Err(HttpError::from(format!(
+                    "too many txids, the limit is {}",
+                    MAX_BATCH_TXS
+                )));
+            }
+            json_response(lookup_txs_batch(txids, query)?, TTL_SHORT)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"addresses"), Some(&"balances"), None, None) => {
+            let request: BalancesRequest = serde_json::from_slice(body)?;
+            let scripts: Vec<(&str, &str)> = request
+                .addresses
+                .iter()
+                .map(|address| ("address", address.as_str()))
+                .chain(
+                    request
+                        .scripthashes
+                        .iter()
+                        .map(|scripthash| ("scripthash", scripthash.as_str())),
+                )
+                .collect();
+            if scripts.len() > MAX_BATCH_SCRIPTS {
+                return Err(HttpError::from(format!(
+                    "too many addresses and scripthashes, the limit is {}",
+                    MAX_BATCH_SCRIPTS
+                )));
+            }
+            json_response(lookup_balances_batch(&scripts, query), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"zeroconf"), None) => {
+            let txid = parse_txid(hash)?;
+            let mempool_min_fee = query.min_fees().map(|fees| fees.mempool_min_fee);
+            let value =
+                ZeroConfValue::new(&txid, &query.mempool(), mempool_min_fee).ok_or_else(|| {
+                    HttpError::not_found("Transaction not found in mempool".to_string())
+                })?;
+            json_response(value, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"tx"), Some(hash), Some(relation @ &"ancestors"), None)
+        | (&Method::GET, Some(&"tx"), Some(hash), Some(relation @ &"descendants"), None) => {
+            let txid = parse_txid(hash)?;
+            let mempool = query.mempool();
+            let related = match *relation {
+                "ancestors" => mempool.ancestors(&txid),
+                _ => mempool.descendants(&txid),
+            }
+            .ok_or_else(|| HttpError::not_found("Transaction not found in mempool".to_string()))?;
+
+            let txs: Vec<PackageTxValue> = related
+                .into_iter()
+                .filter_map(|txid| {
+                    let (fee, vsize) = mempool.fee_and_vsize(&txid)?;
+                    Some(PackageTxValue { txid, fee, vsize })
+                })
+                .collect();
+            let (fee, vsize) = mempool.fee_and_vsize(&txid).ok_or_else(|| {
+                HttpError::not_found("Transaction not found in mempool".to_string())
+            })?;
+            let package = PackageValue::new(PackageTxValue { txid, fee, vsize }, &txs);
+            json_response(
+                json!({
+                    "txid": txid,
+                    *relation: txs,
+                    "package": package,
+                }),
+                TTL_SHORT,
+            )
+        }
+
+        #[cfg(feature = "ordinals")]
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"inscriptions"), None) => {
+            let txid = parse_txid(hash)?;
+            let (inscriptions, ttl) =
+                match chain.tx_confirming_block(&txid) {
+                    Some(blockid) => {
+                        let inscriptions = chain
+                            .tx_inscriptions(&txid, &blockid.hash)?
+                            .ok_or_else(|| {
+                                HttpError::not_found(
+                                    "Transaction inscriptions not indexed".to_string(),
+                                )
+                            })?;
+                        (inscriptions, ttl_by_depth(Some(blockid.height), chain))
+                    }
+                    None => {
+                        let tx = query.mempool().lookup_txn(&txid).ok_or_else(|| {
+                            HttpError::not_found("Transaction not found".to_string())
+                        })?;
+                        (parse_inscriptions(&tx), TTL_SHORT)
+                    }
+                };
+            json_response(
+                json!({
+                    "txid": txid,
+                    "inscribed": !inscriptions.is_empty(),
+                    "inscriptions": inscriptions,
+                }),
+                ttl,
+            )
+        }
+
+        #[cfg(feature = "ordinals")]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"inscriptions"), None) => {
+            let hash = parse_blockhash(hash)?;
+            let blockid = chain
+                .blockid_by_hash(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            chain.ensure_indexed(blockid.height)?;
+            let count = chain.block_inscription_count(&hash)?.ok_or_else(|| {
+                HttpError::not_found("Block inscriptions not indexed".to_string())
+            })?;
+            json_response(
+                json!({ "count": count }),
+                ttl_by_depth(Some(blockid.height), chain),
+            )
+        }
+
+        #[cfg(feature = "runes")]
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"runestone"), None) => {
+            let txid = parse_txid(hash)?;
+            let (runestone, ttl) = match chain.tx_confirming_block(&txid) {
+                Some(blockid) => {
+                    let runestone = chain.tx_runestone(&txid, &blockid.hash)?.ok_or_else(|| {
+                        HttpError::not_found("Transaction runestone not indexed".to_string())
+                    })?;
+                    (runestone, ttl_by_depth(Some(blockid.height), chain))
+                }
+                None => {
+                    let tx = query
+                        .mempool()
+                        .lookup_txn(&txid)
+                        .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+                    (parse_runestone(&tx), TTL_SHORT)
+                }
+            };
+            let runestone = runestone
+                .ok_or_else(|| HttpError::not_found("Transaction has no runestone".to_string()))?;
+            json_response(runestone_json(&txid, &runestone), ttl)
+        }
+
+        #[cfg(feature = "runes")]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"runestones"), None) => {
+            let hash = parse_blockhash(hash)?;
+            let blockid = chain
+                .blockid_by_hash(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            chain.ensure_indexed(blockid.height)?;
+            let txids = chain
+                .block_runestone_txids(&hash)?
+                .ok_or_else(|| HttpError::not_found("Block runestones not indexed".to_string()))?;
+            json_response(txids, ttl_by_depth(Some(blockid.height), chain))
+        }
+
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"script-types"), None) => {
+            let hash = parse_blockhash(hash)?;
+            let blockid = chain
+                .blockid_by_hash(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            chain.ensure_indexed(blockid.height)?;
+            let stats = chain.block_script_types(&hash)?.ok_or_else(|| {
+                HttpError::not_found("Block script types not indexed".to_string())
+            })?;
+            json_response(stats.by_type(), ttl_by_depth(Some(blockid.height), chain))
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"age-stats"), None) => {
+            let hash = parse_blockhash(hash)?;
+            let blockid = chain
+                .blockid_by_hash(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            chain.ensure_indexed(blockid.height)?;
+            let stats = chain
+                .block_age_stats(&hash)?
+                .ok_or_else(|| HttpError::not_found("Block age stats not indexed".to_string()))?;
+            let spent_by_age: Vec<serde_json::Value> = stats
+                .spent_by_age
+                .iter()
+                .enumerate()
+                .map(|(bucket, (count, value))| {
+                    json!({
+                        "max_age": AGE_BUCKETS.get(bucket),
+                        "count": count,
+                        "value": value,
+                    })
+                })
+                .collect();
+            json_response(
+                json!({
+                    "coin_days_destroyed": stats.coin_days_destroyed,
+                    "spent_by_age": spent_by_age,
+                }),
+                ttl_by_depth(Some(blockid.height), chain),
+            )
+        }
+
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), start_index) => {
+            let hash = parse_blockhash(hash)?;
+            let blockid = chain
+                .blockid_by_hash(&hash)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let txids = chain
+                .get_block_txids(&hash)?
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+
+            let start_index =
+                start_index.map_or(Ok(0), |index| parse_param("start index", index))?;
+            if start_index % BLOCK_TXS_PER_PAGE != 0 {
+                return Err(HttpError::from(format!(
+                    "start index must be a multiple of {}",
+                    BLOCK_TXS_PER_PAGE
+                )));
+            }
+            if start_index >= txids.len() {
+                return Err(HttpError::not_found("start index out of range".to_string()));
+            }
+
+            let page: Vec<(Txid, BlockId)> = txids
+                .into_iter()
+                .skip(start_index)
+                .take(BLOCK_TXS_PER_PAGE)
+                .map(|txid| (txid, blockid.clone()))
+                .collect();
+            let txs = chain.lookup_txns(&page)?;
+
+            // the previous outputs of the whole page are looked up in a single batch
+            let outpoints = txs
+                .iter()
+                .flat_map(|tx| tx.input.iter())
+                .filter(|txin| has_prevout(txin))
+                .map(|txin| txin.previous_output)
+                .collect();
+            let prevouts = chain.lookup_txos(&outpoints)?;
+
+            let mempool = query.mempool();
+            let mut values = txs
+                .into_iter()
+                .map(|tx| {
+                    let txid = tx.txid();
+                    let fee = chain.tx_fee(&tx, Some(&prevouts))?;
+                    Ok(TransactionValue::new(
+                        tx,
+                        Some(blockid.clone()),
+                        &prevouts,
+                        fee,
+                        chain.tx_first_seen(&txid)?,
+                        mempool.conflicts(&txid),
+                        chain.network(),
+                    ))
+                })
+                .collect::<errors::Result<Vec<TransactionValue>>>()?;
+            annotate_labels(&mut values, chain)?;
+            json_response(values, ttl_by_depth(Some(blockid.height), chain))
+        }
+
+        (&Method::GET, Some(&"block-height"), Some(height), None, None) => {
+            let height = parse_height(height)?;
+            let blockid = chain
+                .blockid_by_height(height)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            http_message(
+                StatusCode::OK,
+                blockid.hash.to_string(),
+                ttl_by_depth(Some(height), chain),
+            )
+        }
+
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None) => {
+            let tip = chain
+                .best_height()
+                .and_then(|height| chain.blockid_by_height(height))
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            http_message(StatusCode::OK, tip.hash.to_string(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None) => {
+            let height = chain
+                .best_height()
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            http_message(StatusCode::OK, height.to_string(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"stats"), None, None, None) => {
+            let stats = chain.chain_stats()?;
+            let store_sizes: HashMap<&str, u64> = chain.store_sizes().into_iter().collect();
+            json_response(
+                json!({
+                    "tx_count": stats.tx_count,
+                    "txo_count": stats.txo_count,
+                    "utxo_count": stats.utxo_count(),
+                    "store_sizes": store_sizes,
+                }),
+                TTL_SHORT,
+            )
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"stats"), Some(&"rich-list"), None, None) => {
+            let limit = check_max(
+                "limit",
+                QueryParams::new(&uri)
+                    .get("limit")?
+                    .unwrap_or(config.rich_list_size),
+                config.rich_list_size,
+            )?;
+            let rich_list = chain.rich_list(limit)?;
+            let scripthashes: Vec<FullHash> = rich_list
+                .entries
+                .iter()
+                .map(|entry| entry.scripthash)
+                .collect();
+            let mut labels = chain.labels(&scripthashes)?;
+            let scripts: Vec<RichListValue> = rich_list
+                .entries
+                .into_iter()
+                .map(|entry| {
+                    let script = Script::from(entry.script);
+                    RichListValue {
+                        scripthash: hex::encode(entry.scripthash),
+                        scriptpubkey_address: script.to_address_or_descriptor(chain.network()),
+                        scriptpubkey: script,
+                        balance: entry.balance,
+                        label: labels.remove(&entry.scripthash),
+                    }
+                })
+                .collect();
+            json_response(
+                json!({ "height": rich_list.height, "scripts": scripts }),
+                TTL_SHORT,
+            )
+        }
+
+        (&Method::GET, Some(&"stats"), Some(&"script-types"), None, None) => {
+            json_response(chain.script_type_stats()?.by_type(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"mempool"), Some(&"txs"), None, None) => {
+            let params = QueryParams::new(&uri);
+            let order: String = params
+                .get("order")?
+                .unwrap_or_else(|| "feerate".to_string());
+            if order != "feerate" {
+                return Err(HttpError::from("order must be feerate"));
+            }
+            let page: usize = params.get("page")?.unwrap_or(0);
+            let txs: Vec<serde_json::Value> = query
+                .mempool()
+                .txs_by_feerate(
+                    page.saturating_mul(MEMPOOL_TXS_PER_PAGE),
+                    MEMPOOL_TXS_PER_PAGE,
+                )
+                .into_iter()
+                .map(|(txid, fee, vsize, effective_feerate)| {
+                    json!({
+                        "txid": txid,
+                        "fee": fee,
+                        "vsize": vsize,
+                        "feerate": fee as f64 / vsize as f64,
+                        "effective_feerate": effective_feerate,
+                    })
+                })
+                .collect();
+            json_response(txs, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"mempool"), Some(&"next-block"), None, None) => {
+            let mempool = query.mempool();
+            let next_block = mempool.next_block();
+            json_response(
+                json!({
+                    "tx_count": next_block.txids.len(),
+                    "total_fees": next_block.total_fees,
+                    "vsize": next_block.vsize,
+                    "min_feerate": next_block.min_feerate,
+                    "txids": next_block.txids,
+                }),
+                TTL_SHORT,
+            )
+        }
+
+        (&Method::GET, Some(&"fee-histogram"), None, None, None) => {
+            json_response(query.mempool().fee_histogram(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"fee-estimates"), None, None, None) => {
+            json_response(query.estimate_fee_map(), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"fee-estimates"), Some(&"minimum"), None, None) => {
+            let min_fees = query
+                .min_fees()
+                .ok_or_else(|| HttpError::not_found("Minimum fees unavailable".to_string()))?;
+            json_response(min_fees, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"fee-estimates"), Some(&"history"), start_height, None) => {
+            let best_height = chain
+                .best_height()
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let start_height = match start_height {
+                Some(height) => check_max("start height", parse_height(height)?, best_height)?,
+                None => best_height,
+            };
+            let history: Vec<FeerateHistoryValue> = chain
+                .feerate_history(start_height, FEERATE_HISTORY_BLOCKS)?
+                .into_iter()
+                .map(|(blockid, feerates)| FeerateHistoryValue {
+                    height: blockid.height,
+                    hash: blockid.hash,
+                    time: blockid.time,
+                    feerates,
+                })
+                .collect();
+            json_response(history, ttl_by_depth(Some(start_height), chain))
+        }
+
+        (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None)
+        | (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None) => {
+            let scripthash = to_scripthash(script_type, script_str, chain.network())?;
+            let (chain_stats, mempool_stats) = query.stats(&scripthash)?;
+            let mut value = json!({
+                *script_type: script_str,
+                "chain_stats": chain_stats,
+                "mempool_stats": mempool_stats,
+            });
+            if let Some(label) = chain.labels(&[scripthash])?.remove(&scripthash) {
+                value["label"] = json!(label);
+            }
+            if let Some(activity) = chain.activity(&scripthash)? {
+                value["first_funded_height"] = json!(activity.first_funded);
+                value["last_active_height"] = json!(activity.last_active);
+            }
+            json_response(value, TTL_SHORT)
+        }
+
+        (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"utxo"),
+            None,
+        )
+        | (&Method::GET, Some(script_type @ &"address"), Some(script_str), Some(&"utxo"), None) => {
+            let scripthash = to_scripthash(script_type, script_str, chain.network())?;
+            let params = QueryParams::new(&uri);
+            let mature_only = params.get("mature_only")?.unwrap_or(false);
+            // the confirmed UTXO set as of a past block, e.g. for audits
+            let at_height = params
+                .get::<usize>("at_height")?
+                .map(|height| {
+                    check_max("at_height", height, chain.best_height().unwrap_or_default())
+                })
+                .transpose()?;
+            let utxos = match at_height {
+                Some(height) => chain.utxo_at_height(&scripthash, height, config.utxos_limit)?,
+                None => query.utxo(&scripthash, config.utxos_limit)?,
+            };
+            let mut utxos = utxos
+                .into_iter()
+                .map(|utxo| UtxoValue::new(utxo, chain))
+                .collect::<errors::Result<Vec<UtxoValue>>>()?;
+            if mature_only {
+                utxos.retain(|utxo| utxo.maturity == Maturity::Mature);
+            }
+            // a past UTXO set is final, but the maturity of its coinbase outputs isn't
+            let ttl = if utxos.iter().any(|utxo| utxo.maturity == Maturity::Immature) {
+                TTL_SHORT
+            } else {
+                ttl_by_depth(at_height, chain)
+            };
+            json_response(utxos, ttl)
+        }
+
+        (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"utxo"),
+            Some(&"select"),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"utxo"),
+            Some(&"select"),
+        ) => {
+            let scripthash = to_scripthash(script_type, script_str, chain.network())?;
+            let params = QueryParams::new(&uri);
+            let amount: u64 = params.require("amount")?;
+            let feerate: f64 = params.require("feerate")?;
+            let input_vsize: u64 = check_max(
+                "input_vsize",
+                params.get("input_vsize")?.unwrap_or(DEFAULT_INPUT_VSIZE),
+                MAX_INPUT_VSIZE,
+            )?;
+            if amount == 0 || !feerate.is_finite() || feerate < 0.0 {
+                return Err(HttpError::from("amount and feerate must be positive"));
+            }
+
+            // immature coinbase outputs can't be spent yet
+            let mut utxos: Vec<Utxo> = vec![];
+            let mut values: Vec<u64> = vec![];
+            for utxo in query.utxo(&scripthash, config.utxos_limit)? {
+                if chain.coinbase_maturity_height(&utxo)?.is_some() {
+                    continue;
+                }
+                if let Some(value) = utxo_explicit_value(&utxo, chain.network()) {
+                    utxos.push(utxo);
+                    values.push(value);
+                }
+            }
+            let selection = select_coins(&values, amount, feerate, input_vsize)
+                .ok_or_else(|| HttpError::from("insufficient funds"))?;
+
+            let selected = utxos
+                .into_iter()
+                .enumerate()
+                .filter(|(index, _)| selection.selected.contains(index))
+                .map(|(_, utxo)| UtxoValue::new(utxo, chain))
+                .collect::<errors::Result<Vec<UtxoValue>>>()?;
+            json_response(
+                json!({
+                    "utxos": selected,
+                    "algorithm": selection.algorithm,
+                    "total": selection.total,
+                    "fee": selection.fee,
+                    "excess": selection.excess,
+                }),
+                TTL_SHORT,
+            )
+        }
+
+        (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"mempool"),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"mempool"),
+        ) => {
+            let scripthash = to_scripthash(script_type, script_str, chain.network())?;
+            chain.ensure_allowed(&scripthash)?;
+
+            // served from the mempool alone, which keeps the previous outputs its
+            // transactions spend
+            let mempool = query.mempool();
+            let mut txids: Vec<(Txid, Option<u32>)> = mempool
+                .history_txids(&scripthash)
+                .into_iter()
+                .map(|txid| (txid, mempool.first_seen(&txid)))
+                .collect();
+            txids.sort_unstable_by(|a, b| b.1.cmp(&a.1)); // newest first
+            let mut values: Vec<TransactionValue> = txids
+                .into_iter()
+                .take(MAX_MEMPOOL_TXS)
+                .filter_map(|(txid, first_seen)| {
+                    let tx = mempool.lookup_txn(&txid)?;
+                    let prevouts = mempool.lookup_prevouts(&tx);
+                    let fee = get_tx_fee(&tx, &prevouts, chain.network());
+                    Some(TransactionValue::new(
+                        tx,
+                        None,
+                        &prevouts,
+                        fee,
+                        first_seen,
+                        mempool.conflicts(&txid),
+                        chain.network(),
+                    ))
+                })
+                .collect();
+            drop(mempool);
+            annotate_labels(&mut values, chain)?;
+            json_response(values, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"op-return"), Some(hexprefix), None, None) => {
+            let prefix = parse_hex(hexprefix)?;
+            let outputs: Vec<OpReturnValue> = chain
+                .op_return_outputs(&prefix, OP_RETURN_RESULTS)?
+                .into_iter()
+                .map(|(txid, vout, blockid)| OpReturnValue {
+                    txid,
+                    vout,
+                    status: TransactionStatus::from(Some(blockid)),
+                })
+                .collect();
+            json_response(outputs, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"channel-like"), Some(outpoint), None, None) => {
+            let outpoint = parse_outpoint(outpoint)?;
+            // channel-like outputs are only told apart once spent, by their witness script
+            let (closing_txid, closing_blockid) = chain
+                .channel_close(&outpoint)?
+                .ok_or_else(|| HttpError::not_found("Channel close not found".to_string()))?;
+            let closing_tx = chain
+                .lookup_txn(&closing_txid, Some(&closing_blockid.hash))?
+                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+            let funding_txo = chain
+                .lookup_txos(&iter::once(outpoint).collect())?
+                .remove(&outpoint);
+
+            #[cfg(not(feature = "liquid"))]
+            let value = funding_txo.map(|txo| txo.value);
+            #[cfg(feature = "liquid")]
+            let value = funding_txo.and_then(|txo| txo.value.explicit());
+
+            let ttl = ttl_by_depth(Some(closing_blockid.height), chain);
+            let value = ChannelLikeValue {
+                funding: ChannelFundingValue {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout,
+                    value,
+                    status: TransactionStatus::from(chain.tx_confirming_block(&outpoint.txid)),
+                },
+                closing: ChannelClosingValue {
+                    txid: closing_txid,
+                    close_type: channel_close_type(&closing_tx),
+                    status: TransactionStatus::from(Some(closing_blockid)),
+                },
+            };
+            json_response(value, ttl)
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::GET, Some(&"asset"), Some(asset_str), None, None) => {
+            let asset_id = parse_asset_id(asset_str)?;
+            // the native asset is issued by the genesis block, which isn't indexed
+            let issuance = match chain.asset_issuance(&asset_id)? {
+                Some((row, blockid)) => Some(AssetIssuanceValue::new(&row, blockid)?),
+                None if asset_id == *chain.network().native_asset() => None,
+                None => return Err(HttpError::not_found("Asset not found".to_string())),
+            };
+            let value = AssetValue {
+                asset_id,
+                issuance,
+                chain_stats: chain.asset_stats(&asset_id)?,
+            };
+            json_response(value, TTL_SHORT)
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::GET, Some(&"asset"), Some(asset_str), Some(&"txs"), last_seen_txid) => {
+            let asset_id = parse_asset_id(asset_str)?;
+            let last_seen_txid = last_seen_txid.map(|txid| parse_txid(txid)).transpose()?;
+            let txs =
+                chain.asset_history(&asset_id, last_seen_txid.as_ref(), ASSET_TXS_PER_PAGE)?;
+
+            // the previous outputs of the whole page are looked up in a single batch
+            let outpoints = txs
+                .iter()
+                .flat_map(|(tx, _)| tx.input.iter())
+                .filter(|txin| has_prevout(txin))
+                .map(|txin| txin.previous_output)
+                .collect();
+            let prevouts = chain.lookup_txos(&outpoints)?;
+
+            let mempool = query.mempool();
+            let mut values = txs
+                .into_iter()
+                .map(|(tx, blockid)| {
+                    let txid = tx.txid();
+                    let fee = chain.tx_fee(&tx, Some(&prevouts))?;
+                    Ok(TransactionValue::new(
+                        tx,
+                        Some(blockid),
+                        &prevouts,
+                        fee,
+                        chain.tx_first_seen(&txid)?,
+                        mempool.conflicts(&txid),
+                        chain.network(),
+                    ))
+                })
+                .collect::<errors::Result<Vec<TransactionValue>>>()?;
+            drop(mempool);
+            annotate_labels(&mut values, chain)?;
+            json_response(values, TTL_SHORT)
+        }
+
+        // Admin endpoints for scripting blocks and reorgs, only available on regtest
+        (&Method::POST, Some(&"regtest"), Some(&"generate"), Some(address), Some(nblocks))
+            if chain.network().is_regtest() =>
+        {
+            let nblocks = check_max(
+                "number of blocks",
+                parse_param("number of blocks", nblocks)?,
+                MAX_GENERATE_BLOCKS,
+            )?;
+            let blockhashes = query.daemon().generatetoaddress(nblocks, address)?;
+            json_response(blockhashes, 0)
+        }
+        (&Method::POST, Some(&"regtest"), Some(&"invalidate"), Some(hash), None)
+            if chain.network().is_regtest() =>
+        {
+            let blockhash = parse_blockhash(hash)?;
+            query.daemon().invalidateblock(&blockhash)?;
+            http_message(StatusCode::OK, "", 0)
+        }
+        (&Method::POST, Some(&"regtest"), Some(&"reconsider"), Some(hash), None)
+            if chain.network().is_regtest() =>
+        {
+            let blockhash = parse_blockhash(hash)?;
+            query.daemon().reconsiderblock(&blockhash)?;
+            http_message(StatusCode::OK, "", 0)
+        }
+
+        (&Method::POST, Some(&"admin"), Some(&"labels"), Some(&"reload"), None) => {
+            if !admin {
+                return Err(HttpError(
+                    StatusCode::FORBIDDEN,
+                    "an admin API key is required".to_string(),
+                ));
+            }
+            let count = chain.load_labels()?;
+            json_response(json!({ "labels": count }), 0)
+        }
+
+        _ => Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            uri.path()
+        ))),
+    }
+}
+
+// Blocks (and what they confirm) may still be reorged out near the tip
+fn ttl_by_depth(height: Option<usize>, chain: &ChainQuery) -> u32 {
+    match height {
+        Some(height)
+            if chain.best_height().map_or(false, |best_height| {
+                best_height.saturating_sub(height) >= CONF_FINAL
+            }) =>
+        {
+            TTL_LONG
+        }
+        _ => TTL_SHORT,
+    }
+}
+
+// The transactions of a batch request, in the requested order, with an error entry for each
+// one that cannot be served. The confirmed transactions and their previous outputs are read
+// in batches.
+fn lookup_txs_batch(txids: Vec<String>, query: &Query) -> Result<Vec<BatchTxValue>, HttpError> {
+    let chain = query.chain();
+    let mempool = query.mempool();
+
+    let found: Vec<Result<(Txid, Option<BlockId>), BatchErrorValue>> = txids
+        .into_iter()
+        .map(|txid_str| {
+            let error = |error: &str| BatchErrorValue {
+                txid: txid_str.clone(),
+                error: error.to_string(),
+            };
+            let txid = parse_txid(&txid_str).map_err(|_| error("Invalid txid"))?;
+            match chain.tx_confirming_block(&txid) {
+                Some(blockid) => Ok((txid, Some(blockid))),
+                None if mempool.lookup_txn(&txid).is_some() => Ok((txid, None)),
+                None => Err(error("Transaction not found")),
+            }
+        })
+        .collect();
+
+    let confirmed: Vec<(Txid, BlockId)> = found
+        .iter()
+        .filter_map(|item| match item {
+            Ok((txid, Some(blockid))) => Some((*txid, blockid.clone())),
+            _ => None,
+        })
+        .collect();
+    let confirmed_txs: HashMap<Txid, Transaction> = chain
+        .lookup_txns(&confirmed)?
+        .into_iter()
+        .map(|tx| (tx.txid(), tx))
+        .collect();
+    let outpoints = confirmed_txs
+        .values()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output)
+        .collect();
+    let confirmed_prevouts = chain.lookup_txos(&outpoints)?;
+
+    let mut values = found
+        .into_iter()
+        .map(|item| {
+            let (txid, blockid) = match item {
+                Ok(item) => item,
+                Err(error) => return Ok(BatchTxValue::Error(error)),
+            };
+            let value = match blockid {
+                Some(blockid) => {
+                    let tx = confirmed_txs[&txid].clone();
+                    let fee = chain.tx_fee(&tx, Some(&confirmed_prevouts))?;
+                    TransactionValue::new(
+                        tx,
+                        Some(blockid),
+                        &confirmed_prevouts,
+                        fee,
+                        chain.tx_first_seen(&txid)?,
+                        mempool.conflicts(&txid),
+                        chain.network(),
+                    )
+                }
+                None => {
+                    // checked above, while holding the same mempool lock
+                    let tx = mempool.lookup_txn(&txid).unwrap();
+                    let prevouts = mempool.lookup_prevouts(&tx);
+                    let fee = get_tx_fee(&tx, &prevouts, chain.network());
+                    TransactionValue::new(
+                        tx,
+                        None,
+                        &prevouts,
+                        fee,
+                        mempool.first_seen(&txid),
+                        mempool.conflicts(&txid),
+                        chain.network(),
+                    )
+                }
+            };
+            Ok(BatchTxValue::Found(Box::new(value)))
+        })
+        .collect::<errors::Result<Vec<BatchTxValue>>>()?;
+    drop(mempool);
+
+    annotate_labels(
+        values.iter_mut().filter_map(|value| match value {
+            BatchTxValue::Found(tx) => Some(&mut **tx),
+            BatchTxValue::Error(_) => None,
+        }),
+        chain,
+    )?;
+    Ok(values)
+}
+
+// Set the labels of the (previous) outputs' scripts, with a single lookup for all of them
+fn annotate_labels<'a>(
+    txs: impl IntoIterator<Item = &'a mut TransactionValue>,
+    chain: &ChainQuery,
+) -> errors::Result<()> {
+    let txouts: Vec<&mut TxOutValue> = txs
+        .into_iter()
+        .flat_map(|tx| {
+            let TransactionValue { vin, vout, .. } = tx;
+            vin.iter_mut()
+                .filter_map(|txin| txin.prevout.as_mut())
+                .chain(vout.iter_mut())
+        })
+        .collect();
+    let scripthashes: Vec<FullHash> = txouts
+        .iter()
+        .map(|txout| compute_script_hash(&txout.scriptpubkey))
+        .collect();
+    let labels = chain.labels(&scripthashes)?;
+    for (txout, scripthash) in txouts.into_iter().zip(&scripthashes) {
+        txout.scriptpubkey_label = labels.get(scripthash).cloned();
+    }
+    Ok(())
+}
+
+// The confirmed and unconfirmed balances of `(script_type, script_str)` pairs, in the
+// requested order, with an error entry for each one that cannot be served
+#[cfg(not(feature = "liquid"))]
+fn lookup_balances_batch(scripts: &[(&str, &str)], query: &Query) -> Vec<serde_json::Value> {
+    let network = query.chain().network();
+    let scripthashes: Vec<Result<FullHash, HttpError>> = scripts
+        .iter()
+        .map(|(script_type, script_str)| to_scripthash(script_type, script_str, network))
+        .collect();
+    let valid: Vec<FullHash> = scripthashes
+        .iter()
+        .filter_map(|scripthash| scripthash.as_ref().ok().copied())
+        .collect();
+    let stats = query.stats_batch(&valid);
+
+    scripts
+        .iter()
+        .zip(scripthashes)
+        .map(|((script_type, script_str), scripthash)| {
+            let result = scripthash.and_then(|scripthash| {
+                stats[&scripthash]
+                    .as_ref()
+                    .map_err(|e| HttpError::from(e.to_string()))
+            });
+            match result {
+                Ok((chain_stats, mempool_stats)) => json!({
+                    *script_type: script_str,
+                    "confirmed": chain_stats.funded_txo_sum as i64
+                        - chain_stats.spent_txo_sum as i64,
+                    "unconfirmed": mempool_stats.funded_txo_sum as i64
+                        - mempool_stats.spent_txo_sum as i64,
+                }),
+                Err(e) => json!({
+                    *script_type: script_str,
+                    "error": e.1,
+                }),
+            }
+        })
+        .collect()
+}
+
+fn to_scripthash(
+    script_type: &str,
+    script_str: &str,
+    network: Network,
+) -> Result<FullHash, HttpError> {
+    match script_type {
+        "address" => Ok(compute_script_hash(&address_to_script(
+            script_str, network,
+        )?)),
+        "scripthash" => parse_scripthash(script_str),
+        _ => unreachable!(),
+    }
+}
+
+// The integers are up to 128 bits, beyond the precision of the JSON numbers in most clients,
+// so they're serialized as decimal strings
+#[cfg(feature = "runes")]
+fn runestone_json(txid: &Txid, runestone: &Runestone) -> serde_json::Value {
+    json!({
+        "txid": txid,
+        "vout": runestone.vout,
+        "cenotaph": runestone.cenotaph,
+        "fields": runestone.fields.iter().map(|(tag, value)| json!({
+            "tag": tag.to_string(),
+            "value": value.to_string(),
+        })).collect::<Vec<_>>(),
+        "edicts": runestone.edicts.iter().map(|[block, tx, amount, output]| json!({
+            "block": block.to_string(),
+            "tx": tx.to_string(),
+            "amount": amount.to_string(),
+            "output": output.to_string(),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+// Only the (native asset) outputs of known value can be selected to fund a transaction
+fn utxo_explicit_value(utxo: &Utxo, network: Network) -> Option<u64> {
+    #[cfg(not(feature = "liquid"))]
+    let _ = network;
+    #[cfg(not(feature = "liquid"))]
+    return Some(utxo.value);
+
+    #[cfg(feature = "liquid")]
+    return match utxo.asset.explicit() {
+        Some(asset) if asset == *network.native_asset() => utxo.value.explicit(),
+        _ => None,
+    };
+}
+
+#[derive(Serialize)]
+struct UtxoValue {
+    txid: Txid,
+    vout: u32,
+    status: TransactionStatus,
+    #[cfg(not(feature = "liquid"))]
+    value: u64,
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<u64>,
+    maturity: Maturity,
+    /// The height of the first block that may spend an immature coinbase output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maturity_height: Option<usize>,
+}
+
+#[derive(Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Maturity {
+    Mature,
+    /// A coinbase output with less than 100 confirmations, which bitcoind refuses to spend
+    Immature,
+}
+
+impl UtxoValue {
+    fn new(utxo: Utxo, chain: &ChainQuery) -> errors::Result<Self> {
+        let maturity_height = chain.coinbase_maturity_height(&utxo)?;
+
+        #[cfg(not(feature = "liquid"))]
+        let value = utxo.value;
+        #[cfg(feature = "liquid")]
+        let value = utxo.value.explicit();
+
+        Ok(UtxoValue {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            status: TransactionStatus::from(utxo.confirmed),
+            value,
+            maturity: match maturity_height {
+                Some(_) => Maturity::Immature,
+                None => Maturity::Mature,
+            },
+            maturity_height,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct PackageTxValue {
+    txid: Txid,
+    fee: Option<u64>,
+    vsize: u64,
+}
+
+/// The aggregates of a mempool transaction together with its ancestors or descendants
+#[derive(Serialize)]
+struct PackageValue {
+    count: usize,
+    fee: Option<u64>, // unknown when the fee of any transaction in the package is
+    vsize: u64,
+    feerate: Option<f64>,
+}
+
+impl PackageValue {
+    fn new(tx: PackageTxValue, related: &[PackageTxValue]) -> Self {
+        let txs = || iter::once(&tx).chain(related.iter());
+        let fee = txs().try_fold(0, |total, tx| Some(total + tx.fee?));
+        let vsize = txs().map(|tx| tx.vsize).sum();
+        PackageValue {
+            count: related.len() + 1,
+            fee,
+            vsize,
+            feerate: fee.map(|fee| fee as f64 / vsize as f64),
+        }
+    }
+}
+
+/// Advisory signals on the risk of accepting an unconfirmed transaction as payment. Low risk
+/// is no guarantee: nodes running full-RBF replace transactions that don't signal it too.
+#[derive(Serialize)]
+struct ZeroConfValue {
+    txid: Txid,
+    /// "low", "medium" or "high"
+    risk: &'static str,
+    /// The signals raising the risk above low
+    reasons: Vec<&'static str>,
+    signals_rbf: bool,
+    ancestor_signals_rbf: bool,
+    /// In sat/vB, unknown when a previous output is
+    feerate: Option<f64>,
+    /// The feerate together with the unconfirmed ancestors, which are mined along
+    package_feerate: Option<f64>,
+    mempool_min_fee: Option<f64>,
+    ancestor_count: usize,
+    /// The longest chain of unconfirmed ancestors
+    ancestor_depth: usize,
+    conflicts: Vec<Txid>,
+}
+
+impl ZeroConfValue {
+    fn new(txid: &Txid, mempool: &Mempool, mempool_min_fee: Option<f64>) -> Option<Self> {
+        let tx = mempool.lookup_txn(txid)?;
+        let ancestors = mempool.ancestors(txid)?;
+        let ancestor_depth = mempool.ancestor_depth(txid)?;
+        let conflicts = mempool.conflicts(txid);
+
+        let package_tx = |txid: Txid| {
+            let (fee, vsize) = mempool.fee_and_vsize(&txid)?;
+            Some(PackageTxValue { txid, fee, vsize })
+        };
+        let tx_value = package_tx(*txid)?;
+        let feerate = tx_value.fee.map(|fee| fee as f64 / tx_value.vsize as f64);
+        let ancestor_values: Vec<PackageTxValue> = ancestors
+            .iter()
+            .filter_map(|txid| package_tx(*txid))
+            .collect();
+        let package_feerate = PackageValue::new(tx_value, &ancestor_values).feerate;
+
+        let ancestor_signals_rbf = ancestors
+            .iter()
+            .filter_map(|txid| mempool.lookup_txn(txid))
+            .any(|tx| signals_rbf(&tx));
+        let signals_rbf = signals_rbf(&tx);
+        let below_min_fee = match (package_feerate, mempool_min_fee) {
+            (Some(feerate), Some(min_fee)) => feerate < min_fee,
+            _ => false,
+        };
+
+        // conflicting or evictable transactions are likely to never confirm
+        let signals = [
+            (!conflicts.is_empty(), "conflicts", "high"),
+            (below_min_fee, "below_mempool_min_fee", "high"),
+            (signals_rbf, "signals_rbf", "medium"),
+            (ancestor_signals_rbf, "ancestor_signals_rbf", "medium"),
+            (ancestor_depth > 0, "unconfirmed_ancestors", "medium"),
+            (package_feerate.is_none(), "unknown_fee", "medium"),
+        ];
+        let raised: Vec<(&'static str, &'static str)> = signals
+            .iter()
+            .filter(|(raised, _, _)| *raised)
+            .map(|(_, reason, risk)| (*reason, *risk))
+            .collect();
+        let risk = if raised.iter().any(|(_, risk)| *risk == "high") {
+            "high"
+        } else if !raised.is_empty() {
+            "medium"
+        } else {
+            "low"
+        };
+
+        Some(ZeroConfValue {
+            txid: *txid,
+            risk,
+            reasons: raised.into_iter().map(|(reason, _)| reason).collect(),
+            signals_rbf,
+            ancestor_signals_rbf,
+            feerate,
+            package_feerate,
+            mempool_min_fee,
+            ancestor_count: ancestors.len(),
+            ancestor_depth,
+            conflicts,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct FeerateHistoryValue {
+    height: usize,
+    hash: BlockHash,
+    time: u32,
+    #[serde(flatten)]
+    feerates: FeeratePercentiles,
+}
+
+#[derive(Serialize)]
+struct ServerValue<'a> {
+    server_version: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    donation_address: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operator_contact: Option<&'a str>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Deserialize)]
+struct BalancesRequest {
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    scripthashes: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchTxValue {
+    Found(Box<TransactionValue>),
+    Error(BatchErrorValue),
+}
+
+#[derive(Serialize)]
+struct BatchErrorValue {
+    txid: String,
+    error: String,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct RichListValue {
+    scripthash: String,
+    scriptpubkey: Script,
+    scriptpubkey_address: String,
+    balance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OpReturnValue {
+    txid: Txid,
+    vout: u32,
+    status: TransactionStatus,
+}
+
+#[cfg(feature = "liquid")]
+#[derive(Serialize)]
+struct AssetValue {
+    asset_id: AssetId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issuance: Option<AssetIssuanceValue>,
+    chain_stats: AssetStats,
+}
+
+// The initial issuance of an asset, its reissuances being in its history
+#[cfg(feature = "liquid")]
+#[derive(Serialize)]
+struct AssetIssuanceValue {
+    txid: Txid,
+    vin: u16,
+    prevout_txid: Txid,
+    prevout_vout: u32,
+    reissuance_token: AssetId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contract_hash: Option<ContractHash>,
+    status: TransactionStatus,
+}
+
+#[cfg(feature = "liquid")]
+impl AssetIssuanceValue {
+    fn new(row: &AssetRow, blockid: BlockId) -> errors::Result<Self> {
+        let prevout = row.issuance_prevout();
+        Ok(AssetIssuanceValue {
+            txid: row.issuance_txid(),
+            vin: row.issuance_vin,
+            prevout_txid: prevout.txid,
+            prevout_vout: prevout.vout,
+            reissuance_token: row.reissuance_token(),
+            contract_hash: row.contract_hash()?,
+            status: TransactionStatus::from(Some(blockid)),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ChannelLikeValue {
+    funding: ChannelFundingValue,
+    closing: ChannelClosingValue,
+}
+
+#[derive(Serialize)]
+struct ChannelFundingValue {
+    txid: Txid,
+    vout: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<u64>,
+    status: TransactionStatus,
+}
+
+#[derive(Serialize)]
+struct ChannelClosingValue {
+    txid: Txid,
+    /// "force" for a commitment transaction, "mutual" or "unknown" otherwise
+    #[serde(rename = "type")]
+    close_type: &'static str,
+    status: TransactionStatus,
+}
+
+// Mutable resources only change with the chain tip or the mempool contents
+fn current_etag(query: &Query) -> String {
+    let chain = query.chain();
+    let tip = chain
+        .best_height()
+        .and_then(|height| chain.blockid_by_height(height))
+        .map(|blockid| blockid.hash.to_string())
+        .unwrap_or_default();
+    format!("\"{}-{}\"", tip, query.mempool().generation())
+}
+
+/// Tags the successful responses of mutable resources with `etag`, and replaces them by an
+/// empty `304 Not Modified` when the client already has that version.
+fn with_etag(
+    mut resp: Response<Body>,
+    etag: &str,
+    if_none_match: Option<&HeaderValue>,
+) -> Response<Body> {
+    let immutable = resp
+        .headers()
+        .get(CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.contains("immutable"));
+    if resp.status() != StatusCode::OK || immutable {
+        return resp;
+    }
+
+    let not_modified = if_none_match
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+    if not_modified {
+        let mut not_modified = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+        if let Some(cache_control) = resp.headers_mut().remove(CACHE_CONTROL) {
+            not_modified
+                .headers_mut()
+                .insert(CACHE_CONTROL, cache_control);
+        }
+        resp = not_modified;
+    }
+    resp.headers_mut()
+        .insert(ETAG, HeaderValue::from_str(etag).unwrap());
+    resp
+}
+
+/// Compresses the responses of at least `http_compression_min_size` bytes with the encoding
+/// preferred by the client, if it accepts any.
+async fn with_compression(
+    mut resp: Response<Body>,
+    accept_encoding: Option<&HeaderValue>,
+    config: &Config,
+) -> Response<Body> {
+    // caches must keep the compressed and uncompressed representations apart
+    resp.headers_mut()
+        .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    let encoding = accept_encoding
+        .and_then(|value| value.to_str().ok())
+        .and_then(Encoding::negotiate);
+    let encoding = match encoding {
+        Some(encoding) if !resp.headers().contains_key(CONTENT_ENCODING) => encoding,
+        _ => return resp,
+    };
+
+    let (mut parts, body) = resp.into_parts();
+    // the responses are built in memory, reading them back cannot fail
+    let body = hyper::body::to_bytes(body)
+        .await
+        .expect("in-memory response body");
+    if body.len() < config.http_compression_min_size {
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    // compressing large bodies takes a while, keep it off the async workers
+    let (data, level) = (body.clone(), config.http_compression_level);
+    let compressed = tokio::task::spawn_blocking(move || encoding.compress(&data, level))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)));
+    match compressed {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            // the bytes differ from the uncompressed representation's, so the tag is only weak
+            if let Some(etag) = parts.headers.get(ETAG).and_then(|tag| tag.to_str().ok()) {
+                if !etag.starts_with("W/") {
+                    let weak = HeaderValue::from_str(&format!("W/{}", etag)).unwrap();
+                    parts.headers.insert(ETAG, weak);
+                }
+            }
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            warn!("{} compression failed: {}", encoding.name(), e);
+            Response::from_parts(parts, Body::from(body))
+        }
+    }
+}
+
+// Confirmed resources never change, so caches don't need to revalidate them
+fn cache_control(ttl: u32) -> String {
+    if ttl == TTL_LONG {
+        format!("public, max-age={:}, immutable", ttl)
+    } else {
+        format!("public, max-age={:}", ttl)
+    }
+}
+
+fn http_message<T>(status: StatusCode, message: T, ttl: u32) -> Result<Response<Body>, HttpError>
+where
+    T: Into<Body>,
+{
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header(CACHE_CONTROL, cache_control(ttl))
+        .body(message.into())
+        .unwrap())
+}
+
+fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
+    let value = serde_json::to_string(&value)?;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .header(CACHE_CONTROL, cache_control(ttl))
+        .body(Body::from(value))
+        .unwrap())
+}
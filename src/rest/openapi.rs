@@ -0,0 +1,862 @@
+// The OpenAPI (3.0) description of the REST API, served at `/openapi.json` so that client SDKs
+// can be generated from it. The routes below mirror the ones matched by `handle_request`, and
+// the schemas the `*Value` types serialized in the responses.
+
+use serde_json::{Map, Value};
+
+use crate::{
+    chain::{Chain, ChainSpec, Network},
+    config::Config,
+};
+
+struct Route {
+    method: &'static str,
+    /// With the path parameters in braces, e.g. `/tx/{txid}`
+    path: String,
+    summary: &'static str,
+    query: &'static [QueryParam],
+    request: Option<Value>,
+    response: Value,
+}
+
+struct QueryParam {
+    name: &'static str,
+    schema: &'static str,
+    required: bool,
+    description: &'static str,
+}
+
+/// The OpenAPI document of the routes available on `network`
+pub(super) fn document(config: &Config, network: Network) -> Value {
+    let mut paths = Map::new();
+    for route in routes(network) {
+        let operation = operation(&route);
+        paths
+            .entry(route.path.clone())
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap()
+            .insert(route.method.to_string(), operation);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Esplora REST API",
+            "version": config.server_version,
+        },
+        "paths": paths,
+        "components": { "schemas": schemas() },
+    })
+}
+
+fn operation(route: &Route) -> Value {
+    let path_params = route
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": path_param_schema(name),
+            })
+        });
+    let query_params = route.query.iter().map(|param| {
+        json!({
+            "name": param.name,
+            "in": "query",
+            "required": param.required,
+            "description": param.description,
+            "schema": { "type": param.schema },
+        })
+    });
+    let mut operation = json!({
+        "summary": route.summary,
+        "parameters": path_params.chain(query_params).collect::<Vec<Value>>(),
+        "responses": {
+            "200": { "description": "OK", "content": content(&route.response) },
+            "default": {
+                "description": "Error",
+                "content": { "application/json": { "schema": schema_ref("Error") } },
+            },
+        },
+    });
+    if let Some(request) = &route.request {
+        operation["requestBody"] = json!({ "required": true, "content": content(request) });
+    }
+    operation
+}
+
+// the plain text responses are described by a string schema
+fn content(schema: &Value) -> Value {
+    if schema["type"] == "string" {
+        json!({ "text/plain": { "schema": schema } })
+    } else {
+        json!({ "application/json": { "schema": schema } })
+    }
+}
+
+fn path_param_schema(name: &str) -> Value {
+    match name {
+        "txid" | "hash" | "scripthash" | "asset_id" | "last_seen_txid" => hex_schema(Some(64)),
+        "height" | "start_height" | "start_index" | "nblocks" => json!({ "type": "integer" }),
+        "hexprefix" => hex_schema(None),
+        "outpoint" => json!({ "type": "string", "pattern": "^[0-9a-f]{64}:[0-9]+$" }),
+        _ => json!({ "type": "string" }),
+    }
+}
+
+fn hex_schema(len: Option<usize>) -> Value {
+    match len {
+        Some(len) => json!({ "type": "string", "pattern": format!("^[0-9a-f]{{{}}}$", len) }),
+        None => json!({ "type": "string", "pattern": "^([0-9a-f]{2})+$" }),
+    }
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array_of(schema: Value) -> Value {
+    json!({ "type": "array", "items": schema })
+}
+
+fn text() -> Value {
+    json!({ "type": "string" })
+}
+
+const NO_QUERY: &[QueryParam] = &[];
+
+fn routes(network: Network) -> Vec<Route> {
+    let mut routes = vec![
+        Route {
+            method: "get",
+            path: "/".into(),
+            summary: "Server version and operator details",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("Server"),
+        },
+        Route {
+            method: "get",
+            path: "/openapi.json".into(),
+            summary: "This document",
+            query: NO_QUERY,
+            request: None,
+            response: json!({ "type": "object" }),
+        },
+        Route {
+            method: "get",
+            path: "/tx/{txid}".into(),
+            summary: "A confirmed or unconfirmed transaction",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("Transaction"),
+        },
+        Route {
+            method: "post",
+            path: "/txs".into(),
+            summary: "A batch of transactions, with an error in place of the ones not found",
+            query: NO_QUERY,
+            request: Some(array_of(hex_schema(Some(64)))),
+            response: array_of(json!({
+                "oneOf": [schema_ref("Transaction"), schema_ref("BatchError")],
+            })),
+        },
+        Route {
+            method: "get",
+            path: "/tx/{txid}/zeroconf".into(),
+            summary: "The risk of accepting an unconfirmed transaction",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("ZeroConf"),
+        },
+        Route {
+            method: "get",
+            path: "/tx/{txid}/ancestors".into(),
+            summary: "The unconfirmed ancestors of a mempool transaction",
+            query: NO_QUERY,
+            request: None,
+            response: package_schema("ancestors"),
+        },
+        Route {
+            method: "get",
+            path: "/tx/{txid}/descendants".into(),
+            summary: "The descendants of a mempool transaction",
+            query: NO_QUERY,
+            request: None,
+            response: package_schema("descendants"),
+        },
+        Route {
+            method: "get",
+            path: "/block/{hash}/txs".into(),
+            summary: "The first page of the transactions of a block",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("Transaction")),
+        },
+        Route {
+            method: "get",
+            path: "/block/{hash}/txs/{start_index}".into(),
+            summary: "A page of the transactions of a block, from a multiple of 25",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("Transaction")),
+        },
+        Route {
+            method: "get",
+            path: "/block/{hash}/script-types".into(),
+            summary: "The outputs created by a block, counted by script type",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("ScriptTypes"),
+        },
+        #[cfg(not(feature = "liquid"))]
+        Route {
+            method: "get",
+            path: "/block/{hash}/age-stats".into(),
+            summary: "The coin days destroyed by a block and the ages of the outputs it spends, by bucket (ages in blocks, the last bucket without a max_age)",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "required": ["coin_days_destroyed", "spent_by_age"],
+                "properties": {
+                    "coin_days_destroyed": { "type": "number" },
+                    "spent_by_age": array_of(json!({
+                        "type": "object",
+                        "required": ["max_age", "count", "value"],
+                        "properties": {
+                            "max_age": { "type": "integer", "nullable": true },
+                            "count": { "type": "integer" },
+                            "value": { "type": "integer" },
+                        },
+                    })),
+                },
+            }),
+        },
+        Route {
+            method: "get",
+            path: "/block-height/{height}".into(),
+            summary: "The hash of the best chain block at a height",
+            query: NO_QUERY,
+            request: None,
+            response: text(),
+        },
+        Route {
+            method: "get",
+            path: "/blocks/tip/hash".into(),
+            summary: "The hash of the best block",
+            query: NO_QUERY,
+            request: None,
+            response: text(),
+        },
+        Route {
+            method: "get",
+            path: "/blocks/tip/height".into(),
+            summary: "The height of the best block",
+            query: NO_QUERY,
+            request: None,
+            response: text(),
+        },
+        Route {
+            method: "get",
+            path: "/stats".into(),
+            summary: "The size of the chain and of the index",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("ChainStats"),
+        },
+        Route {
+            method: "get",
+            path: "/stats/script-types".into(),
+            summary: "The outputs created by the whole chain, counted by script type",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("ScriptTypes"),
+        },
+        Route {
+            method: "get",
+            path: "/mempool/next-block".into(),
+            summary: "The projection of the next block, selecting the mempool transactions by effective feerate within the block size. Computed again at most every 10 seconds",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "required": ["tx_count", "total_fees", "vsize", "min_feerate", "txids"],
+                "properties": {
+                    "tx_count": { "type": "integer" },
+                    "total_fees": { "type": "integer" },
+                    "vsize": { "type": "integer" },
+                    "min_feerate": { "type": "number", "nullable": true, "description": "The lowest feerate (in sat/vB) of the packages included" },
+                    "txids": array_of(hex_schema(Some(64))),
+                },
+            }),
+        },
+        Route {
+            method: "get",
+            path: "/mempool/txs".into(),
+            summary: "The mempool transactions by effective feerate (in sat/vB, including their mempool ancestors), highest first, 25 per page. The transactions spending unknown outputs are left out",
+            query: &[
+                QueryParam {
+                    name: "order",
+                    schema: "string",
+                    required: false,
+                    description: "The order of the transactions, only feerate (the default)",
+                },
+                QueryParam {
+                    name: "page",
+                    schema: "integer",
+                    required: false,
+                    description: "The page number, starting at 0",
+                },
+            ],
+            request: None,
+            response: array_of(json!({
+                "type": "object",
+                "required": ["txid", "fee", "vsize", "feerate", "effective_feerate"],
+                "properties": {
+                    "txid": hex_schema(Some(64)),
+                    "fee": { "type": "integer" },
+                    "vsize": { "type": "integer" },
+                    "feerate": { "type": "number" },
+                    "effective_feerate": { "type": "number" },
+                },
+            })),
+        },
+        Route {
+            method: "get",
+            path: "/fee-histogram".into(),
+            summary: "The fee histogram of the mempool, as served by Electrum's mempool.get_fee_histogram: [feerate, vsize] pairs by decreasing feerate (in sat/vB), each with the vsize of the transactions paying between its feerate and the previous one",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(array_of(json!({ "type": "number" }))),
+        },
+        Route {
+            method: "get",
+            path: "/fee-estimates".into(),
+            summary: "The fee rates (in sat/vB) estimated by confirmation target",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "additionalProperties": { "type": "number" },
+            }),
+        },
+        Route {
+            method: "get",
+            path: "/fee-estimates/minimum".into(),
+            summary: "The minimum fee rates relayed and accepted by the mempool",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("MinFees"),
+        },
+        Route {
+            method: "get",
+            path: "/fee-estimates/history".into(),
+            summary: "The fee rate percentiles of the latest blocks",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("FeerateHistory")),
+        },
+        Route {
+            method: "get",
+            path: "/fee-estimates/history/{start_height}".into(),
+            summary: "The fee rate percentiles of the blocks below a height",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("FeerateHistory")),
+        },
+        Route {
+            method: "get",
+            path: "/op-return/{hexprefix}".into(),
+            summary: "The OP_RETURN outputs whose payload starts with a prefix",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("OpReturn")),
+        },
+        Route {
+            method: "get",
+            path: "/channel-like/{outpoint}".into(),
+            summary: "The close of a channel-like (2-of-2 multisig P2WSH) output",
+            query: NO_QUERY,
+            request: None,
+            response: schema_ref("ChannelLike"),
+        },
+    ];
+
+    #[cfg(not(feature = "liquid"))]
+    routes.push(Route {
+        method: "post",
+        path: "/addresses/balances".into(),
+        summary: "The stats of a batch of addresses and scripthashes",
+        query: NO_QUERY,
+        request: Some(json!({
+            "type": "object",
+            "properties": {
+                "addresses": array_of(text()),
+                "scripthashes": array_of(hex_schema(Some(64))),
+            },
+        })),
+        response: array_of(schema_ref("AddressStats")),
+    });
+    #[cfg(not(feature = "liquid"))]
+    routes.push(Route {
+        method: "get",
+        path: "/stats/rich-list".into(),
+        summary: "The scripts with the largest balances, as maintained with --rich-list-size",
+        query: &[QueryParam {
+            name: "limit",
+            schema: "integer",
+            required: false,
+            description: "The number of scripts, at most (and by default) --rich-list-size",
+        }],
+        request: None,
+        response: json!({
+            "type": "object",
+            "required": ["height", "scripts"],
+            "properties": {
+                "height": { "type": "integer" },
+                "scripts": array_of(json!({
+                    "type": "object",
+                    "required": ["scripthash", "scriptpubkey", "scriptpubkey_address", "balance"],
+                    "properties": {
+                        "scripthash": hex_schema(Some(64)),
+                        "scriptpubkey": hex_schema(None),
+                        "scriptpubkey_address": text(),
+                        "balance": { "type": "integer" },
+                        "label": text(),
+                    },
+                })),
+            },
+        }),
+    });
+    #[cfg(feature = "liquid")]
+    routes.extend(vec![
+        Route {
+            method: "get",
+            path: "/asset/{asset_id}".into(),
+            summary: "The initial issuance of an asset (none for the native asset) and its confirmed activity",
+            query: NO_QUERY,
+            request: None,
+            response: json!({ "type": "object", "required": ["asset_id", "chain_stats"] }),
+        },
+        Route {
+            method: "get",
+            path: "/asset/{asset_id}/txs".into(),
+            summary: "The last 25 confirmed transactions issuing, burning or pegging an asset",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("Transaction")),
+        },
+        Route {
+            method: "get",
+            path: "/asset/{asset_id}/txs/{last_seen_txid}".into(),
+            summary: "The next 25 confirmed transactions of an asset, after the last one seen",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(schema_ref("Transaction")),
+        },
+    ]);
+    #[cfg(feature = "ordinals")]
+    routes.extend(vec![
+        Route {
+            method: "get",
+            path: "/tx/{txid}/inscriptions".into(),
+            summary: "The ordinals inscriptions revealed by a transaction",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "required": ["txid", "inscribed", "inscriptions"],
+                "properties": {
+                    "txid": hex_schema(Some(64)),
+                    "inscribed": { "type": "boolean" },
+                    "inscriptions": array_of(json!({
+                        "type": "object",
+                        "required": ["vin", "content_length"],
+                        "properties": {
+                            "vin": { "type": "integer" },
+                            "content_type": text(),
+                            "content_length": { "type": "integer" },
+                        },
+                    })),
+                },
+            }),
+        },
+        Route {
+            method: "get",
+            path: "/block/{hash}/inscriptions".into(),
+            summary: "The number of ordinals inscriptions revealed by a block",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "required": ["count"],
+                "properties": { "count": { "type": "integer" } },
+            }),
+        },
+    ]);
+
+    #[cfg(feature = "runes")]
+    routes.extend(vec![
+        Route {
+            method: "get",
+            path: "/tx/{txid}/runestone".into(),
+            summary: "The decoded runestone of a transaction, with the integers as decimal strings",
+            query: NO_QUERY,
+            request: None,
+            response: json!({
+                "type": "object",
+                "required": ["txid", "vout", "cenotaph", "fields", "edicts"],
+                "properties": {
+                    "txid": hex_schema(Some(64)),
+                    "vout": { "type": "integer" },
+                    "cenotaph": { "type": "boolean" },
+                    "fields": array_of(json!({
+                        "type": "object",
+                        "required": ["tag", "value"],
+                        "properties": { "tag": text(), "value": text() },
+                    })),
+                    "edicts": array_of(json!({
+                        "type": "object",
+                        "required": ["block", "tx", "amount", "output"],
+                        "properties": {
+                            "block": text(),
+                            "tx": text(),
+                            "amount": text(),
+                            "output": text(),
+                        },
+                    })),
+                },
+            }),
+        },
+        Route {
+            method: "get",
+            path: "/block/{hash}/runestones".into(),
+            summary: "The txids of the transactions carrying a runestone in a block",
+            query: NO_QUERY,
+            request: None,
+            response: array_of(hex_schema(Some(64))),
+        },
+    ]);
+
+    for script_type in &["address", "scripthash"] {
+        let path = |suffix: &str| format!("/{}/{{{}}}{}", script_type, script_type, suffix);
+        routes.extend(vec![
+            Route {
+                method: "get",
+                path: path(""),
+                summary: "The funded and spent totals of a script",
+                query: NO_QUERY,
+                request: None,
+                response: schema_ref("AddressStats"),
+            },
+            Route {
+                method: "get",
+                path: path("/utxo"),
+                summary: "The unspent outputs of a script",
+                query: &[
+                    QueryParam {
+                        name: "mature_only",
+                        schema: "boolean",
+                        required: false,
+                        description: "Leave out the immature coinbase outputs",
+                    },
+                    QueryParam {
+                        name: "at_height",
+                        schema: "integer",
+                        required: false,
+                        description: "The confirmed outputs that were unspent as of the block at this height, instead of the current ones",
+                    },
+                ],
+                request: None,
+                response: array_of(schema_ref("Utxo")),
+            },
+            Route {
+                method: "get",
+                path: path("/utxo/select"),
+                summary: "A selection of the unspent outputs of a script, paying an amount",
+                query: &[
+                    QueryParam {
+                        name: "amount",
+                        schema: "integer",
+                        required: true,
+                        description: "The amount to pay, in sats",
+                    },
+                    QueryParam {
+                        name: "feerate",
+                        schema: "number",
+                        required: true,
+                        description: "In sat/vB",
+                    },
+                    QueryParam {
+                        name: "input_vsize",
+                        schema: "integer",
+                        required: false,
+                        description: "The vsize of each input (default: 68, for P2WPKH)",
+                    },
+                ],
+                request: None,
+                response: schema_ref("CoinSelection"),
+            },
+            Route {
+                method: "get",
+                path: path("/txs/mempool"),
+                summary: "The unconfirmed transactions of a script, newest first",
+                query: NO_QUERY,
+                request: None,
+                response: array_of(schema_ref("Transaction")),
+            },
+        ]);
+    }
+
+    if network.is_regtest() {
+        routes.extend(vec![
+            Route {
+                method: "post",
+                path: "/regtest/generate/{address}/{nblocks}".into(),
+                summary: "Mine blocks paying to an address",
+                query: NO_QUERY,
+                request: None,
+                response: array_of(hex_schema(Some(64))),
+            },
+            Route {
+                method: "post",
+                path: "/regtest/invalidate/{hash}".into(),
+                summary: "Invalidate a block, reorging it out",
+                query: NO_QUERY,
+                request: None,
+                response: text(),
+            },
+            Route {
+                method: "post",
+                path: "/regtest/reconsider/{hash}".into(),
+                summary: "Reconsider an invalidated block",
+                query: NO_QUERY,
+                request: None,
+                response: text(),
+            },
+        ]);
+    }
+    routes
+}
+
+fn package_schema(relation: &str) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "txid": hex_schema(Some(64)),
+            relation: array_of(schema_ref("PackageTx")),
+            "package": schema_ref("Package"),
+        },
+    })
+}
+
+fn object(required: &[&str], properties: Value) -> Value {
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+fn schemas() -> Value {
+    let hash = hex_schema(Some(64));
+    let integer = json!({ "type": "integer" });
+    let number = json!({ "type": "number" });
+    let boolean = json!({ "type": "boolean" });
+    let string = text();
+
+    let mut txout = json!({
+        "scriptpubkey": hex_schema(None),
+        "scriptpubkey_asm": string,
+        "scriptpubkey_type": string,
+        "scriptpubkey_address": string,
+        "value": integer,
+        "timelocks": array_of(schema_ref("Timelock")),
+        "scriptpubkey_label": string,
+    });
+    if Chain::HAS_ASSETS {
+        for (name, schema) in [
+            ("valuecommitment", &string),
+            ("asset", &hash),
+            ("assetcommitment", &string),
+            ("rangeproof_size", &integer),
+            ("surjectionproof_size", &integer),
+            ("is_fee", &boolean),
+        ] {
+            txout[name] = schema.clone();
+        }
+    }
+
+    let script_stats = object(
+        &["tx_count", "funded_txo_count", "spent_txo_count"],
+        json!({
+            "tx_count": integer,
+            "funded_txo_count": integer,
+            "spent_txo_count": integer,
+            "funded_txo_sum": integer,
+            "spent_txo_sum": integer,
+        }),
+    );
+
+    json!({
+        "Error": object(&["status", "error"], json!({
+            "status": integer,
+            "error": string,
+        })),
+        "Server": object(&["server_version"], json!({
+            "server_version": string,
+            "network": string,
+            "donation_address": string,
+            "operator_contact": string,
+        })),
+        "Transaction": object(
+            &["txid", "version", "locktime", "vin", "vout", "size", "weight", "vsize", "status"],
+            json!({
+                "txid": hash,
+                "version": integer,
+                "locktime": integer,
+                "vin": array_of(schema_ref("TxIn")),
+                "vout": array_of(schema_ref("TxOut")),
+                "size": integer,
+                "weight": integer,
+                "vsize": integer,
+                "stripped_size": integer,
+                "witness_size": integer,
+                "fee": integer,
+                "fee_per_vsize": number,
+                "status": schema_ref("TransactionStatus"),
+                "conflicts": array_of(hash.clone()),
+                "is_final": boolean,
+            }),
+        ),
+        "TxIn": object(
+            &["txid", "vout", "is_coinbase", "scriptsig", "sequence"],
+            json!({
+                "txid": hash,
+                "vout": integer,
+                "is_coinbase": boolean,
+                "prevout": {
+                    "nullable": true,
+                    "allOf": [schema_ref("TxOut")],
+                },
+                "scriptsig": hex_schema(None),
+                "scriptsig_asm": string,
+                "witness": array_of(string.clone()),
+                "sequence": integer,
+                "spend_type": string,
+                "witness_version": integer,
+                "timelocks": array_of(schema_ref("Timelock")),
+            }),
+        ),
+        "TxOut": object(&["scriptpubkey", "scriptpubkey_type"], txout),
+        "Timelock": {
+            "type": "object",
+            "description": "A single one of the properties",
+            "properties": {
+                "absolute_height": integer,
+                "absolute_time": integer,
+                "relative_blocks": integer,
+                "relative_seconds": integer,
+            },
+        },
+        "TransactionStatus": object(&["confirmed"], json!({
+            "confirmed": boolean,
+            "block_height": integer,
+            "block_hash": hash,
+            "block_time": integer,
+            "first_seen": integer,
+        })),
+        "BatchError": object(&["txid", "error"], json!({
+            "txid": string,
+            "error": string,
+        })),
+        "ZeroConf": object(&["txid", "risk", "reasons"], json!({
+            "txid": hash,
+            "risk": { "type": "string", "enum": ["low", "medium", "high"] },
+            "reasons": array_of(string.clone()),
+            "signals_rbf": boolean,
+            "ancestor_signals_rbf": boolean,
+            "feerate": number,
+            "package_feerate": number,
+            "mempool_min_fee": number,
+            "ancestor_count": integer,
+            "ancestor_depth": integer,
+            "conflicts": array_of(hash.clone()),
+        })),
+        "PackageTx": object(&["txid", "vsize"], json!({
+            "txid": hash,
+            "fee": integer,
+            "vsize": integer,
+        })),
+        "Package": object(&["count", "vsize"], json!({
+            "count": integer,
+            "fee": integer,
+            "vsize": integer,
+            "feerate": number,
+        })),
+        "ScriptTypes": {
+            "type": "object",
+            "additionalProperties": integer,
+        },
+        "ChainStats": object(&["tx_count", "txo_count", "utxo_count"], json!({
+            "tx_count": integer,
+            "txo_count": integer,
+            "utxo_count": integer,
+            "store_sizes": { "type": "object", "additionalProperties": integer },
+        })),
+        "MinFees": object(&["relay_fee", "mempool_min_fee"], json!({
+            "relay_fee": number,
+            "mempool_min_fee": number,
+        })),
+        "FeerateHistory": object(&["height", "hash", "time"], json!({
+            "height": integer,
+            "hash": hash,
+            "time": integer,
+            "p10": number,
+            "p50": number,
+            "p90": number,
+        })),
+        "AddressStats": object(&["chain_stats", "mempool_stats"], json!({
+            "address": string,
+            "scripthash": hash,
+            "chain_stats": script_stats,
+            "mempool_stats": script_stats,
+            "label": string,
+            "first_funded_height": integer,
+            "last_active_height": integer,
+        })),
+        "Utxo": object(&["txid", "vout", "status", "maturity"], json!({
+            "txid": hash,
+            "vout": integer,
+            "status": schema_ref("TransactionStatus"),
+            "value": integer,
+            "maturity": { "type": "string", "enum": ["mature", "immature"] },
+            "maturity_height": integer,
+        })),
+        "CoinSelection": object(&["utxos", "algorithm", "total", "fee", "excess"], json!({
+            "utxos": array_of(schema_ref("Utxo")),
+            "algorithm": string,
+            "total": integer,
+            "fee": integer,
+            "excess": integer,
+        })),
+        "OpReturn": object(&["txid", "vout", "status"], json!({
+            "txid": hash,
+            "vout": integer,
+            "status": schema_ref("TransactionStatus"),
+        })),
+        "ChannelLike": object(&["funding", "closing"], json!({
+            "funding": object(&["txid", "vout", "status"], json!({
+                "txid": hash,
+                "vout": integer,
+                "value": integer,
+                "status": schema_ref("TransactionStatus"),
+            })),
+            "closing": object(&["txid", "type", "status"], json!({
+                "txid": hash,
+                "type": string,
+                "status": schema_ref("TransactionStatus"),
+            })),
+        })),
+    })
+}
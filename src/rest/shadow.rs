@@ -0,0 +1,205 @@
+// Compares a sample of the REST responses with the ones of another Esplora-compatible
+// deployment (see `--shadow-compare`), to validate an index or schema change against a
+// known-good instance before switching the traffic over. The sampled requests are replayed by
+// a background thread, so the comparisons never delay the responses.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crossbeam_channel::{self as channel, Sender, TrySendError};
+use hyper::{body::Bytes, Body, Response};
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    config::Config,
+    errors::*,
+    metrics::{CounterVec, MetricOpts, Metrics},
+    util::spawn_thread,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// the comparisons waiting for the other instance, the later ones are dropped
+const QUEUE_SIZE: usize = 1000;
+// the responses that depend on the mempool or the tip, which both instances see at different
+// times, can't be expected to match
+const SKIPPED_PATHS: &[&str] = &["/mempool", "/fee-estimates", "/blocks/tip", "/internal"];
+
+pub(super) struct ShadowCompare {
+    sample: usize,
+    requests: AtomicUsize,
+    queue: Sender<(String, Bytes)>,
+    results: CounterVec,
+}
+
+impl ShadowCompare {
+    pub(super) fn start(config: &Config, metrics: &Metrics) -> Option<Self> {
+        let url = config.shadow_compare_url.clone()?;
+        info!(
+            "comparing 1 of every {} REST responses with {}",
+            config.shadow_compare_sample, url
+        );
+        let results = metrics.counter_vec(
+            MetricOpts::new(
+                "rest_shadow_compare",
+                "# of REST responses compared with the --shadow-compare instance, by result",
+            ),
+            &["result"],
+        );
+        let (queue, rx) = channel::bounded::<(String, Bytes)>(QUEUE_SIZE);
+        let thread_results = results.clone();
+        spawn_thread("shadow-compare", move || {
+            for (path, body) in rx {
+                let result = match fetch(&url, &path) {
+                    Ok(other) => match compare(&body, &other) {
+                        None => "match",
+                        Some(diff) => {
+                            warn!("shadow-compare mismatch for {}: {}", path, diff);
+                            "mismatch"
+                        }
+                    },
+                    Err(e) => {
+                        debug!("shadow-compare failed for {}: {}", path, e);
+                        "error"
+                    }
+                };
+                thread_results.with_label_values(&[result]).inc();
+            }
+        });
+        Some(ShadowCompare {
+            sample: config.shadow_compare_sample,
+            requests: AtomicUsize::new(0),
+            queue,
+            results,
+        })
+    }
+
+    /// Whether the response to a GET request for `path` is to be compared
+    pub(super) fn sampled(&self, path: &str) -> bool {
+        !SKIPPED_PATHS
+            .iter()
+            .any(|skipped| path.starts_with(skipped))
+            && self.requests.fetch_add(1, Ordering::Relaxed) % self.sample == 0
+    }
+
+    /// Queues the comparison of a successful response, which is returned unchanged
+    pub(super) async fn compare(
+        &self,
+        path_and_query: String,
+        resp: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, body) = resp.into_parts();
+        // the responses are built in memory, reading them back cannot fail
+        let body = hyper::body::to_bytes(body)
+            .await
+            .expect("in-memory response body");
+        match self.queue.try_send((path_and_query, body.clone())) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.results.with_label_values(&["dropped"]).inc(),
+            Err(TrySendError::Disconnected(_)) => unreachable!("shadow-compare thread stopped"),
+        }
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+// The body of a successful response. HTTP/1.0 is used so that the body is never chunked, and
+// is read until the connection is closed.
+fn fetch(url: &Url, path_and_query: &str) -> Result<Vec<u8>> {
+    let host = url.host_str().ok_or("missing host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("cannot resolve {}", host))?
+        .next()
+        .ok_or_else(|| format!("no address for {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).chain_err(|| "connect failed")?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .chain_err(|| "cannot set timeouts")?;
+
+    // the path of the other instance's API (e.g. "/api") is prepended
+    let path = format!("{}{}", url.path().trim_end_matches('/'), path_and_query);
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: electrs/{}\r\n\r\n",
+        path,
+        host,
+        env!("CARGO_PKG_VERSION"),
+    );
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| "send failed")?;
+
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .chain_err(|| "receive failed")?;
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("truncated response")?;
+    let status_line = String::from_utf8_lossy(&response[..header_end]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or("invalid response")?;
+    ensure!(status == "200", "responded with status {}", status);
+    Ok(response.split_off(header_end + 4))
+}
+
+// A description of the difference, if any. The JSON responses are compared as values, so that
+// the formatting and the order of the fields don't matter.
+fn compare(ours: &[u8], theirs: &[u8]) -> Option<String> {
+    match (
+        serde_json::from_slice::<Value>(ours),
+        serde_json::from_slice::<Value>(theirs),
+    ) {
+        (Ok(ours), Ok(theirs)) => diff(&ours, &theirs, "$"),
+        _ if ours == theirs => None,
+        _ => Some(format!(
+            "{} bytes differ from {} bytes",
+            ours.len(),
+            theirs.len()
+        )),
+    }
+}
+
+// The first difference found, by its JSON path
+fn diff(ours: &Value, theirs: &Value, path: &str) -> Option<String> {
+    match (ours, theirs) {
+        (Value::Object(ours), Value::Object(theirs)) => {
+            for (key, value) in ours {
+                let found = match theirs.get(key) {
+                    Some(other) => diff(value, other, &format!("{}.{}", path, key)),
+                    None => Some(format!("{}.{} is missing", path, key)),
+                };
+                if found.is_some() {
+                    return found;
+                }
+            }
+            theirs
+                .keys()
+                .find(|key| !ours.contains_key(*key))
+                .map(|key| format!("{}.{} is unexpected", path, key))
+        }
+        (Value::Array(ours), Value::Array(theirs)) if ours.len() == theirs.len() => ours
+            .iter()
+            .zip(theirs)
+            .enumerate()
+            .find_map(|(index, (ours, theirs))| {
+                diff(ours, theirs, &format!("{}[{}]", path, index))
+            }),
+        (Value::Array(ours), Value::Array(theirs)) => Some(format!(
+            "{} has {} items instead of {}",
+            path,
+            ours.len(),
+            theirs.len()
+        )),
+        _ if ours == theirs => None,
+        _ => Some(format!("{} is {} instead of {}", path, ours, theirs)),
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use bitcoin::hashes::hex::ToHex;
+
+use crate::{
+    chain::{BlockHash, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid},
+    util::{
+        block::BlockId,
+        script::{
+            get_innerscripts, get_input_type, get_script_type, get_timelocks, ScriptToAddr,
+            ScriptToAsm, Timelock,
+        },
+    },
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionValue {
+    pub txid: Txid,
+    pub version: u32,
+    pub locktime: u32,
+    pub vin: Vec<TxInValue>,
+    pub vout: Vec<TxOutValue>,
+    pub size: u32,
+    pub weight: u32,
+    pub vsize: u32,
+    /// The size without the witness data
+    pub stripped_size: u32,
+    pub witness_size: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+    /// In sat/vB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_per_vsize: Option<f64>,
+    pub status: TransactionStatus,
+    /// Unconfirmed transactions that were seen spending the same outputs as this one
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub conflicts: Vec<Txid>,
+    /// Whether the locktime of an unconfirmed transaction allows it in the next block
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_final: Option<bool>,
+}
+
+impl TransactionValue {
+    pub fn new(
+        tx: Transaction,
+        blockid: Option<BlockId>,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        fee: Option<u64>,
+        first_seen: Option<u32>,
+        conflicts: Vec<Txid>,
+        network: Network,
+    ) -> Self {
+        let vin = tx
+            .input
+            .iter()
+            .map(|txin| TxInValue::new(txin, prevouts.get(&txin.previous_output), network))
+            .collect();
+        let vout = tx
+            .output
+            .iter()
+            .map(|txout| TxOutValue::new(txout, network))
+            .collect();
+
+        // weight = 3 * stripped size + size, the witness bytes counting once
+        let size = tx.size() as u32;
+        let weight = tx.weight() as u32;
+        let stripped_size = (weight - size) / 3;
+        let vsize = (weight + 3) / 4;
+
+        TransactionValue {
+            txid: tx.txid(),
+            version: tx.version as u32,
+            locktime: tx.lock_time,
+            vin,
+            vout,
+            size,
+            weight,
+            vsize,
+            stripped_size,
+            witness_size: size - stripped_size,
+            fee,
+            fee_per_vsize: fee.map(|fee| fee as f64 / vsize as f64),
+            status: TransactionStatus {
+                first_seen,
+                ..TransactionStatus::from(blockid)
+            },
+            conflicts,
+            is_final: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxInValue {
+    pub txid: Txid,
+    pub vout: u32,
+    pub is_coinbase: bool,
+    /// The spent output, missing for coinbase inputs or when it cannot be found
+    pub prevout: Option<TxOutValue>,
+    pub scriptsig: Script,
+    pub scriptsig_asm: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub witness: Option<Vec<String>>,
+    pub sequence: u32,
+    pub spend_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub witness_version: Option<u8>,
+    /// The timelocks of the redeem or witness script, which encumbered the spent output
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub timelocks: Vec<Timelock>,
+}
+
+impl TxInValue {
+    fn new(txin: &TxIn, prevout: Option<&TxOut>, network: Network) -> Self {
+        #[cfg(not(feature = "liquid"))]
+        let witness = &txin.witness;
+        #[cfg(feature = "liquid")]
+        let witness = &txin.witness.script_witness;
+
+        let witness = if !witness.is_empty() {
+            Some(witness.iter().map(|w| w.to_hex()).collect())
+        } else {
+            None
+        };
+
+        let input_type = get_input_type(txin, prevout);
+        let timelocks = prevout
+            .map(|prevout| {
+                let inner = get_innerscripts(txin, prevout);
+                inner
+                    .redeem_script
+                    .iter()
+                    .chain(inner.witness_script.iter())
+                    .flat_map(get_timelocks)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        TxInValue {
+            txid: txin.previous_output.txid,
+            vout: txin.previous_output.vout,
+            is_coinbase: txin.previous_output.is_null(),
+            prevout: prevout.map(|txo| TxOutValue::new(txo, network)),
+            scriptsig_asm: txin.script_sig.to_asm(),
+            scriptsig: txin.script_sig.clone(),
+            witness,
+            sequence: txin.sequence,
+            spend_type: input_type.spend_type.to_string(),
+            witness_version: input_type.witness_version,
+            timelocks,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TxOutValue {
+    pub scriptpubkey: Script,
+    pub scriptpubkey_asm: String,
+    pub scriptpubkey_type: String,
+    /// The address, or a descriptor for scripts without one
+    pub scriptpubkey_address: String,
+    #[cfg(not(feature = "liquid"))]
+    pub value: u64,
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>,
+    /// The commitment of a blinded value, instead of the value
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valuecommitment: Option<String>,
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+    /// The commitment of a blinded asset, instead of the asset
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assetcommitment: Option<String>,
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rangeproof_size: Option<usize>,
+    #[cfg(feature = "liquid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub surjectionproof_size: Option<usize>,
+    /// Fee outputs have an empty script and an explicit value and asset
+    #[cfg(feature = "liquid")]
+    pub is_fee: bool,
+    /// The timelocks of a bare script, the ones of hashed scripts showing once spent
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub timelocks: Vec<Timelock>,
+    /// The label of the script from `--labels-file`, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub scriptpubkey_label: Option<String>,
+}
+
+impl TxOutValue {
+    fn new(txout: &TxOut, network: Network) -> Self {
+        #[cfg(not(feature = "liquid"))]
+        let value = txout.value;
+        #[cfg(feature = "liquid")]
+        let value = txout.value.explicit();
+
+        TxOutValue {
+            scriptpubkey_asm: txout.script_pubkey.to_asm(),
+            scriptpubkey_type: get_script_type(&txout.script_pubkey).to_string(),
+            scriptpubkey_address: txout.script_pubkey.to_address_or_descriptor(network),
+            scriptpubkey: txout.script_pubkey.clone(),
+            value,
+            #[cfg(feature = "liquid")]
+            valuecommitment: Some(&txout.value)
+                .filter(|value| value.is_confidential())
+                .map(|value| elements::encode::serialize(value).to_hex()),
+            #[cfg(feature = "liquid")]
+            asset: txout.asset.explicit().map(|asset| asset.to_string()),
+            #[cfg(feature = "liquid")]
+            assetcommitment: Some(&txout.asset)
+                .filter(|asset| asset.is_confidential())
+                .map(|asset| elements::encode::serialize(asset).to_hex()),
+            #[cfg(feature = "liquid")]
+            rangeproof_size: txout.witness.rangeproof.as_ref().map(|proof| proof.len()),
+            #[cfg(feature = "liquid")]
+            surjectionproof_size: txout
+                .witness
+                .surjection_proof
+                .as_ref()
+                .map(|proof| proof.len()),
+            #[cfg(feature = "liquid")]
+            is_fee: txout.is_fee(),
+            timelocks: get_timelocks(&txout.script_pubkey),
+            scriptpubkey_label: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransactionStatus {
+    pub confirmed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<BlockHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_time: Option<u32>,
+    /// When the transaction was first seen in the mempool, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<u32>,
+}
+
+impl From<Option<BlockId>> for TransactionStatus {
+    fn from(blockid: Option<BlockId>) -> TransactionStatus {
+        match blockid {
+            Some(b) => TransactionStatus {
+                confirmed: true,
+                block_height: Some(b.height),
+                block_hash: Some(b.hash),
+                block_time: Some(b.time),
+                first_seen: None,
+            },
+            None => TransactionStatus {
+                confirmed: false,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+                first_seen: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Checks of the user input received by the REST server (the request bodies, the path segments
+// and the query parameters) so that malformed input is answered with a 4xx error, and never
+// reaches the handlers.
+
+use std::{collections::HashMap, str::FromStr};
+
+use error_chain::ChainedError;
+use hyper::{body::HttpBody, Body, Method, Response, StatusCode};
+
+use crate::{
+    chain::{BlockHash, OutPoint, Txid},
+    errors,
+    util::{full_hash, FullHash},
+};
+
+#[cfg(feature = "liquid")]
+use crate::chain::AssetId;
+
+// the POST endpoints take batches of txids or addresses
+const MAX_POST_BODY: usize = 256 * 1024;
+// the other endpoints don't read their body, but some clients send an empty json document
+const MAX_OTHER_BODY: usize = 1024;
+
+/// The largest request body accepted for a request method
+pub(super) fn body_limit(method: &Method) -> usize {
+    match *method {
+        Method::POST => MAX_POST_BODY,
+        _ => MAX_OTHER_BODY,
+    }
+}
+
+/// Reads the whole request body, refusing the ones larger than `limit`
+pub(super) async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, HttpError> {
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| format!("failed to read the request body: {}", e))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(HttpError(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body larger than {} bytes", limit),
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+pub(super) fn parse_txid(txid: &str) -> Result<Txid, HttpError> {
+    Txid::from_str(txid).map_err(|_| HttpError::from("Invalid txid"))
+}
+
+#[cfg(feature = "liquid")]
+pub(super) fn parse_asset_id(asset_id: &str) -> Result<AssetId, HttpError> {
+    AssetId::from_str(asset_id).map_err(|_| HttpError::from("Invalid asset id"))
+}
+
+pub(super) fn parse_blockhash(hash: &str) -> Result<BlockHash, HttpError> {
+    BlockHash::from_str(hash).map_err(|_| HttpError::from("Invalid block hash"))
+}
+
+/// An outpoint formatted as `<txid>:<vout>`
+pub(super) fn parse_outpoint(outpoint: &str) -> Result<OutPoint, HttpError> {
+    OutPoint::from_str(outpoint).map_err(|_| HttpError::from("Invalid outpoint"))
+}
+
+pub(super) fn parse_hex(hex: &str) -> Result<Vec<u8>, HttpError> {
+    hex::decode(hex).map_err(|_| HttpError::from("Invalid hex string"))
+}
+
+pub(super) fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
+    let bytes = parse_hex(scripthash)?;
+    if bytes.len() != 32 {
+        return Err(HttpError::from("Invalid scripthash"));
+    }
+    Ok(full_hash(&bytes))
+}
+
+/// A block height, which the chain doesn't need to have reached
+pub(super) fn parse_height(height: &str) -> Result<usize, HttpError> {
+    // heights are stored as u32, larger numbers can't be valid
+    let height: u32 = parse_param("block height", height)?;
+    Ok(height as usize)
+}
+
+/// A number (or any value parsed with `FromStr`), named in the error
+pub(super) fn parse_param<T: FromStr>(name: &str, value: &str) -> Result<T, HttpError> {
+    value
+        .parse()
+        .map_err(|_| HttpError::from(format!("invalid {}", name)))
+}
+
+/// Refuses the values over `max`
+pub(super) fn check_max<T: PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: T,
+    max: T,
+) -> Result<T, HttpError> {
+    if value > max {
+        return Err(HttpError::from(format!("{} must be at most {}", name, max)));
+    }
+    Ok(value)
+}
+
+/// The parameters of the query string, e.g. `?amount=1000&feerate=2`
+pub(super) struct QueryParams<'a>(HashMap<&'a str, &'a str>);
+
+impl<'a> QueryParams<'a> {
+    pub(super) fn new(uri: &'a hyper::Uri) -> Self {
+        QueryParams(
+            uri.query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|param| param.split_once('='))
+                .collect(),
+        )
+    }
+
+    /// The parsed parameter, or None when it's not in the query string
+    pub(super) fn get<T: FromStr>(&self, name: &str) -> Result<Option<T>, HttpError> {
+        self.0
+            .get(name)
+            .map(|value| parse_param(name, value))
+            .transpose()
+    }
+
+    pub(super) fn require<T: FromStr>(&self, name: &str) -> Result<T, HttpError> {
+        self.get(name)?
+            .ok_or_else(|| HttpError::from(format!("missing {}", name)))
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct HttpError(pub StatusCode, pub String);
+
+impl HttpError {
+    pub(super) fn not_found(msg: String) -> Self {
+        HttpError(StatusCode::NOT_FOUND, msg)
+    }
+
+    /// The error as a json document, e.g. `{"status":400,"error":"Invalid txid"}`
+    pub(super) fn into_response(self) -> Response<Body> {
+        let body = json!({ "status": self.0.as_u16(), "error": self.1 });
+        let mut resp = Response::builder()
+            .status(self.0)
+            .header("Content-Type", "application/json");
+        if self.0 == StatusCode::TOO_MANY_REQUESTS {
+            // the rate quotas are per second
+            resp = resp.header("Retry-After", "1");
+        }
+        resp.body(Body::from(body.to_string())).unwrap()
+    }
+}
+
+impl From<String> for HttpError {
+    fn from(msg: String) -> Self {
+        HttpError(StatusCode::BAD_REQUEST, msg)
+    }
+}
+impl From<&str> for HttpError {
+    fn from(msg: &str) -> Self {
+        HttpError(StatusCode::BAD_REQUEST, msg.to_string())
+    }
+}
+impl From<bitcoin::hashes::hex::Error> for HttpError {
+    fn from(_e: bitcoin::hashes::hex::Error) -> Self {
+        HttpError::from("Invalid hex string".to_string())
+    }
+}
+impl From<errors::Error> for HttpError {
+    fn from(e: errors::Error) -> Self {
+        // bad input rather than a failure of ours, not worth a warning
+        match e.kind() {
+            errors::ErrorKind::InvalidAddress(_) => return HttpError::from(e.to_string()),
+            errors::ErrorKind::NotIndexed(_) => return HttpError(StatusCode::GONE, e.to_string()),
+            errors::ErrorKind::DbInconsistency(_) => {
+                error!("errors::Error: {}", e.display_chain());
+                return HttpError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+            _ => (),
+        }
+        warn!("errors::Error: {:?}", e);
+        HttpError::from(e.to_string())
+    }
+}
+impl From<serde_json::Error> for HttpError {
+    fn from(e: serde_json::Error) -> Self {
+        HttpError::from(e.to_string())
+    }
+}
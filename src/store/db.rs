@@ -1,10 +1,35 @@
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path};
 
 use rocksdb;
 
-use crate::{config::Config, util::Bytes};
-
-static DB_VERSION: u32 = 1;
+use crate::{
+    config::{Config, DbCompression, Mode},
+    store::{filter_spent_utxos, merge_rows},
+    util::{request_id, Bytes},
+};
+
+static DB_VERSION: u32 = 4;
+
+// The transactions, confirmations and outputs rows are keyed by a txid, which their per-txid
+// bloom filters check first, so that lookups of txids that aren't indexed skip the disk reads
+const TXID_KEYED_CODES: &[u8] = b"TCO";
+const TXID_PREFIX_LEN: usize = 1 + 32;
+const BLOOM_BITS_PER_KEY: i32 = 10;
+// the rows of each row type read by `usage_by_code`, the usage of the others is extrapolated
+const USAGE_SAMPLE_ROWS: u64 = 100_000;
+// the zstd dictionaries are trained on about this many times their size of sampled data
+#[cfg(not(feature = "oldcpu"))]
+const ZSTD_TRAINING_RATIO: usize = 100;
+
+/// The uncompressed size of a set of rows
+#[derive(Debug, Default)]
+pub struct RowsUsage {
+    pub rows: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// Whether all the rows were read, rather than extrapolated from a sample of them
+    pub exhaustive: bool,
+}
 
 pub struct DBRow {
     pub key: Vec<u8>,
@@ -78,17 +103,30 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
 #[derive(Debug)]
 pub struct DB {
     db: rocksdb::DB,
+    // with `--mode serve-only`, writes are skipped
+    read_only: bool,
 }
 
 impl DB {
-    pub fn open(path: &Path, config: &Config) -> Self {
+    /// Opens the DB at `path`, with its older files moved to `archive_path` once the ones at
+    /// `path` exceed `--txstore-hot-size`
+    pub fn open(path: &Path, archive_path: Option<&Path>, config: &Config) -> Self {
         debug!("opening DB at {:?}", path);
 
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
         db_opts.set_max_open_files(100_000); // make sure to `limit -n` this process correctly
         db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+        // the DBs are named after their directory
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        set_compression(
+            &mut db_opts,
+            config.db_compression(name),
+            config.zstd_dict_size,
+        );
         db_opts.set_target_file_size_base(1_073_741_824);
         db_opts.set_write_buffer_size(256 << 20);
         db_opts.set_disable_auto_compactions(true);
@@ -96,20 +134,132 @@ impl DB {
         // db_opts.set_advise_random_on_open(???);
         db_opts.set_compaction_readahead_size(1 << 20);
         db_opts.increase_parallelism(2);
+        #[cfg(not(feature = "oldcpu"))]
+        db_opts.set_merge_operator_associative("electrs_rows", merge_rows);
+        #[cfg(feature = "oldcpu")]
+        db_opts.set_merge_operator("electrs_rows", merge_rows, None);
+        db_opts.set_compaction_filter("spent_utxos", filter_spent_utxos);
+
+        db_opts.set_prefix_extractor(rocksdb::SliceTransform::create(
+            "txid_prefix",
+            txid_prefix,
+            Some(has_txid_prefix),
+        ));
+        db_opts.set_memtable_prefix_bloom_ratio(0.1);
+        let mut table_opts = rocksdb::BlockBasedOptions::default();
+        table_opts.set_bloom_filter(BLOOM_BITS_PER_KEY, false);
+        db_opts.set_block_based_table_factory(&table_opts);
+
+        // RocksDB writes the files of each level to the first path with room left, so the
+        // upper levels (the recent writes) stay at `path` and the last ones spill over
+        if let Some(archive_path) = archive_path {
+            info!(
+                "keeping {} MB of {:?} on the hot path, the rest in {:?}",
+                config.txstore_hot_size >> 20,
+                path,
+                archive_path
+            );
+            let paths = vec![
+                rocksdb::DBPath::new(path, config.txstore_hot_size).expect("invalid DB path"),
+                rocksdb::DBPath::new(archive_path, u64::MAX)
+                    .expect("invalid --txstore-archive-dir"),
+            ];
+            db_opts.set_db_paths(&paths);
+        }
 
-        let db = Self {
-            db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
+        let read_only = matches!(config.mode, Mode::ServeOnly | Mode::DbStats);
+        let db = if read_only {
+            // fails when the DB doesn't exist, as it isn't created
+            rocksdb::DB::open_for_read_only(&db_opts, path, false)
+                .unwrap_or_else(|e| panic!("failed to open RocksDB {:?} for serving: {}", path, e))
+        } else {
+            rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB")
         };
+        let db = Self { db, read_only };
         db.verify_compatibility(config);
 
         db
     }
 
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        trace_request("get", key);
         self.db.get(key).unwrap().map(|v| v.to_vec())
     }
 
+    /// The values of `keys`, read in a single batch
+    pub fn multi_get(&self, keys: &[Bytes]) -> Vec<Option<Bytes>> {
+        self.db
+            .multi_get(keys)
+            .into_iter()
+            .map(|value| value.unwrap())
+            .collect()
+    }
+
+    /// The size of the DB's files on disk, in bytes
+    pub fn size(&self) -> u64 {
+        self.db
+            .property_int_value("rocksdb.total-sst-files-size")
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    /// RocksDB's estimate of the number of keys
+    pub fn estimate_num_keys(&self) -> u64 {
+        self.db
+            .property_int_value("rocksdb.estimate-num-keys")
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    /// The size of the rows of each row type (the first byte of their keys). Only the first
+    /// `USAGE_SAMPLE_ROWS` rows of each type are read, the usage of the bigger types is
+    /// extrapolated from the share of their key space that these rows cover. This is only
+    /// accurate for the keys starting with a hash after their code (most row types).
+    pub fn usage_by_code(&self) -> BTreeMap<u8, RowsUsage> {
+        let mut usage: BTreeMap<u8, RowsUsage> = BTreeMap::new();
+        let mut next_code = Some(0u8);
+        while let Some(start) = next_code {
+            let code = match self
+                .db
+                .iterator_opt(
+                    rocksdb::IteratorMode::From(&[start], rocksdb::Direction::Forward),
+                    scan_opts(b""),
+                )
+                .next()
+            {
+                Some((key, _)) => key[0],
+                None => break,
+            };
+            let entry = usage.entry(code).or_default();
+            entry.exhaustive = true;
+            let mut last_key = vec![];
+            for row in self.iter_scan(&[code]) {
+                if entry.rows == USAGE_SAMPLE_ROWS {
+                    entry.exhaustive = false;
+                    break;
+                }
+                entry.rows += 1;
+                entry.key_bytes += row.key.len() as u64;
+                entry.value_bytes += row.value.len() as u64;
+                last_key = row.key;
+            }
+            if !entry.exhaustive {
+                let scale = 1.0 / keyspace_share(&last_key[1..]);
+                entry.rows = (entry.rows as f64 * scale) as u64;
+                entry.key_bytes = (entry.key_bytes as f64 * scale) as u64;
+                entry.value_bytes = (entry.value_bytes as f64 * scale) as u64;
+            }
+            next_code = code.checked_add(1);
+        }
+        usage
+    }
+
     pub fn full_compaction(&self) {
+        if self.read_only {
+            return;
+        }
         // TODO: make sure this doesn't fail silently
         debug!("starting full compaction on {:?}", self.db);
         self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
@@ -117,14 +267,43 @@ impl DB {
     }
 
     pub fn enable_auto_compaction(&self) {
+        if self.read_only {
+            return;
+        }
         let opts = [("disable_auto_compactions", "false")];
         self.db.set_options(&opts).unwrap();
     }
 
-    pub fn write(&self, mut rows: Vec<DBRow>, flush: DBFlush) {
+    pub fn write(&self, rows: Vec<DBRow>, flush: DBFlush) {
+        self.write_with_merges(rows, vec![], flush)
+    }
+
+    /// Atomically write `rows` and merge the `merges` operands into their existing values
+    pub fn write_with_merges(&self, rows: Vec<DBRow>, merges: Vec<DBRow>, flush: DBFlush) {
+        self.write_batch(rows, merges, vec![], flush)
+    }
+
+    /// Atomically write `rows`, merge the `merges` operands and delete the `deletes` keys
+    pub fn write_batch(
+        &self,
+        mut rows: Vec<DBRow>,
+        merges: Vec<DBRow>,
+        deletes: Vec<Bytes>,
+        flush: DBFlush,
+    ) {
+        if self.read_only {
+            trace!(
+                "skipping write of {} rows to read-only {:?}",
+                rows.len(),
+                self.db
+            );
+            return;
+        }
         debug!(
-            "writing {} rows to {:?}, flush={:?}",
+            "writing {} rows, {} merges and {} deletes to {:?}, flush={:?}",
             rows.len(),
+            merges.len(),
+            deletes.len(),
             self.db,
             flush
         );
@@ -136,6 +315,18 @@ impl DB {
             #[cfg(feature = "oldcpu")]
             batch.put(&row.key, &row.value).unwrap();
         }
+        for row in merges {
+            #[cfg(not(feature = "oldcpu"))]
+            batch.merge(&row.key, &row.value);
+            #[cfg(feature = "oldcpu")]
+            batch.merge(&row.key, &row.value).unwrap();
+        }
+        for key in deletes {
+            #[cfg(not(feature = "oldcpu"))]
+            batch.delete(&key);
+            #[cfg(feature = "oldcpu")]
+            batch.delete(&key).unwrap();
+        }
         let do_flush = match flush {
             DBFlush::Enable => true,
             DBFlush::Disable => false,
@@ -146,33 +337,62 @@ impl DB {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
+    pub fn delete(&self, keys: Vec<Bytes>) {
+        if self.read_only {
+            return;
+        }
+        debug!("deleting {} rows from {:?}", keys.len(), self.db);
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            #[cfg(not(feature = "oldcpu"))]
+            batch.delete(&key);
+            #[cfg(feature = "oldcpu")]
+            batch.delete(&key).unwrap();
+        }
+        self.db.write(batch).unwrap();
+    }
+
     pub fn flush(&self) {
+        if self.read_only {
+            return;
+        }
         self.db.flush().unwrap();
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) {
+        if self.read_only {
+            return;
+        }
         self.db.put(key, value).unwrap()
     }
 
     pub fn put_sync(&self, key: &[u8], value: &[u8]) {
+        if self.read_only {
+            return;
+        }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         self.db.put_opt(key, value, &opts).unwrap();
     }
 
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
+        trace_request("iter_scan", prefix);
         ScanIterator {
             prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
+            iter: self.db.iterator_opt(
+                rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward),
+                scan_opts(prefix),
+            ),
             done: false,
         }
     }
 
     pub fn iter_scan_from(&self, prefix: &[u8], start_at: &[u8]) -> ScanIterator {
-        let iter = self.db.iterator(rocksdb::IteratorMode::From(
-            start_at,
-            rocksdb::Direction::Forward,
-        ));
+        trace_request("iter_scan_from", start_at);
+        let iter = self.db.iterator_opt(
+            rocksdb::IteratorMode::From(start_at, rocksdb::Direction::Forward),
+            scan_opts(prefix),
+        );
 
         ScanIterator {
             prefix: prefix.to_vec(),
@@ -181,6 +401,18 @@ impl DB {
         }
     }
 
+    pub fn iter_scan_reverse(&self, prefix: &[u8], prefix_max: &[u8]) -> ReverseScanIterator {
+        trace_request("iter_scan_reverse", prefix_max);
+        let mut iter = self.db.raw_iterator_opt(scan_opts(prefix));
+        iter.seek_for_prev(prefix_max);
+
+        ReverseScanIterator {
+            prefix: prefix.to_vec(),
+            iter,
+            done: false,
+        }
+    }
+
     fn verify_compatibility(&self, config: &Config) {
         let mut compatibility_bytes = bincode::serialize(&DB_VERSION).unwrap();
 
@@ -192,6 +424,24 @@ impl DB {
             compatibility_bytes.push(1)
         }
 
+        if config.utxo_accumulator {
+            compatibility_bytes.push(2)
+        }
+
+        if config.intern_scripts {
+            compatibility_bytes.push(3)
+        }
+
+        if config.script_activity {
+            compatibility_bytes.push(5)
+        }
+
+        if config.index_start_height > 0 {
+            compatibility_bytes.push(4);
+            compatibility_bytes
+                .extend_from_slice(&(config.index_start_height as u32).to_be_bytes());
+        }
+
         match self.get(b"V") {
             None => self.put(b"V", &compatibility_bytes),
             Some(ref x) if x != &compatibility_bytes => {
@@ -201,3 +451,75 @@ impl DB {
         }
     }
 }
+
+// With zstd, the two upper levels, which are rewritten the most, use the faster lz4
+fn set_compression(db_opts: &mut rocksdb::Options, compression: DbCompression, dict_size: usize) {
+    use rocksdb::DBCompressionType as Type;
+    match compression {
+        DbCompression::None => db_opts.set_compression_type(Type::None),
+        DbCompression::Snappy => db_opts.set_compression_type(Type::Snappy),
+        DbCompression::Lz4 => db_opts.set_compression_type(Type::Lz4),
+        DbCompression::Zstd => {
+            db_opts.set_compression_type(Type::Zstd);
+            db_opts.set_compression_per_level(&[
+                Type::Lz4,
+                Type::Lz4,
+                Type::Zstd,
+                Type::Zstd,
+                Type::Zstd,
+                Type::Zstd,
+                Type::Zstd,
+            ]);
+            if dict_size > 0 {
+                // the default window bits, level and strategy
+                #[cfg(not(feature = "oldcpu"))]
+                {
+                    db_opts.set_compression_options(-14, 32767, 0, dict_size as i32);
+                    db_opts.set_zstd_max_train_bytes((dict_size * ZSTD_TRAINING_RATIO) as i32);
+                }
+                #[cfg(feature = "oldcpu")]
+                warn!("the zstd dictionaries are not supported with the oldcpu feature");
+            }
+        }
+    }
+}
+
+// Relate the DB reads to the request they were made for
+fn trace_request(op: &str, key: &[u8]) {
+    if let Some(id) = request_id::current() {
+        trace!(
+            "db op={} key={} request_id={}",
+            op,
+            hex::encode(&key[..key.len().min(33)]),
+            id
+        );
+    }
+}
+
+fn has_txid_prefix(key: &[u8]) -> bool {
+    key.len() >= TXID_PREFIX_LEN && TXID_KEYED_CODES.contains(&key[0])
+}
+
+fn txid_prefix(key: &[u8]) -> &[u8] {
+    &key[..TXID_PREFIX_LEN]
+}
+
+// Scans within a single txid use its bloom filters, while the other scans need a total order
+// seek to see past the prefixes of the txid-keyed rows
+// The share of the key space (of uniformly distributed keys) up to `key` included
+fn keyspace_share(key: &[u8]) -> f64 {
+    let mut position = [0u8; 8];
+    let len = key.len().min(8);
+    position[..len].copy_from_slice(&key[..len]);
+    (u64::from_be_bytes(position) as f64 + 1.0) / 2f64.powi(64)
+}
+
+fn scan_opts(prefix: &[u8]) -> rocksdb::ReadOptions {
+    let mut opts = rocksdb::ReadOptions::default();
+    if has_txid_prefix(prefix) {
+        opts.set_prefix_same_as_start(true);
+    } else {
+        opts.set_total_order_seek(true);
+    }
+    opts
+}
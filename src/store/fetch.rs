@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
-use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc::Receiver, Arc, Condvar, Mutex};
+use std::time::Instant;
 use std::{fs, thread};
 
 use bitcoin::consensus::encode::{deserialize, Decodable};
@@ -9,8 +10,13 @@ use bitcoin::{Block, BlockHash};
 use rayon::prelude::*;
 
 use crate::errors::*;
+use crate::metrics::{FloatGauge, GaugeVec, MetricOpts, Metrics};
 use crate::util::{spawn_thread, SyncChannel};
-use crate::{daemon::Daemon, util::block::HeaderEntry};
+use crate::{
+    config::BatchBounds,
+    daemon::{BatchSize, Daemon},
+    util::block::HeaderEntry,
+};
 
 pub type SizedBlock = (Block, u32);
 
@@ -26,25 +32,34 @@ pub struct BlockEntry {
     pub size: u32,
 }
 
+impl BlockEntry {
+    fn memory_size(&self) -> usize {
+        self.size as usize
+    }
+}
+
 pub fn start_fetcher(
     from: FetchFrom,
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    batch_bounds: BatchBounds,
+    memory: &Arc<SyncMemory>,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
-    let fetcher = match from {
-        FetchFrom::Bitcoind => bitcoind_fetcher,
-        FetchFrom::BlkFiles => blkfiles_fetcher,
-    };
-    fetcher(daemon, new_headers)
+    let memory = Arc::clone(memory);
+    match from {
+        FetchFrom::Bitcoind => bitcoind_fetcher(daemon, new_headers, batch_bounds, memory),
+        FetchFrom::BlkFiles => blkfiles_fetcher(daemon, new_headers, memory),
+    }
 }
 
+/// The items produced by a thread, each accounted in the sync memory until it is consumed
 pub struct Fetcher<T> {
-    receiver: Receiver<T>,
+    receiver: Receiver<(T, MemoryHold)>,
     thread: thread::JoinHandle<()>,
 }
 
 impl<T> Fetcher<T> {
-    pub fn from(receiver: Receiver<T>, thread: thread::JoinHandle<()>) -> Self {
+    pub fn from(receiver: Receiver<(T, MemoryHold)>, thread: thread::JoinHandle<()>) -> Self {
         Self { receiver, thread }
     }
 
@@ -52,7 +67,8 @@ impl<T> Fetcher<T> {
     where
         F: FnMut(T),
     {
-        for item in self.receiver {
+        // the memory is released once the item is processed
+        for (item, _hold) in self.receiver {
             func(item);
         }
 
@@ -60,12 +76,127 @@ impl<T> Fetcher<T> {
     }
 }
 
+/// The bytes held by each stage of an index update (the fetched blocks, the blk*.dat files being
+/// parsed, the rows being written...), with a high-water mark above which the fetchers wait for
+/// the indexer to catch up, instead of running out of memory.
+pub struct SyncMemory {
+    stages: Mutex<HashMap<&'static str, usize>>,
+    released: Condvar,
+    // 0 for no limit
+    high_water_mark: usize,
+    bytes: GaugeVec,
+    paused: FloatGauge,
+}
+
+/// Bytes accounted to a stage until dropped
+pub struct MemoryHold {
+    memory: Arc<SyncMemory>,
+    stage: &'static str,
+    bytes: usize,
+}
+
+// The blocks waiting for their ancestors in the blk*.dat files are only released by parsing
+// more files, so they can't pause the parser without deadlocking it
+const UNPAUSABLE_STAGE: &str = "blkfiles_pending";
+// Past this, the next block is fetched from bitcoind rather than waited for in the blk*.dat
+// files, which a node that synced headers-first may have stored far from its parent
+const MAX_PENDING_BYTES: usize = 1 << 30;
+
+impl SyncMemory {
+    pub fn new(high_water_mark: usize, metrics: &Metrics) -> Arc<Self> {
+        Arc::new(SyncMemory {
+            stages: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+            high_water_mark,
+            bytes: metrics.gauge_vec(
+                MetricOpts::new(
+                    "sync_memory_bytes",
+                    "Memory held by the blocks and rows of the index update, by stage (estimated)",
+                ),
+                &["stage"],
+            ),
+            paused: metrics.float_gauge(MetricOpts::new(
+                "sync_fetch_paused_seconds",
+                "Time the block fetchers spent paused above the sync memory high-water mark",
+            )),
+        })
+    }
+
+    pub fn hold(self: &Arc<Self>, stage: &'static str, bytes: usize) -> MemoryHold {
+        let mut hold = MemoryHold {
+            memory: Arc::clone(self),
+            stage,
+            bytes: 0,
+        };
+        hold.resize(bytes);
+        hold
+    }
+
+    /// Blocks while the pausable stages hold more than the high-water mark
+    pub fn wait_below_high_water_mark(&self) {
+        if self.high_water_mark == 0 {
+            return;
+        }
+        let start = Instant::now();
+        let mut stages = self.stages.lock().unwrap();
+        while pausable_bytes(&stages) > self.high_water_mark {
+            stages = self.released.wait(stages).unwrap();
+        }
+        let paused = start.elapsed().as_secs_f64();
+        if paused > 1.0 {
+            debug!(
+                "fetching paused for {:.1}s above the memory high-water mark",
+                paused
+            );
+        }
+        self.paused.add(paused);
+    }
+
+    fn update(&self, stage: &'static str, old_bytes: usize, new_bytes: usize) {
+        let mut stages = self.stages.lock().unwrap();
+        let held = stages.entry(stage).or_default();
+        *held = *held - old_bytes + new_bytes;
+        self.bytes.with_label_values(&[stage]).set(*held as f64);
+        if new_bytes < old_bytes {
+            self.released.notify_all();
+        }
+    }
+}
+
+fn pausable_bytes(stages: &HashMap<&'static str, usize>) -> usize {
+    stages
+        .iter()
+        .filter(|(stage, _)| **stage != UNPAUSABLE_STAGE)
+        .map(|(_, bytes)| bytes)
+        .sum()
+}
+
+impl MemoryHold {
+    pub fn resize(&mut self, bytes: usize) {
+        self.memory.update(self.stage, self.bytes, bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for MemoryHold {
+    fn drop(&mut self) {
+        self.resize(0);
+    }
+}
+
 fn bitcoind_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    batch_bounds: BatchBounds,
+    memory: Arc<SyncMemory>,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     if let Some(tip) = new_headers.last() {
-        debug!("{:?} ({} left to index", tip, new_headers.len());
+        debug!(
+            "fetching up to height={} blockhash={} ({} left to index)",
+            tip.height(),
+            tip.hash(),
+            new_headers.len()
+        );
     }
 
     let daemon = daemon.reconnect()?;
@@ -75,8 +206,15 @@ fn bitcoind_fetcher(
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("bitcoind_fetcher", move || {
-            for entries in new_headers.chunks(100) {
+            // starting small, as the recent blocks may be large
+            let mut batch_size = BatchSize::new(batch_bounds.min, batch_bounds);
+            let mut remaining = &new_headers[..];
+            while !remaining.is_empty() {
+                let (entries, rest) = remaining.split_at(batch_size.get().min(remaining.len()));
+                remaining = rest;
+                memory.wait_below_high_water_mark();
                 let blockhashes: Vec<BlockHash> = entries.iter().map(|he| *he.hash()).collect();
+                let start = Instant::now();
                 let blocks = daemon
                     .getblocks(&blockhashes)
                     .expect("failed to get blocks from bitcoind");
@@ -93,8 +231,11 @@ fn bitcoind_fetcher(
                     .collect();
                 assert_eq!(block_entries.len(), entries.len());
 
+                let bytes = block_entries.iter().map(BlockEntry::memory_size).sum();
+                batch_size.observe(entries.len(), bytes, start.elapsed());
+                let hold = memory.hold("fetched", bytes);
                 sender
-                    .send(block_entries)
+                    .send((block_entries, hold))
                     .expect("failed to send fetched blocks");
             }
         }),
@@ -104,85 +245,163 @@ fn bitcoind_fetcher(
 fn blkfiles_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
+    memory: Arc<SyncMemory>,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     let magic = daemon.magic();
 
     let blk_files = daemon.list_block_files()?;
+    let xor_key = daemon.block_files_xor_key()?;
+    if xor_key.is_some() {
+        debug!("de-obfuscating blk*.dat files");
+    }
 
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
 
-    let mut entry_map: HashMap<BlockHash, HeaderEntry> =
-        new_headers.into_iter().map(|h| (*h.hash(), h)).collect();
-
-    let parser = blkfiles_parser(blkfiles_reader(blk_files), magic);
+    let parser = blkfiles_parser(blk_files, xor_key, magic, Arc::clone(&memory));
+    let daemon = daemon.reconnect()?;
 
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("blkfiles_fetcher", move || {
+            // blocks are stored in blk*.dat files in the order they were downloaded, so keep
+            // them by hash until all of their ancestors were sent
+            let mut wanted: HashSet<BlockHash> = new_headers.iter().map(|h| *h.hash()).collect();
+            let mut pending: HashMap<BlockHash, SizedBlock> = HashMap::new();
+            let mut pending_hold = memory.hold(UNPAUSABLE_STAGE, 0);
+            let mut pending_bytes = 0;
+            let mut next = 0;
+
             parser.each(|sizedblocks| {
-                let block_entries: Vec<BlockEntry> = sizedblocks
-                    .into_iter()
-                    .filter_map(|(block, size)| {
-                        let blockhash = block.block_hash();
-                        entry_map
-                            .remove(&blockhash)
-                            .map(|entry| BlockEntry { block, entry, size })
-                            .or_else(|| {
-                                trace!("skipping block {}", blockhash);
-                                None
-                            })
-                    })
-                    .collect();
-                trace!("fetched {} blocks", block_entries.len());
-                sender
-                    .send(block_entries)
-                    .expect("failed to send blocks entries from blk*.dat files");
+                for (block, size) in sizedblocks {
+                    let blockhash = block.block_hash();
+                    if wanted.contains(&blockhash) && !pending.contains_key(&blockhash) {
+                        pending_bytes += size as usize;
+                        pending.insert(blockhash, (block, size));
+                    } else {
+                        trace!("skipping block {}", blockhash);
+                    }
+                }
+
+                let mut block_entries = vec![];
+                while let Some(entry) = new_headers.get(next) {
+                    let (block, size) = match pending.remove(entry.hash()) {
+                        Some((block, size)) => {
+                            pending_bytes -= size as usize;
+                            (block, size)
+                        }
+                        None if pending_bytes > MAX_PENDING_BYTES => {
+                            debug!(
+                                "{} bytes of blocks pending, fetching block {} from bitcoind",
+                                pending_bytes,
+                                entry.hash()
+                            );
+                            let block = daemon
+                                .getblocks(&[*entry.hash()])
+                                .expect("failed to get block from bitcoind")
+                                .remove(0);
+                            let size = block.size() as u32;
+                            (block, size)
+                        }
+                        None => break,
+                    };
+                    // skipped if found again in the files
+                    wanted.remove(entry.hash());
+                    block_entries.push(BlockEntry {
+                        block,
+                        entry: entry.clone(),
+                        size,
+                    });
+                    next += 1;
+                }
+                pending_hold.resize(pending_bytes);
+                trace!(
+                    "fetched {} blocks ({} pending)",
+                    block_entries.len(),
+                    pending.len()
+                );
+                if !block_entries.is_empty() {
+                    let bytes = block_entries.iter().map(BlockEntry::memory_size).sum();
+                    let hold = memory.hold("fetched", bytes);
+                    sender
+                        .send((block_entries, hold))
+                        .expect("failed to send blocks entries from blk*.dat files");
+                }
             });
-            if !entry_map.is_empty() {
+            if next < new_headers.len() {
                 panic!(
                     "failed to index {} blocks from blk*.dat files",
-                    entry_map.len()
+                    new_headers.len() - next
                 )
             }
         }),
     ))
 }
 
-fn blkfiles_reader(blk_files: Vec<PathBuf>) -> Fetcher<Vec<u8>> {
-    let chan = SyncChannel::new(1);
-    let sender = chan.sender();
-
-    Fetcher::from(
-        chan.into_receiver(),
-        spawn_thread("blkfiles_reader", move || {
-            for path in blk_files {
-                trace!("reading {:?}", path);
-                let blob = fs::read(&path)
-                    .unwrap_or_else(|e| panic!("failed to  read {:?}: {:?}", path, e));
+fn read_blob(path: &Path, xor_key: Option<&[u8]>) -> Vec<u8> {
+    trace!("reading {:?}", path);
+    let mut blob = fs::read(path).unwrap_or_else(|e| panic!("failed to  read {:?}: {:?}", path, e));
+    if let Some(key) = xor_key {
+        xor_blob(&mut blob, key);
+    }
+    blob
+}
 
-                sender
-                    .send(blob)
-                    .unwrap_or_else(|_| panic!("failed to send: {:?} contents", path));
-            }
-        }),
-    )
+// Each file is XOR-ed with the key repeated from its start
+fn xor_blob(blob: &mut [u8], key: &[u8]) {
+    for (byte, k) in blob.iter_mut().zip(key.iter().cycle()) {
+        *byte ^= k;
+    }
 }
 
-fn blkfiles_parser(blobs: Fetcher<Vec<u8>>, magic: u32) -> Fetcher<Vec<SizedBlock>> {
+// Read and parse several blk*.dat files concurrently, sending their blocks in file order
+fn blkfiles_parser(
+    blk_files: Vec<PathBuf>,
+    xor_key: Option<Vec<u8>>,
+    magic: u32,
+    memory: Arc<SyncMemory>,
+) -> Fetcher<Vec<SizedBlock>> {
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
 
     Fetcher::from(
         chan.into_receiver(),
         spawn_thread("blkfiles_parser", move || {
-            blobs.each(|blob| {
-                trace!("parsing {} bytes", blob.len());
-                let blocks = parse_blocks(blob, magic).expect("failed to parse blk*.dat file");
-                sender
-                    .send(blocks)
-                    .expect("failed to send blocks from blk*.dat file");
-            });
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(0) // CPU-bound
+                .thread_name(|i| format!("parse-blocks-{}", i))
+                .build()
+                .unwrap();
+            let files_per_batch = pool.current_num_threads();
+
+            for paths in blk_files.chunks(files_per_batch) {
+                memory.wait_below_high_water_mark();
+                // the blobs of the batch, then their parsed blocks
+                let parsing = Mutex::new(memory.hold("parsing", 0));
+                let parsed: Vec<Vec<SizedBlock>> = pool.install(|| {
+                    paths
+                        .par_iter()
+                        .map(|path| {
+                            let blob = read_blob(path, xor_key.as_deref());
+                            trace!("parsing {} bytes", blob.len());
+                            {
+                                let mut parsing = parsing.lock().unwrap();
+                                let bytes = parsing.bytes + blob.len();
+                                parsing.resize(bytes);
+                            }
+                            parse_blocks(blob, magic).expect("failed to parse blk*.dat file")
+                        })
+                        .collect()
+                });
+                drop(parsing);
+                for blocks in parsed {
+                    let bytes = blocks.iter().map(|(_, size)| *size as usize).sum();
+                    let hold = memory.hold("parsed", bytes);
+                    sender
+                        .send((blocks, hold))
+                        .expect("failed to send blocks from blk*.dat file");
+                }
+            }
         }),
     )
 }
@@ -224,15 +443,71 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
         cursor.set_position(end as u64);
     }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(0) // CPU-bound
-        .thread_name(|i| format!("parse-blocks-{}", i))
-        .build()
-        .unwrap();
-    Ok(pool.install(|| {
-        slices
-            .into_par_iter()
-            .map(|(slice, size)| (deserialize(slice).expect("failed to parse Block"), size))
-            .collect()
-    }))
+    // runs within the parser's thread pool
+    Ok(slices
+        .into_par_iter()
+        .map(|(slice, size)| (deserialize(slice).expect("failed to parse Block"), size))
+        .collect())
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use std::io::Write;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::Network;
+
+    use super::*;
+
+    // The genesis block as bitcoind stores it in blk00000.dat: magic, size, then the block
+    fn genesis_blob() -> Vec<u8> {
+        let block = serialize(&genesis_block(Network::Bitcoin));
+        let mut blob = Network::Bitcoin.magic().to_le_bytes().to_vec();
+        blob.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&block);
+        blob
+    }
+
+    #[test]
+    fn xor_vectors() {
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut blob = genesis_blob();
+        xor_blob(&mut blob, &key);
+        // the magic f9beb4d9 and the size 285, then the key starts over
+        assert_eq!(
+            &blob[..12],
+            &[0xf8, 0xbc, 0xb7, 0xdd, 0x18, 0x07, 0x07, 0x08, 0x00, 0x02, 0x03, 0x04]
+        );
+        xor_blob(&mut blob, &key);
+        assert_eq!(blob, genesis_blob());
+
+        // a key longer than the data
+        let mut short = vec![0xff, 0x00];
+        xor_blob(&mut short, &key);
+        assert_eq!(short, vec![0xfe, 0x02]);
+    }
+
+    #[test]
+    fn read_obfuscated_blk_file() {
+        let key = [0x9a, 0x3c, 0x00, 0xff, 0x42, 0x17, 0xe5, 0x80];
+        let mut blob = genesis_blob();
+        xor_blob(&mut blob, &key);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&blob).unwrap();
+
+        // not de-obfuscated, the magic isn't found
+        let blocks = parse_blocks(read_blob(file.path(), None), Network::Bitcoin.magic()).unwrap();
+        assert!(blocks.is_empty());
+
+        let blob = read_blob(file.path(), Some(&key));
+        let blocks = parse_blocks(blob, Network::Bitcoin.magic()).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let (block, size) = &blocks[0];
+        assert_eq!(
+            block.block_hash().to_string(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(*size, 285);
+    }
 }
@@ -9,19 +9,30 @@ pub use utxo::*;
 use std::{
     collections::{HashMap, HashSet},
     path::Path,
-    sync::RwLock,
+    sync::{Arc, RwLock},
 };
 
-use bitcoin::{consensus::deserialize, BlockHash, BlockHeader, Script};
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
+use arc_swap::ArcSwap;
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hashes::{sha256, Hash},
+    BlockHash, BlockHeader, OutPoint, Script, TxOut,
+};
+use rayon::prelude::*;
 
 use crate::{
     config::Config,
-    util::{block::HeaderList, Bytes, FullHash},
+    util::{
+        block::{BlockId, HeaderList, HeaderSnapshot},
+        full_hash, Bytes, FullHash,
+    },
 };
 
-const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+use crate::errors::*;
+
+pub const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+const HEADERS_BLOB_KEY: &[u8] = b"H";
+const HEADER_SIZE: usize = 80;
 
 pub struct Store {
     // TODO: should be column families
@@ -30,32 +41,40 @@ pub struct Store {
     pub cache: DB,
     pub added_blockhashes: RwLock<HashSet<BlockHash>>,
     pub indexed_blockhashes: RwLock<HashSet<BlockHash>>,
-    pub indexed_headers: RwLock<HeaderList>,
+    // read without locking by the query paths, both replaced on every tip change
+    indexed_headers: ArcSwap<HeaderList>,
+    height_index: ArcSwap<Vec<BlockId>>,
 }
 
 impl Store {
     pub fn open(path: &Path, config: &Config) -> Self {
-        let txstore = DB::open(&path.join("txstore"), config);
+        let txstore = DB::open(
+            &path.join("txstore"),
+            config.txstore_archive_path.as_deref(),
+            config,
+        );
         let added_blockhashes = load_blockhashes(&txstore, &BlockRow::done_filter());
         debug!("{} blocks were added", added_blockhashes.len());
 
-        let history = DB::open(&path.join("history"), config);
+        let history = DB::open(&path.join("history"), None, config);
         let indexed_blockhashes = load_blockhashes(&history, &BlockRow::done_filter());
         debug!("{} blocks were indexed", indexed_blockhashes.len());
 
-        let cache = DB::open(&path.join("cache"), config);
+        let cache = DB::open(&path.join("cache"), None, config);
 
         let headers = if let Some(tip_hash) = txstore.get(b"t") {
             let tip_hash = deserialize(&tip_hash).expect("invalid chain tip in `t`");
-            let headers_map = load_blockheaders(&txstore);
+            load_headers_blob(&txstore, &tip_hash).unwrap_or_else(|| {
+                let headers_map = load_blockheaders(&txstore);
 
-            debug!(
-                "{} headers were loaded, tip at {:?}",
-                headers_map.len(),
-                tip_hash
-            );
+                debug!(
+                    "{} headers were loaded, tip at {:?}",
+                    headers_map.len(),
+                    tip_hash
+                );
 
-            HeaderList::new(headers_map, tip_hash)
+                HeaderList::new(headers_map, tip_hash)
+            })
         } else {
             HeaderList::default()
         };
@@ -66,7 +85,8 @@ impl Store {
             cache,
             added_blockhashes: RwLock::new(added_blockhashes),
             indexed_blockhashes: RwLock::new(indexed_blockhashes),
-            indexed_headers: RwLock::new(headers),
+            height_index: ArcSwap::from_pointee(build_height_index(&headers)),
+            indexed_headers: ArcSwap::from_pointee(headers),
         }
     }
 
@@ -82,21 +102,136 @@ impl Store {
         &self.cache
     }
 
+    pub fn script_type_stats(&self) -> Result<ScriptTypeStats> {
+        self.history.get(&ScriptTypeStatsRow::key()).map_or_else(
+            || Ok(ScriptTypeStats::default()),
+            |value| ScriptTypeStats::decode(&value),
+        )
+    }
+
+    pub fn chain_stats(&self) -> Result<ChainStats> {
+        self.history.get(&ChainStatsRow::key()).map_or_else(
+            || Ok(ChainStats::default()),
+            |value| ChainStatsRow::value_from_row(&value),
+        )
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn rich_list(&self) -> Result<RichList> {
+        self.history.get(&RichListRow::key()).map_or_else(
+            || Ok(RichList::default()),
+            |value| RichListRow::value_from_row(&value),
+        )
+    }
+
+    /// The on-disk size of each DB, in bytes
+    pub fn sizes(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("txstore", self.txstore.size()),
+            ("history", self.history.size()),
+            ("cache", self.cache.size()),
+        ]
+    }
+
+    /// The DBs with their names, as used in reports
+    pub fn dbs(&self) -> Vec<(&'static str, &DB)> {
+        vec![
+            ("txstore", &self.txstore),
+            ("history", &self.history),
+            ("cache", &self.cache),
+        ]
+    }
+
     pub fn done_initial_sync(&self) -> bool {
         self.txstore.get(b"t").is_some()
     }
+
+    pub fn blockid_by_height(&self, height: usize) -> Option<BlockId> {
+        self.height_index.load().get(height).cloned()
+    }
+
+    pub fn height_index_len(&self) -> usize {
+        self.height_index.load().len()
+    }
+
+    /// A snapshot of the indexed header chain, unaffected by the later tip changes
+    pub fn headers(&self) -> HeaderSnapshot {
+        self.indexed_headers.load_full()
+    }
+
+    /// Publish a new indexed header chain, along with its height index
+    pub fn set_headers(&self, headers: HeaderList) {
+        self.height_index
+            .store(Arc::new(build_height_index(&headers)));
+        self.indexed_headers.store(Arc::new(headers));
+    }
+
+    /// Save the indexed header chain as a single blob, so that the next startup doesn't
+    /// need to scan and chain all the header rows
+    pub fn persist_headers(&self) {
+        let headers = self.headers();
+        let blob: Bytes = headers
+            .iter()
+            .flat_map(|entry| serialize(entry.header()))
+            .collect();
+        self.txstore.put_sync(HEADERS_BLOB_KEY, &blob);
+        debug!("persisted {} headers", headers.len());
+    }
+}
+
+// The blob may lag behind the synced tip (e.g. after a crash), in which case the missing
+// headers are read back from their rows, down to the fork point with the blob's chain.
+fn load_headers_blob(db: &DB, tip_hash: &BlockHash) -> Option<HeaderList> {
+    let blob = db.get(HEADERS_BLOB_KEY)?;
+    if blob.len() % HEADER_SIZE != 0 {
+        warn!("ignoring corrupt headers blob of {} bytes", blob.len());
+        return None;
+    }
+    let chain: Vec<BlockHeader> = blob
+        .chunks(HEADER_SIZE)
+        .map(|chunk| deserialize(chunk).expect("failed to parse BlockHeader"))
+        .collect();
+    let mut headers = HeaderList::default();
+    headers.apply(headers.order(chain));
+
+    let mut missing: Vec<BlockHeader> = vec![];
+    let mut blockhash = *tip_hash;
+    while headers.header_by_blockhash(&blockhash).is_none() {
+        if blockhash == BlockHash::default() {
+            debug!("headers blob doesn't lead to tip {:?}", tip_hash);
+            return None;
+        }
+        let header: BlockHeader =
+            deserialize(&db.get(&BlockRow::header_key(full_hash(&blockhash[..])))?)
+                .expect("failed to parse BlockHeader");
+        blockhash = header.prev_blockhash;
+        missing.push(header);
+    }
+    missing.reverse();
+    headers.apply(headers.order(missing));
+
+    debug!(
+        "{} headers were loaded from blob, tip at {:?}",
+        headers.len(),
+        tip_hash
+    );
+    Some(headers)
+}
+
+fn build_height_index(headers: &HeaderList) -> Vec<BlockId> {
+    headers.iter().map(BlockId::from).collect()
 }
 
 fn load_blockhashes(db: &DB, prefix: &[u8]) -> HashSet<BlockHash> {
     db.iter_scan(prefix)
-        .map(BlockRow::from_row)
+        .map(|row| BlockRow::from_row(row).expect("invalid block row"))
         .map(|r| deserialize(&r.key.hash).expect("failed to parse BlockHash"))
         .collect()
 }
 
 fn load_blockheaders(db: &DB) -> HashMap<BlockHash, BlockHeader> {
     db.iter_scan(&BlockRow::header_filter())
-        .map(BlockRow::from_row)
+        .map(|row| BlockRow::from_row(row).expect("invalid block row"))
         .map(|r| {
             let key: BlockHash = deserialize(&r.key.hash).expect("failed to parse BlockHash");
             let value = deserialize(&r.value).expect("failed to parse BlockHeader");
@@ -105,10 +240,92 @@ fn load_blockheaders(db: &DB) -> HashMap<BlockHash, BlockHeader> {
         .collect()
 }
 
+/// What the rows of a DB with keys starting with `code` are
+pub fn row_type_name(db: &str, code: u8) -> &'static str {
+    match (db, code) {
+        ("txstore", b'T') => "transactions",
+        ("txstore", b'C') => "confirmations",
+        ("txstore", b'O') => "outputs",
+        ("txstore", b'P') => "interned scripts",
+        ("txstore", b'B') => "block headers",
+        ("txstore", b'X') => "block txids",
+        ("txstore", b'M') => "block metadata",
+        ("txstore", b'W') => "first seen times",
+        ("txstore", b'H') => "headers blob",
+        ("txstore", b't') => "synced tip",
+        ("history", b'H') => "script history",
+        ("history", b'S') => "spending edges",
+        ("history", b'R') => "OP_RETURN prefixes",
+        ("history", b'L') => "channel closes",
+        ("history", b'a') => "address search",
+        ("history", b'N') => "script stats",
+        ("history", b'u') => "UTXO accumulator",
+        ("history", b'v') => "script activity",
+        ("history", b'G') => "chain stats",
+        ("history", b'F') => "block feerates",
+        ("history", b'Y') => "block script types",
+        ("history", b'A') => "block age stats",
+        ("history", b'K') => "chain script types",
+        ("history", b'Q') => "rich list",
+        #[cfg(not(feature = "liquid"))]
+        ("history", b'I') => "inscriptions",
+        #[cfg(feature = "liquid")]
+        ("history", b'I') => "asset history",
+        #[cfg(feature = "liquid")]
+        ("history", b'i') => "asset issuances",
+        ("history", b'J') => "block inscription counts",
+        ("history", b'E') => "runestones",
+        ("history", b'Z') => "block runestone txids",
+        ("cache", b'A') => "script stats cache",
+        ("cache", b'U') => "UTXO cache",
+        ("cache", b'E') => "transaction fees",
+        ("cache", b'l') => "labels",
+        ("cache", b'w') => "query log",
+        ("cache", b'L') => "cache access times",
+        (_, b'D') => "indexed blocks",
+        (_, b'V') => "DB version",
+        _ => "unknown",
+    }
+}
+
 pub fn compute_script_hash(script: &Script) -> FullHash {
-    let mut hash = FullHash::default();
-    let mut sha2 = Sha256::new();
-    sha2.input(script.as_bytes());
-    sha2.result(&mut hash);
-    hash
+    sha256::Hash::hash(script.as_bytes()).into_inner()
+}
+
+/// The hashes of the scripts funded and spent by a batch of blocks. Each script is hashed once
+/// (many are used several times in a batch) and in parallel, instead of by each index step.
+pub struct ScriptHashes<'a>(HashMap<&'a Script, FullHash>);
+
+impl<'a> ScriptHashes<'a> {
+    pub fn new(
+        block_entries: &'a [BlockEntry],
+        previous_txos_map: &'a HashMap<OutPoint, TxOut>,
+    ) -> Self {
+        let scripts: HashSet<&Script> = block_entries
+            .iter()
+            .flat_map(|b| b.block.txdata.iter())
+            .flat_map(|tx| tx.output.iter())
+            .chain(previous_txos_map.values())
+            .map(|txo| &txo.script_pubkey)
+            .collect();
+        ScriptHashes(
+            scripts
+                .into_par_iter()
+                .map(|script| (script, compute_script_hash(script)))
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a Script, &FullHash)> {
+        self.0
+            .iter()
+            .map(|(script, scripthash)| (*script, scripthash))
+    }
+
+    pub fn get(&self, script: &Script) -> FullHash {
+        self.0
+            .get(script)
+            .copied()
+            .unwrap_or_else(|| compute_script_hash(script))
+    }
 }
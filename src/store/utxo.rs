@@ -1,4 +1,14 @@
-use std::collections::HashMap;
+#[cfg(not(feature = "liquid"))]
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    iter,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 use bincode::Options;
 use bitcoin::{
@@ -6,17 +16,34 @@ use bitcoin::{
     BlockHash, OutPoint, Script, Transaction, TxOut, Txid,
 };
 
+use rocksdb::{compaction_filter::Decision as CompactionDecision, MergeOperands};
+
 use crate::{
     chain::Value,
+    errors::*,
     store::{compute_script_hash, DBRow},
     util::{
         block::{BlockId, BlockMeta},
-        full_hash, Bytes, FullHash,
+        fees::FeeratePercentiles,
+        full_hash,
+        script::{get_script_type, SCRIPT_TYPES},
+        transaction::{has_prevout, is_dust, is_spendable},
+        Bytes, FullHash, HASH_LEN,
     },
 };
 
 use super::BlockEntry;
 
+#[cfg(feature = "ordinals")]
+use crate::util::inscription::Inscription;
+#[cfg(feature = "runes")]
+use crate::util::runestone::Runestone;
+#[cfg(feature = "liquid")]
+use crate::{
+    chain::AssetId,
+    elements::{asset, peg},
+};
+
 pub type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
 
 #[derive(Debug)]
@@ -50,17 +77,101 @@ pub struct SpendingInput {
     pub confirmed: Option<BlockId>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct ScriptStats {
     pub tx_count: usize,
     pub funded_txo_count: usize,
-    pub spend_txo_count: usize,
+    pub spent_txo_count: usize,
     #[cfg(not(feature = "liquid"))]
     pub funded_txo_sum: u64,
-    #[cfg(feature = "liquid")]
+    #[cfg(not(feature = "liquid"))]
     pub spent_txo_sum: u64,
 }
 
+impl ScriptStats {
+    /// The stats contributed by `tx` to each of the scripts it funds or spends from, hashed
+    /// with `script_hash`, without the outputs below `skip_dust`
+    pub fn from_tx(
+        tx: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+        include_unspendables: bool,
+        skip_dust: u64,
+        script_hash: impl Fn(&Script) -> FullHash,
+    ) -> HashMap<FullHash, ScriptStats> {
+        let mut stats: HashMap<FullHash, ScriptStats> = HashMap::new();
+        for txo in &tx.output {
+            if !(is_spendable(txo) || include_unspendables) || is_dust(&txo.value, skip_dust) {
+                continue;
+            }
+            let entry = stats.entry(script_hash(&txo.script_pubkey)).or_default();
+            entry.funded_txo_count += 1;
+            #[cfg(not(feature = "liquid"))]
+            {
+                entry.funded_txo_sum += txo.value;
+            }
+        }
+        for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+            if let Some(prevout) = prevouts
+                .get(&txin.previous_output)
+                .filter(|prevout| !is_dust(&prevout.value, skip_dust))
+            {
+                let entry = stats
+                    .entry(script_hash(&prevout.script_pubkey))
+                    .or_default();
+                entry.spent_txo_count += 1;
+                #[cfg(not(feature = "liquid"))]
+                {
+                    entry.spent_txo_sum += prevout.value;
+                }
+            }
+        }
+        for entry in stats.values_mut() {
+            entry.tx_count = 1;
+        }
+        stats
+    }
+
+    // the counters wrap around, so that adding the negated stats of a block reverts them
+    pub fn add(&mut self, other: &ScriptStats) {
+        self.tx_count = self.tx_count.wrapping_add(other.tx_count);
+        self.funded_txo_count = self.funded_txo_count.wrapping_add(other.funded_txo_count);
+        self.spent_txo_count = self.spent_txo_count.wrapping_add(other.spent_txo_count);
+        #[cfg(not(feature = "liquid"))]
+        {
+            self.funded_txo_sum = self.funded_txo_sum.wrapping_add(other.funded_txo_sum);
+            self.spent_txo_sum = self.spent_txo_sum.wrapping_add(other.spent_txo_sum);
+        }
+    }
+
+    /// The merge operand reverting these stats, for the blocks disconnected by a reorg
+    pub fn negated(&self) -> ScriptStats {
+        ScriptStats {
+            tx_count: self.tx_count.wrapping_neg(),
+            funded_txo_count: self.funded_txo_count.wrapping_neg(),
+            spent_txo_count: self.spent_txo_count.wrapping_neg(),
+            #[cfg(not(feature = "liquid"))]
+            funded_txo_sum: self.funded_txo_sum.wrapping_neg(),
+            #[cfg(not(feature = "liquid"))]
+            spent_txo_sum: self.spent_txo_sum.wrapping_neg(),
+        }
+    }
+
+    pub fn subtract(&mut self, other: &ScriptStats) {
+        self.tx_count -= other.tx_count;
+        self.funded_txo_count -= other.funded_txo_count;
+        self.spent_txo_count -= other.spent_txo_count;
+        #[cfg(not(feature = "liquid"))]
+        {
+            self.funded_txo_sum -= other.funded_txo_sum;
+            self.spent_txo_sum -= other.spent_txo_sum;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tx_count == 0
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize)]
 pub struct TxRowKey {
     code: u8,
@@ -130,11 +241,51 @@ impl TxConfRow {
         }
     }
 
-    pub fn from_row(row: DBRow) -> Self {
-        TxConfRow {
-            key: bincode::deserialize(&row.key).expect("failed to parse TxConfKey"),
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        let key = bincode::deserialize(&row.key).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid tx conf key {}", hex::encode(&row.key)))
+        })?;
+        Ok(TxConfRow { key })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FirstSeenKey {
+    pub code: u8,
+    pub txid: FullHash,
+}
+
+// The time a transaction was first seen in the mempool, kept once it confirms
+pub struct FirstSeenRow {
+    pub key: FirstSeenKey,
+    pub value: u32, // unix timestamp
+}
+
+impl FirstSeenRow {
+    pub fn new(txid: &Txid, first_seen: u32) -> FirstSeenRow {
+        FirstSeenRow {
+            key: FirstSeenKey {
+                code: b'W',
+                txid: full_hash(&txid[..]),
+            },
+            value: first_seen,
+        }
+    }
+
+    pub fn key(txid: &Txid) -> Bytes {
+        [b"W", &txid[..]].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.value).unwrap(),
         }
     }
+
+    pub fn value_from_row(value: &[u8]) -> Result<u32> {
+        parse_db_value(value, "first seen time")
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -149,6 +300,10 @@ pub struct TxOutRow {
     pub value: Bytes, // serialized output
 }
 
+// The value prefixes of the `O` rows when scripts are interned
+const TXO_INLINE: u8 = 0; // {txout}
+const TXO_INTERNED: u8 = 1; // {scripthash}{txout without its script}
+
 impl TxOutRow {
     pub fn new(txid: &FullHash, vout: usize, txout: &TxOut) -> TxOutRow {
         TxOutRow {
@@ -161,6 +316,60 @@ impl TxOutRow {
         }
     }
 
+    /// Refers to the script by its scripthash when it is longer than that, returning the row
+    /// that stores the script itself too in that case
+    pub fn new_interned(
+        txid: &FullHash,
+        vout: usize,
+        txout: &TxOut,
+    ) -> (TxOutRow, Option<ScriptRow>) {
+        let mut row = TxOutRow::new(txid, vout, txout);
+        if txout.script_pubkey.len() <= HASH_LEN {
+            row.value.insert(0, TXO_INLINE);
+            return (row, None);
+        }
+
+        let scripthash = compute_script_hash(&txout.script_pubkey);
+        let mut stripped = txout.clone();
+        stripped.script_pubkey = Script::new();
+        row.value = [&[TXO_INTERNED][..], &scripthash[..], &serialize(&stripped)].concat();
+        (row, Some(ScriptRow::new(scripthash, &txout.script_pubkey)))
+    }
+
+    /// Parses an `O` row value, looking the interned scripts up with `get_script`
+    pub fn parse_value<F>(value: &[u8], intern_scripts: bool, get_script: F) -> Result<TxOut>
+    where
+        F: FnOnce(&[u8]) -> Option<Bytes>,
+    {
+        let parse_txout = |txout: &[u8]| -> Result<TxOut> {
+            deserialize(txout).chain_err(|| {
+                ErrorKind::DbInconsistency(format!("invalid TxOut {}", hex::encode(value)))
+            })
+        };
+        if !intern_scripts {
+            return parse_txout(value);
+        }
+        match value.split_first() {
+            Some((&TXO_INLINE, txout)) => parse_txout(txout),
+            Some((&TXO_INTERNED, rest)) if rest.len() > HASH_LEN => {
+                let (scripthash, txout) = rest.split_at(HASH_LEN);
+                let mut txout = parse_txout(txout)?;
+                let script = get_script(scripthash).chain_err(|| {
+                    ErrorKind::DbInconsistency(format!(
+                        "missing interned script {}",
+                        hex::encode(scripthash)
+                    ))
+                })?;
+                txout.script_pubkey = Script::from(script);
+                Ok(txout)
+            }
+            _ => bail!(ErrorKind::DbInconsistency(format!(
+                "invalid TxOut row value {}",
+                hex::encode(value)
+            ))),
+        }
+    }
+
     pub fn key(outpoint: &OutPoint) -> Bytes {
         bincode::serialize(&TxOutKey {
             code: b'O',
@@ -178,6 +387,41 @@ impl TxOutRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ScriptKey {
+    pub code: u8,
+    pub scripthash: FullHash,
+}
+
+// An output script that is stored once for all the outputs paying to it
+pub struct ScriptRow {
+    pub key: ScriptKey,
+    pub value: Bytes, // script
+}
+
+impl ScriptRow {
+    pub fn new(scripthash: FullHash, script: &Script) -> ScriptRow {
+        ScriptRow {
+            key: ScriptKey {
+                code: b'P',
+                scripthash,
+            },
+            value: script.to_bytes(),
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [b"P", scripthash].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: self.value,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BlockKey {
     pub code: u8,
@@ -214,6 +458,44 @@ impl BlockRow {
         }
     }
 
+    pub fn new_feerates(hash: FullHash, feerates: &FeeratePercentiles) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'F', hash },
+            value: bincode::serialize(feerates).unwrap(),
+        }
+    }
+
+    pub fn new_script_types(hash: FullHash, stats: &ScriptTypeStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Y', hash },
+            value: stats.encode(),
+        }
+    }
+
+    #[cfg(feature = "ordinals")]
+    pub fn new_inscription_count(hash: FullHash, count: u32) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'J', hash },
+            value: bincode::serialize(&count).unwrap(),
+        }
+    }
+
+    #[cfg(feature = "runes")]
+    pub fn new_runestone_txids(hash: FullHash, txids: &[Txid]) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Z', hash },
+            value: bincode::serialize(txids).unwrap(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn new_age_stats(hash: FullHash, stats: &BlockAgeStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'A', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
+    }
+
     pub fn new_done(hash: FullHash) -> BlockRow {
         BlockRow {
             key: BlockKey { code: b'D', hash },
@@ -225,6 +507,10 @@ impl BlockRow {
         b"B".to_vec()
     }
 
+    pub fn header_key(hash: FullHash) -> Bytes {
+        [b"B", &hash[..]].concat()
+    }
+
     pub fn txids_key(hash: FullHash) -> Bytes {
         [b"X", &hash[..]].concat()
     }
@@ -233,6 +519,33 @@ impl BlockRow {
         [b"M", &hash[..]].concat()
     }
 
+    pub fn feerates_key(hash: FullHash) -> Bytes {
+        [b"F", &hash[..]].concat()
+    }
+
+    pub fn script_types_key(hash: FullHash) -> Bytes {
+        [b"Y", &hash[..]].concat()
+    }
+
+    #[cfg(feature = "ordinals")]
+    pub fn inscription_count_key(hash: FullHash) -> Bytes {
+        [b"J", &hash[..]].concat()
+    }
+
+    #[cfg(feature = "runes")]
+    pub fn runestone_txids_key(hash: FullHash) -> Bytes {
+        [b"Z", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn age_stats_key(hash: FullHash) -> Bytes {
+        [b"A", &hash[..]].concat()
+    }
+
+    pub fn done_key(hash: FullHash) -> Bytes {
+        [b"D", &hash[..]].concat()
+    }
+
     pub fn done_filter() -> Bytes {
         b"D".to_vec()
     }
@@ -244,11 +557,14 @@ impl BlockRow {
         }
     }
 
-    pub fn from_row(row: DBRow) -> Self {
-        BlockRow {
-            key: bincode::deserialize(&row.key).unwrap(),
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        let key = bincode::deserialize(&row.key).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid block key {}", hex::encode(&row.key)))
+        })?;
+        Ok(BlockRow {
+            key,
             value: row.value,
-        }
+        })
     }
 }
 
@@ -284,6 +600,16 @@ pub enum TxHistoryInfo {
 }
 
 impl TxHistoryInfo {
+    /// The value of the funded or spent output
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            TxHistoryInfo::Funding(FundingInfo { value, .. })
+            | TxHistoryInfo::Spending(SpendingInfo { value, .. }) => Some(value),
+            #[cfg(feature = "liquid")]
+            _ => None,
+        }
+    }
+
     pub fn get_txid(&self) -> Txid {
         match self {
             TxHistoryInfo::Funding(FundingInfo { txid, .. })
@@ -299,71 +625,212 @@ impl TxHistoryInfo {
     }
 }
 
-#[derive(Serialize, Deserialize)]
 pub struct TxHistoryKey {
     pub code: u8,              // H for script history or I for asset history (elements only)
     pub hash: FullHash, // either a scripthash (always on bitcoin) or an asset id (elements only)
     pub confirmed_height: u32, // MUST be serialized as big-endian (for correct scans).
-    pub txinfo: TxHistoryInfo,
+    pub tx_position: u16, // of the transaction within its block
 }
 
+// The key is a fixed-size (scripthash, height, ordinal) prefix, ordered by the position of the
+// transaction in the chain, and the entry itself lives in the value. The height isn't
+// delta-encoded: the rows are written independently, and the scans from a height need it whole
+// and big-endian in the key.
 pub struct TxHistoryRow {
     pub key: TxHistoryKey,
+    pub txinfo: TxHistoryInfo,
 }
 
 impl TxHistoryRow {
-    pub fn new(script: &Script, confirmed_height: u32, txinfo: TxHistoryInfo) -> Self {
+    pub fn new(
+        scripthash: FullHash,
+        confirmed_height: u32,
+        tx_position: u16,
+        txinfo: TxHistoryInfo,
+    ) -> Self {
         let key = TxHistoryKey {
             code: b'H',
-            hash: compute_script_hash(&script),
+            hash: scripthash,
             confirmed_height,
-            txinfo,
+            tx_position,
         };
-        TxHistoryRow { key }
+        TxHistoryRow { key, txinfo }
     }
 
-    fn filter(code: u8, hash_prefix: &[u8]) -> Bytes {
+    #[cfg(feature = "liquid")]
+    pub fn new_asset(
+        asset_id: &AssetId,
+        confirmed_height: u32,
+        tx_position: u16,
+        txinfo: TxHistoryInfo,
+    ) -> Self {
+        let key = TxHistoryKey {
+            code: b'I',
+            hash: asset_id.into_inner().into_inner(),
+            confirmed_height,
+            tx_position,
+        };
+        TxHistoryRow { key, txinfo }
+    }
+
+    pub fn filter(code: u8, hash_prefix: &[u8]) -> Bytes {
         [&[code], hash_prefix].concat()
     }
 
-    fn prefix_end(code: u8, hash: &[u8]) -> Bytes {
-        bincode::serialize(&(code, full_hash(&hash[..]), std::u32::MAX)).unwrap()
+    pub fn prefix_end(code: u8, hash: &[u8]) -> Bytes {
+        Self::prefix_height(code, hash, std::u32::MAX)
     }
 
-    fn prefix_height(code: u8, hash: &[u8], height: u32) -> Bytes {
-        bincode::options()
-            .with_big_endian()
-            .serialize(&(code, full_hash(&hash[..]), height))
-            .unwrap()
+    pub fn prefix_height(code: u8, hash: &[u8], height: u32) -> Bytes {
+        [
+            &[code],
+            &full_hash(&hash[..])[..],
+            &height.to_be_bytes()[..],
+        ]
+        .concat()
     }
 
     pub fn into_row(self) -> DBRow {
-        DBRow {
-            key: bincode::options()
-                .with_big_endian()
-                .serialize(&self.key)
-                .unwrap(),
-            value: vec![],
-        }
+        let (tag, index) = self.txinfo.ordinal();
+        let key = [
+            &Self::prefix_height(self.key.code, &self.key.hash, self.key.confirmed_height)[..],
+            &self.key.tx_position.to_be_bytes()[..],
+            &[tag],
+            &index.to_be_bytes()[..],
+        ]
+        .concat();
+        let mut value = vec![];
+        self.txinfo.encode(&mut value);
+        DBRow { key, value }
     }
 
-    pub fn from_row(row: DBRow) -> Self {
-        let key = bincode::options()
-            .with_big_endian()
-            .deserialize(&row.key)
-            .expect("failed to deserialize TxHistoryKey");
-        TxHistoryRow { key }
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        ensure!(
+            row.key.len() == HISTORY_KEY_LEN,
+            ErrorKind::DbInconsistency(format!("invalid history key {}", hex::encode(&row.key)))
+        );
+        let key = TxHistoryKey {
+            code: row.key[0],
+            hash: full_hash(&row.key[1..33]),
+            confirmed_height: u32::from_be_bytes(*array_ref![row.key, 33, 4]),
+            tx_position: u16::from_be_bytes(*array_ref![row.key, 37, 2]),
+        };
+        let txinfo = TxHistoryInfo::decode(&row.value).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid history entry {}", hex::encode(&row.value)))
+        })?;
+        Ok(TxHistoryRow { key, txinfo })
     }
 
     pub fn get_txid(&self) -> Txid {
-        self.key.txinfo.get_txid()
+        self.txinfo.get_txid()
     }
-    fn get_funded_outpoint(&self) -> OutPoint {
-        self.key.txinfo.get_funded_outpoint()
+    pub fn get_funded_outpoint(&self) -> OutPoint {
+        self.txinfo.get_funded_outpoint()
     }
 }
 
+// code, hash, big-endian height, then the tx position, entry tag and in/out index
+const HISTORY_KEY_LEN: usize = 1 + 32 + 4 + 2 + 1 + 2;
+
+const FUNDING_TAG: u8 = 0;
+const SPENDING_TAG: u8 = 1;
+#[cfg(feature = "liquid")]
+const ISSUING_TAG: u8 = 2;
+#[cfg(feature = "liquid")]
+const BURNING_TAG: u8 = 3;
+#[cfg(feature = "liquid")]
+const PEGIN_TAG: u8 = 4;
+#[cfg(feature = "liquid")]
+const PEGOUT_TAG: u8 = 5;
+
+// The history entries are encoded as a tag byte followed by their fields, with the indexes
+// and amounts as LEB128 varints (most outputs are worth less than 2^28 sat, i.e. 4 bytes).
+// The txids stay complete: the spent txid is needed to match spends with their funding rows.
 impl TxHistoryInfo {
+    // Distinguishes the entries of the same transaction
+    fn ordinal(&self) -> (u8, u16) {
+        match self {
+            TxHistoryInfo::Funding(info) => (FUNDING_TAG, info.vout),
+            TxHistoryInfo::Spending(info) => (SPENDING_TAG, info.vin),
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Issuing(info) => (ISSUING_TAG, info.vin),
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Burning(info) => (BURNING_TAG, info.vout),
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Pegin(info) => (PEGIN_TAG, info.vin),
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Pegout(info) => (PEGOUT_TAG, info.vout),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            TxHistoryInfo::Funding(info) => {
+                buf.push(FUNDING_TAG);
+                buf.extend_from_slice(&info.txid);
+                write_varint(buf, info.vout as u64);
+                encode_value(buf, &info.value);
+            }
+            TxHistoryInfo::Spending(info) => {
+                buf.push(SPENDING_TAG);
+                buf.extend_from_slice(&info.txid);
+                write_varint(buf, info.vin as u64);
+                buf.extend_from_slice(&info.prev_txid);
+                write_varint(buf, info.prev_vout as u64);
+                encode_value(buf, &info.value);
+            }
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Issuing(info) => {
+                buf.push(ISSUING_TAG);
+                bincode::serialize_into(buf, info).unwrap();
+            }
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Burning(info) => {
+                buf.push(BURNING_TAG);
+                bincode::serialize_into(buf, info).unwrap();
+            }
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Pegin(info) => {
+                buf.push(PEGIN_TAG);
+                bincode::serialize_into(buf, info).unwrap();
+            }
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Pegout(info) => {
+                buf.push(PEGOUT_TAG);
+                bincode::serialize_into(buf, info).unwrap();
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let (tag, mut rest) = bytes.split_first().chain_err(|| "empty history entry")?;
+        let info = match *tag {
+            FUNDING_TAG => TxHistoryInfo::Funding(FundingInfo {
+                txid: read_hash(&mut rest)?,
+                vout: read_index(&mut rest)?,
+                value: decode_value(&mut rest)?,
+            }),
+            SPENDING_TAG => TxHistoryInfo::Spending(SpendingInfo {
+                txid: read_hash(&mut rest)?,
+                vin: read_index(&mut rest)?,
+                prev_txid: read_hash(&mut rest)?,
+                prev_vout: read_index(&mut rest)?,
+                value: decode_value(&mut rest)?,
+            }),
+            #[cfg(feature = "liquid")]
+            ISSUING_TAG => TxHistoryInfo::Issuing(deserialize_from(&mut rest)?),
+            #[cfg(feature = "liquid")]
+            BURNING_TAG => TxHistoryInfo::Burning(deserialize_from(&mut rest)?),
+            #[cfg(feature = "liquid")]
+            PEGIN_TAG => TxHistoryInfo::Pegin(deserialize_from(&mut rest)?),
+            #[cfg(feature = "liquid")]
+            PEGOUT_TAG => TxHistoryInfo::Pegout(deserialize_from(&mut rest)?),
+            _ => bail!("unknown history entry tag {}", tag),
+        };
+        ensure!(rest.is_empty(), "trailing bytes in history entry");
+        Ok(info)
+    }
+
     // for funding rows, returns the funded output.
     // for spending rows, returns the spent previous output.
     pub fn get_funded_outpoint(&self) -> OutPoint {
@@ -385,17 +852,136 @@ impl TxHistoryInfo {
     }
 }
 
+pub const OP_RETURN_PREFIX_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+pub struct OpReturnKey {
+    pub code: u8,
+    pub prefix: [u8; OP_RETURN_PREFIX_LEN], // zero-padded
+    pub confirmed_height: u32,
+    pub txid: FullHash,
+    pub vout: u16,
+}
+
+// OP_RETURN outputs by the first bytes of the script following the OP_RETURN opcode, which
+// cover the markers of the protocols embedding data (e.g. OP_13 for runestones)
+pub struct OpReturnRow {
+    pub key: OpReturnKey,
+}
+
+impl OpReturnRow {
+    pub fn new(script: &Script, confirmed_height: u32, txid: FullHash, vout: u16) -> Self {
+        let payload = &script.as_bytes()[1..];
+        let mut prefix = [0u8; OP_RETURN_PREFIX_LEN];
+        let len = payload.len().min(OP_RETURN_PREFIX_LEN);
+        prefix[..len].copy_from_slice(&payload[..len]);
+
+        OpReturnRow {
+            key: OpReturnKey {
+                code: b'R',
+                prefix,
+                confirmed_height,
+                txid,
+                vout,
+            },
+        }
+    }
+
+    pub fn filter(prefix: &[u8]) -> Bytes {
+        [b"R", prefix].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::options()
+                .with_big_endian()
+                .serialize(&self.key)
+                .unwrap(),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        let key = bincode::options()
+            .with_big_endian()
+            .deserialize(&row.key)
+            .chain_err(|| {
+                ErrorKind::DbInconsistency(format!(
+                    "invalid OP_RETURN key {}",
+                    hex::encode(&row.key)
+                ))
+            })?;
+        Ok(OpReturnRow { key })
+    }
+
+    pub fn get_txid(&self) -> Txid {
+        deserialize(&self.key.txid).expect("cannot parse Txid")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelCloseKey {
+    code: u8,
+    pub funding_txid: FullHash,
+    pub funding_vout: u16,
+    pub closing_txid: FullHash,
+}
+
+// The spends of P2WSH outputs revealing a 2-of-2 multisig witness script, like the funding
+// outputs of lightning channels do once closed
+pub struct ChannelCloseRow {
+    pub key: ChannelCloseKey,
+}
+
+impl ChannelCloseRow {
+    pub fn new(funding_txid: FullHash, funding_vout: u16, closing_txid: FullHash) -> Self {
+        ChannelCloseRow {
+            key: ChannelCloseKey {
+                code: b'L',
+                funding_txid,
+                funding_vout,
+                closing_txid,
+            },
+        }
+    }
+
+    pub fn filter(outpoint: &OutPoint) -> Bytes {
+        bincode::serialize(&(b'L', full_hash(&outpoint.txid[..]), outpoint.vout as u16)).unwrap()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        let key = bincode::deserialize(&row.key).chain_err(|| {
+            ErrorKind::DbInconsistency(format!(
+                "invalid channel close key {}",
+                hex::encode(&row.key)
+            ))
+        })?;
+        Ok(ChannelCloseRow { key })
+    }
+
+    pub fn get_closing_txid(&self) -> Txid {
+        deserialize(&self.key.closing_txid).expect("cannot parse Txid")
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-struct TxEdgeKey {
+pub struct TxEdgeKey {
     code: u8,
-    funding_txid: FullHash,
-    funding_vout: u16,
-    spending_txid: FullHash,
-    spending_vin: u16,
+    pub funding_txid: FullHash,
+    pub funding_vout: u16,
+    pub spending_txid: FullHash,
+    pub spending_vin: u16,
 }
 
 pub struct TxEdgeRow {
-    key: TxEdgeKey,
+    pub key: TxEdgeKey,
 }
 
 impl TxEdgeRow {
@@ -427,10 +1013,11 @@ impl TxEdgeRow {
         }
     }
 
-    pub fn from_row(row: DBRow) -> Self {
-        TxEdgeRow {
-            key: bincode::deserialize(&row.key).expect("failed to deserialize TxEdgeKey"),
-        }
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        let key = bincode::deserialize(&row.key).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid tx edge key {}", hex::encode(&row.key)))
+        })?;
+        Ok(TxEdgeRow { key })
     }
 }
 
@@ -468,37 +1055,685 @@ impl StatsCacheRow {
     }
 }
 
-pub type CachedUtxoMap = HashMap<(Txid, u32), (u32, Value)>; // (txid,vout) => (block_height,output_value)
-
-pub struct UtxoCacheRow {
-    key: ScriptCacheKey,
-    value: Bytes,
+// The fee of a confirmed transaction, computed on first use
+pub struct TxFeeRow {
+    key: TxRowKey,
+    value: u64,
 }
 
-impl UtxoCacheRow {
-    pub fn new(scripthash: &[u8], utxos: &UtxoMap, blockhash: &BlockHash) -> Self {
-        let utxos_cache = make_utxo_cache(utxos);
-
-        UtxoCacheRow {
-            key: ScriptCacheKey {
-                code: b'U',
-                scripthash: full_hash(scripthash),
+impl TxFeeRow {
+    pub fn new(txid: &Txid, fee: u64) -> Self {
+        TxFeeRow {
+            key: TxRowKey {
+                code: b'E',
+                txid: full_hash(&txid[..]),
             },
-            value: bincode::serialize(&(utxos_cache, blockhash)).unwrap(),
+            value: fee,
         }
     }
 
-    pub fn key(scripthash: &[u8]) -> Bytes {
-        [b"U", scripthash].concat()
+    pub fn key(txid: &Txid) -> Bytes {
+        [b"E", &txid[..]].concat()
     }
 
-    fn into_row(self) -> DBRow {
+    pub fn into_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize(&self.key).unwrap(),
-            value: self.value,
+            value: bincode::serialize(&self.value).unwrap(),
         }
     }
-}
+
+    pub fn value_from_row(value: &[u8]) -> Result<u64> {
+        parse_db_value(value, "tx fee")
+    }
+}
+
+// Per-script counters, incremented by the stats of each indexed block using RocksDB merges
+// (see `merge_rows`), so that reading them doesn't depend on the history size
+pub struct ScriptStatsRow {
+    key: ScriptCacheKey,
+    value: ScriptStats,
+}
+
+const SCRIPT_STATS_CODE: u8 = b'N';
+
+impl ScriptStatsRow {
+    pub fn new(scripthash: &[u8], stats: ScriptStats) -> Self {
+        ScriptStatsRow {
+            key: ScriptCacheKey {
+                code: SCRIPT_STATS_CODE,
+                scripthash: full_hash(scripthash),
+            },
+            value: stats,
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [&[SCRIPT_STATS_CODE], scripthash].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.value).unwrap(),
+        }
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<ScriptStats> {
+        parse_db_value(value, "ScriptStats")
+    }
+}
+
+/// The heights at which a script was first funded and last funded or spent from, as far as
+/// the indexed blocks go. Min and max heights can't be negated: when blocks are disconnected,
+/// the indexer looks up the activity of their scripts again in the history below them.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptActivity {
+    pub first_funded: Option<u32>,
+    pub last_active: Option<u32>,
+}
+
+impl ScriptActivity {
+    pub fn observe(&mut self, height: u32, funded: bool) {
+        if funded {
+            self.first_funded = Some(self.first_funded.map_or(height, |h| h.min(height)));
+        }
+        self.last_active = Some(self.last_active.map_or(height, |h| h.max(height)));
+    }
+
+    pub fn merge(&mut self, other: &ScriptActivity) {
+        if let Some(height) = other.first_funded {
+            self.observe(height, true);
+        }
+        if let Some(height) = other.last_active {
+            self.observe(height, false);
+        }
+    }
+}
+
+const SCRIPT_ACTIVITY_CODE: u8 = b'v';
+
+// Merged by each indexed block using a script (with --script-activity)
+//      v{scripthash} → {first-funded-height}{last-active-height}
+pub struct ScriptActivityRow;
+
+impl ScriptActivityRow {
+    pub fn new(scripthash: &[u8], activity: &ScriptActivity) -> DBRow {
+        DBRow {
+            key: ScriptActivityRow::key(scripthash),
+            value: bincode::serialize(activity).unwrap(),
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [&[SCRIPT_ACTIVITY_CODE], scripthash].concat()
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<ScriptActivity> {
+        parse_db_value(value, "ScriptActivity")
+    }
+}
+
+/// Chain-wide counters, incremented by each indexed block using RocksDB merges. Like the script
+/// stats, the counts of blocks that are later reorged out are reverted by merging their negation.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+pub struct ChainStats {
+    pub tx_count: u64,
+    /// The spendable outputs created
+    pub txo_count: u64,
+    pub spent_txo_count: u64,
+}
+
+impl ChainStats {
+    pub fn from_txs<'a>(txs: impl Iterator<Item = &'a Transaction>) -> Self {
+        let mut stats = ChainStats::default();
+        for tx in txs {
+            stats.tx_count += 1;
+            stats.txo_count += tx.output.iter().filter(|txo| is_spendable(txo)).count() as u64;
+            stats.spent_txo_count +=
+                tx.input.iter().filter(|txin| has_prevout(txin)).count() as u64;
+        }
+        stats
+    }
+
+    // the counters wrap around, so that adding the negated stats of a block reverts them
+    pub fn add(&mut self, other: &ChainStats) {
+        self.tx_count = self.tx_count.wrapping_add(other.tx_count);
+        self.txo_count = self.txo_count.wrapping_add(other.txo_count);
+        self.spent_txo_count = self.spent_txo_count.wrapping_add(other.spent_txo_count);
+    }
+
+    /// The merge operand reverting these stats, for the blocks disconnected by a reorg
+    pub fn negated(&self) -> ChainStats {
+        ChainStats {
+            tx_count: self.tx_count.wrapping_neg(),
+            txo_count: self.txo_count.wrapping_neg(),
+            spent_txo_count: self.spent_txo_count.wrapping_neg(),
+        }
+    }
+
+    pub fn utxo_count(&self) -> u64 {
+        self.txo_count.saturating_sub(self.spent_txo_count)
+    }
+}
+
+// G -> {tx_count}{txo_count}{spent_txo_count}
+pub struct ChainStatsRow;
+
+impl ChainStatsRow {
+    pub fn new(stats: &ChainStats) -> DBRow {
+        DBRow {
+            key: ChainStatsRow::key(),
+            value: bincode::serialize(stats).unwrap(),
+        }
+    }
+
+    pub fn key() -> Bytes {
+        b"G".to_vec()
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<ChainStats> {
+        parse_db_value(value, "ChainStats")
+    }
+}
+
+/// The largest script balances, updated with the scripts used by each indexed batch of blocks.
+/// It is approximate: a script that drops out of the list is only ranked again once it's used,
+/// so a script whose balance grew past it in the meantime may be missing.
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct RichList {
+    /// The height of the last block accounted for
+    pub height: u32,
+    /// By decreasing balance
+    pub entries: Vec<RichListEntry>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RichListEntry {
+    pub scripthash: FullHash,
+    pub script: Bytes,
+    pub balance: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl RichList {
+    /// Replace the balances of the `updated` scripts, keeping the `size` largest ones
+    pub fn update(&mut self, height: u32, updated: Vec<RichListEntry>, size: usize) {
+        let updated_scripts: HashSet<FullHash> =
+            updated.iter().map(|entry| entry.scripthash).collect();
+        let entries = self
+            .entries
+            .drain(..)
+            .filter(|entry| !updated_scripts.contains(&entry.scripthash))
+            .chain(updated)
+            .filter(|entry| entry.balance > 0);
+
+        // a min-heap of the largest balances seen so far
+        let mut heap = BinaryHeap::with_capacity(size + 1);
+        for entry in entries {
+            heap.push(Reverse((entry.balance, entry.scripthash, entry.script)));
+            if heap.len() > size {
+                heap.pop();
+            }
+        }
+        self.entries = heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((balance, scripthash, script))| RichListEntry {
+                scripthash,
+                script,
+                balance,
+            })
+            .collect();
+        self.height = height;
+    }
+}
+
+/// The upper bounds (exclusive, in blocks) of the age buckets of the spent outputs: a day, a
+/// week, a month, a year, 2 and 5 years, followed by a bucket for the older outputs
+#[cfg(not(feature = "liquid"))]
+pub const AGE_BUCKETS: &[u32] = &[144, 1008, 4320, 52_560, 105_120, 262_800];
+
+/// The ages of the outputs spent by a block, counting 144 blocks per day
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct BlockAgeStats {
+    /// The spent value times its age (in BTC-days)
+    pub coin_days_destroyed: f64,
+    /// The count and value (in sats) of the spent outputs by age bucket, see `AGE_BUCKETS`
+    pub spent_by_age: Vec<(u64, u64)>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockAgeStats {
+    /// The stats of a block at `height` spending outputs of (funding height, value)
+    pub fn from_spends(height: u32, spends: impl Iterator<Item = (u32, u64)>) -> Self {
+        let mut stats = BlockAgeStats {
+            coin_days_destroyed: 0.0,
+            spent_by_age: vec![(0, 0); AGE_BUCKETS.len() + 1],
+        };
+        for (funding_height, value) in spends {
+            let age = height.saturating_sub(funding_height);
+            stats.coin_days_destroyed += value as f64 / 1e8 * age as f64 / 144.0;
+            let bucket = AGE_BUCKETS
+                .iter()
+                .position(|max_age| age < *max_age)
+                .unwrap_or(AGE_BUCKETS.len());
+            stats.spent_by_age[bucket].0 += 1;
+            stats.spent_by_age[bucket].1 += value;
+        }
+        stats
+    }
+}
+
+// Q -> {height}{scripthash, script, balance}*
+#[cfg(not(feature = "liquid"))]
+pub struct RichListRow;
+
+#[cfg(not(feature = "liquid"))]
+impl RichListRow {
+    pub fn new(rich_list: &RichList) -> DBRow {
+        DBRow {
+            key: RichListRow::key(),
+            value: bincode::serialize(rich_list).unwrap(),
+        }
+    }
+
+    pub fn key() -> Bytes {
+        b"Q".to_vec()
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<RichList> {
+        parse_db_value(value, "RichList")
+    }
+}
+
+/// The number of outputs of each script type, created by a block or summed up over the chain.
+/// Unlike the chain stats, the counts of blocks that are later reorged out are not reverted.
+#[derive(Default, Debug, Clone)]
+pub struct ScriptTypeStats {
+    counts: Vec<u64>, // in SCRIPT_TYPES order
+}
+
+impl ScriptTypeStats {
+    pub fn from_txs<'a>(txs: impl Iterator<Item = &'a Transaction>) -> Self {
+        let mut counts = vec![0; SCRIPT_TYPES.len()];
+        for txo in txs.flat_map(|tx| tx.output.iter()) {
+            let script_type = get_script_type(&txo.script_pubkey);
+            let index = SCRIPT_TYPES
+                .iter()
+                .position(|t| *t == script_type)
+                .expect("script type missing from SCRIPT_TYPES");
+            counts[index] += 1;
+        }
+        ScriptTypeStats { counts }
+    }
+
+    pub fn add(&mut self, other: &ScriptTypeStats) {
+        // rows written before a script type was appended have fewer counts
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+
+    /// The counts by script type name
+    pub fn by_type(&self) -> BTreeMap<&'static str, u64> {
+        let counts = self.counts.iter().copied().chain(iter::repeat(0));
+        SCRIPT_TYPES.iter().copied().zip(counts).collect()
+    }
+
+    // {count}* as varints, mostly a byte or two each
+    fn encode(&self) -> Bytes {
+        let mut buf = vec![];
+        for count in &self.counts {
+            write_varint(&mut buf, *count);
+        }
+        buf
+    }
+
+    pub fn decode(value: &[u8]) -> Result<Self> {
+        let mut bytes = value;
+        let mut counts = vec![];
+        while !bytes.is_empty() {
+            counts.push(read_varint(&mut bytes).chain_err(|| {
+                ErrorKind::DbInconsistency(format!(
+                    "invalid ScriptTypeStats {}",
+                    hex::encode(value)
+                ))
+            })?);
+        }
+        Ok(ScriptTypeStats { counts })
+    }
+}
+
+// K -> {count}*, summed up by merges
+pub struct ScriptTypeStatsRow;
+
+impl ScriptTypeStatsRow {
+    pub fn new(stats: &ScriptTypeStats) -> DBRow {
+        DBRow {
+            key: ScriptTypeStatsRow::key(),
+            value: stats.encode(),
+        }
+    }
+
+    pub fn key() -> Bytes {
+        b"K".to_vec()
+    }
+}
+
+/// RocksDB merge operator for the `ScriptStatsRow`, `UtxoAccRow`, `ScriptActivityRow`,
+/// `ChainStatsRow` and `ScriptTypeStatsRow` operands. The keys of any other row are left
+/// unmerged, and so are the ones with an invalid value, which RocksDB then reports as a
+/// merge failure instead of the process aborting.
+pub fn merge_rows(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+) -> Option<Vec<u8>> {
+    match key.first() {
+        Some(&UTXO_ACC_CODE) => merge_values(
+            key,
+            existing,
+            operands,
+            UtxoAccValue::decode,
+            |state: &mut UtxoAccValue, operand| state.merge(operand),
+        )
+        .map(|state| state.encode()),
+        Some(&SCRIPT_ACTIVITY_CODE) => merge_values(
+            key,
+            existing,
+            operands,
+            ScriptActivityRow::value_from_row,
+            |activity: &mut ScriptActivity, operand| activity.merge(operand),
+        )
+        .map(|activity| bincode::serialize(&activity).unwrap()),
+        Some(&SCRIPT_STATS_CODE) => merge_values(
+            key,
+            existing,
+            operands,
+            ScriptStatsRow::value_from_row,
+            |stats: &mut ScriptStats, operand| stats.add(operand),
+        )
+        .map(|stats| bincode::serialize(&stats).unwrap()),
+        _ if key == &ChainStatsRow::key()[..] => merge_values(
+            key,
+            existing,
+            operands,
+            ChainStatsRow::value_from_row,
+            |stats: &mut ChainStats, operand| stats.add(operand),
+        )
+        .map(|stats| bincode::serialize(&stats).unwrap()),
+        _ if key == &ScriptTypeStatsRow::key()[..] => merge_values(
+            key,
+            existing,
+            operands,
+            ScriptTypeStats::decode,
+            |stats: &mut ScriptTypeStats, operand| stats.add(operand),
+        )
+        .map(|stats| stats.encode()),
+        _ => {
+            error!("no merge operator for key {}", hex::encode(key));
+            None
+        }
+    }
+}
+
+// Folds the operands into the existing value, or into the default one for a new key
+fn merge_values<T: Default>(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut MergeOperands,
+    parse: impl Fn(&[u8]) -> Result<T>,
+    merge: impl Fn(&mut T, &T),
+) -> Option<T> {
+    let parse = |value| {
+        parse(value)
+            .map_err(|e| error!("cannot merge key {}: {}", hex::encode(key), e))
+            .ok()
+    };
+    let mut state = match existing {
+        Some(value) => parse(value)?,
+        None => T::default(),
+    };
+    for operand in operands {
+        merge(&mut state, &parse(operand)?);
+    }
+    Some(state)
+}
+
+const UTXO_ACC_CODE: u8 = b'u';
+
+// The spent outputs are only dropped from the UTXO accumulator once their spend is this deep,
+// so that a reorg can still revert it
+const SPEND_FINALITY_DEPTH: u32 = 100;
+
+// The height of the indexed tip, below which `filter_spent_utxos` drops the final spends
+static UTXO_ACC_TIP_HEIGHT: AtomicU32 = AtomicU32::new(0);
+
+/// Sets the height of the indexed tip, for the compaction of the UTXO accumulator
+pub fn set_utxo_acc_tip_height(height: u32) {
+    UTXO_ACC_TIP_HEIGHT.store(height, Ordering::Relaxed);
+}
+
+// The UTXO accumulator keeps an entry per output of each script: funding an output merges its
+// height and value into the entry, and spending it merges a tombstone naming the spending
+// transaction. Disconnecting the spending block merges a revert over the tombstone. Once the
+// spend is deeper than `SPEND_FINALITY_DEPTH`, the entry is dropped by the `filter_spent_utxos`
+// compaction filter.
+//      u{scripthash}{funding-txid:vout} → {spend-flag}[{spending-txid}{spent-height}]{funded-height}{value}
+pub struct UtxoAccRow {
+    pub scripthash: FullHash,
+    pub outpoint: OutPoint,
+    pub value: UtxoAccValue,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct UtxoAccValue {
+    pub funded: Option<(u32, u64)>, // (height, value)
+    pub spend: Option<UtxoSpend>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtxoSpend {
+    /// Spent by the transaction, confirmed at the height
+    By(Txid, u32),
+    /// The block of the spend was disconnected
+    Reverted,
+}
+
+const SPEND_NONE: u8 = 0;
+const SPEND_BY: u8 = 1;
+const SPEND_REVERTED: u8 = 2;
+
+impl UtxoAccRow {
+    pub fn funding(scripthash: FullHash, outpoint: OutPoint, height: u32, value: u64) -> Self {
+        UtxoAccRow {
+            scripthash,
+            outpoint,
+            value: UtxoAccValue {
+                funded: Some((height, value)),
+                spend: None,
+            },
+        }
+    }
+
+    pub fn spending(scripthash: FullHash, outpoint: OutPoint, txid: Txid, height: u32) -> Self {
+        UtxoAccRow {
+            scripthash,
+            outpoint,
+            value: UtxoAccValue {
+                funded: None,
+                spend: Some(UtxoSpend::By(txid, height)),
+            },
+        }
+    }
+
+    pub fn reverting(scripthash: FullHash, outpoint: OutPoint) -> Self {
+        UtxoAccRow {
+            scripthash,
+            outpoint,
+            value: UtxoAccValue {
+                funded: None,
+                spend: Some(UtxoSpend::Reverted),
+            },
+        }
+    }
+
+    pub fn filter(scripthash: &[u8]) -> Bytes {
+        [&[UTXO_ACC_CODE], scripthash].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: [
+                &Self::filter(&self.scripthash)[..],
+                &self.outpoint.txid[..],
+                &(self.outpoint.vout as u16).to_be_bytes()[..],
+            ]
+            .concat(),
+            value: self.value.encode(),
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Result<Self> {
+        ensure!(
+            row.key.len() == 1 + 32 + 32 + 2,
+            ErrorKind::DbInconsistency(format!("invalid utxo key {}", hex::encode(&row.key)))
+        );
+        let value = UtxoAccValue::decode(&row.value).chain_err(|| {
+            ErrorKind::DbInconsistency(format!("invalid utxo entry {}", hex::encode(&row.value)))
+        })?;
+        Ok(UtxoAccRow {
+            scripthash: full_hash(&row.key[1..33]),
+            outpoint: OutPoint {
+                txid: deserialize(&row.key[33..65]).unwrap(),
+                vout: u16::from_be_bytes(*array_ref![row.key, 65, 2]) as u32,
+            },
+            value,
+        })
+    }
+}
+
+impl UtxoAccValue {
+    // the latest spend (or revert) wins, as the operands are merged in the order of the blocks
+    fn merge(&mut self, other: &UtxoAccValue) {
+        self.funded = self.funded.or(other.funded);
+        self.spend = other.spend.or(self.spend);
+    }
+
+    /// The transaction spending the output, as far as the index goes
+    pub fn spender(&self) -> Option<Txid> {
+        match self.spend {
+            Some(UtxoSpend::By(txid, _)) => Some(txid),
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut buf = vec![];
+        match self.spend {
+            None => buf.push(SPEND_NONE),
+            Some(UtxoSpend::By(txid, height)) => {
+                buf.push(SPEND_BY);
+                buf.extend_from_slice(&txid[..]);
+                buf.extend_from_slice(&height.to_be_bytes());
+            }
+            Some(UtxoSpend::Reverted) => buf.push(SPEND_REVERTED),
+        }
+        if let Some((height, value)) = self.funded {
+            buf.extend_from_slice(&height.to_be_bytes());
+            write_varint(&mut buf, value);
+        }
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let spend = match bytes.first() {
+            Some(&SPEND_BY) => {
+                ensure!(bytes.len() >= 37, "truncated spend");
+                let txid = deserialize(&bytes[1..33]).unwrap();
+                let height = u32::from_be_bytes(*array_ref![bytes, 33, 4]);
+                bytes = &bytes[37..];
+                Some(UtxoSpend::By(txid, height))
+            }
+            Some(&SPEND_REVERTED) => {
+                bytes = &bytes[1..];
+                Some(UtxoSpend::Reverted)
+            }
+            Some(&SPEND_NONE) => {
+                bytes = &bytes[1..];
+                None
+            }
+            _ => bail!("invalid spend"),
+        };
+        let funded = if bytes.is_empty() {
+            None
+        } else {
+            ensure!(bytes.len() > 4, "truncated funding");
+            let height = u32::from_be_bytes(*array_ref![bytes, 0, 4]);
+            bytes = &bytes[4..];
+            let value = read_varint(&mut bytes)?;
+            Some((height, value))
+        };
+        Ok(UtxoAccValue { funded, spend })
+    }
+}
+
+/// RocksDB compaction filter dropping the accumulated UTXOs that were both funded and spent,
+/// once the spend is too deep to be reorged out
+pub fn filter_spent_utxos(_level: u32, key: &[u8], value: &[u8]) -> CompactionDecision {
+    if key.first() == Some(&UTXO_ACC_CODE) {
+        if let Ok(UtxoAccValue {
+            funded: Some(_),
+            spend: Some(UtxoSpend::By(_, height)),
+        }) = UtxoAccValue::decode(value)
+        {
+            let tip_height = UTXO_ACC_TIP_HEIGHT.load(Ordering::Relaxed);
+            if height + SPEND_FINALITY_DEPTH <= tip_height {
+                return CompactionDecision::Remove;
+            }
+        }
+    }
+    CompactionDecision::Keep
+}
+
+pub type CachedUtxoMap = HashMap<(Txid, u32), (u32, Value)>; // (txid,vout) => (block_height,output_value)
+
+pub struct UtxoCacheRow {
+    key: ScriptCacheKey,
+    value: Bytes,
+}
+
+impl UtxoCacheRow {
+    pub fn new(scripthash: &[u8], utxos: &UtxoMap, blockhash: &BlockHash) -> Self {
+        let utxos_cache = make_utxo_cache(utxos);
+
+        UtxoCacheRow {
+            key: ScriptCacheKey {
+                code: b'U',
+                scripthash: full_hash(scripthash),
+            },
+            value: bincode::serialize(&(utxos_cache, blockhash)).unwrap(),
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [b"U", scripthash].concat()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: self.value,
+        }
+    }
+}
 
 // keep utxo cache with just the block height (the hash/timestamp are read later from the headers to reconstruct BlockId)
 // and use a (txid,vout) tuple instead of OutPoints (they don't play nicely with bincode serialization)
@@ -513,3 +1748,368 @@ pub fn make_utxo_cache(utxos: &UtxoMap) -> CachedUtxoMap {
         })
         .collect()
 }
+
+// A bincode value read from the DB, which fails with `DbInconsistency` when it can't be parsed
+fn parse_db_value<T: serde::de::DeserializeOwned>(value: &[u8], name: &str) -> Result<T> {
+    bincode::deserialize(value).chain_err(|| {
+        ErrorKind::DbInconsistency(format!("invalid {} {}", name, hex::encode(value)))
+    })
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut n = 0u64;
+    for (i, byte) in bytes.iter().enumerate() {
+        // a u64 fits in 10 bytes, the 10th one holding a single bit
+        ensure!(i < 9 || (i == 9 && byte & 0x7e == 0), "varint overflow");
+        n |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Ok(n);
+        }
+    }
+    bail!("truncated varint")
+}
+
+// An input or output index
+fn read_index(bytes: &mut &[u8]) -> Result<u16> {
+    let index = read_varint(bytes)?;
+    u16::try_from(index).chain_err(|| format!("index {} out of range", index))
+}
+
+fn read_hash(bytes: &mut &[u8]) -> Result<FullHash> {
+    ensure!(bytes.len() >= HASH_LEN, "truncated hash");
+    let (hash, rest) = bytes.split_at(HASH_LEN);
+    *bytes = rest;
+    Ok(full_hash(hash))
+}
+
+#[cfg(feature = "liquid")]
+fn deserialize_from<T: serde::de::DeserializeOwned>(bytes: &mut &[u8]) -> Result<T> {
+    bincode::deserialize_from(bytes).chain_err(|| "invalid bincode encoding")
+}
+
+#[cfg(not(feature = "liquid"))]
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    write_varint(buf, *value);
+}
+
+#[cfg(not(feature = "liquid"))]
+fn decode_value(bytes: &mut &[u8]) -> Result<Value> {
+    read_varint(bytes)
+}
+
+#[cfg(feature = "liquid")]
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    bincode::serialize_into(buf, value).unwrap();
+}
+
+#[cfg(feature = "liquid")]
+fn decode_value(bytes: &mut &[u8]) -> Result<Value> {
+    deserialize_from(bytes)
+}
+
+// The external labels of scripts (e.g. exchange tags), loaded from `--labels-file`:
+//      l{scripthash} → {label}
+pub struct LabelRow;
+
+impl LabelRow {
+    pub fn new(scripthash: &[u8], label: &str) -> DBRow {
+        DBRow {
+            key: LabelRow::key(scripthash),
+            value: label.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [b"l", scripthash].concat()
+    }
+
+    pub fn filter() -> Bytes {
+        b"l".to_vec()
+    }
+
+    pub fn label_from_value(value: Bytes) -> Result<String> {
+        String::from_utf8(value)
+            .chain_err(|| ErrorKind::DbInconsistency("invalid label".to_string()))
+    }
+}
+
+// The inscriptions revealed by a confirmed transaction, for the transactions with any:
+//      I{txid} → {inscriptions}
+#[cfg(feature = "ordinals")]
+pub struct InscriptionRow;
+
+#[cfg(feature = "ordinals")]
+impl InscriptionRow {
+    pub fn new(txid: FullHash, inscriptions: &[Inscription]) -> DBRow {
+        DBRow {
+            key: InscriptionRow::key(&txid),
+            value: bincode::serialize(inscriptions).unwrap(),
+        }
+    }
+
+    pub fn key(txid: &[u8]) -> Bytes {
+        [b"I", txid].concat()
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<Vec<Inscription>> {
+        parse_db_value(value, "Inscriptions")
+    }
+}
+
+// The decoded runestone of a confirmed transaction, for the transactions with one:
+//      E{txid} → {runestone}
+#[cfg(feature = "runes")]
+pub struct RunestoneRow;
+
+#[cfg(feature = "runes")]
+impl RunestoneRow {
+    pub fn new(txid: FullHash, runestone: &Runestone) -> DBRow {
+        DBRow {
+            key: RunestoneRow::key(&txid),
+            value: bincode::serialize(runestone).unwrap(),
+        }
+    }
+
+    pub fn key(txid: &[u8]) -> Bytes {
+        [b"E", txid].concat()
+    }
+
+    pub fn value_from_row(value: &[u8]) -> Result<Runestone> {
+        parse_db_value(value, "Runestone")
+    }
+}
+
+// The last time a script's stats or UTXOs were queried, so that the caches of the scripts queried
+// before a restart can be warmed up again:
+//      w{scripthash} → {unix-timestamp}
+pub struct QueryLogRow;
+
+impl QueryLogRow {
+    pub fn new(scripthash: &[u8], time: u32) -> DBRow {
+        DBRow {
+            key: QueryLogRow::key(scripthash),
+            value: bincode::serialize(&time).unwrap(),
+        }
+    }
+
+    pub fn key(scripthash: &[u8]) -> Bytes {
+        [b"w", scripthash].concat()
+    }
+
+    pub fn filter() -> Bytes {
+        b"w".to_vec()
+    }
+
+    /// The scripthash and the last time it was queried
+    pub fn from_row(row: DBRow) -> (FullHash, u32) {
+        let time = bincode::deserialize(&row.value).expect("failed to parse query time");
+        (full_hash(&row.key[1..]), time)
+    }
+}
+
+// The last time a cache row was used, so that the least recently used rows can be evicted:
+//      L{cache-row-key} → {unix-timestamp}
+pub struct CacheAccessRow;
+
+impl CacheAccessRow {
+    pub fn new(cache_key: &[u8], time: u32) -> DBRow {
+        DBRow {
+            key: CacheAccessRow::key(cache_key),
+            value: bincode::serialize(&time).unwrap(),
+        }
+    }
+
+    pub fn key(cache_key: &[u8]) -> Bytes {
+        [b"L", cache_key].concat()
+    }
+
+    pub fn filter() -> Bytes {
+        b"L".to_vec()
+    }
+
+    /// The key of the cache row and its last access time
+    pub fn from_row(row: DBRow) -> (Bytes, u32) {
+        let time = bincode::deserialize(&row.value).expect("failed to parse cache access time");
+        (row.key[1..].to_vec(), time)
+    }
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+pub mod fixtures {
+    use super::*;
+
+    /// The funding of output `vout` by the transaction with a txid of `txid` bytes
+    pub fn funding(txid: u8, vout: u16, value: u64) -> TxHistoryInfo {
+        TxHistoryInfo::Funding(FundingInfo {
+            txid: [txid; 32],
+            vout,
+            value,
+        })
+    }
+
+    /// The spending by input `vin` of `txid` of output `prev_vout` of `prev_txid`
+    pub fn spending(
+        txid: u8,
+        vin: u16,
+        prev_txid: u8,
+        prev_vout: u16,
+        value: u64,
+    ) -> TxHistoryInfo {
+        TxHistoryInfo::Spending(SpendingInfo {
+            txid: [txid; 32],
+            vin,
+            prev_txid: [prev_txid; 32],
+            prev_vout,
+            value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "liquid"))]
+    use fixtures::{funding, spending};
+
+    fn varint(n: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        write_varint(&mut buf, n);
+        buf
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for (n, len) in [
+            (0, 1),
+            (0x7f, 1),
+            (0x80, 2),
+            (0x3fff, 2),
+            (0x4000, 3),
+            (u32::MAX as u64, 5),
+            (u64::MAX - 1, 10),
+            (u64::MAX, 10),
+        ] {
+            let buf = varint(n);
+            assert_eq!(buf.len(), len, "length of {}", n);
+            let mut bytes = &buf[..];
+            assert_eq!(read_varint(&mut bytes).unwrap(), n);
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn varint_leaves_the_rest() {
+        let mut buf = varint(300);
+        buf.push(42);
+        let mut bytes = &buf[..];
+        assert_eq!(read_varint(&mut bytes).unwrap(), 300);
+        assert_eq!(bytes, &[42]);
+    }
+
+    #[test]
+    fn varint_errors() {
+        // the continuation bit is set on the last byte
+        assert!(read_varint(&mut &[][..]).is_err());
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        assert!(read_varint(&mut &varint(u64::MAX)[..9]).is_err());
+
+        // more than the 64 bits of a u64
+        let mut too_big = varint(u64::MAX);
+        too_big[9] = 0x02;
+        assert!(read_varint(&mut &too_big[..]).is_err());
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+
+        let mut bytes = &varint(u16::MAX as u64 + 1)[..];
+        assert!(read_index(&mut bytes).is_err());
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn encode(info: &TxHistoryInfo) -> Vec<u8> {
+        let mut buf = vec![];
+        info.encode(&mut buf);
+        buf
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn history_entry_roundtrip() {
+        for (vout, value) in [(0, 0), (1, 546), (u16::MAX, 2_100_000_000_000_000)] {
+            match TxHistoryInfo::decode(&encode(&funding(1, vout, value))).unwrap() {
+                TxHistoryInfo::Funding(info) => {
+                    assert_eq!((info.txid, info.vout, info.value), ([1; 32], vout, value))
+                }
+                other => panic!("decoded {:?}", other),
+            }
+            match TxHistoryInfo::decode(&encode(&spending(2, vout, 3, 7, value))).unwrap() {
+                TxHistoryInfo::Spending(info) => assert_eq!(
+                    (
+                        info.txid,
+                        info.vin,
+                        info.prev_txid,
+                        info.prev_vout,
+                        info.value
+                    ),
+                    ([2; 32], vout, [3; 32], 7, value)
+                ),
+                other => panic!("decoded {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn history_entry_errors() {
+        let entry = encode(&spending(2, 1, 3, 2, 100_000));
+        assert!(TxHistoryInfo::decode(&[]).is_err());
+        assert!(TxHistoryInfo::decode(&entry[..entry.len() - 1]).is_err());
+        assert!(TxHistoryInfo::decode(&entry[..40]).is_err());
+        assert!(TxHistoryInfo::decode(&[&entry[..], &[0]].concat()).is_err());
+        assert!(TxHistoryInfo::decode(&[&[9], &entry[1..]].concat()).is_err());
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn history_row_roundtrip() {
+        let row = TxHistoryRow::new([4; 32], 700_000, 12, funding(1, 3, 5_000)).into_row();
+        assert!(row
+            .key
+            .starts_with(&TxHistoryRow::prefix_height(b'H', &[4; 32], 700_000)));
+        let row = TxHistoryRow::from_row(row).unwrap();
+        assert_eq!(row.key.hash, [4; 32]);
+        assert_eq!(row.key.confirmed_height, 700_000);
+        assert_eq!(row.key.tx_position, 12);
+        assert_eq!(row.txinfo.get_funded_outpoint().vout, 3);
+    }
+
+    // The layout of the entries before the varints: bincode with its own integer encoding
+    #[cfg(not(feature = "liquid"))]
+    fn bincode_size(info: &TxHistoryInfo) -> usize {
+        bincode::options()
+            .with_big_endian()
+            .serialize(info)
+            .unwrap()
+            .len()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn history_entry_size() {
+        for value in [0, 546, 100_000, 50_000_000, 1 << 33, 2_100_000_000_000_000] {
+            for info in [funding(1, 1, value), spending(2, 0, 3, 1, value)] {
+                assert!(encode(&info).len() <= bincode_size(&info), "{:?}", info);
+            }
+        }
+        // the amounts between 2^16 and 2^21 sat take 3 bytes instead of 5
+        let info = funding(1, 1, 100_000);
+        assert_eq!(bincode_size(&info) - encode(&info).len(), 2);
+    }
+}
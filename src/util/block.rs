@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::iter::FromIterator;
-use std::{fmt, slice};
+use std::{fmt, ops::Range, sync::Arc};
 
 use bitcoin::{BlockHash, BlockHeader};
 use time::format_description::well_known::Rfc3339;
@@ -62,13 +62,27 @@ impl fmt::Debug for HeaderEntry {
     }
 }
 
-#[derive(Debug, Default)]
+// the recent headers are moved to the shared part once there are this many of them
+const SEAL_SIZE: usize = 2016;
+
+/// The best chain of headers.
+///
+/// Most of it is kept behind an `Arc`, with only the recent headers held separately, so that
+/// cloning the list (e.g. to publish a new snapshot on every tip change) stays cheap.
+#[derive(Debug, Default, Clone)]
 pub struct HeaderList {
-    headers: Vec<HeaderEntry>,
-    heights: HashMap<BlockHash, usize>,
+    // heights [0..sealed.len())
+    sealed: Arc<Vec<HeaderEntry>>,
+    sealed_heights: Arc<HashMap<BlockHash, usize>>,
+    // heights [sealed.len()..len())
+    recent: Vec<HeaderEntry>,
+    recent_heights: HashMap<BlockHash, usize>,
     tip: BlockHash,
 }
 
+/// An immutable view of the header chain, which can be held without blocking the indexer
+pub type HeaderSnapshot = Arc<HeaderList>;
+
 impl HeaderList {
     pub fn new(mut headers_map: HashMap<BlockHash, BlockHeader>, tip_hash: BlockHash) -> Self {
         trace!(
@@ -159,7 +173,10 @@ impl HeaderList {
             Some(entry) => {
                 let height = entry.height();
                 let expected_prev_blockhash = if height > 0 {
-                    *self.headers[height - 1].hash()
+                    *self
+                        .header_by_height(height - 1)
+                        .unwrap_or_else(|| panic!("missing header at height {}", height - 1))
+                        .hash()
                 } else {
                     BlockHash::default()
                 };
@@ -173,19 +190,38 @@ impl HeaderList {
             new_headers.len(),
             new_height
         );
-        let _removed = self.headers.split_off(new_height); // keep [0..new_height) entries
+        // keep [0..new_height) entries
+        if new_height < self.sealed.len() {
+            // a reorg deeper than the recent headers, which copies the shared ones if needed
+            Arc::make_mut(&mut self.sealed).truncate(new_height);
+            self.recent.clear();
+        } else {
+            self.recent.truncate(new_height - self.sealed.len());
+        }
         for new_header in new_headers {
             let height = new_header.height();
-            assert_eq!(height, self.headers.len());
+            assert_eq!(height, self.len());
             self.tip = *new_header.hash();
-            self.headers.push(new_header);
-            self.heights.insert(self.tip, height);
+            self.recent.push(new_header);
+            self.recent_heights.insert(self.tip, height);
+        }
+        if self.recent.len() >= SEAL_SIZE {
+            self.seal();
         }
     }
 
+    fn seal(&mut self) {
+        Arc::make_mut(&mut self.sealed).extend(self.recent.drain(..));
+        Arc::make_mut(&mut self.sealed_heights).extend(self.recent_heights.drain());
+    }
+
     pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<&HeaderEntry> {
-        let height = self.heights.get(blockhash)?;
-        let header = self.headers.get(*height)?;
+        // the maps keep the hashes of reorged blocks, which are filtered out below
+        let height = self
+            .recent_heights
+            .get(blockhash)
+            .or_else(|| self.sealed_heights.get(blockhash))?;
+        let header = self.header_by_height(*height)?;
         if *blockhash == *header.hash() {
             Some(header)
         } else {
@@ -194,34 +230,77 @@ impl HeaderList {
     }
 
     pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
-        self.headers.get(height).map(|entry| {
+        let entry = if height < self.sealed.len() {
+            self.sealed.get(height)
+        } else {
+            self.recent.get(height - self.sealed.len())
+        };
+        entry.map(|entry| {
             assert_eq!(entry.height(), height);
             entry
         })
     }
 
     pub fn is_empty(&self) -> bool {
-        self.headers.is_empty()
+        self.len() == 0
     }
 
     pub fn len(&self) -> usize {
-        self.headers.len()
+        self.sealed.len() + self.recent.len()
+    }
+
+    fn last(&self) -> Option<&HeaderEntry> {
+        self.recent.last().or_else(|| self.sealed.last())
     }
 
     pub fn equals(&self, other: &HeaderList) -> bool {
-        self.headers.last() == other.headers.last()
+        self.last() == other.last()
+    }
+
+    /// The height of the first block that differs from `other`'s, 0 without any common block
+    pub fn fork_height(&self, other: &HeaderList) -> usize {
+        (0..self.len().min(other.len()))
+            .rev()
+            .find(|&height| self.header_by_height(height) == other.header_by_height(height))
+            .map_or(0, |height| height + 1)
     }
 
     pub fn tip(&self) -> &BlockHash {
-        assert_eq!(
-            self.tip,
-            self.headers.last().map(|h| *h.hash()).unwrap_or_default()
-        );
+        assert_eq!(self.tip, self.last().map(|h| *h.hash()).unwrap_or_default());
         &self.tip
     }
 
-    pub fn iter(&self) -> slice::Iter<HeaderEntry> {
-        self.headers.iter()
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HeaderEntry> {
+        self.sealed.iter().chain(self.recent.iter())
+    }
+
+    /// The headers within `range` heights, truncated to the current tip
+    pub fn iter_range(&self, range: Range<usize>) -> impl DoubleEndedIterator<Item = &HeaderEntry> {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        let split = self.sealed.len();
+        let sealed = &self.sealed[start.min(split)..end.min(split)];
+        let recent = &self.recent[start.saturating_sub(split)..end.saturating_sub(split)];
+        sealed.iter().chain(recent.iter())
+    }
+
+    /// The lowest height with a Median Time Past of at least `time`, found by binary search
+    /// as the MTP never decreases along the chain
+    pub fn height_by_mtp(&self, time: u32) -> Option<usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.get_mtp(mid) < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low < self.len() {
+            Some(low)
+        } else {
+            None
+        }
     }
 
     /// Get the Median Time Past
@@ -229,12 +308,12 @@ impl HeaderList {
         // Use the timestamp as the mtp of the genesis block.
         // Matches bitcoind's behaviour: bitcoin-cli getblock `bitcoin-cli getblockhash 0` | jq '.time == .mediantime'
         if height == 0 {
-            self.headers.first().unwrap().header.time
+            self.header_by_height(0).unwrap().header.time
         } else if height > self.len() - 1 {
             0
         } else {
             let mut timestamps = (height.saturating_sub(MTP_SPAN - 1)..=height)
-                .map(|p_height| self.headers.get(p_height).unwrap().header.time)
+                .map(|p_height| self.header_by_height(p_height).unwrap().header.time)
                 .collect::<Vec<_>>();
             timestamps.sort_unstable();
             timestamps[timestamps.len() / 2]
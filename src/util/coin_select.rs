@@ -0,0 +1,196 @@
+// Bitcoin Core's limit on the branch-and-bound search
+const BNB_MAX_TRIES: usize = 100_000;
+// The size of a P2WPKH change output, used to price the creation of change
+const CHANGE_OUTPUT_VSIZE: u64 = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// An exact match (within the cost of creating change), which needs no change output
+    BranchAndBound,
+    /// The largest coins first, leaving change
+    LargestFirst,
+}
+
+#[derive(Debug)]
+pub struct Selection {
+    /// The indexes of the selected coins, in the given slice
+    pub selected: Vec<usize>,
+    pub algorithm: Algorithm,
+    /// The total value of the selected coins
+    pub total: u64,
+    /// The fee for spending the selected coins
+    pub fee: u64,
+    /// What is left after paying the target and the fee. Below the cost of change for
+    /// branch-and-bound selections, which should add it to the fee instead.
+    pub excess: u64,
+}
+
+/// Selects coins worth at least `target` once the fee for spending them at `feerate` (sat/vB,
+/// with `input_vsize` vbytes per input) is paid. Coins that cost more to spend than they are
+/// worth are never selected. Returns `None` when there aren't enough funds.
+pub fn select_coins(
+    values: &[u64],
+    target: u64,
+    feerate: f64,
+    input_vsize: u64,
+) -> Option<Selection> {
+    let input_fee = (feerate * input_vsize as f64).ceil() as u64;
+    let cost_of_change = (feerate * (CHANGE_OUTPUT_VSIZE + input_vsize) as f64).ceil() as u64;
+
+    // effective values, largest first
+    let mut candidates: Vec<(usize, u64)> = values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value > input_fee)
+        .map(|(index, value)| (index, value - input_fee))
+        .collect();
+    candidates.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    let effective: Vec<u64> = candidates.iter().map(|(_, value)| *value).collect();
+
+    let (chosen, algorithm) = match branch_and_bound(&effective, target, cost_of_change) {
+        Some(chosen) => (chosen, Algorithm::BranchAndBound),
+        None => (largest_first(&effective, target)?, Algorithm::LargestFirst),
+    };
+
+    let selected: Vec<usize> = chosen.iter().map(|&i| candidates[i].0).collect();
+    let total = selected.iter().map(|&i| values[i]).sum();
+    let fee = input_fee * selected.len() as u64;
+    Some(Selection {
+        selected,
+        algorithm,
+        total,
+        fee,
+        excess: total - fee - target,
+    })
+}
+
+// Depth-first search for the subset closest to `target` without exceeding it by more than
+// `window`, over values sorted in descending order
+fn branch_and_bound(values: &[u64], target: u64, window: u64) -> Option<Vec<usize>> {
+    let mut remaining: u64 = values.iter().sum();
+    if remaining < target {
+        return None;
+    }
+
+    let mut selected: Vec<usize> = vec![];
+    let mut current = 0;
+    let mut index = 0;
+    let mut best: Option<(u64, Vec<usize>)> = None;
+
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if current + remaining < target || current > target + window {
+            true
+        } else if current >= target {
+            let excess = current - target;
+            if best
+                .as_ref()
+                .map_or(true, |(best_excess, _)| excess < *best_excess)
+            {
+                best = Some((excess, selected.clone()));
+            }
+            if excess == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // exclude the last selected value, and try again with the ones after it
+            let last = match selected.pop() {
+                Some(last) => last,
+                None => break,
+            };
+            remaining += values[last + 1..index].iter().sum::<u64>();
+            current -= values[last];
+            index = last + 1;
+        } else {
+            current += values[index];
+            remaining -= values[index];
+            selected.push(index);
+            index += 1;
+        }
+    }
+
+    best.map(|(_, selected)| selected)
+}
+
+fn largest_first(values: &[u64], target: u64) -> Option<Vec<usize>> {
+    let mut total = 0;
+    for (index, value) in values.iter().enumerate() {
+        total += value;
+        if total >= target {
+            return Some((0..=index).collect());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 68 vbytes per P2WPKH input, so spending a coin costs 68 sats at 1 sat/vB
+    const INPUT_VSIZE: u64 = 68;
+
+    #[test]
+    fn exact_match() {
+        let values = [100_000, 50_000, 30_000];
+        let target = (50_000 - 68) + (30_000 - 68);
+        let selection = select_coins(&values, target, 1.0, INPUT_VSIZE).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::BranchAndBound);
+        assert_eq!(selection.selected, vec![1, 2]);
+        assert_eq!(selection.total, 80_000);
+        assert_eq!(selection.fee, 136);
+        assert_eq!(selection.excess, 0);
+    }
+
+    #[test]
+    fn match_within_cost_of_change() {
+        // creating change would cost 31 + 68 sats, so an excess below that is kept as fee
+        let values = [50_000, 30_000];
+        let target = 50_000 - 68 - 90;
+        let selection = select_coins(&values, target, 1.0, INPUT_VSIZE).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::BranchAndBound);
+        assert_eq!(selection.selected, vec![0]);
+        assert_eq!(selection.excess, 90);
+    }
+
+    #[test]
+    fn largest_first_fallback() {
+        let values = [30_000, 100_000];
+        let selection = select_coins(&values, 50_000, 1.0, INPUT_VSIZE).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::LargestFirst);
+        assert_eq!(selection.selected, vec![1]);
+        assert_eq!(selection.total, 100_000);
+        assert_eq!(selection.fee, 68);
+        assert_eq!(selection.excess, 100_000 - 68 - 50_000);
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        assert!(select_coins(&[], 1, 1.0, INPUT_VSIZE).is_none());
+        assert!(select_coins(&[1_000, 2_000], 3_000, 1.0, INPUT_VSIZE).is_none());
+        // the uneconomical coins don't count toward the target
+        assert!(select_coins(&[68, 60, 1_000], 1_000, 1.0, INPUT_VSIZE).is_none());
+        assert_eq!(
+            select_coins(&[68, 60, 1_068], 1_000, 1.0, INPUT_VSIZE)
+                .unwrap()
+                .selected,
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn iteration_limit() {
+        // the sums of equal coins never land within the cost of change above the target, and
+        // trying all of their subsets would take far more than BNB_MAX_TRIES steps
+        let values = [1_068; 40];
+        let selection = select_coins(&values, 20_500, 1.0, INPUT_VSIZE).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::LargestFirst);
+        assert_eq!(selection.selected.len(), 21);
+        assert_eq!(selection.excess, 21 * 1_000 - 20_500);
+    }
+}
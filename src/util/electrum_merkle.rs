@@ -0,0 +1,178 @@
+use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Hash};
+
+use crate::{errors::*, util::block::HeaderList};
+
+/// The merkle branch of the header at `height`, in the tree of all the block hashes up to
+/// `cp_height` (included), and the root of that tree
+pub fn get_header_merkle_proof(
+    headers: &HeaderList,
+    height: usize,
+    cp_height: usize,
+) -> Result<(Vec<Sha256dHash>, Sha256dHash)> {
+    ensure!(
+        height <= cp_height,
+        "cp_height #{} < height #{}",
+        cp_height,
+        height
+    );
+    let best_height = headers
+        .len()
+        .checked_sub(1)
+        .chain_err(|| "no blocks indexed yet")?;
+    ensure!(
+        cp_height <= best_height,
+        "cp_height #{} above best block height #{}",
+        cp_height,
+        best_height
+    );
+
+    let hashes: Vec<Sha256dHash> = (0..=cp_height)
+        .map(|height| {
+            let hash = headers.header_by_height(height).unwrap().hash();
+            Sha256dHash::from_inner(hash.into_inner())
+        })
+        .collect();
+
+    Ok(create_merkle_branch_and_root(hashes, height))
+}
+
+fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
+    let data = [&left[..], &right[..]].concat();
+    Sha256dHash::hash(&data)
+}
+
+fn create_merkle_branch_and_root(
+    mut hashes: Vec<Sha256dHash>,
+    mut index: usize,
+) -> (Vec<Sha256dHash>, Sha256dHash) {
+    let mut merkle = vec![];
+    while hashes.len() > 1 {
+        if hashes.len() % 2 != 0 {
+            let last = *hashes.last().unwrap();
+            hashes.push(last);
+        }
+        index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        merkle.push(hashes[index]);
+        index /= 2;
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merklize(pair[0], pair[1]))
+            .collect()
+    }
+    (merkle, hashes[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{BlockHash, BlockHeader, TxMerkleNode};
+
+    use super::*;
+
+    // The mainnet blocks 0 to 8
+    const MAINNET_HASHES: [&str; 9] = [
+        "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048",
+        "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd",
+        "0000000082b5015589a3fdf2d4baff403e6f0be035a5d9742c1cae6295464449",
+        "000000004ebadb55ee9096c9a2f8880e09da59c0d68b1c228da88e48844a1485",
+        "000000009b7262315dbf071787ad3656097b892abffd1f95a1a022f896f533fc",
+        "000000003031a0e73735690c5a1ff2a4be82553b2a12b776fbd3a215dc8f778d",
+        "0000000071966c2b1d065fd446b1e485b2c9d9594acd2007ccbd5441cfc89444",
+        "00000000408c48f847aa786c2268fc3e6ec2af68e8468a34a28c61b7f1de0dc6",
+    ];
+
+    fn hash(hex: &str) -> Sha256dHash {
+        Sha256dHash::from_str(hex).unwrap()
+    }
+
+    // A chain of `len` blocks
+    fn chain(len: u32) -> HeaderList {
+        let mut headers = HeaderList::default();
+        let mut prev_blockhash = BlockHash::default();
+        let new_headers: Vec<BlockHeader> = (0..len)
+            .map(|nonce| {
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: TxMerkleNode::default(),
+                    time: 1_600_000_000 + nonce,
+                    bits: 0x207fffff,
+                    nonce,
+                };
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect();
+        headers.apply(headers.order(new_headers));
+        headers
+    }
+
+    // The root of the tree with the hash at `index`, according to its branch
+    fn fold_branch(mut hash: Sha256dHash, mut index: usize, branch: &[Sha256dHash]) -> Sha256dHash {
+        for sibling in branch {
+            hash = if index % 2 == 0 {
+                merklize(hash, *sibling)
+            } else {
+                merklize(*sibling, hash)
+            };
+            index /= 2;
+        }
+        hash
+    }
+
+    #[test]
+    fn mainnet_branch() {
+        // the blockchain.block.header(5, 8) example of the Electrum protocol docs
+        let hashes = MAINNET_HASHES.iter().map(|hex| hash(hex)).collect();
+        let (branch, root) = create_merkle_branch_and_root(hashes, 5);
+        assert_eq!(
+            branch,
+            vec![
+                hash("000000004ebadb55ee9096c9a2f8880e09da59c0d68b1c228da88e48844a1485"),
+                hash("96cbbc84783888e4cc971ae8acf86dd3c1a419370336bb3c634c97695a8c5ac9"),
+                hash("965ac94082cebbcffe458075651e9cc33ce703ab0115c72d9e8b1a9906b2b636"),
+                hash("89e5daa6950b895190716dd26054432b564ccdc2868188ba1da76de8e1dc7591"),
+            ]
+        );
+        assert_eq!(
+            root,
+            hash("e347b1c43fd9b5415bf0d92708db8284b78daf4d0e24f9c3405f45feb85e25db")
+        );
+    }
+
+    #[test]
+    fn header_proofs() {
+        // odd levels duplicate their last hash
+        let headers = chain(7);
+        for cp_height in 0..7 {
+            let root = get_header_merkle_proof(&headers, cp_height, cp_height)
+                .unwrap()
+                .1;
+            for height in 0..=cp_height {
+                let (branch, proof_root) =
+                    get_header_merkle_proof(&headers, height, cp_height).unwrap();
+                assert_eq!(proof_root, root);
+                let blockhash = headers.header_by_height(height).unwrap().hash();
+                let leaf = Sha256dHash::from_inner(blockhash.into_inner());
+                assert_eq!(fold_branch(leaf, height, &branch), root);
+            }
+        }
+        // a single block is its own root
+        let (branch, root) = get_header_merkle_proof(&headers, 0, 0).unwrap();
+        assert!(branch.is_empty());
+        assert_eq!(
+            root,
+            Sha256dHash::from_inner(headers.header_by_height(0).unwrap().hash().into_inner())
+        );
+    }
+
+    #[test]
+    fn header_proof_errors() {
+        let headers = chain(3);
+        assert!(get_header_merkle_proof(&headers, 2, 1).is_err());
+        assert!(get_header_merkle_proof(&headers, 0, 3).is_err());
+        assert!(get_header_merkle_proof(&HeaderList::default(), 0, 0).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+use crate::{
+    chain::Txid,
+    util::{block::BlockId, FullHash},
+};
+
+/// An entry of a script history, as sent to Electrum clients
+#[derive(Serialize, Debug)]
+pub struct HistoryEntry {
+    #[serde(rename = "tx_hash")]
+    pub txid: Txid,
+    pub height: isize,
+}
+
+impl HistoryEntry {
+    /// The height is the confirming block's, or 0 for an unconfirmed transaction and -1 when
+    /// it also has unconfirmed parents
+    pub fn new(txid: Txid, blockid: Option<&BlockId>, has_unconfirmed_parents: bool) -> Self {
+        let height = match blockid {
+            Some(blockid) => blockid.height as isize,
+            None if has_unconfirmed_parents => -1,
+            None => 0,
+        };
+        HistoryEntry { txid, height }
+    }
+}
+
+/// The status of a script: the sha256 of the `txid:height:` of all its history entries,
+/// confirmed ones first (oldest first), or None if it has no history
+pub fn status_hash(history: &[HistoryEntry]) -> Option<FullHash> {
+    if history.is_empty() {
+        return None;
+    }
+    let mut engine = sha256::Hash::engine();
+    for entry in history {
+        engine.input(format!("{}:{}:", entry.txid, entry.height).as_bytes());
+    }
+    Some(sha256::Hash::from_engine(engine).into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::BlockHash;
+
+    use super::*;
+
+    fn txid(hex: &str) -> Txid {
+        Txid::from_str(hex).unwrap()
+    }
+
+    fn blockid(height: usize) -> BlockId {
+        BlockId {
+            height,
+            hash: BlockHash::default(),
+            time: 0,
+        }
+    }
+
+    fn genesis_coinbase() -> Txid {
+        txid("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+    }
+
+    fn block1_coinbase() -> Txid {
+        txid("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098")
+    }
+
+    #[test]
+    fn entry_heights() {
+        let confirmed = HistoryEntry::new(genesis_coinbase(), Some(&blockid(7)), false);
+        assert_eq!(confirmed.height, 7);
+        // a confirmed transaction has no unconfirmed parents
+        let confirmed = HistoryEntry::new(genesis_coinbase(), Some(&blockid(7)), true);
+        assert_eq!(confirmed.height, 7);
+        assert_eq!(HistoryEntry::new(genesis_coinbase(), None, false).height, 0);
+        assert_eq!(HistoryEntry::new(genesis_coinbase(), None, true).height, -1);
+    }
+
+    #[test]
+    fn empty_history() {
+        assert_eq!(status_hash(&[]), None);
+    }
+
+    #[test]
+    fn known_status() {
+        // sha256("{txid}:0:{txid}:1:"), with the txids in their usual (reversed) hex
+        let history = [
+            HistoryEntry::new(genesis_coinbase(), Some(&blockid(0)), false),
+            HistoryEntry::new(block1_coinbase(), Some(&blockid(1)), false),
+        ];
+        assert_eq!(
+            hex::encode(status_hash(&history).unwrap()),
+            "35176d70a6ff63ace71db3491e0c63a896a939aa33d75cb7723572507b0ee4de"
+        );
+    }
+
+    #[test]
+    fn unconfirmed_status() {
+        let status = |height| {
+            let mut engine = sha256::Hash::engine();
+            engine.input(format!("{}:{}:", genesis_coinbase(), height).as_bytes());
+            Some(sha256::Hash::from_engine(engine).into_inner())
+        };
+        let unconfirmed = [HistoryEntry::new(genesis_coinbase(), None, false)];
+        assert_eq!(status_hash(&unconfirmed), status(0));
+        let unconfirmed_parents = [HistoryEntry::new(genesis_coinbase(), None, true)];
+        assert_eq!(status_hash(&unconfirmed_parents), status(-1));
+        assert_ne!(status_hash(&unconfirmed), status_hash(&unconfirmed_parents));
+    }
+
+    #[test]
+    fn status_depends_on_order() {
+        let history = [
+            HistoryEntry::new(block1_coinbase(), Some(&blockid(1)), false),
+            HistoryEntry::new(genesis_coinbase(), Some(&blockid(0)), false),
+        ];
+        assert_eq!(
+            hex::encode(status_hash(&history).unwrap()),
+            "fd1a62fc602413db049f02d05f9267ad27dbe3d38e40a680c6e80e844fac0f67"
+        );
+    }
+}
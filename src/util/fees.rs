@@ -0,0 +1,91 @@
+// bitcoind reports fee rates in BTC/kvB, while they are served in sat/vB
+const SAT_PER_BTC: f64 = 100_000_000.0;
+const VBYTES_PER_KVB: f64 = 1_000.0;
+
+/// Converts a BTC/kvB fee rate to sat/vB. bitcoind's rates are whole sat/kvB, so the result is
+/// rounded to 3 decimals to drop the floating point noise.
+pub fn btc_per_kvb_to_sat_per_vb(rate: f64) -> f64 {
+    (rate * SAT_PER_BTC).round() / VBYTES_PER_KVB
+}
+
+/// Converts a sat/vB fee rate to BTC/kvB, rounded up to a whole sat/kvB so that the rate
+/// never ends up below the one that was given.
+pub fn sat_per_vb_to_btc_per_kvb(rate: f64) -> f64 {
+    // the epsilon keeps exact values (e.g. 0.1 * 1000 = 100.00000000000001) from rounding up
+    (rate * VBYTES_PER_KVB - 1e-6).ceil().max(0.0) / SAT_PER_BTC
+}
+
+// the fee histogram buckets span at least this many vbytes
+const HISTOGRAM_BUCKET_VSIZE: u64 = 50_000;
+
+/// The fee histogram of Electrum's `mempool.get_fee_histogram`, from the fee and vsize of each
+/// transaction: `(feerate, vsize)` pairs by decreasing feerate (in sat/vB), each with the vsize
+/// of the transactions paying at least its feerate but less than the one of the previous
+/// bucket. Summed in order, they give the vsize of the transactions above each feerate.
+pub fn fee_histogram(txs: impl Iterator<Item = (u64, u64)>) -> Vec<(f64, u64)> {
+    let mut txs: Vec<(f64, u64)> = txs
+        .filter(|(_, vsize)| *vsize > 0)
+        .map(|(fee, vsize)| (fee as f64 / vsize as f64, vsize))
+        .collect();
+    txs.sort_unstable_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
+
+    let mut histogram = vec![];
+    let mut bucket_vsize = 0;
+    let mut last_feerate = 0.0;
+    for (feerate, vsize) in txs {
+        // the transactions of the same feerate are kept in the same bucket
+        if bucket_vsize > HISTOGRAM_BUCKET_VSIZE && feerate < last_feerate {
+            histogram.push((last_feerate, bucket_vsize));
+            bucket_vsize = 0;
+        }
+        last_feerate = feerate;
+        bucket_vsize += vsize;
+    }
+    if bucket_vsize > 0 {
+        histogram.push((last_feerate, bucket_vsize));
+    }
+    histogram
+}
+
+/// The feerates (in sat/vB) paid by a block's transactions, at percentiles of the block's size
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FeeratePercentiles {
+    pub p10: f64,
+    pub p50: f64,
+    pub p90: f64,
+}
+
+impl FeeratePercentiles {
+    /// From the fee and vsize of each transaction, `None` when there are none. Percentiles are
+    /// weighted by vsize, as with bitcoind's `getblockstats`.
+    pub fn from_txs(mut txs: Vec<(u64, u64)>) -> Option<Self> {
+        txs.retain(|(_, vsize)| *vsize > 0);
+        if txs.is_empty() {
+            return None;
+        }
+        // compare a/b < c/d as a*d < c*b, to avoid float rounding
+        txs.sort_unstable_by(|(fee_a, vsize_a), (fee_b, vsize_b)| {
+            (*fee_a as u128 * *vsize_b as u128).cmp(&(*fee_b as u128 * *vsize_a as u128))
+        });
+        let total_vsize: u64 = txs.iter().map(|(_, vsize)| vsize).sum();
+
+        let percentile = |p: f64| {
+            let threshold = total_vsize as f64 * p;
+            let mut cumulative = 0;
+            let (fee, vsize) = txs
+                .iter()
+                .find(|(_, vsize)| {
+                    cumulative += vsize;
+                    cumulative as f64 >= threshold
+                })
+                .unwrap_or_else(|| txs.last().unwrap());
+            *fee as f64 / *vsize as f64
+        };
+
+        Some(FeeratePercentiles {
+            p10: percentile(0.1),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+        })
+    }
+}
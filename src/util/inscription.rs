@@ -0,0 +1,243 @@
+// Detection of the ordinals inscriptions, for the explorers that want to flag them without
+// running a separate ordinals indexer. An inscription is an envelope in the tapscript of a
+// script-path spend:
+//
+//      OP_FALSE OP_IF "ord" [<tag> <value>]... [OP_0 <body>...] OP_ENDIF
+//
+// Only its presence, content type and size are extracted, the inscription numbers and the
+// sat tracking are out of scope.
+
+use bitcoin::{
+    blockdata::{
+        opcodes::all::{OP_ENDIF, OP_IF},
+        script::Instruction,
+    },
+    Script, Transaction, Witness,
+};
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const CONTENT_TYPE_TAG: &[u8] = &[1];
+// the last witness element is an annex when starting with this byte (BIP341)
+const ANNEX_PREFIX: u8 = 0x50;
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Inscription {
+    /// The input revealing the inscription
+    pub vin: u32,
+    pub content_type: Option<String>,
+    pub content_length: usize,
+}
+
+/// The inscriptions revealed by the inputs of `tx`, in order
+pub fn parse_inscriptions(tx: &Transaction) -> Vec<Inscription> {
+    let mut inscriptions = vec![];
+    for (vin, txin) in tx.input.iter().enumerate() {
+        if let Some(script) = tapscript(&txin.witness) {
+            for (content_type, content_length) in parse_envelopes(&script) {
+                inscriptions.push(Inscription {
+                    vin: vin as u32,
+                    content_type,
+                    content_length,
+                });
+            }
+        }
+    }
+    inscriptions
+}
+
+// The script of a script-path spend is followed by the control block (and the optional annex)
+fn tapscript(witness: &Witness) -> Option<Script> {
+    let mut elements: Vec<&[u8]> = witness.iter().collect();
+    if elements.len() >= 2 && elements.last()?.first() == Some(&ANNEX_PREFIX) {
+        elements.pop();
+    }
+    if elements.len() < 2 {
+        return None;
+    }
+    Some(Script::from(elements[elements.len() - 2].to_vec()))
+}
+
+// The content type and body size of each envelope, ignoring a truncated last one
+fn parse_envelopes(script: &Script) -> Vec<(Option<String>, usize)> {
+    let instructions: Vec<Instruction> = script.instructions().map_while(Result::ok).collect();
+    let mut envelopes = vec![];
+    let mut i = 0;
+    while i + 2 < instructions.len() {
+        let start = &instructions[i..i + 3];
+        if start[0] != Instruction::PushBytes(&[])
+            || start[1] != Instruction::Op(OP_IF)
+            || start[2] != Instruction::PushBytes(PROTOCOL_ID)
+        {
+            i += 1;
+            continue;
+        }
+        i += 3;
+
+        let mut content_type = None;
+        let mut body: Option<usize> = None;
+        while let Some(instruction) = instructions.get(i) {
+            i += 1;
+            match (instruction, body) {
+                (Instruction::Op(OP_ENDIF), _) => {
+                    envelopes.push((content_type.take(), body.unwrap_or(0)));
+                    break;
+                }
+                (Instruction::PushBytes(data), Some(size)) => body = Some(size + data.len()),
+                // the body tag, followed by the body pushes
+                (Instruction::PushBytes(&[]), None) => body = Some(0),
+                (Instruction::PushBytes(tag), None) => {
+                    if let Some(Instruction::PushBytes(value)) = instructions.get(i) {
+                        if *tag == CONTENT_TYPE_TAG {
+                            let value = &value[..value.len().min(MAX_CONTENT_TYPE_LEN)];
+                            content_type = Some(String::from_utf8_lossy(value).into_owned());
+                        }
+                    }
+                    i += 1;
+                }
+                // not a valid envelope
+                (Instruction::Op(_), _) => break,
+            }
+        }
+    }
+    envelopes
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::{opcodes::all::OP_CHECKSIG, script::Builder};
+    use bitcoin::{OutPoint, TxIn};
+
+    use super::*;
+
+    fn reveal(witnesses: Vec<Vec<Vec<u8>>>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: witnesses
+                .into_iter()
+                .map(|witness| TxIn {
+                    previous_output: OutPoint::default(),
+                    script_sig: Script::new(),
+                    sequence: 0xfffffffd,
+                    witness: Witness::from_vec(witness),
+                })
+                .collect(),
+            output: vec![],
+        }
+    }
+
+    // The pushes of the fields, then of the body
+    type Envelope<'a> = (&'a [&'a [u8]], &'a [&'a [u8]]);
+
+    // <pubkey> OP_CHECKSIG, then the envelopes
+    fn tapscript(envelopes: &[Envelope]) -> Vec<u8> {
+        let mut builder = Builder::new().push_slice(&[7; 32]).push_opcode(OP_CHECKSIG);
+        for (fields, body) in envelopes {
+            builder = builder
+                .push_slice(&[])
+                .push_opcode(OP_IF)
+                .push_slice(PROTOCOL_ID);
+            for field in fields.iter() {
+                builder = builder.push_slice(field);
+            }
+            if !body.is_empty() {
+                builder = builder.push_slice(&[]);
+                for data in body.iter() {
+                    builder = builder.push_slice(data);
+                }
+            }
+            builder = builder.push_opcode(OP_ENDIF);
+        }
+        builder.into_script().into_bytes()
+    }
+
+    fn script_path(script: Vec<u8>) -> Vec<Vec<u8>> {
+        vec![vec![1; 64], script, [&[0xc0][..], &[2; 32]].concat()]
+    }
+
+    fn summary(tx: &Transaction) -> Vec<(u32, Option<String>, usize)> {
+        parse_inscriptions(tx)
+            .into_iter()
+            .map(|i| (i.vin, i.content_type, i.content_length))
+            .collect()
+    }
+
+    #[test]
+    fn hello_world() {
+        let script = tapscript(&[(
+            &[CONTENT_TYPE_TAG, b"text/plain;charset=utf-8"],
+            &[b"Hello, world!"],
+        )]);
+        // the raw envelope, after the 34 bytes of the pubkey push and OP_CHECKSIG
+        assert_eq!(
+            &script[34..43],
+            &[0x00, 0x63, 0x03, b'o', b'r', b'd', 0x01, 0x01, 0x18]
+        );
+        assert_eq!(
+            summary(&reveal(vec![script_path(script)])),
+            vec![(0, Some("text/plain;charset=utf-8".to_string()), 13)]
+        );
+    }
+
+    #[test]
+    fn envelopes() {
+        let big = vec![0xaa; 520];
+        let tx = reveal(vec![
+            // a key path spend
+            vec![vec![1; 64]],
+            // two envelopes, the first with its body over several pushes and an unknown tag
+            script_path(tapscript(&[
+                (
+                    &[&[2], &[9, 9], CONTENT_TYPE_TAG, b"image/png"],
+                    &[&big, &big, b"end"],
+                ),
+                (&[], &[]),
+            ])),
+            // with an annex
+            [
+                script_path(tapscript(&[(&[CONTENT_TYPE_TAG, b"a"], &[b"b"])])),
+                vec![vec![ANNEX_PREFIX, 0]],
+            ]
+            .concat(),
+        ]);
+        assert_eq!(
+            summary(&tx),
+            vec![
+                (1, Some("image/png".to_string()), 1043),
+                (1, None, 0),
+                (2, Some("a".to_string()), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_inscriptions() {
+        let script = tapscript(&[(&[CONTENT_TYPE_TAG, b"text/plain"], &[b"x"])]);
+
+        // truncated before OP_ENDIF
+        let truncated = script[..script.len() - 1].to_vec();
+        // another protocol
+        let other = Builder::new()
+            .push_slice(&[])
+            .push_opcode(OP_IF)
+            .push_slice(b"xyz")
+            .push_slice(b"x")
+            .push_opcode(OP_ENDIF)
+            .into_script()
+            .into_bytes();
+        // an opcode within the envelope
+        let mut invalid = script.clone();
+        let endif = invalid.len() - 1;
+        invalid.insert(endif, OP_CHECKSIG.into_u8());
+
+        let tx = reveal(vec![
+            script_path(truncated),
+            script_path(other),
+            script_path(invalid),
+            // the envelope as the last element, not the tapscript
+            vec![vec![1; 64], script],
+        ]);
+        assert!(parse_inscriptions(&tx).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::{self, Write};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::util::request_id;
+
+/// Logs one JSON object per line to stderr, for ingestion by log aggregators.
+///
+/// `key=value` tokens of the message (e.g. `height=123 blockhash=00..`) are also exposed as
+/// separate fields, so log lines should name the values they carry this way.
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    pub fn init(verbosity: usize) -> Result<(), log::SetLoggerError> {
+        let level = match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(JsonLogger { level }))
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        let mut entry = Map::new();
+        entry.insert("timestamp".into(), json!(timestamp));
+        entry.insert("level".into(), json!(level_name(record.level())));
+        entry.insert("module".into(), json!(record.module_path()));
+        if let Some(id) = request_id::current() {
+            entry.insert("request_id".into(), json!(id));
+        }
+        let fields = message_fields(&message);
+        if !fields.is_empty() {
+            entry.insert("fields".into(), Value::Object(fields));
+        }
+        entry.insert("message".into(), json!(message));
+
+        let line = Value::Object(entry).to_string() + "\n";
+        // a single write per entry, so that lines from concurrent threads aren't interleaved
+        let _ = io::stderr().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn message_fields(message: &str) -> Map<String, Value> {
+    message
+        .split_whitespace()
+        .filter_map(|token| {
+            let (key, value) = token.split_once('=')?;
+            let value = value.trim_end_matches(',');
+            if key.is_empty()
+                || value.is_empty()
+                || !key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            {
+                return None;
+            }
+            let value = match value.parse::<u64>() {
+                Ok(number) => json!(number),
+                Err(_) => json!(value),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
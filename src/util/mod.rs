@@ -1,18 +1,37 @@
 pub mod block;
+pub mod coin_select;
+pub mod electrum_merkle;
+pub mod electrum_status;
+pub mod fees;
+#[cfg(feature = "ordinals")]
+pub mod inscription;
+pub mod json_log;
+pub mod request_id;
+#[cfg(feature = "runes")]
+pub mod runestone;
 pub mod script;
+pub mod systemd;
 pub mod transaction;
+pub mod webhook;
+pub mod zmq;
 
 use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
     sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
     thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use rayon::ThreadPool;
+
 pub type Bytes = Vec<u8>;
 // TODO: replace by a separate opaque type (similar to Sha256dHash, but without the "double")
 pub type FullHash = [u8; 32]; // serialized SHA256 result
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
-const HASH_LEN: usize = 32;
+pub const HASH_LEN: usize = 32;
 
 pub fn spawn_thread<F, T>(name: &str, f: F) -> thread::JoinHandle<T>
 where
@@ -26,6 +45,71 @@ where
         .unwrap()
 }
 
+/// A rayon pool of `threads` threads (one per CPU for 0), pinned to `cpus` when given
+pub fn thread_pool(name: &'static str, threads: usize, cpus: Option<&[usize]>) -> ThreadPool {
+    let cpus = cpus.map(<[usize]>::to_vec);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{}-{}", name, i))
+        .start_handler(move |_| {
+            if let Some(cpus) = &cpus {
+                pin_current_thread(cpus);
+            }
+        })
+        .build()
+        .expect("failed to create thread pool")
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) {
+    // safe: the set is plain data, initialized by zeroing before setting the CPUs in it
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        warn!(
+            "failed to pin thread to CPUs {:?}: {}",
+            cpus,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpus: &[usize]) {
+    warn!("pinning threads to CPUs is only supported on Linux");
+}
+
+/// The current unix timestamp, in seconds
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs() as u32
+}
+
+/// Removes the unix socket file left at `path` by a previous run, so that it can be bound again
+pub fn remove_stale_socket(path: &Path) {
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Sets the permissions of a bound unix socket file, which control who may connect to it
+pub fn set_socket_mode(path: &Path, mode: Option<u32>) {
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap_or_else(|e| {
+            panic!("failed to set the permissions of {}: {}", path.display(), e)
+        });
+    }
+}
+
 pub fn full_hash(hash: &[u8]) -> FullHash {
     *array_ref![hash, 0, HASH_LEN]
 }
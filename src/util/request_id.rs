@@ -0,0 +1,90 @@
+use std::{
+    cell::Cell,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub type RequestId = u64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// The ID follows the task serving the request, whichever worker thread polls it. The blocking
+// tasks and the thread pools that the request hands work to don't see it, so it is passed to
+// them explicitly with `enter()`, which keeps it in a thread-local.
+tokio::task_local! {
+    static TASK_ID: RequestId;
+}
+
+thread_local! {
+    static THREAD_ID: Cell<Option<RequestId>> = Cell::new(None);
+}
+
+/// A new request ID
+pub fn next() -> RequestId {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Run `fut` under a new request ID, visible to everything it calls through `current()`
+pub async fn scope<F: Future>(fut: F) -> F::Output {
+    TASK_ID.scope(next(), fut).await
+}
+
+/// Run `f` under `id` on this thread, for the work that a request runs outside of its task
+pub fn enter<T>(id: Option<RequestId>, f: impl FnOnce() -> T) -> T {
+    let prev = THREAD_ID.with(|current| current.replace(id));
+    let _restore = Restore(prev);
+    f()
+}
+
+/// The ID of the request being handled by this task or thread, if any
+pub fn current() -> Option<RequestId> {
+    TASK_ID
+        .try_with(|id| *id)
+        .ok()
+        .or_else(|| THREAD_ID.with(|current| current.get()))
+}
+
+// Restores the outer request ID, even when `f` panics
+struct Restore(Option<RequestId>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        THREAD_ID.with(|current| current.set(self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn task_scope() {
+        assert_eq!(current(), None);
+        let (id, blocking_id, nested) = scope(async {
+            let id = current();
+            // handed to a blocking task explicitly
+            let blocking_id = tokio::task::spawn_blocking(move || enter(id, current))
+                .await
+                .unwrap();
+            let nested = scope(async { current() }).await;
+            (id, blocking_id, nested)
+        })
+        .await;
+        assert!(id.is_some());
+        assert_eq!(blocking_id, id);
+        assert_ne!(nested, id);
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn thread_scope() {
+        enter(Some(1), || {
+            assert_eq!(current(), Some(1));
+            enter(Some(2), || assert_eq!(current(), Some(2)));
+            assert_eq!(current(), Some(1));
+            // a thread of a pool doesn't see it without `enter()`
+            assert_eq!(std::thread::spawn(current).join().unwrap(), None);
+        });
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,238 @@
+// Decoding of the runes protocol messages, for the downstream runes indexers that source their
+// data from electrs instead of the raw blocks. A runestone is the first output whose script is
+// OP_RETURN OP_13 followed by data pushes, which concatenated are a sequence of LEB128 integers:
+// tag/value fields, then (after a 0 tag) the edicts, 4 integers each.
+//
+// The message is decoded, but not interpreted: the fields are passed on as tag/value pairs and
+// the rune balances are left to the runes indexers.
+
+use bitcoin::{
+    blockdata::{
+        opcodes::all::{OP_PUSHNUM_13, OP_RETURN},
+        script::Instruction,
+    },
+    Transaction,
+};
+
+const TAG_BODY: u128 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Runestone {
+    pub vout: u32,
+    /// The tag/value pairs, in order
+    pub fields: Vec<(u128, u128)>,
+    /// The (block, tx, amount, output) edicts, the rune ids delta-encoded as in the message
+    pub edicts: Vec<[u128; 4]>,
+    /// Malformed, which burns the input runes
+    pub cenotaph: bool,
+}
+
+/// The runestone of `tx`, if it has any
+pub fn parse_runestone(tx: &Transaction) -> Option<Runestone> {
+    tx.output.iter().enumerate().find_map(|(vout, txo)| {
+        let mut instructions = txo.script_pubkey.instructions();
+        if instructions.next() != Some(Ok(Instruction::Op(OP_RETURN)))
+            || instructions.next() != Some(Ok(Instruction::Op(OP_PUSHNUM_13)))
+        {
+            return None;
+        }
+        let mut payload = vec![];
+        let mut cenotaph = false;
+        for instruction in instructions {
+            match instruction {
+                Ok(Instruction::PushBytes(data)) => payload.extend_from_slice(data),
+                // only data pushes are allowed
+                Ok(Instruction::Op(_)) | Err(_) => {
+                    cenotaph = true;
+                    break;
+                }
+            }
+        }
+        Some(decode_message(vout as u32, &payload, cenotaph))
+    })
+}
+
+fn decode_message(vout: u32, payload: &[u8], cenotaph: bool) -> Runestone {
+    let mut runestone = Runestone {
+        vout,
+        fields: vec![],
+        edicts: vec![],
+        cenotaph,
+    };
+    if cenotaph {
+        return runestone;
+    }
+    let integers = match decode_integers(payload) {
+        Some(integers) => integers,
+        None => {
+            runestone.cenotaph = true;
+            return runestone;
+        }
+    };
+
+    let mut integers = integers.into_iter();
+    while let Some(tag) = integers.next() {
+        if tag == TAG_BODY {
+            let body: Vec<u128> = integers.collect();
+            let edicts = body.chunks_exact(4);
+            runestone.cenotaph = !edicts.remainder().is_empty();
+            runestone.edicts = edicts.map(|e| [e[0], e[1], e[2], e[3]]).collect();
+            break;
+        }
+        match integers.next() {
+            Some(value) => runestone.fields.push((tag, value)),
+            // a tag without a value
+            None => runestone.cenotaph = true,
+        }
+    }
+    runestone
+}
+
+// LEB128, failing on truncated or overflowing integers
+fn decode_integers(payload: &[u8]) -> Option<Vec<u128>> {
+    let mut integers = vec![];
+    let mut i = 0;
+    while i < payload.len() {
+        let mut n: u128 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *payload.get(i)?;
+            i += 1;
+            let bits = u128::from(byte & 0x7f);
+            if shift > 127 || (shift == 126 && bits > 0b11) {
+                return None;
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        integers.push(n);
+    }
+    Some(integers)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::{opcodes::all::OP_CHECKSIG, script::Builder};
+    use bitcoin::{Script, TxOut};
+
+    use super::*;
+
+    fn tx(scripts: Vec<Script>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: scripts
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 0,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    fn runestone_script(pushes: &[&[u8]]) -> Script {
+        let mut builder = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_PUSHNUM_13);
+        for data in pushes {
+            builder = builder.push_slice(data);
+        }
+        builder.into_script()
+    }
+
+    #[test]
+    fn leb128() {
+        assert_eq!(decode_integers(&[]), Some(vec![]));
+        assert_eq!(
+            decode_integers(&[0x00, 0x7f, 0x80, 0x01, 0xac, 0x02]),
+            Some(vec![0, 127, 128, 300])
+        );
+        let max = [&[0xff; 18][..], &[0x03]].concat();
+        assert_eq!(decode_integers(&max), Some(vec![u128::MAX]));
+
+        // overflowing
+        let overflow = [&[0xff; 18][..], &[0x04]].concat();
+        assert_eq!(decode_integers(&overflow), None);
+        let too_long = [&[0x80; 19][..], &[0x00]].concat();
+        assert_eq!(decode_integers(&too_long), None);
+        // truncated
+        assert_eq!(decode_integers(&[0x01, 0x80]), None);
+    }
+
+    #[test]
+    fn runestone() {
+        // divisibility 3, then an edict of 1000 units of 840000:1 to output 2
+        let script =
+            runestone_script(&[&[0x02, 0x03, 0x00, 0xc0, 0xa2, 0x33, 0x01, 0xe8, 0x07, 0x02]]);
+        assert_eq!(script.as_bytes()[..3], [0x6a, 0x5d, 0x0a]);
+        let other = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_slice(b"memo")
+            .into_script();
+        let expected = Runestone {
+            vout: 1,
+            fields: vec![(2, 3)],
+            edicts: vec![[840_000, 1, 1000, 2]],
+            cenotaph: false,
+        };
+        assert_eq!(
+            parse_runestone(&tx(vec![other.clone(), script, other.clone()])),
+            Some(expected.clone())
+        );
+
+        // the payload may be split over several pushes, even within an integer
+        let split = runestone_script(&[
+            &[0x02, 0x03, 0x00, 0xc0],
+            &[0xa2, 0x33, 0x01, 0xe8],
+            &[0x07, 0x02],
+        ]);
+        assert_eq!(
+            parse_runestone(&tx(vec![other.clone(), split])),
+            Some(expected)
+        );
+
+        // only the first runestone counts
+        let empty = Runestone {
+            vout: 0,
+            fields: vec![],
+            edicts: vec![],
+            cenotaph: false,
+        };
+        assert_eq!(
+            parse_runestone(&tx(vec![
+                runestone_script(&[]),
+                runestone_script(&[&[2, 3]])
+            ])),
+            Some(empty)
+        );
+
+        assert_eq!(parse_runestone(&tx(vec![other])), None);
+        assert_eq!(parse_runestone(&tx(vec![])), None);
+    }
+
+    #[test]
+    fn cenotaphs() {
+        let cenotaph = |script: Script| parse_runestone(&tx(vec![script])).unwrap().cenotaph;
+
+        assert!(!cenotaph(runestone_script(&[&[2, 3, 0, 1, 2, 3, 4]])));
+        // an opcode within the payload
+        let opcode = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_PUSHNUM_13)
+            .push_slice(&[2, 3])
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert!(cenotaph(opcode));
+        // a truncated integer
+        assert!(cenotaph(runestone_script(&[&[2, 0x83]])));
+        // a tag without its value
+        assert!(cenotaph(runestone_script(&[&[2, 3, 4]])));
+        // an incomplete edict
+        assert!(cenotaph(runestone_script(&[&[2, 3, 0, 1, 2, 3]])));
+    }
+}
@@ -1,8 +1,22 @@
+use std::convert::TryFrom;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::blockdata::opcodes;
+use bitcoin::hashes::hex::ToHex;
 #[cfg(feature = "liquid")]
-use elements::address as elements_address;
+use elements::opcodes;
 
-use crate::chain::{script, Network, Script, TxIn, TxOut};
-use script::Instruction::PushBytes;
+use crate::{
+    chain::{script, Chain, ChainSpec, Network, Script, TxIn, TxOut},
+    errors::*,
+    util::transaction::{
+        has_prevout, LOCKTIME_THRESHOLD, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+        SEQUENCE_LOCKTIME_GRANULARITY, SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+    },
+};
+use script::Instruction::{self, Op, PushBytes};
+
+const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
 
 pub struct InnerScripts {
     pub redeem_script: Option<Script>,
@@ -21,18 +35,214 @@ impl ScriptToAsm for elements::Script {}
 
 pub trait ScriptToAddr {
     fn to_address_str(&self, network: Network) -> Option<String>;
+
+    /// A descriptor of the script, for scripts without an address
+    fn to_descriptor_str(&self) -> String;
+
+    fn to_address_or_descriptor(&self, network: Network) -> String {
+        self.to_address_str(network)
+            .unwrap_or_else(|| self.to_descriptor_str())
+    }
 }
-#[cfg(not(feature = "liquid"))]
-impl ScriptToAddr for bitcoin::Script {
+impl ScriptToAddr for Script {
     fn to_address_str(&self, network: Network) -> Option<String> {
-        bitcoin::Address::from_script(self, network.into()).map(|s| s.to_string())
+        Chain::script_to_address(self, network)
+    }
+
+    fn to_descriptor_str(&self) -> String {
+        script_descriptor(self)
     }
 }
-#[cfg(feature = "liquid")]
-impl ScriptToAddr for elements::Script {
-    fn to_address_str(&self, network: Network) -> Option<String> {
-        elements_address::Address::from_script(self, None, network.address_params())
-            .map(|a| a.to_string())
+
+// `pk()` and `multi()` for bare public key and multisig scripts, `raw()` for anything else
+fn script_descriptor(script: &Script) -> String {
+    pk_descriptor(script)
+        .or_else(|| multi_descriptor(script))
+        .unwrap_or_else(|| format!("raw({})", script.as_bytes().to_hex()))
+}
+
+fn pk_descriptor(script: &Script) -> Option<String> {
+    if !script.is_p2pk() {
+        return None;
+    }
+    match script.instructions().next() {
+        Some(Ok(PushBytes(pubkey))) => Some(format!("pk({})", pubkey.to_hex())),
+        _ => None,
+    }
+}
+
+// <m> <pubkey>... <n> OP_CHECKMULTISIG, with n matching the number of keys
+fn multi_descriptor(script: &Script) -> Option<String> {
+    let instructions = script
+        .instructions_minimal()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .ok()?;
+    let (threshold, rest) = instructions.split_first()?;
+    let (checkmultisig, rest) = rest.split_last()?;
+    let (nkeys, pubkeys) = rest.split_last()?;
+    if *checkmultisig != Op(opcodes::all::OP_CHECKMULTISIG) {
+        return None;
+    }
+
+    let pubkeys = pubkeys
+        .iter()
+        .map(|instruction| match instruction {
+            PushBytes(pubkey) if pubkey.len() == 33 || pubkey.len() == 65 => Some(pubkey.to_hex()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let threshold = pushnum(threshold)?;
+    if threshold > pubkeys.len() || pushnum(nkeys)? != pubkeys.len() {
+        return None;
+    }
+    Some(format!("multi({},{})", threshold, pubkeys.join(",")))
+}
+
+// The value of OP_1 to OP_16
+fn pushnum(instruction: &Instruction) -> Option<usize> {
+    let first = opcodes::all::OP_PUSHNUM_1.into_u8();
+    let last = opcodes::all::OP_PUSHNUM_16.into_u8();
+    match instruction {
+        Op(op) if (first..=last).contains(&op.into_u8()) => {
+            Some((op.into_u8() - first) as usize + 1)
+        }
+        _ => None,
+    }
+}
+
+/// Parse an address of `network` into its output script
+pub fn address_to_script(addr: &str, network: Network) -> Result<Script> {
+    Chain::address_to_script(addr, network)
+}
+
+/// The output script types returned by `get_script_type`, in the order their counts are
+/// stored by the index. New types are to be appended.
+pub const SCRIPT_TYPES: [&str; 11] = [
+    "empty",
+    "op_return",
+    "p2pk",
+    "p2pkh",
+    "p2sh",
+    "v0_p2wpkh",
+    "v0_p2wsh",
+    "v1_p2tr",
+    "unknown_witness",
+    "provably_unspendable",
+    "non_standard",
+];
+
+/// The type of an output script, as used in API responses
+pub fn get_script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_v1_p2tr() {
+        "v1_p2tr"
+    } else if script.is_witness_program() {
+        "unknown_witness"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "non_standard"
+    }
+}
+
+/// How an input spends its previous output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputType {
+    pub spend_type: &'static str,
+    pub witness_version: Option<u8>,
+}
+
+/// Classify the spend of an input. With the previous output the classification is exact,
+/// without it the type is inferred from the shape of the witness.
+pub fn get_input_type(txin: &TxIn, prevout: Option<&TxOut>) -> InputType {
+    let input_type = |spend_type, witness_version| InputType {
+        spend_type,
+        witness_version,
+    };
+    if !has_prevout(txin) {
+        return input_type("coinbase", None);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    let witness: Vec<&[u8]> = txin.witness.iter().collect();
+    #[cfg(feature = "liquid")]
+    let witness: Vec<&[u8]> = txin.witness.script_witness.iter().map(|w| &w[..]).collect();
+
+    let prevout_script = match prevout {
+        Some(prevout) => &prevout.script_pubkey,
+        None => {
+            return if witness.is_empty() {
+                input_type("legacy", None)
+            } else if let Some(spend_type) = taproot_spend_type(&witness) {
+                input_type(spend_type, Some(1))
+            } else {
+                input_type("witness", Some(0))
+            };
+        }
+    };
+
+    // P2SH-wrapped segwit spends are identified by the redeem script
+    let (program, wrapped) = match get_innerscripts(txin, prevout.unwrap()).redeem_script {
+        Some(redeem_script) if redeem_script.is_witness_program() => (redeem_script, true),
+        _ => (prevout_script.clone(), false),
+    };
+
+    let spend_type = if program.is_v0_p2wpkh() {
+        if wrapped {
+            "p2sh-v0_p2wpkh"
+        } else {
+            "v0_p2wpkh"
+        }
+    } else if program.is_v0_p2wsh() {
+        if wrapped {
+            "p2sh-v0_p2wsh"
+        } else {
+            "v0_p2wsh"
+        }
+    } else if program.is_v1_p2tr() {
+        taproot_spend_type(&witness).unwrap_or("v1_p2tr_unknown")
+    } else if program.is_witness_program() {
+        "unknown_witness"
+    } else {
+        get_script_type(prevout_script)
+    };
+
+    input_type(spend_type, program.witness_version().map(|v| v.into_num()))
+}
+
+// BIP341: a key path spend has a single signature, a script path spend ends with the
+// script and the control block (ignoring the optional annex in both cases)
+fn taproot_spend_type(witness: &[&[u8]]) -> Option<&'static str> {
+    let witness = match witness.split_last() {
+        Some((last, rest)) if !rest.is_empty() && last.first() == Some(&TAPROOT_ANNEX_PREFIX) => {
+            rest
+        }
+        _ => witness,
+    };
+    match witness {
+        [sig] if sig.len() == 64 || sig.len() == 65 => Some("v1_p2tr_keypath"),
+        [.., _script, control]
+            if control.len() >= 33
+                && (control.len() - 33) % 32 == 0
+                && (control.len() - 33) / 32 <= 128
+                && control[0] & 0xfe == 0xc0 =>
+        {
+            Some("v1_p2tr_scriptpath")
+        }
+        _ => None,
     }
 }
 
@@ -73,3 +283,372 @@ pub fn get_innerscripts(txin: &TxIn, prevout: &TxOut) -> InnerScripts {
         witness_script,
     }
 }
+
+/// Whether an input spends a P2WSH output with a 2-of-2 multisig witness script, as the funding
+/// outputs of lightning channels are (BOLT 3)
+pub fn is_channel_funding_spend(txin: &TxIn, prevout: &TxOut) -> bool {
+    if !prevout.script_pubkey.is_v0_p2wsh() {
+        return false;
+    }
+    match get_innerscripts(txin, prevout).witness_script {
+        Some(script) => is_two_of_two_multisig(&script),
+        None => false,
+    }
+}
+
+// OP_2 <pubkey1> <pubkey2> OP_2 OP_CHECKMULTISIG, with compressed public keys
+fn is_two_of_two_multisig(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    let op_2 = opcodes::all::OP_PUSHNUM_2.into_u8();
+    bytes.len() == 71
+        && bytes[0] == op_2
+        && bytes[1] == 33
+        && bytes[35] == 33
+        && bytes[69] == op_2
+        && bytes[70] == opcodes::all::OP_CHECKMULTISIG.into_u8()
+}
+
+/// A timelock encumbering a script, through OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timelock {
+    /// Spendable from a block height on
+    AbsoluteHeight(u32),
+    /// Spendable once the median time past reaches a unix time
+    AbsoluteTime(u32),
+    /// Spendable a number of blocks after the output confirms
+    RelativeBlocks(u16),
+    /// Spendable a number of seconds after the output confirms, in 512 seconds granularity
+    RelativeSeconds(u32),
+}
+
+/// The timelocks of the common `<n> OP_CHECKLOCKTIMEVERIFY` and `<n> OP_CHECKSEQUENCEVERIFY`
+/// templates within a script, whatever the branch they are in
+pub fn get_timelocks(script: &Script) -> Vec<Timelock> {
+    let instructions = match script
+        .instructions()
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(instructions) => instructions,
+        Err(_) => return vec![],
+    };
+    instructions
+        .windows(2)
+        .filter_map(|pair| {
+            let value = u32::try_from(script_num(&pair[0])?).ok()?;
+            let op = match pair[1] {
+                Op(op) => op,
+                _ => return None,
+            };
+            if op == opcodes::all::OP_CLTV {
+                Some(if value < LOCKTIME_THRESHOLD {
+                    Timelock::AbsoluteHeight(value)
+                } else {
+                    Timelock::AbsoluteTime(value)
+                })
+            } else if op == opcodes::all::OP_CSV {
+                // BIP112: with the disable flag set, the opcode does nothing
+                if value & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                    None
+                } else if value & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                    Some(Timelock::RelativeSeconds(
+                        (value & SEQUENCE_LOCKTIME_MASK) << SEQUENCE_LOCKTIME_GRANULARITY,
+                    ))
+                } else {
+                    Some(Timelock::RelativeBlocks(
+                        (value & SEQUENCE_LOCKTIME_MASK) as u16,
+                    ))
+                }
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// A number pushed by the script, encoded as little-endian sign and magnitude. Timelocks
+// may use up to 5 bytes, one more than the other numbers.
+fn script_num(instruction: &Instruction) -> Option<i64> {
+    match instruction {
+        PushBytes(bytes) if bytes.len() <= 5 => {
+            let (last, rest) = match bytes.split_last() {
+                Some(split) => split,
+                None => return Some(0),
+            };
+            let magnitude = rest
+                .iter()
+                .rev()
+                .fold((last & 0x7f) as i64, |acc, byte| acc << 8 | *byte as i64);
+            Some(if last & 0x80 != 0 {
+                -magnitude
+            } else {
+                magnitude
+            })
+        }
+        _ => pushnum(instruction).map(|n| n as i64),
+    }
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use bitcoin::bech32::ToBase32;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::{OutPoint, Witness};
+
+    use super::*;
+    use crate::chain::SignetParams;
+
+    fn script(hex: &str) -> Script {
+        Script::from(Vec::<u8>::from_hex(hex).unwrap())
+    }
+
+    fn txin(script_sig: &str, witness: Vec<Vec<u8>>) -> TxIn {
+        TxIn {
+            previous_output: OutPoint::new(Default::default(), 0),
+            script_sig: script(script_sig),
+            sequence: 0xffffffff,
+            witness: Witness::from_vec(witness),
+        }
+    }
+
+    fn txout(script_pubkey: &str) -> TxOut {
+        TxOut {
+            value: 1_000,
+            script_pubkey: script(script_pubkey),
+        }
+    }
+
+    const P2WPKH: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    const P2TR: &str = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn script_types() {
+        let types: Vec<&str> = [
+            "",
+            "6a04deadbeef",
+            // the output of the genesis coinbase
+            "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
+            "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+            "a914748284390f9e263a4b766a75d0633c50426eb87587",
+            // BIP173 and BIP350
+            P2WPKH,
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            P2TR,
+            "5210751e76e8199196d454941c45d1b3a323",
+            "50",
+            "51",
+        ]
+        .iter()
+        .map(|hex| get_script_type(&script(hex)))
+        .collect();
+        assert_eq!(types, SCRIPT_TYPES);
+    }
+
+    #[test]
+    fn input_types() {
+        let sig = vec![0x30; 71];
+        let pubkey = vec![0x02; 33];
+        let input_type = |txin: &TxIn, prevout: Option<&TxOut>| {
+            let input_type = get_input_type(txin, prevout);
+            (input_type.spend_type, input_type.witness_version)
+        };
+
+        let coinbase = TxIn {
+            previous_output: OutPoint::null(),
+            ..txin("03a08601", vec![])
+        };
+        assert_eq!(input_type(&coinbase, None), ("coinbase", None));
+
+        let p2pkh = txin(
+            "4730440220000000000000000000000000000000000000000000000000000000000000000002200000000000000000000000000000000000000000000000000000000000000000012102000000000000000000000000000000000000000000000000000000000000000000",
+            vec![],
+        );
+        let prevout = txout("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        assert_eq!(input_type(&p2pkh, Some(&prevout)), ("p2pkh", None));
+        assert_eq!(input_type(&p2pkh, None), ("legacy", None));
+
+        let p2wpkh = txin("", vec![sig.clone(), pubkey.clone()]);
+        assert_eq!(
+            input_type(&p2wpkh, Some(&txout(P2WPKH))),
+            ("v0_p2wpkh", Some(0))
+        );
+        assert_eq!(input_type(&p2wpkh, None), ("witness", Some(0)));
+
+        // the redeem script pushed by the scriptSig is the P2WPKH program
+        let wrapped = txin(&format!("16{}", P2WPKH), vec![sig, pubkey]);
+        let prevout = TxOut {
+            value: 1_000,
+            script_pubkey: Script::new_p2sh(&script(P2WPKH).script_hash()),
+        };
+        assert_eq!(
+            input_type(&wrapped, Some(&prevout)),
+            ("p2sh-v0_p2wpkh", Some(0))
+        );
+    }
+
+    #[test]
+    fn taproot_input_types() {
+        let input_type = |witness: Vec<Vec<u8>>, prevout: Option<&TxOut>| {
+            let input_type = get_input_type(&txin("", witness), prevout);
+            (input_type.spend_type, input_type.witness_version)
+        };
+        let prevout = txout(P2TR);
+        let keypath = ("v1_p2tr_keypath", Some(1));
+        let scriptpath = ("v1_p2tr_scriptpath", Some(1));
+
+        assert_eq!(input_type(vec![vec![1; 64]], Some(&prevout)), keypath);
+        // with a sighash type
+        assert_eq!(input_type(vec![vec![1; 65]], Some(&prevout)), keypath);
+        // with an annex
+        assert_eq!(
+            input_type(vec![vec![1; 64], vec![0x50, 1]], Some(&prevout)),
+            keypath
+        );
+        assert_eq!(input_type(vec![vec![1; 64]], None), keypath);
+
+        // a leaf at depth 1: the script, then the control block with one hash
+        let mut control = vec![0xc1];
+        control.extend_from_slice(&[2; 64]);
+        let witness = vec![vec![1; 64], vec![0x51], control.clone()];
+        assert_eq!(input_type(witness.clone(), Some(&prevout)), scriptpath);
+        assert_eq!(input_type(witness, None), scriptpath);
+
+        // a control block of an invalid size
+        control.push(0);
+        assert_eq!(
+            input_type(vec![vec![0x51], control], Some(&prevout)),
+            ("v1_p2tr_unknown", Some(1))
+        );
+    }
+
+    fn is_invalid_address(result: Result<Script>) -> bool {
+        matches!(result.map_err(|e| e.0), Err(ErrorKind::InvalidAddress(_)))
+    }
+
+    #[test]
+    fn addresses() {
+        let parse = |addr| address_to_script(addr, Network::Bitcoin).unwrap();
+        assert_eq!(
+            parse("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"),
+            script("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac")
+        );
+        // BIP173 and BIP350, in either case
+        assert_eq!(
+            parse("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            script(P2WPKH)
+        );
+        assert_eq!(
+            parse("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4"),
+            script(P2WPKH)
+        );
+        assert_eq!(
+            parse("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"),
+            script(P2TR)
+        );
+    }
+
+    #[test]
+    fn address_errors() {
+        let parse = |addr, network| address_to_script(addr, network);
+        // a bad checksum
+        assert!(is_invalid_address(parse(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb",
+            Network::Bitcoin
+        )));
+        assert!(is_invalid_address(parse(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+            Network::Bitcoin
+        )));
+        assert!(is_invalid_address(parse("", Network::Bitcoin)));
+        assert!(is_invalid_address(parse(
+            "not an address",
+            Network::Bitcoin
+        )));
+
+        // a witness program with the checksum variant of the other versions
+        let program = Vec::<u8>::from_hex(&P2WPKH[4..]).unwrap();
+        let mut data = vec![bitcoin::bech32::u5::try_from_u8(0).unwrap()];
+        data.extend(program.to_base32());
+        let bech32m =
+            bitcoin::bech32::encode("bc", data, bitcoin::bech32::Variant::Bech32m).unwrap();
+        assert!(is_invalid_address(parse(&bech32m, Network::Bitcoin)));
+
+        // testnet addresses are valid on signet, but not on mainnet and regtest (segwit ones)
+        let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert_eq!(parse(testnet, Network::Testnet).unwrap(), script(P2WPKH));
+        assert_eq!(parse(testnet, Network::Signet).unwrap(), script(P2WPKH));
+        assert!(is_invalid_address(parse(testnet, Network::Bitcoin)));
+        assert!(is_invalid_address(parse(testnet, Network::Regtest)));
+        assert!(is_invalid_address(parse(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            Network::Testnet
+        )));
+    }
+
+    #[test]
+    fn custom_signet_addresses() {
+        let params: &'static SignetParams = Box::leak(Box::new(SignetParams {
+            p2pkh_prefix: 63,
+            p2sh_prefix: 125,
+            bech32_hrp: "sb".to_string(),
+            ..SignetParams::default_signet()
+        }));
+        let network = Network::CustomSignet(params);
+
+        let p2pkh = script("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        for script_pubkey in &[p2pkh, script(P2WPKH), script(P2TR)] {
+            let addr = script_pubkey.to_address_str(network).unwrap();
+            assert_eq!(&address_to_script(&addr, network).unwrap(), script_pubkey);
+        }
+
+        // the prefixes and hrp of other networks
+        assert!(is_invalid_address(address_to_script(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            network
+        )));
+        assert!(is_invalid_address(address_to_script(
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            network
+        )));
+    }
+
+    #[test]
+    fn descriptors() {
+        // the public keys of the generator point and of its double
+        let g = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let g2 = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+        let describe = |hex: &str| script(hex).to_address_or_descriptor(Network::Bitcoin);
+
+        assert_eq!(describe(&format!("21{}ac", g)), format!("pk({})", g));
+        let genesis_pubkey = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+        assert_eq!(
+            describe(&format!("41{}ac", genesis_pubkey)),
+            format!("pk({})", genesis_pubkey)
+        );
+
+        assert_eq!(
+            describe(&format!("5121{}21{}52ae", g, g2)),
+            format!("multi(1,{},{})", g, g2)
+        );
+        assert_eq!(
+            describe(&format!("5221{}21{}52ae", g, g2)),
+            format!("multi(2,{},{})", g, g2)
+        );
+        // a threshold above the number of keys, a wrong number of keys, a key of the wrong size
+        for hex in &[
+            format!("5321{}21{}52ae", g, g2),
+            format!("5121{}21{}53ae", g, g2),
+            format!("5121{}20{}52ae", g, &g2[2..]),
+        ] {
+            assert_eq!(describe(hex), format!("raw({})", hex));
+        }
+
+        assert_eq!(describe("6a04deadbeef"), "raw(6a04deadbeef)");
+        assert_eq!(describe(""), "raw()");
+        // scripts with an address are described by it
+        assert_eq!(
+            describe("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac"),
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+        );
+    }
+}
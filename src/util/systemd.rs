@@ -0,0 +1,99 @@
+// The service manager's readiness notifications (Type=notify) and socket activation protocols,
+// implemented directly instead of linking libsystemd.
+
+use std::{
+    collections::HashMap,
+    env, net,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::{UnixDatagram, UnixListener},
+    },
+    process,
+    sync::Mutex,
+};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket, on TCP or unix
+pub enum ListenSocket {
+    Tcp(net::TcpListener),
+    Unix(UnixListener),
+}
+
+lazy_static! {
+    // read from the environment once, so that each socket is handed out a single time
+    static ref SOCKETS: Mutex<HashMap<String, ListenSocket>> = Mutex::new(listen_fds());
+}
+
+/// The socket passed by systemd with `FileDescriptorName=<name>` in its socket unit
+pub fn take_socket(name: &str) -> Option<ListenSocket> {
+    SOCKETS.lock().unwrap().remove(name)
+}
+
+/// Sends a state change (e.g. "READY=1") to systemd, when it expects notifications
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    match result {
+        Ok(_) => debug!("notified systemd of {}", state),
+        Err(e) => warn!("failed to notify systemd of {}: {}", state, e),
+    }
+}
+
+fn listen_fds() -> HashMap<String, ListenSocket> {
+    // the sockets are meant for this process, and not for its children
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(process::id()) {
+        return HashMap::new();
+    }
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    let names: Vec<String> = env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(String::from).collect())
+        .unwrap_or_default();
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    (0..count)
+        .filter_map(|index| {
+            let name = names
+                .get(index as usize)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            // safe: systemd passes the sockets to this process only, numbered from 3
+            let socket = unsafe { socket2::Socket::from_raw_fd(LISTEN_FDS_START + index) };
+            if let Err(e) = socket.set_cloexec(true) {
+                warn!("ignoring activated socket {}: {}", name, e);
+                return None;
+            }
+            let is_inet = match socket.local_addr() {
+                Ok(addr) => addr.as_socket().is_some(),
+                Err(e) => {
+                    warn!("ignoring activated socket {}: {}", name, e);
+                    return None;
+                }
+            };
+            info!("using the {} socket passed by systemd", name);
+            let socket = if is_inet {
+                ListenSocket::Tcp(socket.into())
+            } else {
+                ListenSocket::Unix(socket.into())
+            };
+            Some((name, socket))
+        })
+        .collect()
+}
@@ -1,18 +1,73 @@
-use bitcoin::{TxIn, TxOut};
+use std::collections::HashMap;
+
+use crate::chain::{Chain, ChainSpec, Network, OutPoint, Transaction, TxIn, TxOut, Value};
+
+/// The confirmations a coinbase output needs before it can be spent
+pub const COINBASE_MATURITY: usize = 100;
+
+/// Locktimes below this are block heights, and unix times from it on
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Inputs with this sequence don't enforce the transaction's locktime
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+
+// BIP68 relative locktimes
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
 
 pub fn is_spendable(txout: &TxOut) -> bool {
-    #[cfg(not(feature = "liquid"))]
-    return !txout.script_pubkey.is_provably_unspendable();
-    #[cfg(feature = "liquid")]
-    return !txout.is_fee() && !txout.script_pubkey.is_provably_unspendable();
+    Chain::is_spendable(txout)
+}
+
+/// Whether an output value is below `threshold` (0 for none). The confidential values are never
+/// dust, as their amount is unknown.
+pub fn is_dust(value: &Value, threshold: u64) -> bool {
+    Chain::explicit_value(value).map_or(false, |value| value < threshold)
 }
 
 pub fn has_prevout(txin: &TxIn) -> bool {
-    #[cfg(not(feature = "liquid"))]
-    return !txin.previous_output.is_null();
-    #[cfg(feature = "liquid")]
-    return !txin.is_coinbase()
-        && !txin.is_pegin
-        && txin.previous_output.txid != *REGTEST_INITIAL_ISSUANCE_PREVOUT
-        && txin.previous_output.txid != *TESTNET_INITIAL_ISSUANCE_PREVOUT;
+    Chain::has_prevout(txin)
+}
+
+/// Whether a transaction signals that it can be replaced (BIP125), with an input sequence
+/// below 0xfffffffe
+pub fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input
+        .iter()
+        .any(|txin| txin.sequence < SEQUENCE_FINAL - 1)
+}
+
+/// Whether the locktime of a transaction allows it in a block at `height`, whose previous
+/// block has a median time past of `mtp`
+pub fn is_final(tx: &Transaction, height: usize, mtp: u32) -> bool {
+    let limit = if tx.lock_time < LOCKTIME_THRESHOLD {
+        height as u32
+    } else {
+        mtp
+    };
+    tx.lock_time == 0
+        || tx.lock_time < limit
+        || tx.input.iter().all(|txin| txin.sequence == SEQUENCE_FINAL)
+}
+
+/// A guess of how a lightning channel was closed by the transaction spending its funding output.
+/// Commitment transactions, broadcast by one of the peers alone, encode the obscured commitment
+/// number in their locktime and sequence (BOLT 3).
+pub fn channel_close_type(tx: &Transaction) -> &'static str {
+    match &tx.input[..] {
+        [txin] if tx.lock_time >> 24 == 0x20 && txin.sequence >> 24 == 0x80 => "force",
+        [_] => "mutual",
+        _ => "unknown",
+    }
+}
+
+/// The fee paid by a transaction, or None when some of its previous outputs are unknown
+pub fn get_tx_fee(
+    tx: &Transaction,
+    prevouts: &HashMap<OutPoint, TxOut>,
+    network: Network,
+) -> Option<u64> {
+    Chain::tx_fee(tx, prevouts, network)
 }
@@ -0,0 +1,210 @@
+// HTTP webhooks fired when blocks are indexed and on reorgs, for the downstream systems that
+// can't hold a connection open. Each URL is delivered to by its own thread, in order, retrying
+// the failed deliveries with an exponential backoff.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use bitcoin::hashes::hex::ToHex;
+use crossbeam_channel::{self as channel, Sender, TrySendError};
+use error_chain::ChainedError;
+use serde_json::Value;
+use url::{Position, Url};
+
+use crate::{
+    config::Config,
+    errors::*,
+    indexer::query::ChainQuery,
+    metrics::{CounterVec, MetricOpts, Metrics},
+    util::{block::HeaderList, spawn_thread},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// the events waiting for delivery to a slow or unreachable URL, the later ones are dropped
+const QUEUE_SIZE: usize = 1000;
+// when catching up with many blocks (e.g. after downtime), only the latest ones are notified
+const MAX_BLOCK_EVENTS: usize = 100;
+
+pub struct Webhooks {
+    queues: Vec<Sender<Arc<String>>>,
+    deliveries: CounterVec,
+}
+
+impl Webhooks {
+    pub fn start(config: &Config, metrics: &Metrics) -> Self {
+        let deliveries = metrics.counter_vec(
+            MetricOpts::new(
+                "webhook_deliveries",
+                "# of webhook events, by delivery result",
+            ),
+            &["result"],
+        );
+        let queues = config
+            .webhook_urls
+            .iter()
+            .map(|url| {
+                let (tx, rx) = channel::bounded::<Arc<String>>(QUEUE_SIZE);
+                let (url, retries, deliveries) =
+                    (url.clone(), config.webhook_retries, deliveries.clone());
+                spawn_thread("webhook", move || {
+                    for payload in rx {
+                        let result = match deliver(&url, &payload, retries) {
+                            Ok(()) => "ok",
+                            Err(e) => {
+                                warn!("giving up on webhook {}: {}", url, e.display_chain());
+                                "failed"
+                            }
+                        };
+                        deliveries.with_label_values(&[result]).inc();
+                    }
+                });
+                tx
+            })
+            .collect();
+        Webhooks { queues, deliveries }
+    }
+
+    /// Notify the blocks connected and disconnected between the `old` and `new` header chains
+    pub fn tip_changed(&self, old: &HeaderList, new: &HeaderList, chain: &ChainQuery) {
+        if self.queues.is_empty() || old.equals(new) {
+            return;
+        }
+        let fork_height = old.fork_height(new);
+
+        let disconnected: Vec<Value> = old
+            .iter_range(fork_height..old.len())
+            .map(|entry| json!({ "id": entry.hash(), "height": entry.height() }))
+            .collect();
+        if !disconnected.is_empty() {
+            self.send(json!({
+                "event": "reorg",
+                "fork_height": fork_height,
+                "disconnected": disconnected,
+            }));
+        }
+
+        let connected = fork_height..new.len();
+        if connected.len() > MAX_BLOCK_EVENTS {
+            info!(
+                "notifying webhooks of the last {} of {} new blocks",
+                MAX_BLOCK_EVENTS,
+                connected.len()
+            );
+        }
+        for height in connected.rev().take(MAX_BLOCK_EVENTS).rev() {
+            match block_summary(new, height, chain) {
+                Ok(block) => self.send(json!({ "event": "block", "block": block })),
+                Err(e) => warn!("no webhook for block #{}: {}", height, e.display_chain()),
+            }
+        }
+    }
+
+    fn send(&self, event: Value) {
+        let payload = Arc::new(event.to_string());
+        for queue in &self.queues {
+            match queue.try_send(Arc::clone(&payload)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    self.deliveries.with_label_values(&["dropped"]).inc();
+                }
+                Err(TrySendError::Disconnected(_)) => unreachable!("webhook thread stopped"),
+            }
+        }
+    }
+}
+
+/// The same fields as esplora's block json
+pub fn block_summary(headers: &HeaderList, height: usize, chain: &ChainQuery) -> Result<Value> {
+    let entry = headers
+        .header_by_height(height)
+        .ok_or("block not in the chain")?;
+    let header = entry.header();
+    let mut block = json!({
+        "id": entry.hash(),
+        "height": height,
+        "version": header.version,
+        "timestamp": header.time,
+        "mediantime": headers.get_mtp(height),
+        "bits": header.bits,
+        "nonce": header.nonce,
+        "merkle_root": header.merkle_root.to_hex(),
+        "previousblockhash": header.prev_blockhash,
+    });
+    if let Some(meta) = chain.get_block_meta(entry.hash())? {
+        block["tx_count"] = json!(meta.tx_count);
+        block["size"] = json!(meta.size);
+        block["weight"] = json!(meta.weight);
+    }
+    Ok(block)
+}
+
+fn deliver(url: &Url, payload: &str, retries: usize) -> Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        match post(url, payload) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                debug!("webhook {} failed, retrying in {:?}: {}", url, backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => return Err(e).chain_err(|| format!("{} attempts failed", attempt + 1)),
+        }
+    }
+}
+
+fn post(url: &Url, payload: &str) -> Result<()> {
+    let host = url.host_str().ok_or("missing host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("cannot resolve {}", host))?
+        .next()
+        .ok_or_else(|| format!("no address for {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).chain_err(|| "connect failed")?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .chain_err(|| "cannot set timeouts")?;
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: electrs/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        &url[Position::BeforePath..],
+        host_header,
+        env!("CARGO_PKG_VERSION"),
+        payload.len(),
+        payload
+    );
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| "send failed")?;
+
+    // e.g. "HTTP/1.1 204 No Content", the rest of the response is ignored
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .chain_err(|| "no response")?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("invalid response {:?}", status_line.trim_end()))?;
+    ensure!(
+        (200..300).contains(&status),
+        "responded with status {}",
+        status
+    );
+    Ok(())
+}
@@ -0,0 +1,393 @@
+// An outbound ZMQ PUB socket mirroring the index events, implemented directly (ZMTP 3.0 with
+// the NULL mechanism, over TCP) instead of linking libzmq. As with bitcoind's notifications,
+// each event is a topic frame, a body frame (json here) and a 4-byte little-endian sequence
+// number, counted per topic. The same framing subscribes to bitcoind's `sequence`
+// notifications, which the mempool applies between its full refreshes.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bitcoin::hashes::Hash;
+use crossbeam_channel::{self as channel, Receiver, Sender, TrySendError};
+use serde_json::Value;
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::{
+    chain::Txid,
+    indexer::{mempool::MempoolChanges, query::ChainQuery},
+    metrics::{Gauge, MetricOpts, Metrics},
+    util::{block::HeaderList, spawn_thread, webhook::block_summary},
+};
+
+// the events waiting to be sent to a slow subscriber, the later ones are dropped (as with
+// libzmq's high water mark)
+const QUEUE_SIZE: usize = 10_000;
+// subscription messages are a topic prefix, much shorter than this
+const MAX_INCOMING_FRAME: usize = 1024;
+// far more than the few topics published, and small enough to match each event against
+const MAX_TOPICS: usize = 64;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// the idle time before probing the connection to bitcoind, then the time between the probes
+const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+pub struct Publisher {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    sequences: Mutex<HashMap<&'static str, u32>>,
+}
+
+struct Subscriber {
+    // the topic prefixes subscribed to
+    topics: Arc<Mutex<Vec<Vec<u8>>>>,
+    tx: Sender<Arc<Vec<u8>>>,
+}
+
+impl Publisher {
+    /// Listens for subscribers on `addr`
+    pub fn start(addr: SocketAddr, metrics: &Metrics) -> Self {
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|e| panic!("bind({}) for ZMQ failed: {}", addr, e));
+        info!("ZMQ publisher running on {}", addr);
+        let connections = metrics.gauge(MetricOpts::new(
+            "zmq_subscribers",
+            "# of connected ZMQ subscribers",
+        ));
+
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        let accepted = Arc::clone(&subscribers);
+        spawn_thread("zmq-accept", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("ZMQ subscriber failed to connect: {}", e);
+                        continue;
+                    }
+                };
+                // the handshake is on the connection's thread, so that a silent peer doesn't
+                // hold up the next connections
+                let accepted = Arc::clone(&accepted);
+                let connections = connections.clone();
+                spawn_thread("zmq-subscriber", move || {
+                    if let Err(e) = accept(stream, &accepted, &connections) {
+                        debug!("ZMQ subscriber failed to connect: {}", e);
+                    }
+                });
+            }
+        });
+
+        Publisher {
+            subscribers,
+            sequences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Publish the blocks connected and disconnected between the `old` and `new` header chains
+    pub fn tip_changed(&self, old: &HeaderList, new: &HeaderList, chain: &ChainQuery) {
+        if old.equals(new) {
+            return;
+        }
+        let fork_height = old.fork_height(new);
+        let disconnected: Vec<Value> = old
+            .iter_range(fork_height..old.len())
+            .map(|entry| json!({ "id": entry.hash(), "height": entry.height() }))
+            .collect();
+        if !disconnected.is_empty() {
+            self.publish(
+                "reorg",
+                json!({ "fork_height": fork_height, "disconnected": disconnected }),
+            );
+        }
+        for height in fork_height..new.len() {
+            match block_summary(new, height, chain) {
+                Ok(block) => self.publish("block", block),
+                Err(e) => warn!("no ZMQ event for block #{}: {}", height, e),
+            }
+        }
+    }
+
+    pub fn mempool_changed(&self, changes: &MempoolChanges) {
+        let txid_event = |txid: &Txid| json!({ "txid": txid });
+        for txid in &changes.added {
+            self.publish("mempool.add", txid_event(txid));
+        }
+        for txid in &changes.removed {
+            self.publish("mempool.remove", txid_event(txid));
+        }
+    }
+
+    fn publish(&self, topic: &'static str, body: Value) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let sequence = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let next = sequences.entry(topic).or_insert(0);
+            let sequence = *next;
+            *next = sequence.wrapping_add(1);
+            sequence
+        };
+        let mut message = vec![];
+        write_frame(&mut message, FLAG_MORE, topic.as_bytes());
+        write_frame(&mut message, FLAG_MORE, body.to_string().as_bytes());
+        write_frame(&mut message, 0, &sequence.to_le_bytes());
+        let message = Arc::new(message);
+
+        subscribers.retain(|subscriber| {
+            let subscribed = subscriber
+                .topics
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|prefix| topic.as_bytes().starts_with(prefix));
+            if !subscribed {
+                return true;
+            }
+            match subscriber.tx.try_send(Arc::clone(&message)) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// A notification of bitcoind's `-zmqpubsequence`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceEvent {
+    /// A transaction entered the mempool, with the mempool sequence of the event
+    Added(Txid, u64),
+    /// A transaction left the mempool for another reason than being mined
+    Removed(Txid, u64),
+    /// A block was connected or disconnected, or notifications may have been missed
+    Resync,
+}
+
+/// Subscribes to bitcoind's `sequence` notifications published on `addr`, reconnecting
+/// whenever the connection drops
+pub fn subscribe_sequence(addr: SocketAddr) -> Receiver<SequenceEvent> {
+    let (tx, rx) = channel::unbounded();
+    spawn_thread("zmq-sequence", move || loop {
+        match follow_sequence(addr, &tx) {
+            Ok(()) => break, // the mempool is gone
+            Err(e) => warn!("ZMQ sequence subscription to {} failed: {}", addr, e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+    rx
+}
+
+fn follow_sequence(addr: SocketAddr, tx: &Sender<SequenceEvent>) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, HANDSHAKE_TIMEOUT)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    handshake(&mut stream, "SUB")?;
+    stream.set_read_timeout(None)?;
+    // bitcoind may stay silent for long with a quiet mempool, so a dead connection (that would
+    // otherwise block the reads forever) is detected by the keepalive probes instead
+    SockRef::from(&stream).set_tcp_keepalive(
+        &TcpKeepalive::new()
+            .with_time(KEEPALIVE_TIME)
+            .with_interval(KEEPALIVE_INTERVAL),
+    )?;
+    let mut subscription = vec![];
+    write_frame(&mut subscription, 0, b"\x01sequence");
+    stream.write_all(&subscription)?;
+    info!("following the ZMQ sequence notifications of {}", addr);
+
+    // the notifications published while disconnected are missed
+    if tx.send(SequenceEvent::Resync).is_err() {
+        return Ok(());
+    }
+    loop {
+        let frames = read_message(&mut stream)?;
+        let event = match frames.as_slice() {
+            [topic, body, ..] if topic == b"sequence" => parse_sequence(body)?,
+            _ => continue,
+        };
+        if tx.send(event).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// {hash}{label}[{mempool sequence}], the hash in reversed byte order and the mempool sequence
+// in little-endian, only present for the mempool additions and removals
+fn parse_sequence(body: &[u8]) -> io::Result<SequenceEvent> {
+    let mempool_event = |body: &[u8]| -> io::Result<(Txid, u64)> {
+        if body.len() != 41 {
+            return Err(invalid_data("invalid mempool sequence notification"));
+        }
+        let mut hash = body[..32].to_vec();
+        hash.reverse();
+        let txid = Txid::from_slice(&hash).unwrap();
+        Ok((txid, u64::from_le_bytes(*array_ref![body, 33, 8])))
+    };
+    match body.get(32) {
+        Some(b'A') => mempool_event(body).map(|(txid, seq)| SequenceEvent::Added(txid, seq)),
+        Some(b'R') => mempool_event(body).map(|(txid, seq)| SequenceEvent::Removed(txid, seq)),
+        Some(b'C') | Some(b'D') => Ok(SequenceEvent::Resync),
+        _ => Err(invalid_data("invalid sequence notification")),
+    }
+}
+
+// The frames of the next message, skipping the commands
+fn read_message(stream: &mut TcpStream) -> io::Result<Vec<Vec<u8>>> {
+    let mut frames = vec![];
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & FLAG_MORE == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+// The handshake, then reading the subscriptions while another thread sends the events
+fn accept(
+    mut stream: TcpStream,
+    subscribers: &Mutex<Vec<Subscriber>>,
+    connections: &Gauge,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    handshake(&mut stream, "PUB")?;
+    stream.set_read_timeout(None)?;
+    debug!("ZMQ subscriber {} connected", peer);
+
+    let topics = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = channel::bounded::<Arc<Vec<u8>>>(QUEUE_SIZE);
+
+    let mut writer = stream.try_clone()?;
+    spawn_thread("zmq-publisher", move || {
+        for message in rx {
+            if writer.write_all(&message).is_err() {
+                break;
+            }
+        }
+    });
+    subscribers.lock().unwrap().push(Subscriber {
+        topics: Arc::clone(&topics),
+        tx,
+    });
+
+    connections.inc();
+    if let Err(e) = read_subscriptions(&mut stream, &topics) {
+        debug!("ZMQ subscriber {} disconnected: {}", peer, e);
+    }
+    // stops the sender thread, which drops the queue
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    connections.dec();
+    Ok(())
+}
+
+fn handshake(stream: &mut TcpStream, socket_type: &str) -> io::Result<()> {
+    // signature, version 3.0, the NULL mechanism and as-server (ignored by NULL), then filler
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+
+    let mut peer_greeting = [0u8; 64];
+    stream.read_exact(&mut peer_greeting)?;
+    if peer_greeting[0] != 0xff || peer_greeting[9] != 0x7f || peer_greeting[10] < 3 {
+        return Err(invalid_data("not a ZMTP 3 peer"));
+    }
+    if &peer_greeting[12..16] != b"NULL" {
+        return Err(invalid_data("only the NULL mechanism is supported"));
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    let mut command = vec![];
+    write_frame(&mut command, FLAG_COMMAND, &ready);
+    stream.write_all(&command)?;
+
+    // the peer's READY, whose socket type isn't checked
+    match read_frame(stream)? {
+        (flags, body) if flags & FLAG_COMMAND != 0 && body.get(1..6) == Some(b"READY") => Ok(()),
+        _ => Err(invalid_data("expected a READY command")),
+    }
+}
+
+// Subscriptions are messages starting with 1 (or 0 to unsubscribe), followed by the topic prefix
+fn read_subscriptions(stream: &mut TcpStream, topics: &Mutex<Vec<Vec<u8>>>) -> io::Result<()> {
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue;
+        }
+        match body.split_first() {
+            Some((1, prefix)) => {
+                let mut topics = topics.lock().unwrap();
+                if topics.len() >= MAX_TOPICS {
+                    return Err(invalid_data("too many subscriptions"));
+                }
+                topics.push(prefix.to_vec());
+            }
+            Some((0, prefix)) => {
+                let mut topics = topics.lock().unwrap();
+                if let Some(index) = topics.iter().position(|topic| topic == prefix) {
+                    topics.remove(index);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn write_frame(buf: &mut Vec<u8>, flags: u8, body: &[u8]) {
+    if body.len() > u8::MAX as usize {
+        buf.push(flags | FLAG_LONG);
+        buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        buf.push(flags);
+        buf.push(body.len() as u8);
+    }
+    buf.extend_from_slice(body);
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8];
+    stream.read_exact(&mut flags)?;
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size)?;
+        u64::from_be_bytes(size) as usize
+    } else {
+        let mut size = [0u8];
+        stream.read_exact(&mut size)?;
+        size[0] as usize
+    };
+    if size > MAX_INCOMING_FRAME {
+        return Err(invalid_data("frame too large"));
+    }
+    let mut body = vec![0u8; size];
+    stream.read_exact(&mut body)?;
+    Ok((flags, body))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}